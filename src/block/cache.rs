@@ -0,0 +1,441 @@
+// A buffer cache shared by every block device. Devices are handed over to
+// the cache, which gives back a CachedDisk to use in their place. Reads are
+// served from memory when the block is already cached, and writes stay in
+// memory until their buffer is needed for another block or the device is
+// flushed, so slow devices are touched as little as possible. When every
+// buffer is taken, the least recently used one is reused.
+
+use super::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::global_constants::{BUFFER_CACHE_SIZE, MAX_CACHED_DEVICES};
+use crate::lock::InterruptMutex;
+use crate::memman::MemManager;
+use core::cmp::max;
+use core::ptr::{null_mut, write_volatile};
+
+// The cache shared by the kernel, set up by BufferCache::init
+pub static mut BUFFER_CACHE: *mut BufferCache = null_mut();
+
+// Device of buffers that don't hold a block
+const NO_DEVICE: u32 = core::u32::MAX;
+
+struct Buffer {
+    // Index of the device the block belongs to, or NO_DEVICE
+    device: u32,
+    block_number: u32,
+    // Set when the buffer has changes that aren't on the device yet
+    dirty: bool,
+    // Value of the cache's clock when the buffer was last used
+    last_used: u32,
+}
+
+pub struct BufferCache {
+    // Held for every operation, since both the trap path and processes
+    // use the cache. Interrupts stay off while it's held, so a system call
+    // can't interrupt a process holding it and then wait on it forever.
+    lock: InterruptMutex,
+    // Headers of the BUFFER_CACHE_SIZE buffers
+    buffers: *mut Buffer,
+    // Contents of the buffers, one block each
+    data: *mut u8,
+    // Devices handed over to the cache, indexed by the device number kept
+    // in the buffers
+    devices: [Option<*mut dyn BlockDevice>; MAX_CACHED_DEVICES],
+    // Counts uses of buffers to find the least recently used one
+    clock: u32,
+    // Number of reads that found their block cached, and that didn't
+    hits: u32,
+    misses: u32,
+}
+
+// A device whose blocks go through the buffer cache
+pub struct CachedDisk {
+    cache: *mut BufferCache,
+    // Index of the device in the cache
+    device: u32,
+    block_count: u32,
+    writable: bool,
+}
+
+impl BufferCache {
+    // Allocates the kernel's buffer cache
+    pub fn init() -> Result<(), BlockError> {
+        let cache = BufferCache::new()?;
+        unsafe {
+            let address =
+                match MemManager::kmalloc(core::mem::size_of::<BufferCache>()) {
+                    Ok(address) => address as *mut BufferCache,
+                    Err(_) => return Err(BlockError::OutOfMemory),
+                };
+            write_volatile(address, cache);
+            BUFFER_CACHE = address;
+        }
+        Ok(())
+    }
+
+    // Makes a cache with every buffer empty
+    fn new() -> Result<BufferCache, BlockError> {
+        let buffers = match MemManager::kmalloc(BUFFER_CACHE_SIZE *
+                                                core::mem::size_of::<Buffer>())
+        {
+            Ok(address) => address as *mut Buffer,
+            Err(_) => return Err(BlockError::OutOfMemory),
+        };
+        let data = match MemManager::kmalloc(BUFFER_CACHE_SIZE *
+                                             SECTOR_SIZE as usize)
+        {
+            Ok(address) => address as *mut u8,
+            Err(_) => {
+                MemManager::kfree(buffers as u32).ok();
+                return Err(BlockError::OutOfMemory);
+            }
+        };
+
+        for index in 0..BUFFER_CACHE_SIZE {
+            unsafe {
+                write_volatile(buffers.add(index),
+                               Buffer { device: NO_DEVICE,
+                                        block_number: 0,
+                                        dirty: false,
+                                        last_used: 0 });
+            }
+        }
+
+        Ok(BufferCache { lock: InterruptMutex::new(),
+                         buffers: buffers,
+                         data: data,
+                         devices: [None; MAX_CACHED_DEVICES],
+                         clock: 0,
+                         hits: 0,
+                         misses: 0 })
+    }
+
+    // Hands |disk| over to the cache and returns the device to use instead.
+    // The disk is dropped along with the CachedDisk.
+    pub fn register<B: BlockDevice + 'static>(
+        &mut self,
+        disk: B)
+        -> Result<CachedDisk, BlockError> {
+        // Buffers are only big enough for blocks of up to a sector
+        if disk.block_size() != SECTOR_SIZE {
+            return Err(BlockError::BadBuffer);
+        }
+
+        let block_count = disk.block_count();
+        let writable = disk.is_writable();
+
+        // The cache owns the disk from here on, so it gets a copy on the
+        // heap
+        let size = max(core::mem::size_of::<B>(), 1);
+        let address = match MemManager::kmalloc(size) {
+            Ok(address) => address as *mut B,
+            Err(_) => return Err(BlockError::OutOfMemory),
+        };
+        unsafe {
+            write_volatile(address, disk);
+        }
+
+        self.lock.lock();
+        let slot = self.devices.iter().position(|device| device.is_none());
+        if let Some(device) = slot {
+            self.devices[device] = Some(address as *mut dyn BlockDevice);
+        }
+        self.lock.unlock();
+
+        match slot {
+            Some(device) => Ok(CachedDisk { cache: self as *mut BufferCache,
+                                            device: device as u32,
+                                            block_count: block_count,
+                                            writable: writable }),
+            None => {
+                unsafe {
+                    core::ptr::drop_in_place(address);
+                }
+                MemManager::kfree(address as u32).ok();
+                Err(BlockError::TooManyDevices)
+            }
+        }
+    }
+
+    // Writes every changed buffer back to its device
+    pub fn flush_all(&mut self) -> Result<(), BlockError> {
+        self.lock.lock();
+        let mut result = Ok(());
+        for index in 0..BUFFER_CACHE_SIZE {
+            if let Err(e) = self.write_back(index) {
+                result = Err(e);
+            }
+        }
+        self.lock.unlock();
+        result
+    }
+
+    // Returns the number of reads that found their block in the cache
+    pub fn hits(&self) -> u32 {
+        self.hits
+    }
+
+    // Returns the number of reads that had to go to a device
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    // Copies the block |block_number| of |device| into |buf|
+    fn read(&mut self,
+            device: u32,
+            block_number: u32,
+            buf: &mut [u8])
+            -> Result<(), BlockError> {
+        self.lock.lock();
+        let result = self.read_locked(device, block_number, buf);
+        self.lock.unlock();
+        result
+    }
+
+    fn read_locked(&mut self,
+                   device: u32,
+                   block_number: u32,
+                   buf: &mut [u8])
+                   -> Result<(), BlockError> {
+        let index = match self.find(device, block_number) {
+            Some(index) => {
+                self.hits += 1;
+                index
+            }
+            None => {
+                self.misses += 1;
+                let index = self.take_buffer()?;
+                let disk = self.disk(device);
+                unsafe {
+                    (*disk).read_block(block_number,
+                                       self.buffer_data_mut(index))?;
+                }
+
+                let buffer = self.buffer_mut(index);
+                buffer.device = device;
+                buffer.block_number = block_number;
+                index
+            }
+        };
+
+        self.touch(index);
+        buf.copy_from_slice(self.buffer_data(index));
+        Ok(())
+    }
+
+    // Replaces the block |block_number| of |device| with |buf| in the cache.
+    // The device sees the change once the buffer is written back.
+    fn write(&mut self,
+             device: u32,
+             block_number: u32,
+             buf: &[u8])
+             -> Result<(), BlockError> {
+        self.lock.lock();
+        let result = self.write_locked(device, block_number, buf);
+        self.lock.unlock();
+        result
+    }
+
+    fn write_locked(&mut self,
+                    device: u32,
+                    block_number: u32,
+                    buf: &[u8])
+                    -> Result<(), BlockError> {
+        // The whole block is replaced, so there's no need to read it first
+        let index = match self.find(device, block_number) {
+            Some(index) => index,
+            None => self.take_buffer()?,
+        };
+
+        self.buffer_data_mut(index).copy_from_slice(buf);
+        let buffer = self.buffer_mut(index);
+        buffer.device = device;
+        buffer.block_number = block_number;
+        buffer.dirty = true;
+        self.touch(index);
+        Ok(())
+    }
+
+    // Writes the changed buffers of |device| back to it
+    fn flush(&mut self, device: u32) -> Result<(), BlockError> {
+        self.lock.lock();
+        let mut result = Ok(());
+        for index in 0..BUFFER_CACHE_SIZE {
+            if self.buffer(index).device == device {
+                if let Err(e) = self.write_back(index) {
+                    result = Err(e);
+                }
+            }
+        }
+        self.lock.unlock();
+        result
+    }
+
+    // Writes back and forgets every buffer of |device|, then drops the disk
+    // behind it. Changes that can't be written back are lost.
+    fn release(&mut self, device: u32) {
+        self.lock.lock();
+        for index in 0..BUFFER_CACHE_SIZE {
+            if self.buffer(index).device == device {
+                self.write_back(index).ok();
+                let buffer = self.buffer_mut(index);
+                buffer.device = NO_DEVICE;
+                buffer.dirty = false;
+            }
+        }
+
+        if let Some(disk) = self.devices[device as usize].take() {
+            unsafe {
+                core::ptr::drop_in_place(disk);
+            }
+            MemManager::kfree(disk as *mut u8 as u32).ok();
+        }
+        self.lock.unlock();
+    }
+
+    // Finds the buffer holding the block |block_number| of |device|
+    fn find(&self, device: u32, block_number: u32) -> Option<usize> {
+        (0..BUFFER_CACHE_SIZE).find(|index| {
+                                  let buffer = self.buffer(*index);
+                                  buffer.device == device &&
+                                  buffer.block_number == block_number
+                              })
+    }
+
+    // Empties a buffer to hold another block, preferring unused buffers and
+    // then the least recently used one. Returns the buffer's index.
+    fn take_buffer(&mut self) -> Result<usize, BlockError> {
+        let index = (0..BUFFER_CACHE_SIZE).min_by_key(|index| {
+                                              let buffer = self.buffer(*index);
+                                              if buffer.device == NO_DEVICE {
+                                                  0
+                                              } else {
+                                                  buffer.last_used as u64 + 1
+                                              }
+                                          })
+                                          .unwrap_or(0);
+
+        // A buffer whose changes can't be written back is kept
+        self.write_back(index)?;
+        self.buffer_mut(index).device = NO_DEVICE;
+        Ok(index)
+    }
+
+    // Writes the buffer |index| back to its device if it was changed
+    fn write_back(&mut self, index: usize) -> Result<(), BlockError> {
+        let (device, block_number, dirty) = {
+            let buffer = self.buffer(index);
+            (buffer.device, buffer.block_number, buffer.dirty)
+        };
+        if device == NO_DEVICE || !dirty {
+            return Ok(());
+        }
+
+        let disk = self.disk(device);
+        unsafe {
+            (*disk).write_block(block_number, self.buffer_data(index))?;
+        }
+        self.buffer_mut(index).dirty = false;
+        Ok(())
+    }
+
+    // Marks the buffer |index| as the most recently used
+    fn touch(&mut self, index: usize) {
+        // Start the clock over rather than letting it wrap around, which
+        // would make the newest buffers look like the oldest
+        if self.clock == core::u32::MAX {
+            for other in 0..BUFFER_CACHE_SIZE {
+                self.buffer_mut(other).last_used = 0;
+            }
+            self.clock = 0;
+        }
+
+        self.clock += 1;
+        self.buffer_mut(index).last_used = self.clock;
+    }
+
+    // Returns the header of the buffer |index|
+    fn buffer(&self, index: usize) -> &Buffer {
+        unsafe { &*self.buffers.add(index) }
+    }
+
+    fn buffer_mut(&mut self, index: usize) -> &mut Buffer {
+        unsafe { &mut *self.buffers.add(index) }
+    }
+
+    // Returns the contents of the buffer |index|
+    fn buffer_data(&self, index: usize) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self.data
+                                            .add(index * SECTOR_SIZE as usize),
+                                        SECTOR_SIZE as usize)
+        }
+    }
+
+    fn buffer_data_mut(&mut self, index: usize) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.data
+                                                .add(index *
+                                                     SECTOR_SIZE as usize),
+                                            SECTOR_SIZE as usize)
+        }
+    }
+
+    // Returns the disk registered as |device|. Using it while the buffers
+    // are borrowed is up to the caller.
+    fn disk(&self, device: u32) -> *mut dyn BlockDevice {
+        self.devices[device as usize].unwrap()
+    }
+}
+
+impl CachedDisk {
+    // Returns the CachedDisk's device number in the cache
+    pub fn device(&self) -> u32 {
+        self.device
+    }
+}
+
+impl BlockDevice for CachedDisk {
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+        unsafe { (*self.cache).read(self.device, block_number, buf) }
+    }
+
+    fn write_block(&mut self,
+                   block_number: u32,
+                   buf: &[u8])
+                   -> Result<(), BlockError> {
+        if !self.writable {
+            return Err(BlockError::ReadOnly);
+        }
+        check_transfer(self, block_number, buf.len())?;
+        unsafe { (*self.cache).write(self.device, block_number, buf) }
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        unsafe { (*self.cache).flush(self.device) }
+    }
+}
+
+impl Drop for CachedDisk {
+    // Writes back the disk's changes and gives the disk back to be dropped
+    fn drop(&mut self) {
+        unsafe {
+            (*self.cache).release(self.device);
+        }
+    }
+}
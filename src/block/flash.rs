@@ -0,0 +1,67 @@
+// The filesystem image linked into the kernel by asm/fs.S. It lives in
+// .rodata, so it can only be read.
+
+use super::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+use core::ptr::read_volatile;
+
+// Filesystem Labels from Assembly
+extern "C" {
+    static FILE_SYSTEM: u8;
+    static FILE_SYSTEM_END: u8;
+}
+
+pub struct FlashImage {
+    start: *const u8,
+    block_count: u32,
+}
+
+impl FlashImage {
+    // Makes a device covering the whole linked-in image
+    pub fn new() -> FlashImage {
+        unsafe {
+            let start = &FILE_SYSTEM as *const u8;
+            let length =
+                &FILE_SYSTEM_END as *const u8 as usize - start as usize;
+            FlashImage { start: start,
+                         block_count: length as u32 / SECTOR_SIZE }
+        }
+    }
+}
+
+impl BlockDevice for FlashImage {
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+
+        let block = unsafe {
+            self.start.add(block_number as usize * SECTOR_SIZE as usize)
+        };
+        for (i, byte) in buf.iter_mut().enumerate() {
+            unsafe {
+                *byte = read_volatile(block.add(i));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self,
+                   _block_number: u32,
+                   _buf: &[u8])
+                   -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,172 @@
+// Block devices: storage that is read and written in fixed size blocks.
+// Filesystems only talk to storage through the BlockDevice trait, so the
+// same driver works on the image linked into the kernel, on a copy of it in
+// RAM, or on any other buffer holding a disk image.
+
+pub mod cache;
+pub mod flash;
+pub mod partition;
+pub mod ramdisk;
+pub mod slice;
+
+pub use cache::BufferCache;
+pub use flash::FlashImage;
+pub use partition::{Partition, PartitionTable};
+pub use ramdisk::RamDisk;
+#[cfg(feature = "testing")]
+pub use slice::SliceDisk;
+
+use core::cmp::min;
+
+// Size of the blocks of the devices here, which matches a disk sector
+pub const SECTOR_SIZE: u32 = 512;
+
+// Errors returned by block devices
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BlockError {
+    // The block number is past the end of the device
+    OutOfRange,
+    // The buffer isn't exactly one block long
+    BadBuffer,
+    // The device can't be written to
+    ReadOnly,
+    // There wasn't enough memory to hold the device
+    OutOfMemory,
+    // Every device slot of the buffer cache is taken
+    TooManyDevices,
+    // The disk's partition table is damaged or has partitions past the end
+    // of the disk
+    BadPartitionTable,
+}
+
+pub trait BlockDevice {
+    // Returns the size in bytes of each block
+    fn block_size(&self) -> u32;
+
+    // Returns the number of blocks on the device
+    fn block_count(&self) -> u32;
+
+    // Copies the block |block_number| into |buf|, which has to be one block
+    // long
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError>;
+
+    // Overwrites the block |block_number| with |buf|, which has to be one
+    // block long
+    fn write_block(&mut self,
+                   block_number: u32,
+                   buf: &[u8])
+                   -> Result<(), BlockError>;
+
+    // Checks whether write_block can succeed at all, so that callers can
+    // refuse to start changes they couldn't finish
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    // Makes sure every block written so far has reached the storage. Only
+    // devices that hold on to writes have anything to do.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+// Checks the arguments of a block transfer on |device|
+fn check_transfer<D: BlockDevice + ?Sized>(device: &D,
+                                           block_number: u32,
+                                           length: usize)
+                                           -> Result<(), BlockError> {
+    if block_number >= device.block_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    if length != device.block_size() as usize {
+        return Err(BlockError::BadBuffer);
+    }
+
+    Ok(())
+}
+
+// Copies the bytes at |position| on |disk| into |buf| one device block at a
+// time. Filesystems whose blocks don't match the device's address the disk
+// by byte through this and write_at.
+pub fn read_at<D: BlockDevice + ?Sized>(disk: &D,
+                                        position: u64,
+                                        buf: &mut [u8])
+                                        -> Result<(), BlockError> {
+    let sector_size = disk.block_size() as usize;
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    let mut done = 0;
+
+    while done < buf.len() {
+        let (sector_number, start) = sector_of(disk, position + done as u64)?;
+        let count = min(sector_size - start, buf.len() - done);
+        if count == sector_size {
+            // Whole device blocks can go straight into |buf|
+            disk.read_block(sector_number, &mut buf[done..done + count])?;
+        } else {
+            disk.read_block(sector_number, &mut sector[..sector_size])?;
+            buf[done..done + count].copy_from_slice(&sector[start..
+                                                            start + count]);
+        }
+        done += count;
+    }
+
+    Ok(())
+}
+
+// Copies |buf| onto |disk| at |position|. Device blocks that are only partly
+// covered are read first so the rest of them is kept.
+pub fn write_at<D: BlockDevice + ?Sized>(disk: &mut D,
+                                         position: u64,
+                                         buf: &[u8])
+                                         -> Result<(), BlockError> {
+    let sector_size = disk.block_size() as usize;
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    let mut done = 0;
+
+    while done < buf.len() {
+        let (sector_number, start) = sector_of(disk, position + done as u64)?;
+        let count = min(sector_size - start, buf.len() - done);
+        if count == sector_size {
+            disk.write_block(sector_number, &buf[done..done + count])?;
+        } else {
+            disk.read_block(sector_number, &mut sector[..sector_size])?;
+            sector[start..start + count].copy_from_slice(&buf[done..
+                                                              done + count]);
+            disk.write_block(sector_number, &sector[..sector_size])?;
+        }
+        done += count;
+    }
+
+    Ok(())
+}
+
+// Reads a T stored at the byte |position| of |disk|. T has to be one of the
+// plain on-disk structures, for which any bytes make a valid value.
+pub fn read_struct_at<T, D: BlockDevice + ?Sized>(disk: &D,
+                                                  position: u64)
+                                                  -> Result<T, BlockError> {
+    let mut value: T = unsafe { core::mem::zeroed() };
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8,
+                                        core::mem::size_of::<T>())
+    };
+    read_at(disk, position, bytes)?;
+    Ok(value)
+}
+
+// Finds the device block holding the byte |position| of |disk| and the
+// offset of the byte within it
+fn sector_of<D: BlockDevice + ?Sized>(disk: &D,
+                                      position: u64)
+                                      -> Result<(u32, usize), BlockError> {
+    let sector_size = disk.block_size() as u64;
+    let sector_number = position / sector_size;
+    if sector_number >= disk.block_count() as u64 {
+        return Err(BlockError::OutOfRange);
+    }
+
+    Ok((sector_number as u32, (position % sector_size) as usize))
+}
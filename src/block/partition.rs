@@ -0,0 +1,439 @@
+// Partition tables, which divide a disk into several filesystems. An MBR
+// table is the last 66 bytes of the disk's first block, and a GPT table is
+// in the blocks after a protective MBR, with a backup copy at the end of the
+// disk. Partitions are numbered from 1 the way Linux names them: MBR
+// partitions by their slot and GPT partitions by their entry. Logical
+// partitions inside an MBR extended partition aren't listed.
+
+use super::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::global_constants::MAX_PARTITIONS;
+use core::fmt;
+
+// Where the four MBR entries start, and the signature that ends the block
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+// MBR type of the partition covering a disk that has a GPT table
+const MBR_TYPE_GPT: u8 = 0xEE;
+
+// Block of the primary GPT header
+const GPT_HEADER_BLOCK: u32 = 1;
+
+// What a GPT header starts with
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+// Smallest GPT header, which is as far as the fields in revision 1.0 go
+const GPT_MIN_HEADER_SIZE: usize = 92;
+
+// Smallest GPT partition entry
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+
+// Type GUIDs, stored the way they are on disk, and the MBR types that mean
+// the same thing
+pub const LINUX_FILESYSTEM: Guid = Guid([0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84,
+                                         0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69,
+                                         0xD8, 0x47, 0x7D, 0xE4]);
+pub const MICROSOFT_BASIC_DATA: Guid =
+    Guid([0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68,
+          0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+pub const EFI_SYSTEM: Guid = Guid([0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2,
+                                   0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+                                   0xC9, 0x3B]);
+const MBR_EQUIVALENTS: [(u8, Guid); 5] = [(0x83, LINUX_FILESYSTEM),
+                                          (0x07, MICROSOFT_BASIC_DATA),
+                                          (0x0B, MICROSOFT_BASIC_DATA),
+                                          (0x0C, MICROSOFT_BASIC_DATA),
+                                          (0xEF, EFI_SYSTEM)];
+
+// A GUID in the mixed endian layout GPT uses, where the first three groups
+// are little endian and the rest are stored as written
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Guid(pub [u8; 16]);
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+// What a partition is meant to hold
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PartitionType {
+    // The type byte of an MBR entry
+    Mbr(u8),
+    // The type GUID of a GPT entry
+    Gpt(Guid),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct PartitionInfo {
+    // Number of the partition, from 1
+    pub number: u32,
+    pub kind: PartitionType,
+    // Blocks of the disk the partition covers
+    pub first_block: u32,
+    pub block_count: u32,
+}
+
+pub struct PartitionTable {
+    scheme: Scheme,
+    // The partitions in the order of their numbers. Only the first
+    // MAX_PARTITIONS are kept.
+    partitions: [PartitionInfo; MAX_PARTITIONS],
+    count: usize,
+}
+
+// The blocks of one partition, as a device of their own
+pub struct Partition<B: BlockDevice> {
+    disk: B,
+    first_block: u32,
+    block_count: u32,
+}
+
+impl Guid {
+    // Parses a GUID written like "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+    pub fn parse(text: &str) -> Option<Guid> {
+        // Where each byte of the text ends up on disk
+        const ORDER: [usize; 16] =
+            [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+        let text = text.as_bytes();
+        if text.len() != 36 {
+            return None;
+        }
+
+        let mut guid = [0u8; 16];
+        let mut digits = 0;
+        for (i, byte) in text.iter().enumerate() {
+            if i == 8 || i == 13 || i == 18 || i == 23 {
+                if *byte != b'-' {
+                    return None;
+                }
+                continue;
+            }
+            let digit = (*byte as char).to_digit(16)? as u8;
+            guid[ORDER[digits / 2]] |= digit << (4 * (1 - digits % 2));
+            digits += 1;
+        }
+        Some(Guid(guid))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f,
+               "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+               b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6])?;
+        write!(f, "{:02X}{:02X}-", b[8], b[9])?;
+        for byte in b[10..].iter() {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartitionType {
+    // Checks whether the partition has the type |guid|. MBR types count if
+    // they're the usual equivalent of the GUID.
+    pub fn matches(&self, guid: &Guid) -> bool {
+        match self {
+            PartitionType::Gpt(kind) => kind == guid,
+            PartitionType::Mbr(kind) => {
+                MBR_EQUIVALENTS.iter()
+                               .any(|(mbr, gpt)| mbr == kind && gpt == guid)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(kind) => write!(f, "type {:02X}", kind),
+            PartitionType::Gpt(kind) => write!(f, "type {}", kind),
+        }
+    }
+}
+
+impl fmt::Display for PartitionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}: blocks {}-{}, {}",
+               self.number,
+               self.first_block,
+               self.first_block + self.block_count - 1,
+               self.kind)
+    }
+}
+
+impl PartitionTable {
+    // Reads the partition table of |disk|, or returns None if the disk
+    // doesn't start with one
+    pub fn read<D: BlockDevice + ?Sized>(
+        disk: &D)
+        -> Result<Option<PartitionTable>, BlockError> {
+        let mut block = [0u8; SECTOR_SIZE as usize];
+        if disk.block_count() == 0 {
+            return Ok(None);
+        }
+        disk.read_block(0, &mut block)?;
+        if block[MBR_SIGNATURE_OFFSET..] != MBR_SIGNATURE {
+            return Ok(None);
+        }
+
+        let mut table = PartitionTable::new(Scheme::Mbr);
+        for slot in 0..4 {
+            let start = MBR_ENTRIES_OFFSET + slot * MBR_ENTRY_SIZE;
+            let entry = &block[start..start + MBR_ENTRY_SIZE];
+            // Only bit 7 of the status can be set, so anything else is boot
+            // code rather than a table
+            if entry[0] & 0x7F != 0 {
+                return Ok(None);
+            }
+
+            let kind = entry[4];
+            let first_block = le_u32(&entry[8..12]);
+            let block_count = le_u32(&entry[12..16]);
+            if kind == MBR_TYPE_GPT {
+                return PartitionTable::read_gpt(disk, &mut block).map(Some);
+            }
+            if kind == 0 || block_count == 0 {
+                continue;
+            }
+            table.add(disk,
+                      PartitionInfo { number: slot as u32 + 1,
+                                      kind: PartitionType::Mbr(kind),
+                                      first_block: first_block,
+                                      block_count: block_count })?;
+        }
+
+        if table.count == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(table))
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    pub fn partitions(&self) -> &[PartitionInfo] {
+        &self.partitions[..self.count]
+    }
+
+    // Returns the partition numbered |number|
+    pub fn by_number(&self, number: u32) -> Option<PartitionInfo> {
+        self.partitions()
+            .iter()
+            .find(|partition| partition.number == number)
+            .cloned()
+    }
+
+    // Returns the first partition with the type |guid|
+    pub fn by_type(&self, guid: &Guid) -> Option<PartitionInfo> {
+        self.partitions()
+            .iter()
+            .find(|partition| partition.kind.matches(guid))
+            .cloned()
+    }
+
+    fn new(scheme: Scheme) -> PartitionTable {
+        PartitionTable { scheme: scheme,
+                         partitions: [PartitionInfo { number: 0,
+                                                     kind:
+                                                         PartitionType::Mbr(0),
+                                                     first_block: 0,
+                                                     block_count: 0 };
+                                      MAX_PARTITIONS],
+                         count: 0 }
+    }
+
+    // Adds |partition| if there's room, after checking that it fits on
+    // |disk|
+    fn add<D: BlockDevice + ?Sized>(&mut self,
+                                    disk: &D,
+                                    partition: PartitionInfo)
+                                    -> Result<(), BlockError> {
+        match partition.first_block.checked_add(partition.block_count) {
+            Some(end) if end <= disk.block_count() => {}
+            _ => return Err(BlockError::BadPartitionTable),
+        }
+        if self.count < MAX_PARTITIONS {
+            self.partitions[self.count] = partition;
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    // Reads the GPT table from the primary header, or from the backup at
+    // the end of the disk if the primary is damaged. |block| is the sector
+    // buffer read already used for the MBR, which keeps a second one off
+    // the boot stack.
+    fn read_gpt<D: BlockDevice + ?Sized>(
+        disk: &D,
+        block: &mut [u8; SECTOR_SIZE as usize])
+        -> Result<PartitionTable, BlockError> {
+        if let Some(table) =
+            PartitionTable::read_gpt_at(disk, GPT_HEADER_BLOCK, block)?
+        {
+            return Ok(table);
+        }
+        let backup = disk.block_count() - 1;
+        match PartitionTable::read_gpt_at(disk, backup, block)? {
+            Some(table) => Ok(table),
+            None => Err(BlockError::BadPartitionTable),
+        }
+    }
+
+    // Reads the GPT table whose header is in |header_block|, or returns None
+    // if the header or its entries fail their checksums. The header and then
+    // the entries are read into |block|.
+    fn read_gpt_at<D: BlockDevice + ?Sized>(
+        disk: &D,
+        header_block: u32,
+        block: &mut [u8; SECTOR_SIZE as usize])
+        -> Result<Option<PartitionTable>, BlockError> {
+        disk.read_block(header_block, block)?;
+        if !block.starts_with(GPT_SIGNATURE) {
+            return Ok(None);
+        }
+
+        // The header's checksum is taken with its own field zeroed
+        let header_size = le_u32(&block[12..16]) as usize;
+        if header_size < GPT_MIN_HEADER_SIZE || header_size > block.len() {
+            return Ok(None);
+        }
+        let header_crc = le_u32(&block[16..20]);
+        for byte in block[16..20].iter_mut() {
+            *byte = 0;
+        }
+        if crc32(!0, &block[..header_size]) != !header_crc {
+            return Ok(None);
+        }
+
+        let entries_block = le_u64(&block[72..80]);
+        let entry_count = le_u32(&block[80..84]) as usize;
+        let entry_size = le_u32(&block[84..88]) as usize;
+        let entries_crc = le_u32(&block[88..92]);
+        if entry_size < GPT_MIN_ENTRY_SIZE ||
+           block.len() % entry_size != 0 ||
+           entries_block >= disk.block_count() as u64
+        {
+            return Ok(None);
+        }
+
+        let mut table = PartitionTable::new(Scheme::Gpt);
+        let per_block = block.len() / entry_size;
+        let mut crc = !0;
+        for index in 0..entry_count {
+            if index % per_block == 0 {
+                let number = entries_block + (index / per_block) as u64;
+                if number >= disk.block_count() as u64 {
+                    return Ok(None);
+                }
+                disk.read_block(number as u32, block)?;
+            }
+            let start = index % per_block * entry_size;
+            let entry = &block[start..start + entry_size];
+            crc = crc32(crc, entry);
+
+            let mut kind = [0u8; 16];
+            kind.copy_from_slice(&entry[..16]);
+            if kind == [0; 16] {
+                continue;
+            }
+            let first = le_u64(&entry[32..40]);
+            let last = le_u64(&entry[40..48]);
+            if last < first || last >= disk.block_count() as u64 {
+                return Err(BlockError::BadPartitionTable);
+            }
+            table.add(disk,
+                      PartitionInfo { number: index as u32 + 1,
+                                      kind: PartitionType::Gpt(Guid(kind)),
+                                      first_block: first as u32,
+                                      block_count: (last - first + 1)
+                                                   as u32 })?;
+        }
+
+        if crc != !entries_crc {
+            return Ok(None);
+        }
+        Ok(Some(table))
+    }
+}
+
+impl<B: BlockDevice> Partition<B> {
+    // Makes a device of the blocks of |disk| that |partition| covers
+    pub fn new(disk: B,
+               partition: &PartitionInfo)
+               -> Result<Partition<B>, BlockError> {
+        match partition.first_block.checked_add(partition.block_count) {
+            Some(end) if end <= disk.block_count() => {}
+            _ => return Err(BlockError::OutOfRange),
+        }
+        Ok(Partition { disk: disk,
+                       first_block: partition.first_block,
+                       block_count: partition.block_count })
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for Partition<B> {
+    fn block_size(&self) -> u32 {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+        self.disk.read_block(self.first_block + block_number, buf)
+    }
+
+    fn write_block(&mut self,
+                   block_number: u32,
+                   buf: &[u8])
+                   -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+        self.disk.write_block(self.first_block + block_number, buf)
+    }
+
+    fn is_writable(&self) -> bool {
+        self.disk.is_writable()
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 |
+    (bytes[1] as u32) << 8 |
+    (bytes[2] as u32) << 16 |
+    (bytes[3] as u32) << 24
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    le_u32(&bytes[..4]) as u64 | (le_u32(&bytes[4..8]) as u64) << 32
+}
+
+// Continues the CRC-32 |crc| over |bytes|, without the final inversion. GPT
+// uses the same CRC as zlib, which starts from !0 and inverts at the end.
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
@@ -0,0 +1,192 @@
+// A writable disk held in memory from the MemManager. Filesystems that need
+// to be modified are copied onto one of these out of read-only storage, and
+// tests build small images on them. Blocks are kept in pieces of PIECE_SIZE
+// bytes and pieces that are all zeros aren't kept at all, so a disk only
+// takes memory for what's been written to it. Freshly made filesystems are
+// mostly zeros, which is what lets them fit in the heap.
+
+use super::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memman::MemManager;
+use core::ptr::{null_mut, write_volatile};
+
+// Size of the pieces blocks are kept in, which matches the inodes of
+// revision 0 images
+const PIECE_SIZE: usize = 128;
+
+const PIECES_PER_BLOCK: usize = SECTOR_SIZE as usize / PIECE_SIZE;
+
+pub struct RamDisk {
+    // Pieces that aren't all zeros, in order of where they are on the disk
+    pieces: *mut Piece,
+    block_count: u32,
+}
+
+struct Piece {
+    next: *mut Piece,
+    // Position of the piece on the disk, counted in pieces
+    index: u32,
+    data: [u8; PIECE_SIZE],
+}
+
+impl RamDisk {
+    // Makes a disk of |block_count| zeroed blocks, which take no memory until
+    // they're written to
+    pub fn new(block_count: u32) -> Result<RamDisk, BlockError> {
+        Ok(RamDisk { pieces: null_mut(),
+                     block_count: block_count })
+    }
+
+    // Makes a disk holding a copy of everything on |device|
+    pub fn copy_of<D: BlockDevice + ?Sized>(device: &D)
+                                            -> Result<RamDisk, BlockError> {
+        if device.block_size() != SECTOR_SIZE {
+            return Err(BlockError::BadBuffer);
+        }
+
+        let mut disk = RamDisk::new(device.block_count())?;
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        for block_number in 0..device.block_count() {
+            device.read_block(block_number, &mut buf)?;
+            disk.write_block(block_number, &buf)?;
+        }
+
+        Ok(disk)
+    }
+
+    // Returns the number of bytes of the disk kept in memory
+    pub fn memory_used(&self) -> usize {
+        let mut count = 0;
+        let mut piece = self.pieces;
+        while !piece.is_null() {
+            count += 1;
+            piece = unsafe { (*piece).next };
+        }
+        count * PIECE_SIZE
+    }
+
+    // Returns the first piece at or after |index|
+    fn find(&self, index: u32) -> *mut Piece {
+        let mut piece = self.pieces;
+        unsafe {
+            while !piece.is_null() && (*piece).index < index {
+                piece = (*piece).next;
+            }
+        }
+        piece
+    }
+
+    // Returns the link pointing at the first piece at or after |index|, which
+    // is where a piece for |index| belongs
+    fn link(&mut self, index: u32) -> *mut *mut Piece {
+        let mut link = &mut self.pieces as *mut *mut Piece;
+        unsafe {
+            while !(*link).is_null() && (**link).index < index {
+                link = &mut (**link).next;
+            }
+        }
+        link
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+
+        let first = block_number * PIECES_PER_BLOCK as u32;
+        let mut piece = self.find(first);
+        for (index, chunk) in (first..).zip(buf.chunks_mut(PIECE_SIZE)) {
+            unsafe {
+                if !piece.is_null() && (*piece).index == index {
+                    chunk.copy_from_slice(&(*piece).data);
+                    piece = (*piece).next;
+                } else {
+                    for byte in chunk.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self,
+                   block_number: u32,
+                   buf: &[u8])
+                   -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+
+        // Pieces the block needs are allocated before anything changes, so
+        // running out of memory doesn't leave half of the block written
+        let first = block_number * PIECES_PER_BLOCK as u32;
+        let mut new_pieces = [null_mut(); PIECES_PER_BLOCK];
+        for (i, chunk) in buf.chunks(PIECE_SIZE).enumerate() {
+            let index = first + i as u32;
+            let piece = self.find(index);
+            let present =
+                unsafe { !piece.is_null() && (*piece).index == index };
+            if present || chunk.iter().all(|byte| *byte == 0) {
+                continue;
+            }
+
+            match MemManager::kmalloc(core::mem::size_of::<Piece>()) {
+                Ok(address) => new_pieces[i] = address as *mut Piece,
+                Err(_) => {
+                    for piece in new_pieces.iter().filter(|p| !p.is_null()) {
+                        MemManager::kfree(*piece as u32).ok();
+                    }
+                    return Err(BlockError::OutOfMemory);
+                }
+            }
+        }
+
+        for (i, chunk) in buf.chunks(PIECE_SIZE).enumerate() {
+            let index = first + i as u32;
+            let link = self.link(index);
+            unsafe {
+                if !(*link).is_null() && (**link).index == index {
+                    let piece = *link;
+                    if chunk.iter().all(|byte| *byte == 0) {
+                        // Zeros read back the same without the piece
+                        *link = (*piece).next;
+                        MemManager::kfree(piece as u32).ok();
+                    } else {
+                        (*piece).data.copy_from_slice(chunk);
+                    }
+                } else if !new_pieces[i].is_null() {
+                    let piece = new_pieces[i];
+                    let mut data = [0u8; PIECE_SIZE];
+                    data.copy_from_slice(chunk);
+                    write_volatile(piece,
+                                   Piece { next: *link,
+                                           index: index,
+                                           data: data });
+                    *link = piece;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RamDisk {
+    // Gives the disk's memory back to the MemManager
+    fn drop(&mut self) {
+        let mut piece = self.pieces;
+        while !piece.is_null() {
+            let next = unsafe { (*piece).next };
+            MemManager::kfree(piece as u32).ok();
+            piece = next;
+        }
+    }
+}
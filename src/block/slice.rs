@@ -0,0 +1,47 @@
+// A disk backed by a borrowed byte slice, such as an image read into memory
+// by a test. Any bytes past the last whole block are ignored.
+
+use super::{check_transfer, BlockDevice, BlockError, SECTOR_SIZE};
+
+pub struct SliceDisk<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> SliceDisk<'a> {
+    // Makes a disk whose blocks are stored in |data|
+    pub fn new(data: &'a mut [u8]) -> SliceDisk<'a> {
+        SliceDisk { data: data }
+    }
+}
+
+impl<'a> BlockDevice for SliceDisk<'a> {
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.data.len() / SECTOR_SIZE as usize) as u32
+    }
+
+    fn read_block(&self,
+                  block_number: u32,
+                  buf: &mut [u8])
+                  -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+
+        let start = block_number as usize * SECTOR_SIZE as usize;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_block(&mut self,
+                   block_number: u32,
+                   buf: &[u8])
+                   -> Result<(), BlockError> {
+        check_transfer(self, block_number, buf.len())?;
+
+        let start = block_number as usize * SECTOR_SIZE as usize;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
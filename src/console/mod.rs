@@ -32,11 +32,17 @@ impl Console {
     // The write function simply takes a string and writes its characters
     // individually via the writechar function of the UART
     pub fn write(s: &str) -> Result<(), Error> {
+        Console::write_bytes(s.as_bytes())
+    }
+
+    // Writes raw bytes, such as those written to a file descriptor, to the
+    // UART
+    pub fn write_bytes(bytes: &[u8]) -> Result<(), Error> {
         unsafe {
             (*IO_LOCK).lock();
         }
-        for c in s.chars() {
-            uart::writechar(c as u8);
+        for b in bytes.iter() {
+            uart::writechar(*b);
         }
         unsafe {
             (*IO_LOCK).unlock();
@@ -57,6 +63,32 @@ impl Console {
         }
     }
 
+    // Reads a line like read into |buf| as bytes ending in '\n', and returns
+    // the number of bytes read. Whatever doesn't fit in |buf| is dropped, and
+    // an unhandled control character such as Ctrl+D reads as 0 bytes, the
+    // end of the input.
+    pub fn read_bytes(buf: &mut [u8]) -> usize {
+        let line = match Console::read() {
+            Some(line) => line,
+            None => return 0,
+        };
+
+        // The enter key isn't echoed, so move on to the next line here
+        Console::write_char('\r');
+        Console::write_char('\n');
+
+        let mut count = 0;
+        let chars = line.iter().take_while(|c| **c != '\0').chain(Some(&'\n'));
+        for c in chars {
+            if count == buf.len() {
+                break;
+            }
+            buf[count] = *c as u8;
+            count += 1;
+        }
+        count
+    }
+
     // The read function of the console allows one to read continually
     // until a new line is found
     pub fn read() -> Option<[char; BUFFER_LENGTH]> {
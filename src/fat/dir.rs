@@ -0,0 +1,745 @@
+// Directories, which are cluster chains of 32 byte entries. Each file has a
+// short entry holding an 8.3 name, its attributes, first cluster and size.
+// A long name is kept in the entries just before the short one, 13 UTF-16
+// characters each, last part first, tied to the short entry by a checksum of
+// its name. Removed entries start with DELETED_ENTRY, and the first entry
+// starting with 0 marks the end of the directory.
+
+use super::name::{self,
+                  LAST_LONG_ENTRY,
+                  LONG_NAME_CHARS,
+                  MAX_LONG_NAME_ENTRIES,
+                  SHORT_NAME_LEN};
+use super::{Fat32, DIR_ENTRY_SIZE, FAT_ROOT_INODE};
+use crate::block::BlockDevice;
+use crate::filesystem::FsError;
+use crate::memman::MemManager;
+use crate::vfs::MAX_NAME_LEN;
+
+// Bits of dir_attr
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+// Long name entries have every one of the low 4 bits set, a combination no
+// short entry can have
+const ATTR_LONG_NAME: u8 =
+    ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+const ATTR_LONG_NAME_MASK: u8 = ATTR_LONG_NAME | ATTR_DIRECTORY | ATTR_ARCHIVE;
+
+// First byte of the name of an entry that has been removed
+pub const DELETED_ENTRY: u8 = 0xE5;
+
+// Directories can't grow past 65536 entries
+const MAX_DIRECTORY_SIZE: u32 = 65536 * DIR_ENTRY_SIZE;
+
+// Positions of the "." and ".." entries read_dir makes up for every
+// directory. Positions after them are byte offsets within the directory
+// plus DOT_POSITIONS.
+const DOT_POSITIONS: u32 = 2;
+
+// A short directory entry as it's stored on disk
+#[repr(C)]
+#[derive(Clone)]
+pub struct DirectoryEntry {
+    // 8.3 name, padded with spaces and without the dot
+    pub(super) dir_name: [u8; SHORT_NAME_LEN],
+    // ATTR_* bits
+    pub(super) dir_attr: u8,
+    // Whether the base and extension of the name are shown in lower case
+    pub(super) dir_nt_res: u8,
+    // Hundredths of a second to add to dir_crt_time
+    pub(super) dir_crt_time_tenth: u8,
+    // Time and date the file was created
+    pub(super) dir_crt_time: u16,
+    pub(super) dir_crt_date: u16,
+    // Date the file was last accessed
+    pub(super) dir_lst_acc_date: u16,
+    // High 16 bits of the first cluster
+    pub(super) dir_fst_clus_hi: u16,
+    // Time and date the file was last written
+    pub(super) dir_wrt_time: u16,
+    pub(super) dir_wrt_date: u16,
+    // Low 16 bits of the first cluster
+    pub(super) dir_fst_clus_lo: u16,
+    // Size of the file in bytes, always 0 for directories
+    pub(super) dir_file_size: u32,
+}
+
+// A directory entry read out of the volume, with its long name if it has
+// one and its short name otherwise
+pub struct DirEntry {
+    pub inode: u32,
+    // ATTR_* bits
+    pub attributes: u8,
+    pub size: u32,
+    // First cluster of the file, 0 if it has none
+    pub cluster: u32,
+    // Byte offsets within the directory of the first entry of the long name
+    // and of the short entry
+    pub(super) start: u32,
+    pub(super) offset: u32,
+    short_name: [u8; SHORT_NAME_LEN],
+    case: u8,
+    name_len: usize,
+    name: [u8; MAX_NAME_LEN],
+}
+
+// Iterator over the files of a directory, starting with "." and ".."
+pub struct ReadDir<'a, B: BlockDevice + 'a> {
+    volume: &'a Fat32<B>,
+    // Inode number of the directory
+    inode: u32,
+    // First cluster of the directory
+    first_cluster: u32,
+    // Position of the next entry
+    position: u32,
+    // The last cluster read and its index in the chain, so reading entries
+    // in order doesn't walk the chain from the start for each one
+    cluster: u32,
+    cluster_index: u32,
+    long_name: LongName,
+}
+
+// The parts of a long name collected so far while reading a directory. The
+// 520 bytes of UTF-16 are on the heap rather than the kernel stack.
+struct LongName {
+    units: &'static mut [u16],
+    // Ordinal of the entry expected next, 0 once the last one has been read
+    expected: u8,
+    // Number of entries the name takes up, 0 if there's no name
+    entries: u8,
+    checksum: u8,
+    // Byte offset of the first entry within the directory
+    start: u32,
+}
+
+impl<B: BlockDevice> Fat32<B> {
+    // Returns an iterator over the entries of the directory |inode_number|
+    pub fn readdir(&self, inode_number: u32) -> Result<ReadDir<B>, FsError> {
+        let first_cluster = self.dir_cluster(inode_number)?;
+        Ok(ReadDir { volume: self,
+                     inode: inode_number,
+                     first_cluster: first_cluster,
+                     position: 0,
+                     cluster: first_cluster,
+                     cluster_index: 0,
+                     long_name: LongName::new()? })
+    }
+
+    // Resolves a path such as "/DCIM/100CANON" to an inode number by starting
+    // at the root directory and looking up each component in turn. Names are
+    // matched without regard to case, like Windows does.
+    pub fn lookup(&self, path: &str) -> Result<u32, FsError> {
+        let mut inode_number = FAT_ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode_number = self.lookup_in(inode_number, name)?;
+        }
+        Ok(inode_number)
+    }
+
+    // Looks up |name| in the directory |dir| and returns its inode number
+    pub fn lookup_in(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        match name {
+            "." => self.dir_cluster(dir).map(|_| dir),
+            ".." => self.parent_of(dir),
+            _ => Ok(self.find_entry(dir, name)?.inode),
+        }
+    }
+
+    // Finds the entry named |name| in the directory |dir|, by either its
+    // long or its short name
+    pub(super) fn find_entry(&self,
+                             dir: u32,
+                             name: &str)
+                             -> Result<DirEntry, FsError> {
+        let mut entries = self.readdir(dir)?;
+        entries.seek(DOT_POSITIONS);
+        match entries.find(|entry| entry.matches(name)) {
+            Some(entry) => Ok(entry),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Returns the first cluster of the directory |inode_number|
+    pub(super) fn dir_cluster(&self,
+                              inode_number: u32)
+                              -> Result<u32, FsError> {
+        if inode_number == FAT_ROOT_INODE {
+            return Ok(self.bpb.root_cluster);
+        }
+        let entry = self.load_entry(inode_number)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        self.check_cluster(entry.cluster())
+    }
+
+    // Loads the short entry numbered |inode_number|
+    pub(super) fn load_entry(&self,
+                             inode_number: u32)
+                             -> Result<DirectoryEntry, FsError> {
+        let entry: DirectoryEntry =
+            self.read_struct_at(self.entry_position(inode_number)?)?;
+        if entry.is_end() ||
+           entry.is_deleted() ||
+           entry.is_long_name() ||
+           entry.is_volume_label()
+        {
+            return Err(FsError::InvalidInode);
+        }
+        Ok(entry)
+    }
+
+    // Writes |entry| back as the short entry numbered |inode_number|
+    pub(super) fn write_entry(&mut self,
+                              inode_number: u32,
+                              entry: &DirectoryEntry)
+                              -> Result<(), FsError> {
+        let position = self.entry_position(inode_number)?;
+        self.write_struct_at(position, entry)
+    }
+
+    // Returns the inode number of the directory holding the directory
+    // |inode_number|. Its ".." entry gives the parent's first cluster, and
+    // the parent's own entry is found by looking for that cluster in the
+    // directory its ".." names in turn.
+    pub(super) fn parent_of(&self, inode_number: u32) -> Result<u32, FsError> {
+        let cluster = self.dir_cluster(inode_number)?;
+        if inode_number == FAT_ROOT_INODE {
+            return Ok(FAT_ROOT_INODE);
+        }
+
+        let parent = self.dot_dot_cluster(cluster)?;
+        if parent == self.bpb.root_cluster {
+            return Ok(FAT_ROOT_INODE);
+        }
+        let grandparent = self.dot_dot_cluster(parent)?;
+
+        let mut entries = ReadDir { volume: self,
+                                    inode: 0,
+                                    first_cluster: grandparent,
+                                    position: DOT_POSITIONS,
+                                    cluster: grandparent,
+                                    cluster_index: 0,
+                                    long_name: LongName::new()? };
+        match entries.find(|entry| {
+                         entry.is_directory() && entry.cluster == parent
+                     }) {
+            Some(entry) => Ok(entry.inode),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Returns the cluster the ".." entry of the directory starting at
+    // |cluster| points to. Directories in the root point to cluster 0.
+    pub(super) fn dot_dot_cluster(&self, cluster: u32) -> Result<u32, FsError> {
+        if cluster == self.bpb.root_cluster {
+            return Ok(cluster);
+        }
+        let entry: DirectoryEntry =
+            self.read_struct_at(self.cluster_position(cluster,
+                                                      DIR_ENTRY_SIZE))?;
+        if &entry.dir_name[..] != name::DOT_DOT {
+            return Err(FsError::NotFound);
+        }
+        match entry.cluster() {
+            0 => Ok(self.bpb.root_cluster),
+            parent => self.check_cluster(parent),
+        }
+    }
+
+    // Returns the byte position on the disk of |offset| bytes into the
+    // directory starting at |first_cluster|
+    pub(super) fn dir_position(&self,
+                               first_cluster: u32,
+                               offset: u32)
+                               -> Result<u64, FsError> {
+        let cluster_size = self.cluster_size;
+        match self.nth_cluster(first_cluster, offset / cluster_size)? {
+            Some(cluster) => {
+                Ok(self.cluster_position(cluster, offset % cluster_size))
+            }
+            None => Err(FsError::InvalidBlock),
+        }
+    }
+
+    // Adds |entry| to the directory |dir| under |name|, with a long name
+    // unless |name| fits in the short entry. Returns the new inode number.
+    // The directory grows by a cluster when it has no run of free entries
+    // long enough.
+    pub(super) fn add_entry(&mut self,
+                            dir: u32,
+                            name: &str,
+                            entry: &DirectoryEntry)
+                            -> Result<u32, FsError> {
+        self.check_writable()?;
+        let first_cluster = self.dir_cluster(dir)?;
+        let mut entry = entry.clone();
+        // A name that fits in a short entry still needs a long one if
+        // another file has that short name, which happens when a rename
+        // only changes the case of a name
+        let exact = match name::exact_short_name(name) {
+            Some((short_name, case)) => {
+                if self.has_short_name(first_cluster, &short_name)? {
+                    None
+                } else {
+                    Some((short_name, case))
+                }
+            }
+            None => None,
+        };
+        let long_entries = match exact {
+            Some((short_name, case)) => {
+                entry.dir_name = short_name;
+                entry.dir_nt_res = case;
+                0
+            }
+            None => {
+                entry.dir_name = self.unique_short_name(first_cluster, name)?;
+                entry.dir_nt_res = 0;
+                name::long_entry_count(name)
+            }
+        };
+
+        // Find room for the long name entries followed by the short one
+        let needed = long_entries + 1;
+        let cluster_size = self.cluster_size;
+        let mut cluster = first_cluster;
+        let mut start = 0;
+        let mut run = 0;
+        let mut offset = 0;
+        while run < needed {
+            if offset >= MAX_DIRECTORY_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            if offset > 0 && offset % cluster_size == 0 {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None => self.alloc_cluster(Some(cluster))?,
+                };
+            }
+
+            let slot: DirectoryEntry =
+                self.read_struct_at(self.cluster_position(cluster,
+                                                          offset %
+                                                          cluster_size))?;
+            if slot.is_end() || slot.is_deleted() {
+                if run == 0 {
+                    start = offset;
+                }
+                run += 1;
+            } else {
+                run = 0;
+            }
+            offset += DIR_ENTRY_SIZE;
+        }
+
+        let checksum = name::checksum(&entry.dir_name);
+        for i in 0..long_entries {
+            // The last part of the name comes first
+            let ordinal = (long_entries - i) as u8;
+            let mut bytes = [0u8; DIR_ENTRY_SIZE as usize];
+            name::fill_long_entry(&mut bytes, name, ordinal, checksum);
+            if i == 0 {
+                bytes[0] |= LAST_LONG_ENTRY;
+            }
+            let position =
+                self.dir_position(first_cluster, start + i * DIR_ENTRY_SIZE)?;
+            self.write_disk(position, &bytes)?;
+        }
+
+        // The short entry ends the run, in the last cluster looked at
+        let offset = offset - DIR_ENTRY_SIZE;
+        self.write_struct_at(self.cluster_position(cluster,
+                                                   offset % cluster_size),
+                             &entry)?;
+        self.entry_inode(cluster, offset % cluster_size)
+    }
+
+    // Marks the short entry of |entry| and its long name entries in the
+    // directory starting at |first_cluster| as deleted
+    pub(super) fn remove_entry(&mut self,
+                               first_cluster: u32,
+                               entry: &DirEntry)
+                               -> Result<(), FsError> {
+        let mut offset = entry.start;
+        while offset <= entry.offset {
+            let position = self.dir_position(first_cluster, offset)?;
+            self.write_disk(position, &[DELETED_ENTRY])?;
+            offset += DIR_ENTRY_SIZE;
+        }
+        Ok(())
+    }
+
+    // Picks a short name for |name| that no other entry of the directory
+    // starting at |first_cluster| has. The name is used as it is when it
+    // only had to change case, and otherwise gets a numeric tail like
+    // "LONGFI~1.TXT".
+    fn unique_short_name(&self,
+                         first_cluster: u32,
+                         name: &str)
+                         -> Result<[u8; SHORT_NAME_LEN], FsError> {
+        let (basis, lossy) = name::basis_name(name);
+        if !lossy && !self.has_short_name(first_cluster, &basis)? {
+            return Ok(basis);
+        }
+        for tail in 1..1_000_000 {
+            let short_name = name::with_tail(&basis, tail);
+            if !self.has_short_name(first_cluster, &short_name)? {
+                return Ok(short_name);
+            }
+        }
+        Err(FsError::AlreadyExists)
+    }
+
+    // Checks whether the directory starting at |first_cluster| has an entry
+    // with the short name |short_name|
+    fn has_short_name(&self,
+                      first_cluster: u32,
+                      short_name: &[u8; SHORT_NAME_LEN])
+                      -> Result<bool, FsError> {
+        let mut entries = ReadDir { volume: self,
+                                    inode: 0,
+                                    first_cluster: first_cluster,
+                                    position: DOT_POSITIONS,
+                                    cluster: first_cluster,
+                                    cluster_index: 0,
+                                    long_name: LongName::new()? };
+        Ok(entries.any(|entry| &entry.short_name == short_name))
+    }
+
+    // Checks whether the directory |inode_number| has no entries besides "."
+    // and ".."
+    pub(super) fn is_empty_directory(&self,
+                                     inode_number: u32)
+                                     -> Result<bool, FsError> {
+        let mut entries = self.readdir(inode_number)?;
+        entries.seek(DOT_POSITIONS);
+        Ok(entries.next().is_none())
+    }
+}
+
+impl DirectoryEntry {
+    // Makes an entry with the attributes |attributes| and no data, created
+    // at |time|. The name is filled in when it's added to a directory.
+    pub fn new(attributes: u8, time: u32) -> DirectoryEntry {
+        let mut entry = DirectoryEntry { dir_name: [b' '; SHORT_NAME_LEN],
+                                         dir_attr: attributes,
+                                         dir_nt_res: 0,
+                                         dir_crt_time_tenth: 0,
+                                         dir_crt_time: 0,
+                                         dir_crt_date: 0,
+                                         dir_lst_acc_date: 0,
+                                         dir_fst_clus_hi: 0,
+                                         dir_wrt_time: 0,
+                                         dir_wrt_date: 0,
+                                         dir_fst_clus_lo: 0,
+                                         dir_file_size: 0 };
+        let (date, time) = super::fat_time(time);
+        entry.dir_crt_date = date;
+        entry.dir_crt_time = time;
+        entry.dir_wrt_date = date;
+        entry.dir_wrt_time = time;
+        entry.dir_lst_acc_date = date;
+        entry
+    }
+
+    // Returns the first cluster of the file, 0 if it has none
+    pub fn cluster(&self) -> u32 {
+        ((self.dir_fst_clus_hi as u32) << 16) | self.dir_fst_clus_lo as u32
+    }
+
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.dir_fst_clus_hi = (cluster >> 16) as u16;
+        self.dir_fst_clus_lo = cluster as u16;
+    }
+
+    // Records that the file was written at |time|, which also marks it for
+    // backup programs
+    pub fn touch(&mut self, time: u32) {
+        let (date, time) = super::fat_time(time);
+        self.dir_wrt_date = date;
+        self.dir_wrt_time = time;
+        self.dir_lst_acc_date = date;
+        self.dir_attr |= ATTR_ARCHIVE;
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.dir_attr & ATTR_DIRECTORY != 0
+    }
+
+    // Checks whether this is the entry that ends the directory
+    fn is_end(&self) -> bool {
+        self.dir_name[0] == 0
+    }
+
+    fn is_deleted(&self) -> bool {
+        self.dir_name[0] == DELETED_ENTRY
+    }
+
+    fn is_long_name(&self) -> bool {
+        self.dir_attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME
+    }
+
+    fn is_volume_label(&self) -> bool {
+        !self.is_long_name() && self.dir_attr & ATTR_VOLUME_ID != 0
+    }
+
+    // Checks whether this is the "." or ".." entry of a directory, the only
+    // short names starting with a dot
+    fn is_dot(&self) -> bool {
+        self.dir_name[0] == b'.'
+    }
+
+    // Returns the entry as the bytes stored on disk, which is how long name
+    // entries are read
+    fn bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const DirectoryEntry
+                                        as *const u8,
+                                        DIR_ENTRY_SIZE as usize)
+        }
+    }
+}
+
+impl DirEntry {
+    // Makes the "." or ".." entry of a directory
+    fn dot(inode: u32, name: &str) -> DirEntry {
+        let mut entry = DirEntry { inode: inode,
+                                   attributes: ATTR_DIRECTORY,
+                                   size: 0,
+                                   cluster: 0,
+                                   start: 0,
+                                   offset: 0,
+                                   short_name: [b' '; SHORT_NAME_LEN],
+                                   case: 0,
+                                   name_len: name.len(),
+                                   name: [0; MAX_NAME_LEN] };
+        entry.short_name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    // Returns the name, which is the long name if there is one
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(self.name_bytes()).unwrap_or("")
+    }
+
+    // Checks whether |name| names this entry by either its long or short
+    // name, ignoring case
+    fn matches(&self, name: &str) -> bool {
+        if name.eq_ignore_ascii_case(self.name()) {
+            return true;
+        }
+        let mut short_name = [0u8; name::SHORT_NAME_DISPLAY_LEN];
+        let length = name::display_short_name(&self.short_name,
+                                              self.case,
+                                              &mut short_name);
+        name.as_bytes().eq_ignore_ascii_case(&short_name[..length])
+    }
+}
+
+impl<'a, B: BlockDevice> ReadDir<'a, B> {
+    // Returns the position of the next entry
+    pub fn offset(&self) -> u32 {
+        self.position
+    }
+
+    // Continues iterating from |offset|, which has to be an offset returned
+    // by offset()
+    pub fn seek(&mut self, offset: u32) {
+        self.position = offset;
+    }
+
+    // Returns the cluster holding the byte |offset| of the directory, or None
+    // past the end of its chain
+    fn cluster_at(&mut self, offset: u32) -> Result<Option<u32>, FsError> {
+        let index = offset / self.volume.cluster_size;
+        if index < self.cluster_index {
+            self.cluster = self.first_cluster;
+            self.cluster_index = 0;
+        }
+        while self.cluster_index < index {
+            match self.volume.next_cluster(self.cluster)? {
+                Some(next) => self.cluster = next,
+                None => return Ok(None),
+            }
+            self.cluster_index += 1;
+        }
+        Ok(Some(self.cluster))
+    }
+}
+
+impl<'a, B: BlockDevice> Iterator for ReadDir<'a, B> {
+    type Item = DirEntry;
+
+    // Iteration ends early if the device fails to read a cluster
+    fn next(&mut self) -> Option<DirEntry> {
+        if self.position == 0 {
+            self.position = 1;
+            return Some(DirEntry::dot(self.inode, "."));
+        }
+        if self.position == 1 {
+            self.position = DOT_POSITIONS;
+            let parent = self.volume.parent_of(self.inode).ok()?;
+            return Some(DirEntry::dot(parent, ".."));
+        }
+
+        let cluster_size = self.volume.cluster_size;
+        self.long_name.clear();
+        loop {
+            let offset = self.position - DOT_POSITIONS;
+            if offset >= MAX_DIRECTORY_SIZE {
+                return None;
+            }
+            let cluster = self.cluster_at(offset).ok()??;
+            let position =
+                self.volume.cluster_position(cluster, offset % cluster_size);
+            let entry: DirectoryEntry =
+                self.volume.read_struct_at(position).ok()?;
+
+            // Nothing past the end marker is used
+            if entry.is_end() {
+                return None;
+            }
+            self.position += DIR_ENTRY_SIZE;
+
+            if entry.is_deleted() {
+                self.long_name.clear();
+                continue;
+            }
+            if entry.is_long_name() {
+                self.long_name.add(&entry, offset);
+                continue;
+            }
+            if entry.is_volume_label() || entry.is_dot() {
+                self.long_name.clear();
+                continue;
+            }
+
+            let inode = self.volume
+                            .entry_inode(cluster, offset % cluster_size)
+                            .ok()?;
+            let mut dir_entry = DirEntry { inode: inode,
+                                           attributes: entry.dir_attr,
+                                           size: entry.dir_file_size,
+                                           cluster: entry.cluster(),
+                                           start: offset,
+                                           offset: offset,
+                                           short_name: entry.dir_name,
+                                           case: entry.dir_nt_res,
+                                           name_len: 0,
+                                           name: [0; MAX_NAME_LEN] };
+
+            // A long name only counts if it's whole and belongs to this
+            // entry, since one left over from a deleted file could come
+            // before it
+            let long_length = if self.long_name.is_complete(&entry) {
+                name::decode_long_name(self.long_name.units(),
+                                       &mut dir_entry.name)
+            } else {
+                None
+            };
+            match long_length {
+                Some(length) => {
+                    dir_entry.name_len = length;
+                    dir_entry.start = self.long_name.start;
+                }
+                None => {
+                    dir_entry.name_len =
+                        name::display_short_name(&entry.dir_name,
+                                                 entry.dir_nt_res,
+                                                 &mut dir_entry.name);
+                }
+            }
+            return Some(dir_entry);
+        }
+    }
+}
+
+impl LongName {
+    fn new() -> Result<LongName, FsError> {
+        let length = MAX_LONG_NAME_ENTRIES * LONG_NAME_CHARS;
+        let address = match MemManager::kmalloc(length * 2) {
+            Ok(address) => address,
+            Err(_) => return Err(FsError::OutOfMemory),
+        };
+        let units = unsafe {
+            core::slice::from_raw_parts_mut(address as *mut u16, length)
+        };
+        Ok(LongName { units: units,
+                      expected: 0,
+                      entries: 0,
+                      checksum: 0,
+                      start: 0 })
+    }
+
+    fn clear(&mut self) {
+        self.expected = 0;
+        self.entries = 0;
+    }
+
+    // Adds the long name entry |entry| found |offset| bytes into the
+    // directory. The first entry of a name has LAST_LONG_ENTRY set in its
+    // ordinal, and the ones after it count down to 1.
+    fn add(&mut self, entry: &DirectoryEntry, offset: u32) {
+        let bytes = entry.bytes();
+        let ordinal = bytes[0] & !LAST_LONG_ENTRY;
+        if bytes[0] & LAST_LONG_ENTRY != 0 {
+            if ordinal == 0 || ordinal as usize > MAX_LONG_NAME_ENTRIES {
+                self.clear();
+                return;
+            }
+            self.entries = ordinal;
+            self.checksum = name::long_entry_checksum(bytes);
+            self.start = offset;
+            // Whatever the last entry doesn't fill isn't part of the name
+            for unit in
+                self.units[ordinal as usize * LONG_NAME_CHARS..].iter_mut()
+            {
+                *unit = 0;
+            }
+        } else if self.entries == 0 ||
+                  ordinal == 0 ||
+                  ordinal != self.expected ||
+                  name::long_entry_checksum(bytes) != self.checksum
+        {
+            self.clear();
+            return;
+        }
+
+        let start = (ordinal as usize - 1) * LONG_NAME_CHARS;
+        name::read_long_entry(bytes,
+                              &mut self.units[start..start + LONG_NAME_CHARS]);
+        self.expected = ordinal - 1;
+    }
+
+    // Checks whether every part of the name has been read and it belongs to
+    // the short entry |entry|
+    fn is_complete(&self, entry: &DirectoryEntry) -> bool {
+        self.entries != 0 &&
+        self.expected == 0 &&
+        self.checksum == name::checksum(&entry.dir_name)
+    }
+
+    fn units(&self) -> &[u16] {
+        &self.units[..self.entries as usize * LONG_NAME_CHARS]
+    }
+}
+
+impl Drop for LongName {
+    fn drop(&mut self) {
+        MemManager::kfree(self.units.as_ptr() as u32).ok();
+    }
+}
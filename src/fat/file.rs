@@ -0,0 +1,218 @@
+// Reading and writing the data of files, which is kept in the cluster chain
+// starting at the first cluster of their directory entry. Empty files have
+// no clusters at all.
+
+use super::dir::DirectoryEntry;
+use super::{Fat32, FAT_ROOT_INODE};
+use crate::block::BlockDevice;
+use crate::filesystem::FsError;
+use core::cmp::{max, min};
+
+// Largest a file can be, since dir_file_size is 32 bits
+const MAX_FILE_SIZE: u32 = 0xFFFF_FFFF;
+
+impl<B: BlockDevice> Fat32<B> {
+    // Reads from the data of the file |inode_number| starting at |offset|
+    // into |buf|, returning the number of bytes read. Reading stops at the
+    // end of the file.
+    pub fn read_at(&self,
+                   inode_number: u32,
+                   offset: u32,
+                   buf: &mut [u8])
+                   -> Result<usize, FsError> {
+        let entry = self.load_file_entry(inode_number)?;
+        let size = entry.dir_file_size;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        // Don't read past the end of the file
+        let length = min(buf.len(), (size - offset) as usize);
+        let cluster_size = self.cluster_size;
+        let mut cluster =
+            match self.nth_cluster(entry.cluster(), offset / cluster_size)? {
+                Some(cluster) => cluster,
+                None => return Err(FsError::InvalidBlock),
+            };
+        let mut done = 0;
+
+        loop {
+            let within = (offset + done as u32) % cluster_size;
+            let count = min((cluster_size - within) as usize, length - done);
+            self.read_disk(self.cluster_position(cluster, within),
+                           &mut buf[done..done + count])?;
+            done += count;
+            if done == length {
+                return Ok(done);
+            }
+
+            // A chain shorter than the size means the FAT is damaged
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Err(FsError::InvalidBlock),
+            };
+        }
+    }
+
+    // Writes |buf| into the file |inode_number| starting at |offset|,
+    // allocating clusters as needed, and returns the number of bytes
+    // written. Writing past the end of the file fills the gap with zeros.
+    // The write stops short if the volume fills up part way through.
+    pub fn write_at(&mut self,
+                    inode_number: u32,
+                    offset: u32,
+                    buf: &[u8])
+                    -> Result<usize, FsError> {
+        self.check_writable()?;
+        let mut entry = self.load_file_entry(inode_number)?;
+        let length = min(buf.len(), (MAX_FILE_SIZE - offset) as usize);
+        if length == 0 {
+            return if buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(FsError::NoSpace)
+            };
+        }
+        if offset > entry.dir_file_size {
+            self.zero_tail(&entry)?;
+        }
+
+        let mut done = 0;
+        let result =
+            self.write_clusters(&mut entry, offset, &buf[..length], &mut done);
+
+        // Clusters may have been added even if nothing was written
+        entry.dir_file_size = max(entry.dir_file_size, offset + done as u32);
+        entry.touch(self.timestamp());
+        self.write_entry(inode_number, &entry)?;
+
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    // Copies |buf| into the clusters of |entry| starting at |offset|,
+    // counting the bytes written in |done|
+    fn write_clusters(&mut self,
+                      entry: &mut DirectoryEntry,
+                      offset: u32,
+                      buf: &[u8],
+                      done: &mut usize)
+                      -> Result<(), FsError> {
+        let cluster_size = self.cluster_size;
+        let mut cluster = self.cluster_for_write(entry, offset / cluster_size)?;
+
+        loop {
+            let within = (offset + *done as u32) % cluster_size;
+            let count =
+                min((cluster_size - within) as usize, buf.len() - *done);
+            self.write_disk(self.cluster_position(cluster, within),
+                            &buf[*done..*done + count])?;
+            *done += count;
+            if *done == buf.len() {
+                return Ok(());
+            }
+
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.alloc_cluster(Some(cluster))?,
+            };
+        }
+    }
+
+    // Returns the |index|th cluster of the file of |entry|, first growing
+    // its chain to that length if it's shorter
+    fn cluster_for_write(&mut self,
+                         entry: &mut DirectoryEntry,
+                         index: u32)
+                         -> Result<u32, FsError> {
+        if entry.cluster() == 0 {
+            let first = self.alloc_cluster(None)?;
+            entry.set_cluster(first);
+        }
+
+        let mut cluster = self.check_cluster(entry.cluster())?;
+        for _ in 0..index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.alloc_cluster(Some(cluster))?,
+            };
+        }
+        Ok(cluster)
+    }
+
+    // Zeros the rest of the last cluster of the file of |entry| past its
+    // end, so growing the file doesn't bring back whatever was there.
+    // Clusters are zeroed when they're allocated, so the rest of any gap
+    // already is.
+    fn zero_tail(&mut self, entry: &DirectoryEntry) -> Result<(), FsError> {
+        let size = entry.dir_file_size;
+        let within = size % self.cluster_size;
+        if within == 0 {
+            return Ok(());
+        }
+        match self.nth_cluster(entry.cluster(), size / self.cluster_size)? {
+            Some(cluster) => {
+                let position = self.cluster_position(cluster, within);
+                self.zero_disk(position, (self.cluster_size - within) as usize)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Sets the size of the file |inode_number| to |size| bytes, freeing the
+    // clusters past the end when it shrinks and adding zeroed ones when it
+    // grows
+    pub fn truncate(&mut self,
+                    inode_number: u32,
+                    size: u32)
+                    -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut entry = self.load_file_entry(inode_number)?;
+        let cluster_size = self.cluster_size;
+        let mut result = Ok(());
+
+        if size < entry.dir_file_size {
+            let keep = (size + cluster_size - 1) / cluster_size;
+            if keep == 0 {
+                self.free_chain(entry.cluster())?;
+                entry.set_cluster(0);
+            } else {
+                match self.nth_cluster(entry.cluster(), keep - 1)? {
+                    Some(last) => self.end_chain_at(last)?,
+                    None => return Err(FsError::InvalidBlock),
+                }
+            }
+        } else if size > entry.dir_file_size {
+            self.zero_tail(&entry)?;
+            result = self.cluster_for_write(&mut entry,
+                                            (size - 1) / cluster_size)
+                         .map(|_| ());
+        }
+
+        // Any clusters added are kept with the old size if the volume filled
+        // up before all of them were
+        if result.is_ok() {
+            entry.dir_file_size = size;
+        }
+        entry.touch(self.timestamp());
+        self.write_entry(inode_number, &entry)?;
+        result
+    }
+
+    // Loads the directory entry of the file |inode_number|, which can't be a
+    // directory
+    fn load_file_entry(&self,
+                       inode_number: u32)
+                       -> Result<DirectoryEntry, FsError> {
+        if inode_number == FAT_ROOT_INODE {
+            return Err(FsError::IsADirectory);
+        }
+        let entry = self.load_entry(inode_number)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(entry)
+    }
+}
@@ -0,0 +1,760 @@
+// FAT32, the filesystem SD cards come with when they're formatted on Windows
+// or in a camera. The volume starts with a boot sector holding the BIOS
+// Parameter Block, which gives the size of the reserved area, the File
+// Allocation Tables after it and the clusters after them. Each 32 bit entry
+// of a FAT holds the number of the next cluster of a file, so a file is a
+// chain of clusters starting at the one its directory entry names.
+//
+// FAT has no inodes, so the inode number of a file comes from where its
+// directory entry is: entries are numbered through the data area in 32 byte
+// steps, and the root directory, which has no entry, is FAT_ROOT_INODE. A
+// file keeps its number until it's renamed, which moves its entry.
+//
+// FAT12 and FAT16 volumes, which keep the root directory outside the data
+// area, aren't supported.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::filesystem::FsError;
+use crate::global_constants::CLOCK_FREQ;
+use crate::trap::timer::get_current_time;
+use core::cmp::min;
+
+pub mod dir;
+pub mod file;
+pub mod name;
+pub mod namespace;
+pub mod vfs;
+
+// Inode number of the root directory
+pub const FAT_ROOT_INODE: u32 = 1;
+// Inode number of the first directory entry of the data area
+const FIRST_ENTRY_INODE: u32 = 2;
+
+// Signature at the end of the boot sector and the FSInfo sector
+const BOOT_SIGNATURE: u16 = 0xAA55;
+// Signatures of the FSInfo sector, which caches the free cluster count
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+// Where the free cluster count and next free cluster hint are in FSInfo
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+// Value of either FSInfo field when it isn't known
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// Only the low 28 bits of a FAT32 entry are used. The top 4 are reserved and
+// have to be kept when an entry is rewritten.
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+// Marks a cluster that can't be used
+const FAT_BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+// Entries at or above this end a chain, and this is what new chains end with
+const FAT_END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const FAT_END_MARKER: u32 = 0x0FFF_FFFF;
+// The first two FAT entries are reserved, so clusters are numbered from 2
+const FIRST_CLUSTER: u32 = 2;
+
+// Set in ext_flags when only one FAT is in use rather than all of them being
+// kept the same, and the bits giving which one
+const EXT_FLAGS_NO_MIRRORING: u16 = 0x0080;
+const EXT_FLAGS_ACTIVE_FAT: u16 = 0x000F;
+
+// Size of a directory entry
+pub const DIR_ENTRY_SIZE: u32 = 32;
+
+// Unix time of the FAT epoch, Jan 1st 1980
+const FAT_EPOCH: u32 = 315_532_800;
+
+// The fields of the BIOS Parameter Block this driver uses. Several of them
+// are unaligned in the boot sector, so they're picked out one at a time
+// instead of reading the sector in as a structure.
+pub struct BiosParameterBlock {
+    // Bytes in each sector, which has to match the block device
+    pub bytes_per_sector: u16,
+    // Sectors in each cluster, a power of 2
+    pub sectors_per_cluster: u8,
+    // Sectors before the first FAT, including the boot sector
+    pub reserved_sectors: u16,
+    // Number of copies of the FAT
+    pub fat_count: u8,
+    // Entries in the FAT12/16 root directory, always 0 on FAT32
+    pub root_entry_count: u16,
+    // Sectors in the volume if it fits in 16 bits, otherwise 0
+    pub total_sectors_16: u16,
+    // Sectors in each FAT12/16 FAT, always 0 on FAT32
+    pub fat_size_16: u16,
+    // Sectors in the volume when total_sectors_16 is 0
+    pub total_sectors_32: u32,
+    // Sectors in each FAT
+    pub fat_size_32: u32,
+    // Whether the FATs are mirrored, and which one is used if not
+    pub ext_flags: u16,
+    // Version of the FAT32 layout, which has only ever been 0
+    pub fs_version: u16,
+    // First cluster of the root directory
+    pub root_cluster: u32,
+    // Sector of the FSInfo structure within the reserved area
+    pub fs_info: u16,
+    // Volume label, padded with spaces
+    pub volume_label: [u8; 11],
+}
+
+pub struct Fat32<B: BlockDevice> {
+    // Where the volume is stored
+    disk: B,
+    pub bpb: BiosParameterBlock,
+    // First sector of the first FAT
+    fat_start: u32,
+    // Sectors in each FAT
+    fat_size: u32,
+    // FAT read from, and the only one written when they aren't mirrored
+    active_fat: Option<u32>,
+    // First sector of cluster 2
+    data_start: u32,
+    // Bytes in each cluster
+    cluster_size: u32,
+    // One past the last cluster of the volume
+    cluster_limit: u32,
+    // Number of free clusters, or FSINFO_UNKNOWN until they're counted
+    free_count: u32,
+    // Cluster to start looking for a free one from
+    next_free: u32,
+    // Set when free_count or next_free need writing back to FSInfo
+    fs_info_dirty: bool,
+    // Set when the disk holding the volume can be written
+    writable: bool,
+}
+
+impl<B: BlockDevice> Fat32<B> {
+    // Create a Fat32 for the volume on |disk|, which can be used once the
+    // BPB has been read
+    pub fn new(disk: B) -> Fat32<B> {
+        Fat32 { disk: disk,
+                bpb: BiosParameterBlock::new(),
+                fat_start: 0,
+                fat_size: 0,
+                active_fat: None,
+                data_start: 0,
+                cluster_size: 0,
+                cluster_limit: 0,
+                free_count: FSINFO_UNKNOWN,
+                next_free: FIRST_CLUSTER,
+                fs_info_dirty: false,
+                writable: false }
+    }
+
+    // Reads the BPB from the boot sector and works out where the FATs and
+    // clusters are
+    pub fn read_bpb(&mut self) -> Result<(), FsError> {
+        let sector_size = self.disk.block_size();
+        if sector_size == 0 || sector_size > SECTOR_SIZE {
+            return Err(FsError::DeviceError(BlockError::BadBuffer));
+        }
+
+        let mut boot = [0u8; SECTOR_SIZE as usize];
+        self.read_disk(0, &mut boot[..sector_size as usize])?;
+        if sector_size < 512 || le_u16(&boot[510..]) != BOOT_SIGNATURE {
+            return Err(FsError::BadSuperblock);
+        }
+        let bpb = BiosParameterBlock::parse(&boot);
+
+        // Sectors have to line up with the device's blocks
+        if bpb.bytes_per_sector as u32 != sector_size {
+            return Err(FsError::BadSuperblock);
+        }
+        if bpb.sectors_per_cluster == 0 ||
+           !bpb.sectors_per_cluster.is_power_of_two() ||
+           bpb.reserved_sectors == 0 ||
+           bpb.fat_count == 0
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        // Like Linux, the layout decides whether it's FAT32 rather than the
+        // number of clusters, so small volumes can be FAT32 too
+        if bpb.fat_size_16 != 0 || bpb.root_entry_count != 0 {
+            return Err(FsError::NotSupported);
+        }
+        if bpb.fs_version != 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        let total_sectors = if bpb.total_sectors_16 != 0 {
+            bpb.total_sectors_16 as u32
+        } else {
+            bpb.total_sectors_32
+        };
+        let fat_sectors = bpb.fat_size_32 as u64 * bpb.fat_count as u64;
+        let data_start = bpb.reserved_sectors as u64 + fat_sectors;
+        if bpb.fat_size_32 == 0 ||
+           total_sectors > self.disk.block_count() ||
+           data_start >= total_sectors as u64
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        // Every cluster needs an entry in the FAT
+        let data_start = data_start as u32;
+        let clusters =
+            (total_sectors - data_start) / bpb.sectors_per_cluster as u32;
+        let fat_entries = bpb.fat_size_32 as u64 * sector_size as u64 / 4;
+        let cluster_limit = clusters as u64 + FIRST_CLUSTER as u64;
+        if clusters == 0 ||
+           cluster_limit > fat_entries ||
+           cluster_limit > FAT_BAD_CLUSTER as u64
+        {
+            return Err(FsError::BadSuperblock);
+        }
+        let cluster_limit = cluster_limit as u32;
+        if bpb.root_cluster < FIRST_CLUSTER || bpb.root_cluster >= cluster_limit
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        let active_fat = if bpb.ext_flags & EXT_FLAGS_NO_MIRRORING != 0 {
+            let active = (bpb.ext_flags & EXT_FLAGS_ACTIVE_FAT) as u32;
+            if active >= bpb.fat_count as u32 {
+                return Err(FsError::BadSuperblock);
+            }
+            Some(active)
+        } else {
+            None
+        };
+
+        self.fat_start = bpb.reserved_sectors as u32;
+        self.fat_size = bpb.fat_size_32;
+        self.active_fat = active_fat;
+        self.data_start = data_start;
+        self.cluster_size = sector_size * bpb.sectors_per_cluster as u32;
+        self.cluster_limit = cluster_limit;
+        self.bpb = bpb;
+        self.read_fs_info()?;
+        self.writable = self.disk.is_writable();
+        Ok(())
+    }
+
+    // Picks up the free cluster count and next free cluster from FSInfo.
+    // They're only hints, so a missing or damaged FSInfo just leaves the
+    // free clusters to be counted when they're needed.
+    fn read_fs_info(&mut self) -> Result<(), FsError> {
+        self.free_count = FSINFO_UNKNOWN;
+        self.next_free = FIRST_CLUSTER;
+        self.fs_info_dirty = false;
+        if !self.has_fs_info() {
+            return Ok(());
+        }
+
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        self.read_disk(self.fs_info_position(), &mut sector)?;
+        if le_u32(&sector[0..]) != FSINFO_LEAD_SIGNATURE ||
+           le_u32(&sector[484..]) != FSINFO_STRUCT_SIGNATURE ||
+           le_u32(&sector[508..]) != FSINFO_TRAIL_SIGNATURE
+        {
+            return Ok(());
+        }
+
+        let free_count = le_u32(&sector[FSINFO_FREE_COUNT..]);
+        if free_count <= self.cluster_limit - FIRST_CLUSTER {
+            self.free_count = free_count;
+        }
+        let next_free = le_u32(&sector[FSINFO_NEXT_FREE..]);
+        if next_free >= FIRST_CLUSTER && next_free < self.cluster_limit {
+            self.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    // Checks whether the volume has an FSInfo sector
+    fn has_fs_info(&self) -> bool {
+        self.bpb.fs_info != 0 &&
+        self.bpb.fs_info < self.bpb.reserved_sectors &&
+        self.bpb.bytes_per_sector as u32 == SECTOR_SIZE
+    }
+
+    fn fs_info_position(&self) -> u64 {
+        self.bpb.fs_info as u64 * self.bpb.bytes_per_sector as u64
+    }
+
+    // Returns the volume label from the BPB, without its padding
+    pub fn volume_label(&self) -> &[u8] {
+        let label = &self.bpb.volume_label;
+        let length = label.iter()
+                          .rposition(|byte| *byte != b' ')
+                          .map_or(0, |last| last + 1);
+        &label[..length]
+    }
+
+    // Returns the size of a cluster in bytes
+    pub fn cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    // Returns the number of clusters in the volume
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_limit - FIRST_CLUSTER
+    }
+
+    // Returns the number of free clusters, counting them if FSInfo didn't
+    // say
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        if self.free_count != FSINFO_UNKNOWN {
+            return Ok(self.free_count);
+        }
+        self.count_free_clusters()
+    }
+
+    // Returns the byte position on the disk of the FAT entry of |cluster| in
+    // the FAT numbered |fat|
+    fn fat_position(&self, fat: u32, cluster: u32) -> u64 {
+        let sector = self.fat_start as u64 + fat as u64 * self.fat_size as u64;
+        sector * self.bpb.bytes_per_sector as u64 + cluster as u64 * 4
+    }
+
+    // Returns the byte position on the disk of |offset| bytes into |cluster|
+    fn cluster_position(&self, cluster: u32, offset: u32) -> u64 {
+        let sector = self.data_start as u64 +
+                     (cluster - FIRST_CLUSTER) as u64 *
+                     self.bpb.sectors_per_cluster as u64;
+        sector * self.bpb.bytes_per_sector as u64 + offset as u64
+    }
+
+    // Checks that |cluster| is one of the volume's clusters
+    fn check_cluster(&self, cluster: u32) -> Result<u32, FsError> {
+        if cluster >= FIRST_CLUSTER && cluster < self.cluster_limit {
+            Ok(cluster)
+        } else {
+            Err(FsError::InvalidBlock)
+        }
+    }
+
+    // Returns the FAT entry of |cluster|
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        let fat = self.active_fat.unwrap_or(0);
+        self.read_disk(self.fat_position(fat, cluster), &mut bytes)?;
+        Ok(le_u32(&bytes) & FAT_ENTRY_MASK)
+    }
+
+    // Sets the FAT entry of |cluster| to |value| in every FAT in use
+    fn set_fat_entry(&mut self,
+                     cluster: u32,
+                     value: u32)
+                     -> Result<(), FsError> {
+        self.check_writable()?;
+        let (first, last) = match self.active_fat {
+            Some(fat) => (fat, fat),
+            None => (0, self.bpb.fat_count as u32 - 1),
+        };
+
+        for fat in first..=last {
+            let position = self.fat_position(fat, cluster);
+            let mut bytes = [0u8; 4];
+            self.read_disk(position, &mut bytes)?;
+            let entry =
+                le_u32(&bytes) & !FAT_ENTRY_MASK | value & FAT_ENTRY_MASK;
+            self.write_disk(position, &entry.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Returns the cluster after |cluster| in its chain, or None if it's the
+    // last one. Free and bad clusters can't be part of a chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        match self.fat_entry(cluster)? {
+            entry if entry >= FAT_END_OF_CHAIN => Ok(None),
+            entry => self.check_cluster(entry).map(Some),
+        }
+    }
+
+    // Follows the chain starting at |first| to its |index|th cluster, or
+    // None if the chain is shorter than that
+    fn nth_cluster(&self,
+                   first: u32,
+                   index: u32)
+                   -> Result<Option<u32>, FsError> {
+        if first == 0 {
+            return Ok(None);
+        }
+        let mut cluster = self.check_cluster(first)?;
+        for _ in 0..index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(cluster))
+    }
+
+    // Returns the number of clusters in the chain starting at |first|
+    fn chain_length(&self, first: u32) -> Result<u32, FsError> {
+        if first == 0 {
+            return Ok(0);
+        }
+        let mut cluster = self.check_cluster(first)?;
+        let mut length = 1;
+        while let Some(next) = self.next_cluster(cluster)? {
+            // A chain longer than the volume has to loop
+            if length >= self.cluster_count() {
+                return Err(FsError::InvalidBlock);
+            }
+            cluster = next;
+            length += 1;
+        }
+        Ok(length)
+    }
+
+    // Finds a free cluster, looking from |start| to the end of the FAT and
+    // then from the beginning. The FAT is read a sector at a time.
+    fn find_free_cluster(&self, start: u32) -> Result<u32, FsError> {
+        let sector_size = self.bpb.bytes_per_sector as u32;
+        let per_sector = sector_size / 4;
+        let fat = self.active_fat.unwrap_or(0);
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        let mut cluster = start;
+        let mut checked = 0;
+
+        while checked < self.cluster_count() {
+            if cluster >= self.cluster_limit {
+                cluster = FIRST_CLUSTER;
+            }
+            let first = cluster - cluster % per_sector;
+            self.read_disk(self.fat_position(fat, first),
+                           &mut sector[..sector_size as usize])?;
+            let end = min(first + per_sector, self.cluster_limit);
+            while cluster < end && checked < self.cluster_count() {
+                let index = ((cluster - first) * 4) as usize;
+                if le_u32(&sector[index..]) & FAT_ENTRY_MASK == 0 {
+                    return Ok(cluster);
+                }
+                cluster += 1;
+                checked += 1;
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    // Counts the free clusters by reading through the whole FAT
+    fn count_free_clusters(&self) -> Result<u32, FsError> {
+        let sector_size = self.bpb.bytes_per_sector as u32;
+        let per_sector = sector_size / 4;
+        let fat = self.active_fat.unwrap_or(0);
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        let mut free = 0;
+
+        let mut first = 0;
+        while first < self.cluster_limit {
+            self.read_disk(self.fat_position(fat, first),
+                           &mut sector[..sector_size as usize])?;
+            let start = if first == 0 { FIRST_CLUSTER } else { first };
+            for cluster in start..min(first + per_sector, self.cluster_limit) {
+                let index = ((cluster - first) * 4) as usize;
+                if le_u32(&sector[index..]) & FAT_ENTRY_MASK == 0 {
+                    free += 1;
+                }
+            }
+            first += per_sector;
+        }
+        Ok(free)
+    }
+
+    // Allocates a cluster filled with zeros and links it onto the end of the
+    // chain after |last|, if there is one
+    fn alloc_cluster(&mut self, last: Option<u32>) -> Result<u32, FsError> {
+        self.check_writable()?;
+        if self.free_count == 0 {
+            return Err(FsError::NoSpace);
+        }
+
+        let cluster = self.find_free_cluster(self.next_free)?;
+        self.zero_cluster(cluster)?;
+        self.set_fat_entry(cluster, FAT_END_MARKER)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+
+        if self.free_count != FSINFO_UNKNOWN {
+            self.free_count -= 1;
+        }
+        self.next_free = cluster + 1;
+        self.fs_info_dirty = true;
+        Ok(cluster)
+    }
+
+    // Frees every cluster of the chain starting at |first|
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        if first == 0 {
+            return Ok(());
+        }
+        let mut cluster = self.check_cluster(first)?;
+        for _ in 0..self.cluster_count() {
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            if self.free_count != FSINFO_UNKNOWN {
+                self.free_count += 1;
+            }
+            self.fs_info_dirty = true;
+
+            // Stop at the end of the chain, or anywhere it's damaged
+            match self.check_cluster(next) {
+                Ok(next) => cluster = next,
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Cuts the chain off after |cluster|, freeing the clusters that followed
+    fn end_chain_at(&mut self, cluster: u32) -> Result<(), FsError> {
+        let next = self.next_cluster(cluster)?;
+        self.set_fat_entry(cluster, FAT_END_MARKER)?;
+        match next {
+            Some(next) => self.free_chain(next),
+            None => Ok(()),
+        }
+    }
+
+    // Fills a cluster with zeros
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FsError> {
+        let position = self.cluster_position(cluster, 0);
+        self.zero_disk(position, self.cluster_size as usize)
+    }
+
+    // Fills |length| bytes at |position| on the disk with zeros
+    fn zero_disk(&mut self,
+                 position: u64,
+                 length: usize)
+                 -> Result<(), FsError> {
+        let zeros = [0u8; SECTOR_SIZE as usize];
+        let mut done = 0;
+        while done < length {
+            let count = min(zeros.len(), length - done);
+            self.write_disk(position + done as u64, &zeros[..count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Returns the inode number of the directory entry |offset| bytes into
+    // |cluster|. Entries past the first 4G of them can't be numbered, which
+    // only matters for directories at the far end of volumes over 2 TB.
+    fn entry_inode(&self, cluster: u32, offset: u32) -> Result<u32, FsError> {
+        let per_cluster = (self.cluster_size / DIR_ENTRY_SIZE) as u64;
+        let inode = (cluster - FIRST_CLUSTER) as u64 * per_cluster +
+                    (offset / DIR_ENTRY_SIZE) as u64 +
+                    FIRST_ENTRY_INODE as u64;
+        if inode > core::u32::MAX as u64 {
+            return Err(FsError::InvalidInode);
+        }
+        Ok(inode as u32)
+    }
+
+    // Returns the byte position on the disk of the directory entry numbered
+    // |inode_number|
+    fn entry_position(&self, inode_number: u32) -> Result<u64, FsError> {
+        if inode_number < FIRST_ENTRY_INODE {
+            return Err(FsError::InvalidInode);
+        }
+        let per_cluster = self.cluster_size / DIR_ENTRY_SIZE;
+        let index = inode_number - FIRST_ENTRY_INODE;
+        let cluster = index / per_cluster + FIRST_CLUSTER;
+        if cluster >= self.cluster_limit {
+            return Err(FsError::InvalidInode);
+        }
+        Ok(self.cluster_position(cluster, index % per_cluster * DIR_ENTRY_SIZE))
+    }
+
+    // Gives back the disk holding the volume
+    pub fn into_disk(self) -> B {
+        self.disk
+    }
+
+    // Writes the free cluster count back to FSInfo and makes sure every
+    // change to the volume has reached the disk
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        if self.writable && self.fs_info_dirty && self.has_fs_info() {
+            let position = self.fs_info_position();
+            let mut sector = [0u8; SECTOR_SIZE as usize];
+            self.read_disk(position, &mut sector)?;
+            if le_u32(&sector[0..]) == FSINFO_LEAD_SIGNATURE {
+                // Counted once here so the next mount doesn't have to
+                if self.free_count == FSINFO_UNKNOWN {
+                    self.free_count = self.count_free_clusters()?;
+                }
+                let free_count = self.free_count.to_le_bytes();
+                let next_free = self.next_free.to_le_bytes();
+                sector[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4]
+                    .copy_from_slice(&free_count);
+                sector[FSINFO_NEXT_FREE..FSINFO_NEXT_FREE + 4]
+                    .copy_from_slice(&next_free);
+                self.write_disk(position, &sector)?;
+            }
+            self.fs_info_dirty = false;
+        }
+        self.disk.flush()?;
+        Ok(())
+    }
+
+    // Returns the time to record in directory entries. There's no real-time
+    // clock and FAT doesn't record when the volume was last written, so this
+    // is the time since boot counted from the FAT epoch.
+    pub fn timestamp(&self) -> u32 {
+        FAT_EPOCH + (get_current_time() / CLOCK_FREQ) as u32
+    }
+
+    // Checks that the volume can be modified
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(FsError::ReadOnly)
+        }
+    }
+
+    // Reads a T stored at the byte |position| of the disk. T has to be one of
+    // the plain on-disk structures, for which any bytes make a valid value.
+    fn read_struct_at<T>(&self, position: u64) -> Result<T, FsError> {
+        Ok(block::read_struct_at(&self.disk, position)?)
+    }
+
+    // Writes |value| at the byte |position| of the disk
+    fn write_struct_at<T>(&mut self,
+                          position: u64,
+                          value: &T)
+                          -> Result<(), FsError> {
+        self.check_writable()?;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(value as *const T as *const u8,
+                                        core::mem::size_of::<T>())
+        };
+        self.write_disk(position, bytes)
+    }
+
+    // Copies the bytes at |position| on the disk into |buf|
+    fn read_disk(&self, position: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_at(&self.disk, position, buf)?)
+    }
+
+    // Copies |buf| onto the disk at |position|, if the volume can be
+    // modified
+    fn write_disk(&mut self, position: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        Ok(block::write_at(&mut self.disk, position, buf)?)
+    }
+}
+
+impl BiosParameterBlock {
+    // Makes an empty BPB
+    pub fn new() -> BiosParameterBlock {
+        BiosParameterBlock { bytes_per_sector: 0,
+                             sectors_per_cluster: 0,
+                             reserved_sectors: 0,
+                             fat_count: 0,
+                             root_entry_count: 0,
+                             total_sectors_16: 0,
+                             fat_size_16: 0,
+                             total_sectors_32: 0,
+                             fat_size_32: 0,
+                             ext_flags: 0,
+                             fs_version: 0,
+                             root_cluster: 0,
+                             fs_info: 0,
+                             volume_label: [b' '; 11] }
+    }
+
+    // Picks the fields out of a boot sector
+    fn parse(boot: &[u8]) -> BiosParameterBlock {
+        let mut volume_label = [0u8; 11];
+        volume_label.copy_from_slice(&boot[71..82]);
+        BiosParameterBlock { bytes_per_sector: le_u16(&boot[11..]),
+                             sectors_per_cluster: boot[13],
+                             reserved_sectors: le_u16(&boot[14..]),
+                             fat_count: boot[16],
+                             root_entry_count: le_u16(&boot[17..]),
+                             total_sectors_16: le_u16(&boot[19..]),
+                             fat_size_16: le_u16(&boot[22..]),
+                             total_sectors_32: le_u32(&boot[32..]),
+                             fat_size_32: le_u32(&boot[36..]),
+                             ext_flags: le_u16(&boot[40..]),
+                             fs_version: le_u16(&boot[42..]),
+                             root_cluster: le_u32(&boot[44..]),
+                             fs_info: le_u16(&boot[48..]),
+                             volume_label: volume_label }
+    }
+}
+
+// Converts a FAT date and time to seconds since Jan 1st 1970. Dates count
+// years from 1980 in bits 9-15, then the month and day, and times hold the
+// hour, minute and seconds / 2. A date of 0 means the time wasn't recorded.
+pub fn unix_time(date: u16, time: u16) -> u32 {
+    if date == 0 {
+        return 0;
+    }
+    let year = 1980 + (date >> 9) as u32;
+    let month = min(max_one(((date >> 5) & 0xF) as u32), 12);
+    let day = max_one((date & 0x1F) as u32);
+    let seconds = (time >> 11) as u32 * 3600 +
+                  ((time >> 5) & 0x3F) as u32 * 60 +
+                  (time & 0x1F) as u32 * 2;
+    days_from_civil(year, month, day) * 86400 + seconds
+}
+
+// Converts seconds since Jan 1st 1970 to a FAT date and time. Times before
+// the FAT epoch are recorded as the epoch.
+pub fn fat_time(time: u32) -> (u16, u16) {
+    let time = core::cmp::max(time, FAT_EPOCH);
+    let (year, month, day) = civil_from_days(time / 86400);
+    let seconds = time % 86400;
+    let date = ((min(year - 1980, 127) << 9) | (month << 5) | day) as u16;
+    let time = ((seconds / 3600) << 11) |
+               ((seconds / 60 % 60) << 5) |
+               (seconds % 60 / 2);
+    (date, time as u16)
+}
+
+fn max_one(value: u32) -> u32 {
+    core::cmp::max(value, 1)
+}
+
+// Returns the number of days from Jan 1st 1970 to a date on or after it,
+// treating March as the first month of the year so leap days come last
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Returns the year, month and day |days| after Jan 1st 1970
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146_096) /
+                      365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | ((bytes[1] as u16) << 8)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 |
+    ((bytes[1] as u32) << 8) |
+    ((bytes[2] as u32) << 16) |
+    ((bytes[3] as u32) << 24)
+}
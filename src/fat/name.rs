@@ -0,0 +1,305 @@
+// Short and long names. Short names are 8.3 names in upper case, which can
+// be shown in lower case through flags in dir_nt_res. Any other name is
+// stored as UTF-16 in long name entries, along with a short name made from
+// it for systems that only read those.
+
+use super::DIR_ENTRY_SIZE;
+use crate::filesystem::FsError;
+use crate::vfs::MAX_NAME_LEN;
+
+// Length of a short name, 8 for the base and 3 for the extension
+pub const SHORT_NAME_LEN: usize = 11;
+const BASE_LEN: usize = 8;
+// Longest a short name gets when it's shown with its dot
+pub const SHORT_NAME_DISPLAY_LEN: usize = SHORT_NAME_LEN + 1;
+
+// Short names of the "." and ".." entries of a directory
+pub const DOT: &[u8] = b".          ";
+pub const DOT_DOT: &[u8] = b"..         ";
+
+// Bits of dir_nt_res that show the base or extension in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+// Characters a short name can have besides letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+// Characters no name can have
+const INVALID_CHARACTERS: &str = "\"*/:<>?\\|";
+
+// Number of UTF-16 characters each long name entry holds, and where they
+// are in the entry
+pub const LONG_NAME_CHARS: usize = 13;
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+// Where the attributes and the checksum of the short name are in a long
+// name entry
+const LONG_NAME_ATTR: usize = 11;
+const LONG_NAME_CHECKSUM: usize = 13;
+// Longest a long name can be, in UTF-16 characters and in entries
+const MAX_LONG_NAME: usize = 255;
+pub const MAX_LONG_NAME_ENTRIES: usize = 20;
+// Set in the ordinal of the long name entry holding the end of the name,
+// which is the first one in the directory
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+// Attributes of every long name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+// Checks that |name| can be the name of a file. Windows drops dots and
+// spaces from the end of names, so names ending in them are refused rather
+// than made unreachable there.
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() ||
+       name.len() > MAX_NAME_LEN ||
+       name == "." ||
+       name == ".." ||
+       name.ends_with('.') ||
+       name.ends_with(' ') ||
+       name.chars()
+           .any(|c| c < ' ' || INVALID_CHARACTERS.contains(c)) ||
+       name.encode_utf16().count() > MAX_LONG_NAME
+    {
+        return Err(FsError::InvalidName);
+    }
+    Ok(())
+}
+
+// Returns the short name and case flags |name| is stored as when it needs
+// no long name: a base of 1 to 8 characters and an extension of up to 3
+// that could be in a short name, each all in one case
+pub fn exact_short_name(name: &str) -> Option<([u8; SHORT_NAME_LEN], u8)> {
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > BASE_LEN || extension.len() > 3 {
+        return None;
+    }
+    if name.rfind('.').is_some() && extension.is_empty() {
+        return None;
+    }
+
+    let mut short_name = [b' '; SHORT_NAME_LEN];
+    let mut case = 0;
+    for (part, start, flag) in [(base, 0, CASE_LOWER_BASE),
+                                (extension, BASE_LEN, CASE_LOWER_EXT)].iter()
+    {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|byte| is_short_name_byte(*byte)) {
+            return None;
+        }
+        let lower = bytes.iter().any(|byte| byte.is_ascii_lowercase());
+        let upper = bytes.iter().any(|byte| byte.is_ascii_uppercase());
+        if lower && upper {
+            return None;
+        }
+        if lower {
+            case |= *flag;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            short_name[*start + i] = byte.to_ascii_uppercase();
+        }
+    }
+    Some((short_name, case))
+}
+
+// Makes the short name Windows would start from for |name|: upper case,
+// without spaces or leading and extra dots, with characters short names
+// can't have replaced by '_', cut down to 8.3. Also returns whether
+// anything besides case was lost, in which case the name needs a numeric
+// tail.
+pub fn basis_name(name: &str) -> ([u8; SHORT_NAME_LEN], bool) {
+    let trimmed = name.trim_start_matches('.');
+    let mut lossy = trimmed.len() != name.len();
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+
+    let mut short_name = [b' '; SHORT_NAME_LEN];
+    lossy |= fill_basis(base, &mut short_name[..BASE_LEN]);
+    lossy |= fill_basis(extension, &mut short_name[BASE_LEN..]);
+    if short_name[0] == b' ' {
+        short_name[0] = b'_';
+        lossy = true;
+    }
+    (short_name, lossy)
+}
+
+// Copies the characters of |part| that fit into |field|, returning whether
+// any were dropped or replaced
+fn fill_basis(part: &str, field: &mut [u8]) -> bool {
+    let mut lossy = false;
+    let mut length = 0;
+    for c in part.chars() {
+        if c == ' ' || c == '.' {
+            lossy = true;
+            continue;
+        }
+        if length == field.len() {
+            return true;
+        }
+        let byte = c as u32 as u8;
+        field[length] = if c.is_ascii() && is_short_name_byte(byte) {
+            byte.to_ascii_uppercase()
+        } else {
+            lossy = true;
+            b'_'
+        };
+        length += 1;
+    }
+    lossy
+}
+
+fn is_short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&byte)
+}
+
+// Puts the numeric tail "~|tail|" on the end of the base of |basis|,
+// shortening the base to make room for it
+pub fn with_tail(basis: &[u8; SHORT_NAME_LEN],
+                 tail: u32)
+                 -> [u8; SHORT_NAME_LEN] {
+    let mut digits = [0u8; 10];
+    let mut digit_count = 0;
+    let mut value = tail;
+    loop {
+        digits[digit_count] = b'0' + (value % 10) as u8;
+        digit_count += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+
+    let base_len = basis[..BASE_LEN].iter()
+                                    .position(|byte| *byte == b' ')
+                                    .unwrap_or(BASE_LEN);
+    let keep = core::cmp::min(base_len, BASE_LEN - 1 - digit_count);
+    let mut short_name = *basis;
+    short_name[keep] = b'~';
+    for i in 0..digit_count {
+        short_name[keep + 1 + i] = digits[digit_count - 1 - i];
+    }
+    for byte in short_name[keep + 1 + digit_count..BASE_LEN].iter_mut() {
+        *byte = b' ';
+    }
+    short_name
+}
+
+// Writes |short_name| into |out| the way it's shown, with a dot before any
+// extension and in lower case where |case| says. Returns the number of bytes
+// written, which is at most SHORT_NAME_DISPLAY_LEN.
+pub fn display_short_name(short_name: &[u8; SHORT_NAME_LEN],
+                          case: u8,
+                          out: &mut [u8])
+                          -> usize {
+    let base_len = short_name[..BASE_LEN].iter()
+                                         .rposition(|byte| *byte != b' ')
+                                         .map_or(0, |last| last + 1);
+    let extension_len = short_name[BASE_LEN..].iter()
+                                              .rposition(|byte| *byte != b' ')
+                                              .map_or(0, |last| last + 1);
+
+    let mut length = 0;
+    for (i, byte) in short_name[..base_len].iter().enumerate() {
+        // A first byte of 0xE5 is stored as 0x05 so it isn't taken for a
+        // deleted entry
+        let byte = if i == 0 && *byte == 0x05 { 0xE5 } else { *byte };
+        out[length] = display_byte(byte, case & CASE_LOWER_BASE != 0);
+        length += 1;
+    }
+    if extension_len > 0 {
+        out[length] = b'.';
+        length += 1;
+        for byte in short_name[BASE_LEN..BASE_LEN + extension_len].iter() {
+            out[length] = display_byte(*byte, case & CASE_LOWER_EXT != 0);
+            length += 1;
+        }
+    }
+    length
+}
+
+// Short names can have characters from the code page the volume was made
+// with, which can't be shown without knowing it, so they show as '_'
+fn display_byte(byte: u8, lower: bool) -> u8 {
+    if !byte.is_ascii() {
+        b'_'
+    } else if lower {
+        byte.to_ascii_lowercase()
+    } else {
+        byte
+    }
+}
+
+// Returns the checksum of a short name that its long name entries hold
+pub fn checksum(short_name: &[u8; SHORT_NAME_LEN]) -> u8 {
+    short_name.iter()
+              .fold(0, |sum: u8, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+// Returns the number of long name entries |name| takes up
+pub fn long_entry_count(name: &str) -> u32 {
+    let length = name.encode_utf16().count();
+    ((length + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS) as u32
+}
+
+// Returns the checksum held by the long name entry |bytes|
+pub fn long_entry_checksum(bytes: &[u8]) -> u8 {
+    bytes[LONG_NAME_CHECKSUM]
+}
+
+// Copies the characters held by the long name entry |bytes| into |units|
+pub fn read_long_entry(bytes: &[u8], units: &mut [u16]) {
+    for (unit, offset) in units.iter_mut().zip(LONG_NAME_OFFSETS.iter()) {
+        *unit = bytes[*offset] as u16 | ((bytes[*offset + 1] as u16) << 8);
+    }
+}
+
+// Fills |bytes| with the long name entry numbered |ordinal| of |name|. The
+// name ends with a 0 if there's room, and the rest of the entry is padded
+// with 0xFFFF.
+pub fn fill_long_entry(bytes: &mut [u8; DIR_ENTRY_SIZE as usize],
+                       name: &str,
+                       ordinal: u8,
+                       checksum: u8) {
+    let start = (ordinal as usize - 1) * LONG_NAME_CHARS;
+    let mut units = name.encode_utf16().skip(start);
+    let mut ended = false;
+    for offset in LONG_NAME_OFFSETS.iter() {
+        let unit = match units.next() {
+            Some(unit) => unit,
+            None if !ended => {
+                ended = true;
+                0
+            }
+            None => 0xFFFF,
+        };
+        bytes[*offset] = unit as u8;
+        bytes[*offset + 1] = (unit >> 8) as u8;
+    }
+    bytes[0] = ordinal;
+    bytes[LONG_NAME_ATTR] = ATTR_LONG_NAME;
+    bytes[LONG_NAME_CHECKSUM] = checksum;
+}
+
+// Decodes a long name from |units| into |out| as UTF-8. Returns the length
+// of the name, or None if it isn't valid UTF-16 or doesn't fit.
+pub fn decode_long_name(units: &[u16], out: &mut [u8]) -> Option<usize> {
+    let length = units.iter()
+                      .position(|unit| *unit == 0)
+                      .unwrap_or(units.len());
+    let mut done = 0;
+    for c in core::char::decode_utf16(units[..length].iter().cloned()) {
+        let c = c.ok()?;
+        if done + c.len_utf8() > out.len() {
+            return None;
+        }
+        c.encode_utf8(&mut out[done..]);
+        done += c.len_utf8();
+    }
+    if done == 0 {
+        None
+    } else {
+        Some(done)
+    }
+}
@@ -0,0 +1,227 @@
+// Operations on the directory tree: creating files, making and removing
+// directories, unlinking and renaming. FAT has no links, so each file has
+// exactly one entry and removing it frees the file's clusters.
+
+use super::dir::{DirectoryEntry,
+                 ATTR_ARCHIVE,
+                 ATTR_DIRECTORY,
+                 ATTR_READ_ONLY};
+use super::name::{self, DOT, DOT_DOT};
+use super::{Fat32, DIR_ENTRY_SIZE, FAT_ROOT_INODE};
+use crate::block::BlockDevice;
+use crate::filesystem::FsError;
+
+impl<B: BlockDevice> Fat32<B> {
+    // Creates an empty file named |name| in the directory |parent| and
+    // returns its inode number. The file is read-only if |mode| has no write
+    // permission, since that's the only permission FAT keeps.
+    pub fn create_in(&mut self,
+                     parent: u32,
+                     name: &str,
+                     mode: u16)
+                     -> Result<u32, FsError> {
+        self.check_new_entry(parent, name)?;
+        let entry = DirectoryEntry::new(ATTR_ARCHIVE | read_only(mode),
+                                        self.timestamp());
+        self.add_entry(parent, name, &entry)
+    }
+
+    // Makes an empty directory named |name| in the directory |parent| and
+    // returns its inode number. It starts with one cluster holding its "."
+    // and ".." entries.
+    pub fn mkdir_in(&mut self,
+                    parent: u32,
+                    name: &str,
+                    mode: u16)
+                    -> Result<u32, FsError> {
+        self.check_new_entry(parent, name)?;
+        let parent_cluster = self.dir_cluster(parent)?;
+        let cluster = self.alloc_cluster(None)?;
+        let time = self.timestamp();
+
+        let mut dot = DirectoryEntry::new(ATTR_DIRECTORY, time);
+        dot.dir_name.copy_from_slice(DOT);
+        dot.set_cluster(cluster);
+        // The ".." entry of a directory in the root points to cluster 0
+        let mut dot_dot = DirectoryEntry::new(ATTR_DIRECTORY, time);
+        dot_dot.dir_name.copy_from_slice(DOT_DOT);
+        dot_dot.set_cluster(self.dot_dot_target(parent, parent_cluster));
+
+        let mut entry =
+            DirectoryEntry::new(ATTR_DIRECTORY | read_only(mode), time);
+        entry.set_cluster(cluster);
+
+        let result = self.write_struct_at(self.cluster_position(cluster, 0),
+                                          &dot)
+                         .and_then(|_| {
+                             let position =
+                                 self.cluster_position(cluster, DIR_ENTRY_SIZE);
+                             self.write_struct_at(position, &dot_dot)
+                         })
+                         .and_then(|_| self.add_entry(parent, name, &entry));
+        if result.is_err() {
+            self.free_chain(cluster)?;
+        }
+        result
+    }
+
+    // Removes the file named |name| from the directory |parent| and frees
+    // its clusters
+    pub fn unlink_in(&mut self,
+                     parent: u32,
+                     name: &str)
+                     -> Result<(), FsError> {
+        self.check_entry_name(parent, name)?;
+        let entry = self.find_entry(parent, name)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        let first_cluster = self.dir_cluster(parent)?;
+        self.remove_entry(first_cluster, &entry)?;
+        self.free_chain(entry.cluster)
+    }
+
+    // Removes the empty directory named |name| from the directory |parent|
+    pub fn rmdir_in(&mut self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_entry_name(parent, name)?;
+        let entry = self.find_entry(parent, name)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        if !self.is_empty_directory(entry.inode)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        let first_cluster = self.dir_cluster(parent)?;
+        self.remove_entry(first_cluster, &entry)?;
+        self.free_chain(entry.cluster)
+    }
+
+    // Moves the entry |old_name| of the directory |old_parent| to |new_name|
+    // in the directory |new_parent|, replacing whatever |new_name| named
+    // before. Directories can only replace empty directories and files can
+    // only replace files. Renaming gives the file a new inode number, since
+    // its entry moves.
+    pub fn rename_in(&mut self,
+                     old_parent: u32,
+                     old_name: &str,
+                     new_parent: u32,
+                     new_name: &str)
+                     -> Result<(), FsError> {
+        self.check_entry_name(old_parent, old_name)?;
+        self.check_entry_name(new_parent, new_name)?;
+        let entry = self.find_entry(old_parent, old_name)?;
+        let mut replaced = match self.find_entry(new_parent, new_name) {
+            Ok(replaced) => Some(replaced),
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        // Names differing only in case name the same file, so renaming a file
+        // onto one of them only changes how its name is written
+        if replaced.as_ref().map(|existing| existing.inode) == Some(entry.inode)
+        {
+            if entry.name() == new_name {
+                return Ok(());
+            }
+            replaced = None;
+        }
+
+        let old_cluster = self.dir_cluster(old_parent)?;
+        let new_cluster = self.dir_cluster(new_parent)?;
+        if entry.is_directory() && self.is_within(new_cluster, entry.cluster)? {
+            // A directory can't be moved inside itself
+            return Err(FsError::InvalidArgument);
+        }
+
+        if let Some(replaced) = replaced {
+            if entry.is_directory() && !replaced.is_directory() {
+                return Err(FsError::NotADirectory);
+            }
+            if !entry.is_directory() && replaced.is_directory() {
+                return Err(FsError::IsADirectory);
+            }
+            if replaced.is_directory() &&
+               !self.is_empty_directory(replaced.inode)?
+            {
+                return Err(FsError::NotEmpty);
+            }
+            self.remove_entry(new_cluster, &replaced)?;
+            self.free_chain(replaced.cluster)?;
+        }
+
+        // The new entry is added before the old one is removed, so the file
+        // isn't lost if the new directory has no room
+        let position = self.dir_position(old_cluster, entry.offset)?;
+        let short_entry: DirectoryEntry = self.read_struct_at(position)?;
+        self.add_entry(new_parent, new_name, &short_entry)?;
+        self.remove_entry(old_cluster, &entry)?;
+
+        // A directory that changed parents has to point its ".." at the new
+        // one
+        if entry.is_directory() && new_parent != old_parent {
+            let position = self.cluster_position(entry.cluster, DIR_ENTRY_SIZE);
+            let mut dot_dot: DirectoryEntry = self.read_struct_at(position)?;
+            if &dot_dot.dir_name[..] == DOT_DOT {
+                dot_dot.set_cluster(self.dot_dot_target(new_parent,
+                                                        new_cluster));
+                self.write_struct_at(position, &dot_dot)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks whether the directory starting at |cluster| is the directory
+    // starting at |ancestor| or somewhere below it
+    fn is_within(&self, cluster: u32, ancestor: u32) -> Result<bool, FsError> {
+        let mut cluster = cluster;
+        for _ in 0..self.cluster_count() {
+            if cluster == ancestor {
+                return Ok(true);
+            }
+            if cluster == self.bpb.root_cluster {
+                return Ok(false);
+            }
+            cluster = self.dot_dot_cluster(cluster)?;
+        }
+        Err(FsError::InvalidBlock)
+    }
+
+    // Returns the cluster the ".." entry of a directory in the directory
+    // |parent|, which starts at |parent_cluster|, points to
+    fn dot_dot_target(&self, parent: u32, parent_cluster: u32) -> u32 {
+        if parent == FAT_ROOT_INODE {
+            0
+        } else {
+            parent_cluster
+        }
+    }
+
+    // Checks that |name| can be an entry of the directory |parent|
+    fn check_entry_name(&self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        name::check_name(name)?;
+        self.dir_cluster(parent)?;
+        Ok(())
+    }
+
+    // Like check_entry_name, but the entry must not exist yet
+    fn check_new_entry(&self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_entry_name(parent, name)?;
+        match self.find_entry(parent, name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Returns the attribute for a file or directory made with |mode|
+fn read_only(mode: u16) -> u8 {
+    if mode & 0o222 == 0 {
+        ATTR_READ_ONLY
+    } else {
+        0
+    }
+}
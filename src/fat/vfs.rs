@@ -0,0 +1,192 @@
+// FAT32 as a backend of the VFS. FAT only records whether a file is a
+// directory and whether it's read-only, so the rest of its metadata is made
+// up: everything belongs to root, directories are rwxr-xr-x and files are
+// rw-r--r--.
+
+use super::dir::ATTR_READ_ONLY;
+use super::{unix_time, Fat32, FAT_ROOT_INODE};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::filesystem::FsError;
+use crate::vfs::{DirEntry,
+                 FileOperations,
+                 FileType,
+                 FsStats,
+                 InodeOperations,
+                 Metadata};
+
+// Permissions of directories and files, and the ones taken away from
+// read-only files
+const DIRECTORY_PERMISSIONS: u16 = 0o755;
+const FILE_PERMISSIONS: u16 = 0o644;
+const WRITE_PERMISSIONS: u16 = 0o222;
+
+impl<B: BlockDevice> InodeOperations for Fat32<B> {
+    fn root(&self) -> u32 {
+        FAT_ROOT_INODE
+    }
+
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError> {
+        if inode == FAT_ROOT_INODE || self.load_entry(inode)?.is_directory() {
+            Ok(FileType::Directory)
+        } else {
+            Ok(FileType::Regular)
+        }
+    }
+
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError> {
+        if inode == FAT_ROOT_INODE {
+            let clusters = self.chain_length(self.bpb.root_cluster)?;
+            return Ok(self.directory_metadata(inode, clusters, 0, 0));
+        }
+
+        let entry = self.load_entry(inode)?;
+        let mtime = unix_time(entry.dir_wrt_date, entry.dir_wrt_time);
+        let atime = unix_time(entry.dir_lst_acc_date, 0);
+        if entry.is_directory() {
+            let clusters = self.chain_length(entry.cluster())?;
+            return Ok(self.directory_metadata(inode, clusters, atime, mtime));
+        }
+
+        let mut permissions = FILE_PERMISSIONS;
+        if entry.dir_attr & ATTR_READ_ONLY != 0 {
+            permissions &= !WRITE_PERMISSIONS;
+        }
+        let size = entry.dir_file_size;
+        let clusters = (size as u64 + self.cluster_size as u64 - 1) /
+                       self.cluster_size as u64;
+        Ok(Metadata { inode: inode,
+                      file_type: FileType::Regular,
+                      permissions: permissions,
+                      uid: 0,
+                      gid: 0,
+                      size: size,
+                      links: 1,
+                      atime: atime,
+                      mtime: mtime,
+                      // FAT doesn't record when an entry changed, so this
+                      // is the last write like on Linux
+                      ctime: mtime,
+                      blocks: self.sectors(clusters as u32) })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        self.lookup_in(dir, name)
+    }
+
+    fn create(&mut self,
+              dir: u32,
+              name: &str,
+              mode: u16)
+              -> Result<u32, FsError> {
+        self.create_in(dir, name, mode)
+    }
+
+    fn mkdir(&mut self,
+             dir: u32,
+             name: &str,
+             mode: u16)
+             -> Result<u32, FsError> {
+        self.mkdir_in(dir, name, mode)
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        self.unlink_in(dir, name)
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        self.rmdir_in(dir, name)
+    }
+
+    fn rename(&mut self,
+              old_dir: u32,
+              old_name: &str,
+              new_dir: u32,
+              new_name: &str)
+              -> Result<(), FsError> {
+        self.rename_in(old_dir, old_name, new_dir, new_name)
+    }
+}
+
+impl<B: BlockDevice> FileOperations for Fat32<B> {
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError> {
+        self.read_at(inode, offset, buf)
+    }
+
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        self.write_at(inode, offset, buf)
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        Fat32::truncate(self, inode, size)
+    }
+
+    // Positions 0 and 1 are "." and "..", and the rest are byte offsets of
+    // entries within the directory plus 2
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError> {
+        let mut entries = self.readdir(dir)?;
+        entries.seek(position);
+
+        Ok(entries.next().map(|entry| {
+                             let file_type = if entry.is_directory() {
+                                 FileType::Directory
+                             } else {
+                                 FileType::Regular
+                             };
+                             (DirEntry::new(entry.inode,
+                                            file_type,
+                                            entry.name_bytes()),
+                              entries.offset())
+                         }))
+    }
+
+    // FAT has no inodes to count, so clusters are the only thing reported
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        Ok(FsStats { block_size: self.cluster_size,
+                     blocks: self.cluster_count(),
+                     free_blocks: self.free_clusters()?,
+                     inodes: 0,
+                     free_inodes: 0 })
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Fat32::sync(self)
+    }
+}
+
+impl<B: BlockDevice> Fat32<B> {
+    // Makes the metadata of a directory taking up |clusters| clusters
+    fn directory_metadata(&self,
+                          inode: u32,
+                          clusters: u32,
+                          atime: u32,
+                          mtime: u32)
+                          -> Metadata {
+        Metadata { inode: inode,
+                   file_type: FileType::Directory,
+                   permissions: DIRECTORY_PERMISSIONS,
+                   uid: 0,
+                   gid: 0,
+                   size: clusters * self.cluster_size,
+                   links: 2,
+                   atime: atime,
+                   mtime: mtime,
+                   ctime: mtime,
+                   blocks: self.sectors(clusters) }
+    }
+
+    // Returns the number of 512-byte sectors in |clusters| clusters
+    fn sectors(&self, clusters: u32) -> u32 {
+        clusters * (self.cluster_size / SECTOR_SIZE)
+    }
+}
@@ -0,0 +1,230 @@
+// Allocation of blocks and inodes through the per-group bitmaps. Every
+// allocation keeps the free counts in the group descriptors and the
+// Superblock in step with the bitmaps.
+
+use super::{Device, FsError, Inode};
+use crate::block::BlockDevice;
+use core::cmp::min;
+
+impl<B: BlockDevice> Device<B> {
+    // Allocates a zeroed block, preferring the block group |goal_group|, and
+    // returns its number
+    pub fn alloc_block(&mut self, goal_group: u32) -> Result<u32, FsError> {
+        self.check_writable()?;
+
+        for i in 0..self.group_count {
+            let group = (goal_group + i) % self.group_count;
+            let descriptor = self.group_descriptor(group)?;
+            if descriptor.bg_free_blocks_count == 0 {
+                continue;
+            }
+
+            let bitmap = descriptor.bg_block_bitmap;
+            let count = self.blocks_in_group(group);
+            let bit = match self.find_clear_bit(bitmap, 0, count)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            self.set_bit(bitmap, bit, true)?;
+            self.group_descriptor_mut(group)?.bg_free_blocks_count -= 1;
+            self.superblock.s_free_blocks_count -= 1;
+            self.write_group_descriptor(group)?;
+            self.write_superblock()?;
+
+            let block = self.superblock.s_first_data_block +
+                        group * self.superblock.s_blocks_per_group +
+                        bit;
+            self.zero_block(block)?;
+            return Ok(block);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    // Returns |block| to its group's free pool
+    pub fn free_block(&mut self, block: u32) -> Result<(), FsError> {
+        self.check_writable()?;
+        if block < self.superblock.s_first_data_block ||
+           block >= self.superblock.s_blocks_count
+        {
+            return Err(FsError::InvalidBlock);
+        }
+
+        let relative = block - self.superblock.s_first_data_block;
+        let group = relative / self.superblock.s_blocks_per_group;
+        let bit = relative % self.superblock.s_blocks_per_group;
+        let bitmap = self.group_descriptor(group)?.bg_block_bitmap;
+
+        // Freeing a free block means something else already went wrong
+        if !self.test_bit(bitmap, bit)? {
+            return Err(FsError::InvalidBlock);
+        }
+
+        self.set_bit(bitmap, bit, false)?;
+        self.group_descriptor_mut(group)?.bg_free_blocks_count += 1;
+        self.superblock.s_free_blocks_count += 1;
+        self.write_group_descriptor(group)?;
+        self.write_superblock()?;
+        Ok(())
+    }
+
+    // Allocates an inode, preferring the block group |goal_group|, and returns
+    // its number. The inode is cleared before being handed out.
+    pub fn alloc_inode(&mut self,
+                       directory: bool,
+                       goal_group: u32)
+                       -> Result<u32, FsError> {
+        self.check_writable()?;
+        let per_group = self.superblock.s_inodes_per_group;
+
+        for i in 0..self.group_count {
+            let group = (goal_group + i) % self.group_count;
+            let descriptor = self.group_descriptor(group)?;
+            if descriptor.bg_free_inodes_count == 0 {
+                continue;
+            }
+
+            // Inodes below the first non-reserved inode are never handed out
+            let first = group * per_group + 1;
+            let start = if self.first_inode > first {
+                min(self.first_inode - first, per_group)
+            } else {
+                0
+            };
+
+            let bitmap = descriptor.bg_inode_bitmap;
+            let bit = match self.find_clear_bit(bitmap, start, per_group)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            self.set_bit(bitmap, bit, true)?;
+            {
+                let descriptor = self.group_descriptor_mut(group)?;
+                descriptor.bg_free_inodes_count -= 1;
+                if directory {
+                    descriptor.bg_used_dirs_count += 1;
+                }
+            }
+            self.superblock.s_free_inodes_count -= 1;
+            self.write_group_descriptor(group)?;
+            self.write_superblock()?;
+
+            let inode_number = first + bit;
+            self.write_inode(inode_number, &Inode::new())?;
+            return Ok(inode_number);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    // Returns |inode_number| to its group's free pool. |directory| must match
+    // what the inode was allocated as so the directory count stays right.
+    pub fn free_inode(&mut self,
+                      inode_number: u32,
+                      directory: bool)
+                      -> Result<(), FsError> {
+        self.check_writable()?;
+        if inode_number < self.first_inode ||
+           inode_number > self.superblock.s_inodes_count
+        {
+            return Err(FsError::InvalidInode);
+        }
+
+        let group = (inode_number - 1) / self.superblock.s_inodes_per_group;
+        let bit = (inode_number - 1) % self.superblock.s_inodes_per_group;
+        let bitmap = self.group_descriptor(group)?.bg_inode_bitmap;
+
+        if !self.test_bit(bitmap, bit)? {
+            return Err(FsError::InvalidInode);
+        }
+
+        self.set_bit(bitmap, bit, false)?;
+        {
+            let descriptor = self.group_descriptor_mut(group)?;
+            descriptor.bg_free_inodes_count += 1;
+            if directory {
+                descriptor.bg_used_dirs_count -= 1;
+            }
+        }
+        self.superblock.s_free_inodes_count += 1;
+        self.write_group_descriptor(group)?;
+        self.write_superblock()?;
+        Ok(())
+    }
+
+    // Returns the block group an inode belongs to, which is where its data
+    // blocks are best allocated from
+    pub fn inode_group(&self, inode_number: u32) -> u32 {
+        (inode_number - 1) / self.superblock.s_inodes_per_group
+    }
+
+    // Returns the number of blocks in |group|. The last group is usually
+    // shorter than the rest.
+    pub(super) fn blocks_in_group(&self, group: u32) -> u32 {
+        let data_blocks =
+            self.superblock.s_blocks_count - self.superblock.s_first_data_block;
+        let start = group * self.superblock.s_blocks_per_group;
+        min(self.superblock.s_blocks_per_group, data_blocks - start)
+    }
+
+    // Finds the first clear bit between |start| and |count| in a bitmap block
+    fn find_clear_bit(&self,
+                      bitmap: u32,
+                      start: u32,
+                      count: u32)
+                      -> Result<Option<u32>, FsError> {
+        let mut chunk = [0u8; 64];
+        let mut bit = start;
+
+        while bit < count {
+            // The bitmap is read a chunk of bytes at a time
+            let first_byte = bit / 8;
+            let length =
+                min(chunk.len() as u32, (count + 7) / 8 - first_byte) as usize;
+            self.read_bytes(bitmap, first_byte as usize, &mut chunk[..length])?;
+            let end = min(count, (first_byte + length as u32) * 8);
+
+            while bit < end {
+                let byte = chunk[(bit / 8 - first_byte) as usize];
+
+                // Skip over whole bytes that are full
+                if byte == 0xFF && bit % 8 == 0 {
+                    bit += 8;
+                    continue;
+                }
+                if byte & (1 << (bit % 8)) == 0 {
+                    return Ok(Some(bit));
+                }
+                bit += 1;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Checks whether |bit| is set in a bitmap block
+    pub(super) fn test_bit(&self,
+                           bitmap: u32,
+                           bit: u32)
+                           -> Result<bool, FsError> {
+        let byte: u8 = self.read_struct(bitmap, bit as usize / 8)?;
+        Ok(byte & (1 << (bit % 8)) != 0)
+    }
+
+    // Sets or clears |bit| in a bitmap block
+    pub(super) fn set_bit(&mut self,
+                          bitmap: u32,
+                          bit: u32,
+                          value: bool)
+                          -> Result<(), FsError> {
+        let byte: u8 = self.read_struct(bitmap, bit as usize / 8)?;
+        let byte = if value {
+            byte | (1 << (bit % 8))
+        } else {
+            byte & !(1 << (bit % 8))
+        };
+        self.write_struct(bitmap, bit as usize / 8, &byte)
+    }
+}
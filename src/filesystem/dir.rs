@@ -0,0 +1,467 @@
+use super::{Device,
+            FsError,
+            Inode,
+            EXT2_FEATURE_INCOMPAT_FILETYPE,
+            EXT2_INDEX_FL};
+use crate::block::BlockDevice;
+
+// Longest name a directory entry can hold
+pub const MAX_NAME_LEN: usize = 255;
+
+// Values of the file_type field of a directory entry
+pub const EXT2_FT_UNKNOWN: u8 = 0;
+pub const EXT2_FT_REG_FILE: u8 = 1;
+pub const EXT2_FT_DIR: u8 = 2;
+pub const EXT2_FT_CHRDEV: u8 = 3;
+pub const EXT2_FT_BLKDEV: u8 = 4;
+pub const EXT2_FT_FIFO: u8 = 5;
+pub const EXT2_FT_SOCK: u8 = 6;
+pub const EXT2_FT_SYMLINK: u8 = 7;
+
+// Size of the fixed part of a directory entry that precedes the name
+pub const DIR_ENTRY_HEADER_SIZE: u32 = 8;
+
+// The fixed part of a directory entry, which is followed by |name_len| bytes
+// of name
+#[repr(C)]
+pub struct DirectoryEntry {
+    // Inode number of the entry, 0 if the entry is unused
+    pub(super) inode: u32,
+    // Displacement to the next directory entry
+    pub(super) rec_len: u16,
+    // Number of bytes in the name
+    pub(super) name_len: u8,
+    // Type of the file the entry points to
+    pub(super) file_type: u8,
+}
+
+// A directory entry copied out of the device, along with its name
+pub struct DirEntry {
+    pub inode: u32,
+    pub rec_len: u16,
+    pub file_type: u8,
+    name_len: u8,
+    name: [u8; MAX_NAME_LEN],
+}
+
+// Iterator over the used entries of a directory
+pub struct ReadDir<'a, B: BlockDevice + 'a> {
+    device: &'a Device<B>,
+    inode: Inode,
+    // Byte offset of the next entry within the directory
+    offset: u32,
+    // Whether entries record the type of file they point to
+    has_file_type: bool,
+}
+
+impl<B: BlockDevice> Device<B> {
+    // Returns an iterator over the entries of the directory |inode_number|
+    pub fn readdir(&self, inode_number: u32) -> Result<ReadDir<B>, FsError> {
+        let inode = self.load_inode(inode_number)?;
+        if !inode.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(ReadDir::new(self, inode))
+    }
+
+    // Adds an entry named |name| for |inode_number| to the directory
+    // |dir_number|. Space is split off the slack at the end of an existing
+    // entry when there's enough, otherwise the directory grows by a block.
+    pub(super) fn add_entry(&mut self,
+                            dir_number: u32,
+                            name: &str,
+                            inode_number: u32,
+                            file_type: u8)
+                            -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut dir = self.load_inode(dir_number)?;
+        let needed = entry_size(name.len() as u32);
+        let file_type =
+            if self.has_incompat_feature(EXT2_FEATURE_INCOMPAT_FILETYPE) {
+                file_type
+            } else {
+                EXT2_FT_UNKNOWN
+            };
+
+        // New entries go wherever they fit, which would break a hashed index
+        if dir.i_flags & EXT2_INDEX_FL != 0 {
+            dir.i_flags &= !EXT2_INDEX_FL;
+            self.write_inode(dir_number, &dir)?;
+        }
+
+        let block_size = self.block_size;
+        for index in 0..dir.i_size / block_size {
+            let block = self.get_block_number(&dir, index)?;
+            if block == 0 {
+                continue;
+            }
+
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER_SIZE <= block_size {
+                let header = self.read_entry_header(block, offset)?;
+                let rec_len = header.rec_len as u32;
+                if rec_len < DIR_ENTRY_HEADER_SIZE ||
+                   offset + rec_len > block_size
+                {
+                    break;
+                }
+
+                // Unused entries can be taken over entirely
+                let used = if header.inode == 0 {
+                    0
+                } else {
+                    entry_size(header.name_len as u32)
+                };
+
+                if rec_len >= used + needed {
+                    if used != 0 {
+                        self.set_rec_len(block, offset, used)?;
+                    }
+                    return self.write_entry(block,
+                                            offset + used,
+                                            inode_number,
+                                            rec_len - used,
+                                            file_type,
+                                            name.as_bytes());
+                }
+
+                offset += rec_len;
+            }
+        }
+
+        // No room anywhere, so add a block holding just the new entry
+        let group = self.inode_group(dir_number);
+        let index = dir.i_size / block_size;
+        let block = self.get_or_alloc_block(&mut dir, index, group)?;
+        dir.i_size += block_size;
+        self.write_inode(dir_number, &dir)?;
+        self.write_entry(block,
+                         0,
+                         inode_number,
+                         block_size,
+                         file_type,
+                         name.as_bytes())
+    }
+
+    // Removes the entry named |name| from the directory |dir_number| and
+    // returns the inode number it pointed to. The entry's space is merged into
+    // the entry before it, or marked unused if it starts a block.
+    pub(super) fn remove_entry(&mut self,
+                               dir_number: u32,
+                               name: &str)
+                               -> Result<u32, FsError> {
+        self.check_writable()?;
+        let dir = self.load_inode(dir_number)?;
+        let (block, offset, previous) = self.find_entry_slot(&dir,
+                                                             name.as_bytes())?
+                                            .ok_or(FsError::NotFound)?;
+        let header = self.read_entry_header(block, offset)?;
+
+        match previous {
+            Some(previous) => {
+                let previous_len =
+                    self.read_entry_header(block, previous)?.rec_len as u32;
+                self.set_rec_len(block,
+                                 previous,
+                                 previous_len + header.rec_len as u32)?;
+            }
+            None => {
+                self.write_entry_header(block,
+                                        offset,
+                                        &DirectoryEntry { inode: 0,
+                                                          ..header })?;
+            }
+        }
+
+        Ok(header.inode)
+    }
+
+    // Points the existing entry named |name| in the directory |dir_number| at
+    // |inode_number| instead and returns the inode number it used to hold
+    pub(super) fn replace_entry(&mut self,
+                                dir_number: u32,
+                                name: &str,
+                                inode_number: u32,
+                                file_type: u8)
+                                -> Result<u32, FsError> {
+        self.check_writable()?;
+        let dir = self.load_inode(dir_number)?;
+        let (block, offset, _) = self.find_entry_slot(&dir, name.as_bytes())?
+                                     .ok_or(FsError::NotFound)?;
+        let header = self.read_entry_header(block, offset)?;
+        let file_type =
+            if self.has_incompat_feature(EXT2_FEATURE_INCOMPAT_FILETYPE) {
+                file_type
+            } else {
+                header.file_type
+            };
+
+        self.write_entry_header(block,
+                                offset,
+                                &DirectoryEntry { inode: inode_number,
+                                                  file_type: file_type,
+                                                  ..header })?;
+        Ok(header.inode)
+    }
+
+    // Searches the entries of a directory for |name| and returns the inode
+    // number it refers to
+    pub(super) fn find_entry(&self,
+                             dir: Inode,
+                             name: &str)
+                             -> Result<u32, FsError> {
+        match self.find_entry_slot(&dir, name.as_bytes())? {
+            Some((block, offset, _)) => {
+                Ok(self.read_entry_header(block, offset)?.inode)
+            }
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Finds the used entry named |name| in |dir|. Returns the block holding
+    // it, its offset in the block, and the offset of the entry before it in
+    // the same block if there is one.
+    fn find_entry_slot(&self,
+                       dir: &Inode,
+                       name: &[u8])
+                       -> Result<Option<(u32, u32, Option<u32>)>, FsError> {
+        // Indexed directories can go straight to the right block, unless the
+        // index turns out to be unusable
+        if self.is_indexed(dir) {
+            if let Ok(slot) = self.htree_find_slot(dir, name) {
+                return Ok(slot);
+            }
+        }
+
+        for index in 0..dir.i_size / self.block_size {
+            let block = self.get_block_number(dir, index)?;
+            if block == 0 {
+                continue;
+            }
+
+            if let Some((offset, previous)) = self.find_in_block(block, name)? {
+                return Ok(Some((block, offset, previous)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Finds the used entry named |name| in the directory block |block|.
+    // Returns its offset and the offset of the entry before it if there is
+    // one.
+    pub(super) fn find_in_block(
+        &self,
+        block: u32,
+        name: &[u8])
+        -> Result<Option<(u32, Option<u32>)>, FsError> {
+        let block_size = self.block_size;
+        let mut offset = 0;
+        let mut previous = None;
+
+        while offset + DIR_ENTRY_HEADER_SIZE <= block_size {
+            let header = self.read_entry_header(block, offset)?;
+            let rec_len = header.rec_len as u32;
+            if rec_len < DIR_ENTRY_HEADER_SIZE || offset + rec_len > block_size
+            {
+                break;
+            }
+
+            if header.inode != 0 &&
+               header.name_len as usize == name.len() &&
+               self.entry_name_matches(block, offset, name)?
+            {
+                return Ok(Some((offset, previous)));
+            }
+
+            previous = Some(offset);
+            offset += rec_len;
+        }
+
+        Ok(None)
+    }
+
+    // Compares the name of the entry at |offset| in |block| with |name|
+    fn entry_name_matches(&self,
+                          block: u32,
+                          offset: u32,
+                          name: &[u8])
+                          -> Result<bool, FsError> {
+        let mut entry_name = [0u8; MAX_NAME_LEN];
+        let entry_name = &mut entry_name[..name.len()];
+        self.read_bytes(block,
+                        (offset + DIR_ENTRY_HEADER_SIZE) as usize,
+                        entry_name)?;
+        Ok(entry_name == name)
+    }
+
+    // Reads the header of the entry at |offset| in |block|
+    pub(super) fn read_entry_header(&self,
+                                    block: u32,
+                                    offset: u32)
+                                    -> Result<DirectoryEntry, FsError> {
+        self.read_struct(block, offset as usize)
+    }
+
+    // Overwrites the header of the entry at |offset| in |block|
+    pub(super) fn write_entry_header(&mut self,
+                                     block: u32,
+                                     offset: u32,
+                                     header: &DirectoryEntry)
+                                     -> Result<(), FsError> {
+        self.write_struct(block, offset as usize, header)
+    }
+
+    // Changes the record length of the entry at |offset| in |block|
+    pub(super) fn set_rec_len(&mut self,
+                              block: u32,
+                              offset: u32,
+                              rec_len: u32)
+                              -> Result<(), FsError> {
+        let header = self.read_entry_header(block, offset)?;
+        self.write_entry_header(block,
+                                offset,
+                                &DirectoryEntry { rec_len: rec_len as u16,
+                                                  ..header })
+    }
+
+    // Writes a whole entry, header and name, at |offset| in |block|
+    fn write_entry(&mut self,
+                   block: u32,
+                   offset: u32,
+                   inode_number: u32,
+                   rec_len: u32,
+                   file_type: u8,
+                   name: &[u8])
+                   -> Result<(), FsError> {
+        self.write_entry_header(block,
+                                offset,
+                                &DirectoryEntry { inode: inode_number,
+                                                  rec_len: rec_len as u16,
+                                                  name_len: name.len()
+                                                            as u8,
+                                                  file_type: file_type })?;
+        self.write_bytes(block, (offset + DIR_ENTRY_HEADER_SIZE) as usize, name)
+    }
+}
+
+// Returns the space an entry with a |name_len| byte name needs, rounded up
+// so that every entry starts on a 4 byte boundary
+pub fn entry_size(name_len: u32) -> u32 {
+    (DIR_ENTRY_HEADER_SIZE + name_len + 3) & !3
+}
+
+impl DirEntry {
+    // Returns the raw bytes of the entry's name
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    // Returns the entry's name, or "?" if it isn't valid UTF-8
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(self.name_bytes()).unwrap_or("?")
+    }
+}
+
+impl<'a, B: BlockDevice> ReadDir<'a, B> {
+    // Starts iterating at the first entry of the directory |inode|
+    pub fn new(device: &'a Device<B>, inode: Inode) -> ReadDir<'a, B> {
+        let has_file_type =
+            device.has_incompat_feature(EXT2_FEATURE_INCOMPAT_FILETYPE);
+        ReadDir { device: device,
+                  inode: inode,
+                  offset: 0,
+                  has_file_type: has_file_type }
+    }
+
+    // Returns the byte offset within the directory of the next entry to be
+    // read
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    // Continues iterating from |offset|, which has to be an offset returned
+    // by offset()
+    pub fn seek(&mut self, offset: u32) {
+        self.offset = offset;
+    }
+}
+
+impl<'a, B: BlockDevice> Iterator for ReadDir<'a, B> {
+    type Item = DirEntry;
+
+    // Iteration ends early if the device fails to read a block
+    fn next(&mut self) -> Option<DirEntry> {
+        let block_size = self.device.block_size;
+
+        while self.offset < self.inode.i_size {
+            let index = self.offset / block_size;
+            let block_offset = self.offset % block_size;
+            let block_number =
+                match self.device.get_block_number(&self.inode, index) {
+                    Ok(block_number) => block_number,
+                    Err(_) => return None,
+                };
+
+            // Skip holes and any space too short to hold another entry
+            if block_number == 0 ||
+               block_offset + DIR_ENTRY_HEADER_SIZE > block_size
+            {
+                self.offset += block_size - block_offset;
+                continue;
+            }
+
+            let header =
+                match self.device.read_entry_header(block_number, block_offset)
+                {
+                    Ok(header) => header,
+                    Err(_) => return None,
+                };
+
+            // Entries never cross a block boundary, so a bad record length
+            // means the rest of this block can't be trusted
+            let rec_len = header.rec_len as u32;
+            if rec_len < DIR_ENTRY_HEADER_SIZE ||
+               block_offset + rec_len > block_size ||
+               DIR_ENTRY_HEADER_SIZE + header.name_len as u32 > rec_len
+            {
+                self.offset += block_size - block_offset;
+                continue;
+            }
+            self.offset += rec_len;
+
+            // Unused entries have an inode number of 0
+            if header.inode == 0 {
+                continue;
+            }
+
+            // Without the filetype feature the type byte is the high byte of
+            // the name length, which is always 0 for names we can hold
+            let file_type = if self.has_file_type {
+                header.file_type
+            } else {
+                EXT2_FT_UNKNOWN
+            };
+
+            let mut dir_entry = DirEntry { inode: header.inode,
+                                           rec_len: header.rec_len,
+                                           file_type: file_type,
+                                           name_len: header.name_len,
+                                           name: [0; MAX_NAME_LEN] };
+            let name = (block_offset + DIR_ENTRY_HEADER_SIZE) as usize;
+            if self.device
+                   .read_bytes(block_number,
+                               name,
+                               &mut dir_entry.name[..header.name_len as usize])
+                   .is_err()
+            {
+                return None;
+            }
+
+            return Some(dir_entry);
+        }
+
+        None
+    }
+}
@@ -0,0 +1,181 @@
+// ext4 extent trees. Instead of a map of single blocks, i_block holds the
+// root node of a tree whose leaves each describe a run of contiguous blocks.
+// Every node starts with a header followed by an array of entries: index
+// entries pointing at the node below, or extents in the leaves. Entries are
+// sorted by the first file block they cover.
+
+use super::{Device, FsError, Inode};
+use crate::block::BlockDevice;
+use core::ptr::read_volatile;
+
+// Magic number at the start of every node
+const EXT4_EXT_MAGIC: u16 = 0xF30A;
+// Bytes taken by the header and by each entry of a node
+const EXTENT_ENTRY_SIZE: usize = 12;
+// Bytes of i_block holding the root node
+const ROOT_NODE_SIZE: usize = 60;
+// Extents longer than this are preallocated but not yet written, and their
+// real length is the excess
+const EXT_INIT_MAX_LEN: u16 = 32768;
+
+#[repr(C)]
+struct ExtentHeader {
+    // Always EXT4_EXT_MAGIC
+    eh_magic: u16,
+    // Number of valid entries following the header
+    eh_entries: u16,
+    // Number of entries the node has room for
+    eh_max: u16,
+    // Distance from the leaves, which have a depth of 0
+    eh_depth: u16,
+    // Unused by ext4
+    eh_generation: u32,
+}
+
+#[repr(C)]
+struct ExtentIndex {
+    // First file block covered by the child node
+    ei_block: u32,
+    // Low 32 bits of the child node's block number
+    ei_leaf_lo: u32,
+    // High 16 bits of the child node's block number
+    ei_leaf_hi: u16,
+    ei_unused: u16,
+}
+
+#[repr(C)]
+struct Extent {
+    // First file block covered by this extent
+    ee_block: u32,
+    // Number of blocks covered
+    ee_len: u16,
+    // High 16 bits of the first block number
+    ee_start_hi: u16,
+    // Low 32 bits of the first block number
+    ee_start_lo: u32,
+}
+
+impl<B: BlockDevice> Device<B> {
+    // Translates the |index|th block of an extent-mapped inode into a block
+    // number on the device by walking down the tree from the root in i_block.
+    // Returns 0 for holes, unwritten extents and malformed trees.
+    pub(super) fn extent_block_number(&self,
+                                      inode: &Inode,
+                                      index: u32)
+                                      -> Result<u32, FsError> {
+        // Block 0 never holds a node, so it stands for the root in i_block
+        let mut node = 0;
+        let mut node_size = ROOT_NODE_SIZE;
+        let mut expected_depth = None;
+
+        loop {
+            let header: ExtentHeader = self.read_node_entry(inode, node, 0)?;
+            let entries = header.eh_entries as usize;
+            if header.eh_magic != EXT4_EXT_MAGIC ||
+               header.eh_entries > header.eh_max ||
+               (entries + 1) * EXTENT_ENTRY_SIZE > node_size
+            {
+                return Ok(0);
+            }
+            // Each level down has to be one closer to the leaves, which also
+            // keeps a corrupt tree from looping forever
+            if let Some(depth) = expected_depth {
+                if header.eh_depth != depth {
+                    return Ok(0);
+                }
+            }
+
+            if header.eh_depth == 0 {
+                return self.find_extent(inode, node, entries, index);
+            }
+
+            // Descend into the last child starting at or before |index|
+            let mut child = None;
+            for i in 1..=entries {
+                let entry: ExtentIndex = self.read_node_entry(inode, node, i)?;
+                if entry.ei_block > index {
+                    break;
+                }
+                child = Some(entry);
+            }
+
+            let block = match child {
+                Some(ref entry)
+                    if self.is_valid_extent_block(entry.ei_leaf_hi,
+                                                  entry.ei_leaf_lo,
+                                                  1) =>
+                {
+                    entry.ei_leaf_lo
+                }
+                _ => return Ok(0),
+            };
+            node = block;
+            node_size = self.block_size as usize;
+            expected_depth = Some(header.eh_depth - 1);
+        }
+    }
+
+    // Searches the |entries| extents of the leaf |node| for the one covering
+    // |index| and returns the matching block number
+    fn find_extent(&self,
+                   inode: &Inode,
+                   node: u32,
+                   entries: usize,
+                   index: u32)
+                   -> Result<u32, FsError> {
+        for i in 1..=entries {
+            let extent: Extent = self.read_node_entry(inode, node, i)?;
+            if extent.ee_block > index {
+                break;
+            }
+
+            // Unwritten extents read as zeros just like holes
+            let (length, written) = if extent.ee_len > EXT_INIT_MAX_LEN {
+                (extent.ee_len - EXT_INIT_MAX_LEN, false)
+            } else {
+                (extent.ee_len, true)
+            };
+            let offset = index - extent.ee_block;
+            if offset >= length as u32 {
+                continue;
+            }
+
+            if written &&
+               self.is_valid_extent_block(extent.ee_start_hi,
+                                          extent.ee_start_lo,
+                                          length as u32)
+            {
+                return Ok(extent.ee_start_lo + offset);
+            }
+            return Ok(0);
+        }
+
+        Ok(0)
+    }
+
+    // Reads the |i|th 12 byte slot of the node stored in the block |node|,
+    // or of the root in the i_block of |inode| if |node| is 0. Slot 0 is the
+    // header.
+    fn read_node_entry<T>(&self,
+                          inode: &Inode,
+                          node: u32,
+                          i: usize)
+                          -> Result<T, FsError> {
+        if node != 0 {
+            return self.read_struct(node, i * EXTENT_ENTRY_SIZE);
+        }
+
+        let root = inode.i_block.as_ptr() as *const u8;
+        Ok(unsafe {
+            read_volatile(root.add(i * EXTENT_ENTRY_SIZE) as *const T)
+        })
+    }
+
+    // Checks that the |length| blocks starting at the 48 bit block number
+    // made of |high| and |low| are all on the device
+    fn is_valid_extent_block(&self, high: u16, low: u32, length: u32) -> bool {
+        high == 0 &&
+        low != 0 &&
+        low as u64 + length as u64 <= self.superblock.s_blocks_count as u64
+    }
+}
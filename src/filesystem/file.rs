@@ -0,0 +1,229 @@
+// Writing file data. Files grow by allocating data blocks, and the indirect
+// blocks that map them, on demand, and shrink by freeing them again.
+
+use super::{Device,
+            FsError,
+            Inode,
+            DIRECT_BLOCKS,
+            DOUBLE_INDIRECT_BLOCK,
+            SINGLE_INDIRECT_BLOCK,
+            TRIPLE_INDIRECT_BLOCK};
+use crate::block::BlockDevice;
+use core::cmp::min;
+
+impl<B: BlockDevice> Device<B> {
+    // Writes |buf| into the file |inode_number| starting at |offset| and
+    // returns the number of bytes written. The file grows as needed, and any
+    // gap between the old end of the file and |offset| is left as a hole.
+    pub fn write_at(&mut self,
+                    inode_number: u32,
+                    offset: u32,
+                    buf: &[u8])
+                    -> Result<usize, FsError> {
+        self.check_writable()?;
+        let mut inode = self.load_inode(inode_number)?;
+        let group = self.inode_group(inode_number);
+
+        // i_size can't describe anything past 4 GiB
+        let length = min(buf.len(), (core::u32::MAX - offset) as usize);
+        let mut done = 0;
+        let mut result = Ok(());
+
+        while done < length {
+            let position = offset + done as u32;
+            let block_offset = position % self.block_size;
+            let count =
+                min((self.block_size - block_offset) as usize, length - done);
+
+            let block_number = match self.get_or_alloc_block(&mut inode,
+                                                             position /
+                                                             self.block_size,
+                                                             group)
+            {
+                Ok(block_number) => block_number,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            if let Err(e) = self.write_bytes(block_number,
+                                             block_offset as usize,
+                                             &buf[done..done + count])
+            {
+                result = Err(e);
+                break;
+            }
+
+            done += count;
+        }
+
+        let end = offset + done as u32;
+        if end > inode.i_size {
+            inode.i_size = end;
+        }
+        // Blocks allocated before running out of space are recorded either way
+        self.write_inode(inode_number, &inode)?;
+
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    // Sets the size of the file |inode_number| to |size|, freeing any blocks
+    // past the new end of the file
+    pub fn truncate(&mut self,
+                    inode_number: u32,
+                    size: u32)
+                    -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut inode = self.load_inode(inode_number)?;
+
+        if self.is_fast_symlink(&inode) {
+            // The target is stored in i_block, which holds no block pointers
+            if size < inode.i_size {
+                inode.i_block = [0; 15];
+            }
+        } else if size < inode.i_size {
+            let keep = ((size as u64 + self.block_size as u64 - 1) /
+                        self.block_size as u64) as u32;
+            self.free_blocks_from(&mut inode, keep)?;
+
+            // Clear the rest of the last block so growing the file again
+            // doesn't bring back old data
+            let block_offset = size % self.block_size;
+            let block_number =
+                self.get_block_number(&inode, size / self.block_size)?;
+            if block_offset != 0 && block_number != 0 {
+                self.zero_bytes(block_number,
+                                block_offset as usize,
+                                (self.block_size - block_offset) as usize)?;
+            }
+        }
+
+        inode.i_size = size;
+        self.write_inode(inode_number, &inode)
+    }
+
+    // Like get_block_number, but allocates the data block and any missing
+    // indirect blocks on the way to it. |inode| has to be written back by
+    // the caller.
+    pub(super) fn get_or_alloc_block(&mut self,
+                                     inode: &mut Inode,
+                                     index: u32,
+                                     group: u32)
+                                     -> Result<u32, FsError> {
+        let (slot, depth, relative) = self.block_path(index);
+        let sectors = self.block_size / 512;
+
+        let mut block = inode.i_block[slot];
+        if block == 0 {
+            block = self.alloc_block(group)?;
+            inode.i_block[slot] = block;
+            inode.i_blocks += sectors;
+        }
+
+        let per_block = self.block_size / 4;
+        for level in (0..depth).rev() {
+            let span = per_block.pow(level);
+            let index = (relative / span) % per_block;
+            let mut next = self.read_block_pointer(block, index)?;
+            if next == 0 {
+                next = self.alloc_block(group)?;
+                self.write_block_pointer(block, index, next)?;
+                inode.i_blocks += sectors;
+            }
+            block = next;
+        }
+
+        Ok(block)
+    }
+
+    // Frees every block of |inode| from the |keep|th block on, along with the
+    // indirect blocks that no longer map anything
+    fn free_blocks_from(&mut self,
+                        inode: &mut Inode,
+                        keep: u32)
+                        -> Result<(), FsError> {
+        // Spans of the triply indirect tree pass 2^32 blocks with 8K blocks
+        // and up, so they're counted in 64 bits
+        let per_block = self.block_size as u64 / 4;
+        let mut freed = 0;
+
+        for index in keep..DIRECT_BLOCKS {
+            let block = inode.i_block[index as usize];
+            if block != 0 {
+                self.free_block(block)?;
+                inode.i_block[index as usize] = 0;
+                freed += 1;
+            }
+        }
+
+        let keep = keep as u64;
+        let mut start = DIRECT_BLOCKS as u64;
+        let trees = [(SINGLE_INDIRECT_BLOCK, 1),
+                     (DOUBLE_INDIRECT_BLOCK, 2),
+                     (TRIPLE_INDIRECT_BLOCK, 3)];
+        for &(slot, depth) in trees.iter() {
+            let span = per_block.pow(depth);
+            let relative = if keep > start { keep - start } else { 0 };
+            if relative < span &&
+               self.free_tree_from(inode.i_block[slot],
+                                   depth,
+                                   relative,
+                                   &mut freed)?
+            {
+                inode.i_block[slot] = 0;
+            }
+            start += span;
+        }
+
+        inode.i_blocks -= freed * (self.block_size / 512);
+        Ok(())
+    }
+
+    // Frees the blocks of the tree under |block| that map the |keep|th block
+    // of the tree and beyond. |depth| is the number of levels of indirection
+    // left, with 0 being a data block. Returns true if |block| itself was
+    // freed, and counts every freed block in |freed|.
+    fn free_tree_from(&mut self,
+                      block: u32,
+                      depth: u32,
+                      keep: u64,
+                      freed: &mut u32)
+                      -> Result<bool, FsError> {
+        if block == 0 {
+            return Ok(true);
+        }
+
+        if depth > 0 {
+            let per_block = self.block_size / 4;
+            let span = (per_block as u64).pow(depth - 1);
+
+            for index in 0..per_block {
+                // Children entirely before |keep| stay as they are
+                let start = index as u64 * span;
+                if start + span <= keep {
+                    continue;
+                }
+
+                let child = self.read_block_pointer(block, index)?;
+                let child_keep = if keep > start { keep - start } else { 0 };
+                if child != 0 &&
+                   self.free_tree_from(child, depth - 1, child_keep, freed)?
+                {
+                    self.write_block_pointer(block, index, 0)?;
+                }
+            }
+        }
+
+        if keep > 0 {
+            return Ok(false);
+        }
+
+        self.free_block(block)?;
+        *freed += 1;
+        Ok(true)
+    }
+}
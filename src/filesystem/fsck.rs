@@ -0,0 +1,959 @@
+// A consistency checker in the spirit of e2fsck. It walks every inode and
+// directory in use, works out from them which blocks and inodes should be
+// allocated and how many names each inode has, and compares that with what
+// the bitmaps, inodes and free counts on the image say. Every difference is
+// reported, and the ones with an obvious fix can be repaired on a writable
+// image.
+
+use super::dir::{DirectoryEntry, DIR_ENTRY_HEADER_SIZE};
+use super::{Device,
+            FsError,
+            Inode,
+            DOUBLE_INDIRECT_BLOCK,
+            EXT2_DYNAMIC_REV,
+            EXT2_FEATURE_COMPAT_RESIZE_INODE,
+            EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER,
+            EXT2_S_IFBLK,
+            EXT2_S_IFCHR,
+            EXT2_S_IFIFO,
+            EXT2_S_IFMT,
+            EXT2_S_IFSOCK,
+            EXT4_FEATURE_INCOMPAT_FLEX_BG,
+            SINGLE_INDIRECT_BLOCK,
+            TRIPLE_INDIRECT_BLOCK};
+use crate::block::BlockDevice;
+use crate::global_constants::ROOT_INODE;
+use crate::memman::MemManager;
+use core::cmp::{max, min};
+
+// An inconsistency found on the image
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Problem {
+    // The bitmaps or inode table of a group are outside the group, so
+    // nothing in the group can be checked
+    BadGroupDescriptor {
+        group: u32,
+    },
+    // An inode points at a block number past the end of the filesystem
+    IllegalBlock {
+        inode: u32,
+        block: u32,
+    },
+    // A block is claimed by more than one owner. Inode 0 stands for the
+    // filesystem's own metadata.
+    DuplicateBlock {
+        inode: u32,
+        block: u32,
+    },
+    // An inode's i_blocks doesn't match the blocks it maps
+    BlockCount {
+        inode: u32,
+        on_disk: u32,
+        counted: u32,
+    },
+    // A directory entry has a record length or name length that doesn't fit
+    BadDirectoryEntry {
+        directory: u32,
+        block: u32,
+        offset: u32,
+    },
+    // A directory entry names an inode that isn't in use
+    EntryToUnusedInode {
+        directory: u32,
+        inode: u32,
+    },
+    // An inode's link count doesn't match the entries naming it
+    LinkCount {
+        inode: u32,
+        on_disk: u16,
+        counted: u16,
+    },
+    // An inode is in use, but no directory entry names it
+    UnattachedInode {
+        inode: u32,
+    },
+    // A block's bit in the block bitmap is wrong
+    BlockBitmap {
+        block: u32,
+        marked: bool,
+    },
+    // An inode's bit in the inode bitmap is wrong
+    InodeBitmap {
+        inode: u32,
+        marked: bool,
+    },
+    // The counts in a group descriptor are wrong
+    GroupFreeBlocks {
+        group: u32,
+        on_disk: u32,
+        counted: u32,
+    },
+    GroupFreeInodes {
+        group: u32,
+        on_disk: u32,
+        counted: u32,
+    },
+    GroupDirectories {
+        group: u32,
+        on_disk: u32,
+        counted: u32,
+    },
+    // The free counts in the Superblock are wrong
+    FreeBlocks {
+        on_disk: u32,
+        counted: u32,
+    },
+    FreeInodes {
+        on_disk: u32,
+        counted: u32,
+    },
+}
+
+// What a check found
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct FsckSummary {
+    // Number of problems found
+    pub problems: u32,
+    // Number of them that were repaired
+    pub repaired: u32,
+}
+
+// Zeroed memory from the kernel heap that the checker keeps its counts in,
+// freed when it goes out of scope. Journal replay keeps its revoke records in
+// one too.
+pub(super) struct Scratch {
+    address: u32,
+    length: usize,
+}
+
+// Everything the checker has worked out so far
+struct Checker<'a> {
+    repair: bool,
+    report: &'a mut dyn FnMut(Problem, bool),
+    summary: FsckSummary,
+    // One bit for every block, set once something claims it
+    claimed: Scratch,
+    // Number of directory entries naming each inode
+    links: Scratch,
+    // One byte for every group, set if its descriptor can't be used
+    bad_groups: Scratch,
+}
+
+impl<B: BlockDevice> Device<B> {
+    // Checks the consistency of the image, passing every problem found to
+    // |report| along with whether it was repaired. Problems are only
+    // repaired when |repair| is set, which needs a writable image. Duplicate
+    // blocks, bad group descriptors and unattached inodes are only reported.
+    pub fn fsck(&mut self,
+                repair: bool,
+                report: &mut dyn FnMut(Problem, bool))
+                -> Result<FsckSummary, FsError> {
+        // The checker only understands the block maps of ext2 inodes
+        if self.read_only {
+            return Err(FsError::NotSupported);
+        }
+        if repair {
+            self.check_writable()?;
+        }
+
+        let block_bytes = (self.superblock.s_blocks_count as usize + 7) / 8;
+        let link_bytes = (self.superblock.s_inodes_count as usize + 1) * 2;
+        let groups = self.group_count as usize;
+        let mut checker = Checker { repair: repair,
+                                    report: report,
+                                    summary: FsckSummary { problems: 0,
+                                                           repaired: 0 },
+                                    claimed: Scratch::new(block_bytes)?,
+                                    links: Scratch::new(link_bytes)?,
+                                    bad_groups: Scratch::new(groups)? };
+
+        for group in 0..self.group_count {
+            self.check_group_metadata(&mut checker, group)?;
+        }
+        for inode_number in 1..=self.superblock.s_inodes_count {
+            self.check_inode_blocks(&mut checker, inode_number)?;
+        }
+        for inode_number in 1..=self.superblock.s_inodes_count {
+            self.check_directory(&mut checker, inode_number)?;
+        }
+        for inode_number in 1..=self.superblock.s_inodes_count {
+            self.check_link_count(&mut checker, inode_number)?;
+        }
+        self.check_bitmaps(&mut checker)?;
+
+        if checker.summary.repaired > 0 {
+            self.sync()?;
+        }
+        Ok(checker.summary)
+    }
+
+    // Claims the Superblock, descriptor table, bitmaps and inode table of
+    // |group|, after checking that the descriptor points inside the group
+    fn check_group_metadata(&mut self,
+                            c: &mut Checker,
+                            group: u32)
+                            -> Result<(), FsError> {
+        let start = self.superblock.s_first_data_block +
+                    group * self.superblock.s_blocks_per_group;
+        let end = start + self.blocks_in_group(group);
+        let descriptor = *self.group_descriptor(group)?;
+        let table_blocks = (self.superblock.s_inodes_per_group as u64 *
+                            self.inode_size as u64 +
+                            self.block_size as u64 -
+                            1) /
+                           self.block_size as u64;
+
+        // flex_bg packs the metadata of several groups together, so it can
+        // be anywhere on the image
+        let (low, high) =
+            if self.has_incompat_feature(EXT4_FEATURE_INCOMPAT_FLEX_BG) {
+                (self.superblock.s_first_data_block,
+                 self.superblock.s_blocks_count)
+            } else {
+                (start, end)
+            };
+        let inside = |block: u32, count: u64| {
+            block >= low && block as u64 + count <= high as u64
+        };
+        if !inside(descriptor.bg_block_bitmap, 1) ||
+           !inside(descriptor.bg_inode_bitmap, 1) ||
+           !inside(descriptor.bg_inode_table, table_blocks)
+        {
+            c.bad_groups.set(group as usize, 1u8);
+            c.found(Problem::BadGroupDescriptor { group: group }, false);
+            return Ok(());
+        }
+
+        if self.has_superblock_backup(group) {
+            let descriptor_blocks = (self.group_count as usize *
+                                     self.descriptor_size +
+                                     self.block_size as usize -
+                                     1) /
+                                    self.block_size as usize;
+            let mut length = 1 + descriptor_blocks as u32;
+            // The resize inode maps the reserved blocks when it's there
+            if !self.has_compat_feature(EXT2_FEATURE_COMPAT_RESIZE_INODE) &&
+               self.superblock.s_rev_level >= EXT2_DYNAMIC_REV
+            {
+                length += self.superblock.s_reserved_gdt_blocks as u32;
+            }
+            for block in start..min(start + length, end) {
+                c.claim(0, block);
+            }
+        }
+
+        c.claim(0, descriptor.bg_block_bitmap);
+        c.claim(0, descriptor.bg_inode_bitmap);
+        for block in 0..table_blocks as u32 {
+            c.claim(0, descriptor.bg_inode_table + block);
+        }
+        Ok(())
+    }
+
+    // Checks whether |group| starts with a copy of the Superblock and the
+    // descriptor table. With sparse_super only groups 0, 1 and powers of 3,
+    // 5 and 7 have one.
+    fn has_superblock_backup(&self, group: u32) -> bool {
+        let sparse = self.superblock.s_rev_level >= EXT2_DYNAMIC_REV &&
+                     self.superblock.s_feature_ro_compat &
+                     EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER !=
+                     0;
+        !sparse ||
+        group <= 1 ||
+        is_power_of(group, 3) ||
+        is_power_of(group, 5) ||
+        is_power_of(group, 7)
+    }
+
+    // Claims the blocks mapped by |inode_number| if it's in use, and checks
+    // its block count. Illegal block pointers are cleared by a repair.
+    fn check_inode_blocks(&mut self,
+                          c: &mut Checker,
+                          inode_number: u32)
+                          -> Result<(), FsError> {
+        if !self.is_inode_readable(c, inode_number) {
+            return Ok(());
+        }
+        let mut inode = self.load_inode(inode_number)?;
+        if !self.is_inode_allocated(inode_number, &inode) {
+            return Ok(());
+        }
+
+        let mut changed = false;
+        let mut blocks = 0;
+
+        // Extended attribute blocks can be shared between inodes
+        if inode.i_file_acl != 0 {
+            if self.is_block_legal(inode.i_file_acl) {
+                c.claimed.set_bit(inode.i_file_acl as usize);
+                blocks += 1;
+            } else {
+                c.found(Problem::IllegalBlock { inode: inode_number,
+                                                block: inode.i_file_acl },
+                        c.repair);
+                if c.repair {
+                    inode.i_file_acl = 0;
+                    changed = true;
+                }
+            }
+        }
+
+        // Device files keep their device number in i_block, and fast
+        // symlinks keep their target there
+        let has_blocks = match inode.i_mode & EXT2_S_IFMT {
+            EXT2_S_IFCHR | EXT2_S_IFBLK | EXT2_S_IFIFO | EXT2_S_IFSOCK => false,
+            _ => !self.is_fast_symlink(&inode),
+        };
+        if has_blocks {
+            for slot in 0..inode.i_block.len() {
+                let block = inode.i_block[slot];
+                if block == 0 {
+                    continue;
+                }
+                if !self.is_block_legal(block) {
+                    c.found(Problem::IllegalBlock { inode: inode_number,
+                                                    block: block },
+                            c.repair);
+                    if c.repair {
+                        inode.i_block[slot] = 0;
+                        changed = true;
+                    }
+                    continue;
+                }
+
+                c.claim(inode_number, block);
+                let depth = match slot {
+                    SINGLE_INDIRECT_BLOCK => 1,
+                    DOUBLE_INDIRECT_BLOCK => 2,
+                    TRIPLE_INDIRECT_BLOCK => 3,
+                    _ => 0,
+                };
+                blocks += 1 + self.check_indirect_block(c,
+                                                        inode_number,
+                                                        block,
+                                                        depth)?;
+            }
+        }
+
+        let sectors = blocks * (self.block_size / 512);
+        if inode.i_blocks != sectors {
+            c.found(Problem::BlockCount { inode: inode_number,
+                                          on_disk: inode.i_blocks,
+                                          counted: sectors },
+                    c.repair);
+            if c.repair {
+                inode.i_blocks = sectors;
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.write_inode(inode_number, &inode)?;
+        }
+        Ok(())
+    }
+
+    // Claims the blocks under the indirect block |block|, which is |depth|
+    // levels above the data blocks, and returns how many there were
+    fn check_indirect_block(&mut self,
+                            c: &mut Checker,
+                            inode_number: u32,
+                            block: u32,
+                            depth: u32)
+                            -> Result<u32, FsError> {
+        if depth == 0 {
+            return Ok(0);
+        }
+
+        let mut blocks = 0;
+        for index in 0..self.block_size / 4 {
+            let pointer = self.read_block_pointer(block, index)?;
+            if pointer == 0 {
+                continue;
+            }
+            if !self.is_block_legal(pointer) {
+                c.found(Problem::IllegalBlock { inode: inode_number,
+                                                block: pointer },
+                        c.repair);
+                if c.repair {
+                    self.write_block_pointer(block, index, 0)?;
+                }
+                continue;
+            }
+
+            c.claim(inode_number, pointer);
+            blocks += 1 + self.check_indirect_block(c,
+                                                    inode_number,
+                                                    pointer,
+                                                    depth - 1)?;
+        }
+        Ok(blocks)
+    }
+
+    // Checks the entries of |inode_number| if it's a directory in use, and
+    // counts the links they make
+    fn check_directory(&mut self,
+                       c: &mut Checker,
+                       inode_number: u32)
+                       -> Result<(), FsError> {
+        if !self.is_inode_readable(c, inode_number) {
+            return Ok(());
+        }
+        let inode = self.load_inode(inode_number)?;
+        if !self.is_inode_allocated(inode_number, &inode) ||
+           !inode.is_directory()
+        {
+            return Ok(());
+        }
+
+        let block_count =
+            (inode.i_size + self.block_size - 1) / self.block_size;
+        for index in 0..block_count {
+            // Blocks behind illegal pointers were reported with the inode
+            let block = match self.get_block_number(&inode, index) {
+                Ok(block) if self.is_block_legal(block) => block,
+                _ => continue,
+            };
+            self.check_directory_block(c, inode_number, block)?;
+        }
+        Ok(())
+    }
+
+    // Checks the entries in one block of the directory |inode_number|. When
+    // an entry's lengths are bad, nothing after it in the block can be found,
+    // so a repair gives the rest of the block to the entry before it.
+    fn check_directory_block(&mut self,
+                             c: &mut Checker,
+                             inode_number: u32,
+                             block: u32)
+                             -> Result<(), FsError> {
+        let block_size = self.block_size;
+        let mut offset = 0;
+        let mut previous: Option<u32> = None;
+
+        while offset < block_size {
+            let header = if offset + DIR_ENTRY_HEADER_SIZE <= block_size {
+                Some(self.read_entry_header(block, offset)?)
+            } else {
+                None
+            };
+            let entry = match header {
+                Some(ref entry)
+                    if is_entry_valid(entry, offset, block_size) =>
+                {
+                    entry
+                }
+                _ => {
+                    c.found(Problem::BadDirectoryEntry { directory:
+                                                             inode_number,
+                                                         block: block,
+                                                         offset: offset },
+                            c.repair);
+                    if c.repair {
+                        self.end_directory_block(block, previous, offset)?;
+                    }
+                    return Ok(());
+                }
+            };
+
+            let rec_len = entry.rec_len as u32;
+            let mut merged = false;
+            if entry.inode != 0 {
+                if self.is_entry_target_used(c, entry.inode)? {
+                    c.add_link(entry.inode);
+                } else {
+                    c.found(Problem::EntryToUnusedInode { directory:
+                                                              inode_number,
+                                                          inode:
+                                                              entry.inode },
+                            c.repair);
+                    if c.repair {
+                        merged = self.remove_entry_at(block, previous, offset)?;
+                    }
+                }
+            }
+
+            if !merged {
+                previous = Some(offset);
+            }
+            offset += rec_len;
+        }
+        Ok(())
+    }
+
+    // Makes the entry at |previous| run to the end of |block|, or writes an
+    // empty entry covering the block from |offset| if there's no entry before
+    fn end_directory_block(&mut self,
+                           block: u32,
+                           previous: Option<u32>,
+                           offset: u32)
+                           -> Result<(), FsError> {
+        let block_size = self.block_size;
+        match previous {
+            Some(previous) => {
+                self.set_rec_len(block, previous, block_size - previous)
+            }
+            None => {
+                let empty = DirectoryEntry { inode: 0,
+                                             rec_len: (block_size - offset)
+                                                      as u16,
+                                             name_len: 0,
+                                             file_type: 0 };
+                self.write_entry_header(block, offset, &empty)
+            }
+        }
+    }
+
+    // Removes the entry at |offset| by merging it into the entry at
+    // |previous|, or by marking it unused if it's first in the block.
+    // Returns whether it was merged.
+    fn remove_entry_at(&mut self,
+                       block: u32,
+                       previous: Option<u32>,
+                       offset: u32)
+                       -> Result<bool, FsError> {
+        let entry = self.read_entry_header(block, offset)?;
+        match previous {
+            Some(previous) => {
+                let previous_len =
+                    self.read_entry_header(block, previous)?.rec_len as u32;
+                self.set_rec_len(block,
+                                 previous,
+                                 previous_len + entry.rec_len as u32)?;
+                Ok(true)
+            }
+            None => {
+                self.write_entry_header(block,
+                                        offset,
+                                        &DirectoryEntry { inode: 0, ..entry })?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Compares the link count of |inode_number| with the entries naming it
+    fn check_link_count(&mut self,
+                        c: &mut Checker,
+                        inode_number: u32)
+                        -> Result<(), FsError> {
+        // Reserved inodes other than the root aren't named by any directory
+        if inode_number < self.first_inode && inode_number != ROOT_INODE ||
+           !self.is_inode_readable(c, inode_number)
+        {
+            return Ok(());
+        }
+        let mut inode = self.load_inode(inode_number)?;
+        if inode.i_links_count == 0 {
+            return Ok(());
+        }
+
+        let counted = c.links(inode_number);
+        if counted == 0 {
+            c.found(Problem::UnattachedInode { inode: inode_number }, false);
+        } else if counted != inode.i_links_count {
+            c.found(Problem::LinkCount { inode: inode_number,
+                                         on_disk: inode.i_links_count,
+                                         counted: counted },
+                    c.repair);
+            if c.repair {
+                inode.i_links_count = counted;
+                self.write_inode(inode_number, &inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Compares the bitmaps and free counts of every group, and then the
+    // totals in the Superblock, with what the other passes worked out
+    fn check_bitmaps(&mut self, c: &mut Checker) -> Result<(), FsError> {
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        let mut all_groups_checked = true;
+
+        for group in 0..self.group_count {
+            if c.bad_groups.get::<u8>(group as usize) != 0 {
+                all_groups_checked = false;
+                continue;
+            }
+            free_blocks += self.check_block_bitmap(c, group)?;
+            free_inodes += self.check_inode_bitmap(c, group)?;
+        }
+
+        // The totals can't be known when a group was skipped
+        if !all_groups_checked {
+            return Ok(());
+        }
+
+        let on_disk = self.superblock.s_free_blocks_count;
+        if on_disk != free_blocks {
+            c.found(Problem::FreeBlocks { on_disk: on_disk,
+                                          counted: free_blocks },
+                    c.repair);
+            if c.repair {
+                self.superblock.s_free_blocks_count = free_blocks;
+                self.write_superblock()?;
+            }
+        }
+
+        let on_disk = self.superblock.s_free_inodes_count;
+        if on_disk != free_inodes {
+            c.found(Problem::FreeInodes { on_disk: on_disk,
+                                          counted: free_inodes },
+                    c.repair);
+            if c.repair {
+                self.superblock.s_free_inodes_count = free_inodes;
+                self.write_superblock()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the block bitmap and free block count of |group|, returning the
+    // number of free blocks it has
+    fn check_block_bitmap(&mut self,
+                          c: &mut Checker,
+                          group: u32)
+                          -> Result<u32, FsError> {
+        let start = self.superblock.s_first_data_block +
+                    group * self.superblock.s_blocks_per_group;
+        let bitmap = self.group_descriptor(group)?.bg_block_bitmap;
+
+        let mut free = 0;
+        for bit in 0..self.blocks_in_group(group) {
+            let block = start + bit;
+            let used = c.claimed.test_bit(block as usize);
+            if !used {
+                free += 1;
+            }
+
+            let marked = self.test_bit(bitmap, bit)?;
+            if marked != used {
+                c.found(Problem::BlockBitmap { block: block,
+                                               marked: marked },
+                        c.repair);
+                if c.repair {
+                    self.set_bit(bitmap, bit, used)?;
+                }
+            }
+        }
+
+        let on_disk = self.group_descriptor(group)?.bg_free_blocks_count as u32;
+        if on_disk != free {
+            c.found(Problem::GroupFreeBlocks { group: group,
+                                               on_disk: on_disk,
+                                               counted: free },
+                    c.repair);
+            if c.repair {
+                self.group_descriptor_mut(group)?.bg_free_blocks_count =
+                    free as u16;
+                self.write_group_descriptor(group)?;
+            }
+        }
+        Ok(free)
+    }
+
+    // Checks the inode bitmap, free inode count and directory count of
+    // |group|, returning the number of free inodes it has
+    fn check_inode_bitmap(&mut self,
+                          c: &mut Checker,
+                          group: u32)
+                          -> Result<u32, FsError> {
+        let per_group = self.superblock.s_inodes_per_group;
+        let bitmap = self.group_descriptor(group)?.bg_inode_bitmap;
+
+        let mut free = 0;
+        let mut directories = 0;
+        for bit in 0..per_group {
+            let inode_number = group * per_group + bit + 1;
+            if inode_number > self.superblock.s_inodes_count {
+                break;
+            }
+            let inode = self.load_inode(inode_number)?;
+            let used = self.is_inode_allocated(inode_number, &inode);
+            if !used {
+                free += 1;
+            } else if inode.is_directory() {
+                directories += 1;
+            }
+
+            let marked = self.test_bit(bitmap, bit)?;
+            if marked != used {
+                c.found(Problem::InodeBitmap { inode: inode_number,
+                                               marked: marked },
+                        c.repair);
+                if c.repair {
+                    self.set_bit(bitmap, bit, used)?;
+                }
+            }
+        }
+
+        let descriptor = *self.group_descriptor(group)?;
+        let on_disk = descriptor.bg_free_inodes_count as u32;
+        if on_disk != free {
+            c.found(Problem::GroupFreeInodes { group: group,
+                                               on_disk: on_disk,
+                                               counted: free },
+                    c.repair);
+        }
+        let on_disk = descriptor.bg_used_dirs_count as u32;
+        if on_disk != directories {
+            c.found(Problem::GroupDirectories { group: group,
+                                                on_disk: on_disk,
+                                                counted: directories },
+                    c.repair);
+        }
+        if c.repair &&
+           (descriptor.bg_free_inodes_count as u32 != free ||
+            descriptor.bg_used_dirs_count as u32 != directories)
+        {
+            let descriptor = self.group_descriptor_mut(group)?;
+            descriptor.bg_free_inodes_count = free as u16;
+            descriptor.bg_used_dirs_count = directories as u16;
+            self.write_group_descriptor(group)?;
+        }
+        Ok(free)
+    }
+
+    // Checks whether |inode| is in use. Reserved inodes always are, and the
+    // rest are while they have links.
+    fn is_inode_allocated(&self, inode_number: u32, inode: &Inode) -> bool {
+        inode_number < self.first_inode || inode.i_links_count > 0
+    }
+
+    // Checks whether the descriptor of the group holding |inode_number| can
+    // be trusted to find the inode
+    fn is_inode_readable(&self, c: &Checker, inode_number: u32) -> bool {
+        c.bad_groups
+         .get::<u8>(self.inode_group(inode_number) as usize) ==
+        0
+    }
+
+    // Checks whether a directory entry may name |inode_number|. Reserved
+    // inodes other than the root can't be named, and inodes in groups that
+    // can't be checked are given the benefit of the doubt.
+    fn is_entry_target_used(&self,
+                            c: &Checker,
+                            inode_number: u32)
+                            -> Result<bool, FsError> {
+        if inode_number > self.superblock.s_inodes_count {
+            return Ok(false);
+        }
+        if inode_number == ROOT_INODE {
+            return Ok(true);
+        }
+        if inode_number < self.first_inode {
+            return Ok(false);
+        }
+        if !self.is_inode_readable(c, inode_number) {
+            return Ok(true);
+        }
+        Ok(self.load_inode(inode_number)?.i_links_count > 0)
+    }
+
+    // Checks whether |block| is a block number inside the filesystem. Block 0
+    // never is, even with 4K blocks where s_first_data_block is 0, since a
+    // pointer to it means no block at all.
+    fn is_block_legal(&self, block: u32) -> bool {
+        block != 0 &&
+        block >= self.superblock.s_first_data_block &&
+        block < self.superblock.s_blocks_count
+    }
+}
+
+impl<'a> Checker<'a> {
+    // Records |problem| and passes it on
+    fn found(&mut self, problem: Problem, repaired: bool) {
+        self.summary.problems += 1;
+        if repaired {
+            self.summary.repaired += 1;
+        }
+        (self.report)(problem, repaired);
+    }
+
+    // Marks |block| as used by |inode_number|, reporting it if something
+    // already claimed it
+    fn claim(&mut self, inode_number: u32, block: u32) {
+        if self.claimed.test_bit(block as usize) {
+            self.found(Problem::DuplicateBlock { inode: inode_number,
+                                                 block: block },
+                       false);
+        }
+        self.claimed.set_bit(block as usize);
+    }
+
+    // Counts another directory entry naming |inode_number|
+    fn add_link(&mut self, inode_number: u32) {
+        let links = self.links(inode_number);
+        self.links
+            .set(inode_number as usize, links.saturating_add(1));
+    }
+
+    // Returns the number of directory entries naming |inode_number|
+    fn links(&self, inode_number: u32) -> u16 {
+        self.links.get(inode_number as usize)
+    }
+}
+
+impl Scratch {
+    // Allocates |length| zeroed bytes
+    pub(super) fn new(length: usize) -> Result<Scratch, FsError> {
+        let length = max(length, 1);
+        let address = match MemManager::kmalloc(length) {
+            Ok(address) => address,
+            Err(_) => return Err(FsError::OutOfMemory),
+        };
+        unsafe {
+            core::ptr::write_bytes(address as *mut u8, 0, length);
+        }
+        Ok(Scratch { address: address,
+                     length: length })
+    }
+
+    // Returns the |index|th value of type T
+    pub(super) fn get<T: Copy>(&self, index: usize) -> T {
+        assert!((index + 1) * core::mem::size_of::<T>() <= self.length);
+        unsafe { *(self.address as *const T).add(index) }
+    }
+
+    // Stores |value| as the |index|th value of type T
+    pub(super) fn set<T: Copy>(&mut self, index: usize, value: T) {
+        assert!((index + 1) * core::mem::size_of::<T>() <= self.length);
+        unsafe {
+            *(self.address as *mut T).add(index) = value;
+        }
+    }
+
+    fn test_bit(&self, bit: usize) -> bool {
+        self.get::<u8>(bit / 8) & (1 << (bit % 8)) != 0
+    }
+
+    fn set_bit(&mut self, bit: usize) {
+        let byte = self.get::<u8>(bit / 8) | (1 << (bit % 8));
+        self.set(bit / 8, byte);
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        MemManager::kfree(self.address).ok();
+    }
+}
+
+// Describes the problem like e2fsck would
+impl core::fmt::Display for Problem {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Problem::BadGroupDescriptor { group } => {
+                write!(f, "Group {} descriptor points outside the group", group)
+            }
+            Problem::IllegalBlock { inode, block } => {
+                write!(f, "Inode {} has illegal block {}", inode, block)
+            }
+            Problem::DuplicateBlock { inode: 0, block } => {
+                write!(f, "Metadata block {} is claimed twice", block)
+            }
+            Problem::DuplicateBlock { inode, block } => {
+                write!(f,
+                       "Inode {} claims block {}, which is already in use",
+                       inode, block)
+            }
+            Problem::BlockCount { inode,
+                                  on_disk,
+                                  counted, } => {
+                write!(f,
+                       "Inode {} i_blocks is {}, should be {}",
+                       inode, on_disk, counted)
+            }
+            Problem::BadDirectoryEntry { directory,
+                                         block,
+                                         offset, } => {
+                write!(f,
+                       "Directory inode {} has a bad entry in block {} at \
+                        offset {}",
+                       directory, block, offset)
+            }
+            Problem::EntryToUnusedInode { directory, inode } => {
+                write!(f,
+                       "Entry in directory inode {} refers to unused inode {}",
+                       directory, inode)
+            }
+            Problem::LinkCount { inode,
+                                 on_disk,
+                                 counted, } => {
+                write!(f,
+                       "Inode {} ref count is {}, should be {}",
+                       inode, on_disk, counted)
+            }
+            Problem::UnattachedInode { inode } => {
+                write!(f, "Unattached inode {}", inode)
+            }
+            Problem::BlockBitmap { block,
+                                   marked: true, } => {
+                write!(f, "Block {} is marked in use but is free", block)
+            }
+            Problem::BlockBitmap { block,
+                                   marked: false, } => {
+                write!(f, "Block {} is in use but marked free", block)
+            }
+            Problem::InodeBitmap { inode,
+                                   marked: true, } => {
+                write!(f, "Inode {} is marked in use but is free", inode)
+            }
+            Problem::InodeBitmap { inode,
+                                   marked: false, } => {
+                write!(f, "Inode {} is in use but marked free", inode)
+            }
+            Problem::GroupFreeBlocks { group,
+                                       on_disk,
+                                       counted, } => {
+                write!(f,
+                       "Free blocks count wrong for group {} ({}, counted={})",
+                       group, on_disk, counted)
+            }
+            Problem::GroupFreeInodes { group,
+                                       on_disk,
+                                       counted, } => {
+                write!(f,
+                       "Free inodes count wrong for group {} ({}, counted={})",
+                       group, on_disk, counted)
+            }
+            Problem::GroupDirectories { group,
+                                        on_disk,
+                                        counted, } => {
+                write!(f,
+                       "Directories count wrong for group {} ({}, counted={})",
+                       group, on_disk, counted)
+            }
+            Problem::FreeBlocks { on_disk, counted } => {
+                write!(f,
+                       "Free blocks count wrong ({}, counted={})",
+                       on_disk, counted)
+            }
+            Problem::FreeInodes { on_disk, counted } => {
+                write!(f,
+                       "Free inodes count wrong ({}, counted={})",
+                       on_disk, counted)
+            }
+        }
+    }
+}
+
+// Checks that the entry at |offset| has lengths that fit in its block
+fn is_entry_valid(entry: &DirectoryEntry,
+                  offset: u32,
+                  block_size: u32)
+                  -> bool {
+    let rec_len = entry.rec_len as u32;
+    rec_len >= DIR_ENTRY_HEADER_SIZE &&
+    rec_len % 4 == 0 &&
+    offset + rec_len <= block_size &&
+    DIR_ENTRY_HEADER_SIZE + entry.name_len as u32 <= rec_len
+}
+
+// Checks whether |n| is a power of |base|
+fn is_power_of(mut n: u32, base: u32) -> bool {
+    while n > 1 && n % base == 0 {
+        n /= base;
+    }
+    n == 1
+}
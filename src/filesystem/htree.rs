@@ -0,0 +1,479 @@
+// Hashed directory indexes (htree). The first block of an indexed directory
+// hides a tree of hashes behind its "." and ".." entries, and interior nodes
+// hide behind an empty entry spanning their whole block, so the directory
+// still reads as an ordinary one. Each index entry maps the lowest hash of
+// the names in a leaf block to that block, and the leaves are plain
+// directory blocks.
+
+use super::{Device,
+            FsError,
+            Inode,
+            EXT2_FEATURE_COMPAT_DIR_INDEX,
+            EXT2_INDEX_FL};
+use crate::block::BlockDevice;
+
+// Hash functions an index can be built with
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+// Variants of the above that treat name bytes as unsigned
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+// Set in s_flags when the image's hashes were made with unsigned chars
+const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+// Offset of the root info in the first block, after "." and ".."
+const DX_ROOT_INFO_OFFSET: usize = 24;
+// Offset of the entries in an interior node, after the empty entry
+const DX_NODE_ENTRIES_OFFSET: usize = 8;
+// Most levels of interior nodes between the root and the leaves
+const DX_MAX_INDIRECT_LEVELS: usize = 1;
+// Size of each index entry
+const DX_ENTRY_SIZE: usize = 8;
+// The top 4 bits of an entry's block are reserved
+const DX_BLOCK_MASK: u32 = 0x0FFF_FFFF;
+
+// The largest hash is reserved to mark the end of a directory
+const EXT2_HTREE_EOF: u32 = 0x7FFF_FFFF;
+
+// Start values of the hash state when the Superblock has no seed
+const DEFAULT_HASH_SEED: [u32; 4] =
+    [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+
+// Describes the index, following the "." and ".." entries of the root
+#[repr(C)]
+struct DxRootInfo {
+    // Always 0
+    reserved_zero: u32,
+    // Hash function used, copied from s_def_hash_version when the index was
+    // made
+    hash_version: u8,
+    // Size of this structure
+    info_length: u8,
+    // Levels of interior nodes below the root
+    indirect_levels: u8,
+    unused_flags: u8,
+}
+
+// Maps names hashing to |hash| or above to the directory block |block|
+#[repr(C)]
+struct DxEntry {
+    hash: u32,
+    block: u32,
+}
+
+// The first entry of every node has no hash, which is replaced by the
+// number of entries the node holds and has room for
+#[repr(C)]
+struct DxCountLimit {
+    limit: u16,
+    count: u16,
+}
+
+// The position reached in one node on the way from the root to a leaf
+#[derive(Copy, Clone)]
+struct DxFrame {
+    // Device block holding the node
+    block: u32,
+    // Offset of the node's first entry in its block
+    offset: usize,
+    // Number of entries in the node
+    count: u32,
+    // Entry that was followed
+    position: u32,
+}
+
+impl DxFrame {
+    fn empty() -> DxFrame {
+        DxFrame { block: 0,
+                  offset: 0,
+                  count: 0,
+                  position: 0 }
+    }
+}
+
+impl<B: BlockDevice> Device<B> {
+    // Checks whether lookups in |dir| can use a hashed index
+    pub(super) fn is_indexed(&self, dir: &Inode) -> bool {
+        dir.i_flags & EXT2_INDEX_FL != 0 &&
+        self.has_compat_feature(EXT2_FEATURE_COMPAT_DIR_INDEX)
+    }
+
+    // Finds the used entry named |name| in the indexed directory |dir| the
+    // same way as find_entry_slot. Errors mean the index can't be trusted
+    // and the directory has to be searched linearly instead.
+    pub(super) fn htree_find_slot(
+        &self,
+        dir: &Inode,
+        name: &[u8])
+        -> Result<Option<(u32, u32, Option<u32>)>, FsError> {
+        // "." and ".." are the entries hiding the root, so they aren't in any
+        // leaf
+        if name == b"." || name == b".." {
+            let block = self.get_block_number(dir, 0)?;
+            if block == 0 {
+                return Err(FsError::InvalidBlock);
+            }
+            return Ok(self.find_in_block(block, name)?
+                          .map(|(offset, previous)| {
+                              (block, offset, previous)
+                          }));
+        }
+
+        let root = self.dir_block_number(dir, 0)?;
+        let info: DxRootInfo = self.read_struct(root, DX_ROOT_INFO_OFFSET)?;
+        let levels = info.indirect_levels as usize + 1;
+        if info.reserved_zero != 0 ||
+           (info.info_length as usize) < core::mem::size_of::<DxRootInfo>() ||
+           levels > DX_MAX_INDIRECT_LEVELS + 1
+        {
+            return Err(FsError::InvalidBlock);
+        }
+        let hash = self.dx_hash(info.hash_version, name)?;
+
+        // Walk down from the root to the leaf that should hold the name
+        let mut frames = [DxFrame::empty(); DX_MAX_INDIRECT_LEVELS + 1];
+        let offset = DX_ROOT_INFO_OFFSET + info.info_length as usize;
+        frames[0] = self.dx_probe(root, offset, hash)?;
+        for level in 1..levels {
+            frames[level] = self.dx_probe_child(dir, &frames[level - 1], hash)?;
+        }
+
+        loop {
+            let index = self.dx_followed_block(&frames[levels - 1])?;
+            let leaf = self.get_block_number(dir, index)?;
+            if leaf == 0 {
+                return Err(FsError::InvalidBlock);
+            }
+            if let Some((offset, previous)) = self.find_in_block(leaf, name)? {
+                return Ok(Some((leaf, offset, previous)));
+            }
+
+            if !self.dx_next_leaf(dir, &mut frames[..levels], hash)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Searches the node whose entries start |offset| bytes into the device
+    // block |block| for the last entry whose hash isn't above |hash|
+    fn dx_probe(&self,
+                block: u32,
+                offset: usize,
+                hash: u32)
+                -> Result<DxFrame, FsError> {
+        let count_limit: DxCountLimit = self.read_struct(block, offset)?;
+        let count = count_limit.count as u32;
+        let limit = count_limit.limit as usize;
+        if count == 0 ||
+           count as usize > limit ||
+           offset + limit * DX_ENTRY_SIZE > self.block_size as usize
+        {
+            return Err(FsError::InvalidBlock);
+        }
+
+        let mut frame = DxFrame { block: block,
+                                  offset: offset,
+                                  count: count,
+                                  position: 0 };
+
+        // The first entry covers every hash below the second one
+        let mut low = 1;
+        let mut high = count;
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.dx_entry(&frame, middle)?.hash > hash {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        frame.position = low - 1;
+
+        Ok(frame)
+    }
+
+    // Searches the interior node that |parent| points to
+    fn dx_probe_child(&self,
+                      dir: &Inode,
+                      parent: &DxFrame,
+                      hash: u32)
+                      -> Result<DxFrame, FsError> {
+        let index = self.dx_followed_block(parent)?;
+        let node = self.dir_block_number(dir, index)?;
+        self.dx_probe(node, DX_NODE_ENTRIES_OFFSET, hash)
+    }
+
+    // Reads the |index|th entry of the node |frame| is in
+    fn dx_entry(&self,
+                frame: &DxFrame,
+                index: u32)
+                -> Result<DxEntry, FsError> {
+        self.read_struct(frame.block,
+                         frame.offset + index as usize * DX_ENTRY_SIZE)
+    }
+
+    // Returns the directory block the entry followed in |frame| points to
+    fn dx_followed_block(&self, frame: &DxFrame) -> Result<u32, FsError> {
+        Ok(self.dx_entry(frame, frame.position)?.block & DX_BLOCK_MASK)
+    }
+
+    // Moves |frames| on to the next leaf if it can hold names hashing to
+    // |hash|. Names with colliding hashes can spill over into the next leaf,
+    // which is then marked by setting the low bit of its hash.
+    fn dx_next_leaf(&self,
+                    dir: &Inode,
+                    frames: &mut [DxFrame],
+                    hash: u32)
+                    -> Result<bool, FsError> {
+        // Find the deepest node that has another entry
+        let mut level = frames.len();
+        loop {
+            if level == 0 {
+                return Ok(false);
+            }
+            level -= 1;
+            if frames[level].position + 1 < frames[level].count {
+                break;
+            }
+        }
+
+        frames[level].position += 1;
+        let next = self.dx_entry(&frames[level], frames[level].position)?;
+        if next.hash & !1 != hash {
+            return Ok(false);
+        }
+
+        // Start over at the first entry of each node below it
+        for below in level + 1..frames.len() {
+            let mut frame = self.dx_probe_child(dir, &frames[below - 1], 0)?;
+            frame.position = 0;
+            frames[below] = frame;
+        }
+
+        Ok(true)
+    }
+
+    // Returns the device block holding the |index|th block of the directory
+    // |dir|
+    fn dir_block_number(&self,
+                        dir: &Inode,
+                        index: u32)
+                        -> Result<u32, FsError> {
+        if index >= dir.i_size / self.block_size {
+            return Err(FsError::InvalidBlock);
+        }
+
+        match self.get_block_number(dir, index)? {
+            0 => Err(FsError::InvalidBlock),
+            block => Ok(block),
+        }
+    }
+
+    // Hashes |name| with the hash function |version| and the Superblock's
+    // seed
+    fn dx_hash(&self, version: u8, name: &[u8]) -> Result<u32, FsError> {
+        // The image records whether chars were signed when it was made
+        let version = if version <= DX_HASH_TEA &&
+                         self.superblock.s_flags & EXT2_FLAGS_UNSIGNED_HASH !=
+                         0
+        {
+            version + DX_HASH_LEGACY_UNSIGNED
+        } else {
+            version
+        };
+
+        hash_name(version, name, &self.superblock.s_hash_seed)
+            .ok_or(FsError::InvalidBlock)
+    }
+}
+
+// Hashes |name| with the hash function |version| and |seed|, which is
+// replaced by the default if it's all zeros. The low bit is left clear for
+// marking collisions. Returns None for unknown hash functions.
+pub fn hash_name(version: u8, name: &[u8], seed: &[u32; 4]) -> Option<u32> {
+    let mut state = if seed.iter().all(|word| *word == 0) {
+        DEFAULT_HASH_SEED
+    } else {
+        *seed
+    };
+
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => {
+            dx_hack_hash(name, version == DX_HASH_LEGACY_UNSIGNED)
+        }
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let mut input = [0u32; 8];
+            for (i, chunk) in name.chunks(32).enumerate() {
+                str_to_hash_buf(chunk,
+                                name.len() - i * 32,
+                                &mut input,
+                                version == DX_HASH_HALF_MD4_UNSIGNED);
+                half_md4_transform(&mut state, &input);
+            }
+            state[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let mut input = [0u32; 4];
+            for (i, chunk) in name.chunks(16).enumerate() {
+                str_to_hash_buf(chunk,
+                                name.len() - i * 16,
+                                &mut input,
+                                version == DX_HASH_TEA_UNSIGNED);
+                tea_transform(&mut state, &input);
+            }
+            state[0]
+        }
+        _ => return None,
+    };
+
+    let hash = hash & !1;
+    if hash == EXT2_HTREE_EOF << 1 {
+        Some((EXT2_HTREE_EOF - 1) << 1)
+    } else {
+        Some(hash)
+    }
+}
+
+// Returns the value a name byte adds to a hash, which depends on whether
+// chars were signed on the machine that made the index
+fn char_value(c: u8, unsigned: bool) -> u32 {
+    if unsigned {
+        c as u32
+    } else {
+        c as i8 as i32 as u32
+    }
+}
+
+// The original hash used by the first htree implementation
+fn dx_hack_hash(name: &[u8], unsigned: bool) -> u32 {
+    let mut hash0: u32 = 0x12A3_FE2D;
+    let mut hash1: u32 = 0x37AB_E8F9;
+
+    for c in name.iter() {
+        let mut hash = hash1.wrapping_add(hash0 ^
+                                          char_value(*c, unsigned)
+                                              .wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+// Packs a chunk of a name into the words fed to the MD4 and TEA
+// transforms. Words past the end of the name are filled with padding made
+// from |length|, the number of bytes left in the name from this chunk on.
+fn str_to_hash_buf(chunk: &[u8],
+                   length: usize,
+                   buf: &mut [u32],
+                   unsigned: bool) {
+    let mut pad = length as u32 | ((length as u32) << 8);
+    pad |= pad << 16;
+
+    let mut value = pad;
+    let mut word = 0;
+    for (i, c) in chunk.iter().enumerate() {
+        value = char_value(*c, unsigned).wrapping_add(value << 8);
+        if i % 4 == 3 {
+            buf[word] = value;
+            value = pad;
+            word += 1;
+        }
+    }
+
+    if word < buf.len() {
+        buf[word] = value;
+        word += 1;
+    }
+    for rest in buf[word..].iter_mut() {
+        *rest = pad;
+    }
+}
+
+// The rounds of MD4 used by the half-MD4 hash
+fn half_md4_transform(state: &mut [u32; 4], input: &[u32; 8]) {
+    fn f(x: u32, y: u32, z: u32) -> u32 {
+        z ^ (x & (y ^ z))
+    }
+    fn g(x: u32, y: u32, z: u32) -> u32 {
+        (x & y).wrapping_add((x ^ y) & z)
+    }
+    fn h(x: u32, y: u32, z: u32) -> u32 {
+        x ^ y ^ z
+    }
+    fn round(function: fn(u32, u32, u32) -> u32,
+             a: u32,
+             b: u32,
+             c: u32,
+             d: u32,
+             x: u32,
+             shift: u32)
+             -> u32 {
+        a.wrapping_add(function(b, c, d))
+         .wrapping_add(x)
+         .rotate_left(shift)
+    }
+
+    const K2: u32 = 0x5A82_7999;
+    const K3: u32 = 0x6ED9_EBA1;
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+
+    a = round(f, a, b, c, d, input[0], 3);
+    d = round(f, d, a, b, c, input[1], 7);
+    c = round(f, c, d, a, b, input[2], 11);
+    b = round(f, b, c, d, a, input[3], 19);
+    a = round(f, a, b, c, d, input[4], 3);
+    d = round(f, d, a, b, c, input[5], 7);
+    c = round(f, c, d, a, b, input[6], 11);
+    b = round(f, b, c, d, a, input[7], 19);
+
+    a = round(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    d = round(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    c = round(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    b = round(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    a = round(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    d = round(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    c = round(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    b = round(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    a = round(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    d = round(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    c = round(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    b = round(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    a = round(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    d = round(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    c = round(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    b = round(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+// Sixteen rounds of the Tiny Encryption Algorithm
+fn tea_transform(state: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E37_79B9;
+    let mut sum: u32 = 0;
+    let (mut b0, mut b1) = (state[0], state[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add((b1 << 4).wrapping_add(a) ^
+                             b1.wrapping_add(sum) ^
+                             (b1 >> 5).wrapping_add(b));
+        b1 = b1.wrapping_add((b0 << 4).wrapping_add(c) ^
+                             b0.wrapping_add(sum) ^
+                             (b0 >> 5).wrapping_add(d));
+    }
+
+    state[0] = state[0].wrapping_add(b0);
+    state[1] = state[1].wrapping_add(b1);
+}
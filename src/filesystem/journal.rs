@@ -0,0 +1,498 @@
+// Replaying the journal of ext3 and ext4 images. JBD2 writes every change to
+// metadata into the journal, the file s_journal_inum, before writing it in
+// place, and only counts a transaction once its commit block follows it. An
+// image that was cut off part way through a write is made consistent again
+// by copying the blocks of every committed transaction to their places once
+// more, which is what e2fsck and Linux do before mounting it.
+//
+// Replay goes over the log three times like Linux: once to find where the
+// committed transactions end, once to collect the revoke records, which say
+// a block of an earlier transaction mustn't be copied because it was freed
+// and may have been reused for data, and once to copy the blocks. Unlike the
+// rest of the image, everything in the journal is big-endian.
+
+use super::fsck::Scratch;
+use super::{Device,
+            FsError,
+            Inode,
+            SuperBlock,
+            EXT3_FEATURE_COMPAT_HAS_JOURNAL,
+            EXT3_FEATURE_INCOMPAT_RECOVER};
+use crate::block::BlockDevice;
+use core::cmp::min;
+
+// Magic number at the start of every block of the log
+const JBD2_MAGIC_NUMBER: u32 = 0xC03B_3998;
+
+// Kinds of journal blocks
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+const JBD2_COMMIT_BLOCK: u32 = 2;
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_REVOKE_BLOCK: u32 = 5;
+
+// Incompatible journal features. Journals with checksums or fast commits
+// aren't supported.
+const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 0x0001;
+const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 0x0002;
+const SUPPORTED_JOURNAL_INCOMPAT: u32 =
+    JBD2_FEATURE_INCOMPAT_REVOKE | JBD2_FEATURE_INCOMPAT_64BIT;
+
+// Flags of the tags in descriptor blocks. A block starting with the magic
+// number is escaped by zeroing it, so it isn't taken for a block of the log.
+const JBD2_FLAG_ESCAPE: u16 = 0x1;
+const JBD2_FLAG_SAME_UUID: u16 = 0x2;
+const JBD2_FLAG_LAST_TAG: u16 = 0x8;
+
+// Size of the header of descriptor and revoke blocks, of the revoke block's
+// count of bytes used, and of the UUID following a tag without SAME_UUID
+const HEADER_SIZE: usize = 12;
+const REVOKE_HEADER_SIZE: usize = HEADER_SIZE + 4;
+const UUID_SIZE: usize = 16;
+// Size of tags, which have the high 32 bits of the block number on 64 bit
+// journals
+const TAG_SIZE: usize = 8;
+const TAG_SIZE_64BIT: usize = 12;
+// Where the sequence and start of the log are in the journal superblock
+const SEQUENCE_OFFSET: usize = 24;
+
+// Size of the pieces blocks are copied out of the log in
+const COPY_CHUNK_SIZE: usize = 128;
+
+#[repr(C)]
+struct JournalHeader {
+    // JBD2_MAGIC_NUMBER
+    h_magic: u32,
+    // Kind of block
+    h_blocktype: u32,
+    // Transaction the block belongs to
+    h_sequence: u32,
+}
+
+#[repr(C)]
+struct JournalSuperblock {
+    s_header: JournalHeader,
+    // Size of journal blocks, which is the block size of the image
+    s_blocksize: u32,
+    // Number of blocks in the journal
+    s_maxlen: u32,
+    // First block of the log
+    s_first: u32,
+    // Transaction the log starts with
+    s_sequence: u32,
+    // Block the log starts at, or 0 if there's nothing to replay
+    s_start: u32,
+    // Error from the last time the journal was used
+    s_errno: u32,
+    // The following fields are only valid for JBD2_SUPERBLOCK_V2
+    // Compatible feature set
+    s_feature_compat: u32,
+    // Incompatible feature set
+    s_feature_incompat: u32,
+    // Read-only compatible feature set
+    s_feature_ro_compat: u32,
+}
+
+// The journal being replayed and how far the passes over it have got
+struct Journal {
+    inode: Inode,
+    // First block of the log and the end of the journal
+    first: u32,
+    last: u32,
+    // Block and transaction the log starts with
+    start: u32,
+    sequence: u32,
+    // First transaction that wasn't committed, found by the scan pass
+    end: u32,
+    tag_size: usize,
+    // Size of the block numbers in revoke blocks
+    record_size: usize,
+    // Number of revoke records found by the scan pass
+    revoke_count: usize,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum Pass {
+    Scan,
+    Revoke,
+    Replay,
+}
+
+// Revoked blocks, each with the latest transaction that revoked it
+struct RevokeTable {
+    records: Scratch,
+    count: usize,
+    capacity: usize,
+}
+
+impl<B: BlockDevice> Device<B> {
+    // Copies the committed transactions in the journal to their places in
+    // the image, then marks the journal empty and clears needs_recovery.
+    // This happens even when read_only is set, like Linux replays the journal
+    // of an image mounted read-only. read_only only means the driver can't
+    // change files without breaking structures it doesn't know, and replay
+    // copies whole blocks as the kernel that wrote the journal left them.
+    pub(super) fn recover_journal(&mut self) -> Result<(), FsError> {
+        // Only a disk that can't be written at all stops replay
+        if !self.disk.is_writable() {
+            return Err(FsError::NeedsRecovery);
+        }
+
+        let mut journal = self.open_journal()?;
+        let mut sequence = journal.sequence;
+        if journal.start != 0 {
+            self.journal_pass(&mut journal, Pass::Scan, None)?;
+            let mut revokes = RevokeTable::new(journal.revoke_count)?;
+            self.journal_pass(&mut journal, Pass::Revoke, Some(&mut revokes))?;
+            self.journal_pass(&mut journal, Pass::Replay, Some(&mut revokes))?;
+            // Transactions left in the log past the end mustn't be taken for
+            // new ones
+            sequence = journal.end.wrapping_add(1);
+        }
+
+        // Everything copied has to reach the disk before the journal stops
+        // holding it
+        self.disk.flush()?;
+        let mut log = [0u8; 8];
+        log[..4].copy_from_slice(&sequence.to_be_bytes());
+        let position = self.journal_position(&journal, 0, SEQUENCE_OFFSET)?;
+        self.write_disk(position, &log)?;
+
+        // The Superblock may have been replayed, so it's read again first
+        let mut superblock: SuperBlock = self.read_struct_at(1024)?;
+        superblock.s_feature_incompat &= !EXT3_FEATURE_INCOMPAT_RECOVER;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&superblock as *const SuperBlock
+                                        as *const u8,
+                                        core::mem::size_of::<SuperBlock>())
+        };
+        self.write_disk(1024, bytes)?;
+        self.disk.flush()?;
+        Ok(())
+    }
+
+    // Loads the journal inode and checks its superblock
+    fn open_journal(&self) -> Result<Journal, FsError> {
+        // External journals on another device aren't supported
+        let journal_inode = self.superblock.s_journal_inum;
+        if !self.has_compat_feature(EXT3_FEATURE_COMPAT_HAS_JOURNAL) {
+            return Err(FsError::BadSuperblock);
+        }
+        if journal_inode == 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut journal = Journal { inode: self.load_inode(journal_inode)?,
+                                    first: 0,
+                                    last: 0,
+                                    start: 0,
+                                    sequence: 0,
+                                    end: 0,
+                                    tag_size: TAG_SIZE,
+                                    record_size: 4,
+                                    revoke_count: 0 };
+        let position = self.journal_position(&journal, 0, 0)?;
+        let superblock: JournalSuperblock = self.read_struct_at(position)?;
+        let header = &superblock.s_header;
+        let version = u32::from_be(header.h_blocktype);
+        if u32::from_be(header.h_magic) != JBD2_MAGIC_NUMBER ||
+           (version != JBD2_SUPERBLOCK_V1 && version != JBD2_SUPERBLOCK_V2)
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        let incompat = if version == JBD2_SUPERBLOCK_V2 {
+            u32::from_be(superblock.s_feature_incompat)
+        } else {
+            0
+        };
+        if incompat & !SUPPORTED_JOURNAL_INCOMPAT != 0 {
+            return Err(FsError::NotSupported);
+        }
+        if incompat & JBD2_FEATURE_INCOMPAT_64BIT != 0 {
+            journal.tag_size = TAG_SIZE_64BIT;
+            journal.record_size = 8;
+        }
+
+        journal.first = u32::from_be(superblock.s_first);
+        journal.last = u32::from_be(superblock.s_maxlen);
+        journal.start = u32::from_be(superblock.s_start);
+        journal.sequence = u32::from_be(superblock.s_sequence);
+        if u32::from_be(superblock.s_blocksize) != self.block_size ||
+           journal.first == 0 ||
+           journal.first >= journal.last ||
+           (journal.start != 0 &&
+            (journal.start < journal.first ||
+             journal.start >= journal.last))
+        {
+            return Err(FsError::BadSuperblock);
+        }
+        Ok(journal)
+    }
+
+    // Walks the log from its start, doing |pass| to each transaction. The
+    // scan pass stops at the first block that doesn't belong to the next
+    // transaction and the others stop at the end the scan found.
+    fn journal_pass(&mut self,
+                    journal: &mut Journal,
+                    pass: Pass,
+                    mut revokes: Option<&mut RevokeTable>)
+                    -> Result<(), FsError> {
+        let mut sequence = journal.sequence;
+        let mut log_block = journal.start;
+        // The log can't be longer than the journal, however damaged it is
+        for _ in journal.first..journal.last {
+            if pass != Pass::Scan && sequence == journal.end {
+                return Ok(());
+            }
+
+            let position = self.journal_position(journal, log_block, 0)?;
+            let header: JournalHeader = self.read_struct_at(position)?;
+            if u32::from_be(header.h_magic) != JBD2_MAGIC_NUMBER ||
+               u32::from_be(header.h_sequence) != sequence
+            {
+                break;
+            }
+
+            let block = log_block;
+            log_block = journal.next_block(log_block);
+            match u32::from_be(header.h_blocktype) {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    log_block = self.replay_descriptor(journal, block,
+                                                       log_block, sequence,
+                                                       pass, &revokes)?;
+                }
+                JBD2_COMMIT_BLOCK => sequence = sequence.wrapping_add(1),
+                JBD2_REVOKE_BLOCK => {
+                    self.read_revoke_block(journal,
+                                           block,
+                                           sequence,
+                                           pass,
+                                           &mut revokes)?;
+                }
+                _ => break,
+            }
+        }
+
+        if pass == Pass::Scan {
+            journal.end = sequence;
+            return Ok(());
+        }
+        // The later passes have to get as far as the scan did
+        if sequence == journal.end {
+            Ok(())
+        } else {
+            Err(FsError::InvalidBlock)
+        }
+    }
+
+    // Goes through the tags of the descriptor block |descriptor|, whose
+    // blocks follow it in the log from |log_block|, copying each block to
+    // its place in the image on the replay pass unless it was revoked.
+    // Returns the block of the log after the last of them.
+    fn replay_descriptor(&mut self,
+                         journal: &Journal,
+                         descriptor: u32,
+                         log_block: u32,
+                         sequence: u32,
+                         pass: Pass,
+                         revokes: &Option<&mut RevokeTable>)
+                         -> Result<u32, FsError> {
+        let mut log_block = log_block;
+        let mut offset = HEADER_SIZE;
+        while offset + journal.tag_size <= self.block_size as usize {
+            let mut tag = [0u8; TAG_SIZE_64BIT];
+            let position = self.journal_position(journal, descriptor, offset)?;
+            self.read_disk(position, &mut tag[..journal.tag_size])?;
+            let flags = be_u16(&tag[6..]);
+
+            if pass == Pass::Replay {
+                let block = self.tag_block(journal, &tag)?;
+                let revoked = match *revokes {
+                    Some(ref revokes) => revokes.is_revoked(block, sequence),
+                    None => false,
+                };
+                if !revoked {
+                    self.replay_block(journal,
+                                      log_block,
+                                      block,
+                                      flags & JBD2_FLAG_ESCAPE != 0)?;
+                }
+            }
+
+            log_block = journal.next_block(log_block);
+            offset += journal.tag_size;
+            if flags & JBD2_FLAG_SAME_UUID == 0 {
+                offset += UUID_SIZE;
+            }
+            if flags & JBD2_FLAG_LAST_TAG != 0 {
+                break;
+            }
+        }
+        Ok(log_block)
+    }
+
+    // Returns the block of the image |tag| says to copy its block to
+    fn tag_block(&self, journal: &Journal, tag: &[u8]) -> Result<u32, FsError> {
+        let high = if journal.tag_size == TAG_SIZE_64BIT {
+            be_u32(&tag[8..])
+        } else {
+            0
+        };
+        self.journal_target(high, be_u32(&tag[0..]))
+    }
+
+    // Checks that the block numbered |high| and |low| is one of the image's
+    fn journal_target(&self, high: u32, low: u32) -> Result<u32, FsError> {
+        if high != 0 || low >= self.superblock.s_blocks_count {
+            return Err(FsError::InvalidBlock);
+        }
+        Ok(low)
+    }
+
+    // Copies the block |log_block| of the log to the block |block| of the
+    // image, putting back the magic number it started with if it was
+    // |escaped|
+    fn replay_block(&mut self,
+                    journal: &Journal,
+                    log_block: u32,
+                    block: u32,
+                    escaped: bool)
+                    -> Result<(), FsError> {
+        // The log block is only looked up in the journal inode once
+        let source = self.journal_position(journal, log_block, 0)?;
+        let mut chunk = [0u8; COPY_CHUNK_SIZE];
+        let mut done = 0;
+        while done < self.block_size as usize {
+            let count = min(chunk.len(), self.block_size as usize - done);
+            self.read_disk(source + done as u64, &mut chunk[..count])?;
+            if escaped && done == 0 {
+                chunk[..4].copy_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+            }
+            let position = self.position(block, done);
+            self.write_disk(position, &chunk[..count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Counts the records of the revoke block |block| on the scan pass and
+    // adds them to |revokes| on the revoke pass
+    fn read_revoke_block(&self,
+                         journal: &mut Journal,
+                         block: u32,
+                         sequence: u32,
+                         pass: Pass,
+                         revokes: &mut Option<&mut RevokeTable>)
+                         -> Result<(), FsError> {
+        if pass == Pass::Replay {
+            return Ok(());
+        }
+
+        let mut count = [0u8; 4];
+        let position = self.journal_position(journal, block, HEADER_SIZE)?;
+        self.read_disk(position, &mut count)?;
+        let used = min(be_u32(&count) as usize, self.block_size as usize);
+
+        let mut offset = REVOKE_HEADER_SIZE;
+        while offset + journal.record_size <= used {
+            if pass == Pass::Scan {
+                journal.revoke_count += 1;
+            } else {
+                let mut record = [0u8; 8];
+                let position = self.journal_position(journal, block, offset)?;
+                self.read_disk(position, &mut record[..journal.record_size])?;
+                let revoked = if journal.record_size == 8 {
+                    self.journal_target(be_u32(&record[0..]),
+                                        be_u32(&record[4..]))?
+                } else {
+                    self.journal_target(0, be_u32(&record[0..]))?
+                };
+                if let Some(ref mut revokes) = *revokes {
+                    revokes.add(revoked, sequence);
+                }
+            }
+            offset += journal.record_size;
+        }
+        Ok(())
+    }
+
+    // Returns the byte position on the disk of |offset| bytes into the block
+    // |log_block| of the journal
+    fn journal_position(&self,
+                        journal: &Journal,
+                        log_block: u32,
+                        offset: usize)
+                        -> Result<u64, FsError> {
+        let block = self.get_block_number(&journal.inode, log_block)?;
+        if block == 0 || block >= self.superblock.s_blocks_count {
+            return Err(FsError::InvalidBlock);
+        }
+        Ok(self.position(block, offset))
+    }
+}
+
+impl Journal {
+    // Returns the block of the log after |log_block|, which wraps around
+    // from the end of the journal to the first block of the log
+    fn next_block(&self, log_block: u32) -> u32 {
+        if log_block + 1 >= self.last {
+            self.first
+        } else {
+            log_block + 1
+        }
+    }
+}
+
+impl RevokeTable {
+    // Makes room for |capacity| revoked blocks
+    fn new(capacity: usize) -> Result<RevokeTable, FsError> {
+        Ok(RevokeTable { records: Scratch::new(capacity * 8)?,
+                         count: 0,
+                         capacity: capacity })
+    }
+
+    // Records that the transaction |sequence| revoked |block|
+    fn add(&mut self, block: u32, sequence: u32) {
+        for i in 0..self.count {
+            if self.records.get::<u32>(i * 2) == block {
+                if is_after(sequence, self.records.get::<u32>(i * 2 + 1)) {
+                    self.records.set(i * 2 + 1, sequence);
+                }
+                return;
+            }
+        }
+        if self.count < self.capacity {
+            self.records.set(self.count * 2, block);
+            self.records.set(self.count * 2 + 1, sequence);
+            self.count += 1;
+        }
+    }
+
+    // Checks whether |block| was revoked by the transaction |sequence| or
+    // one after it, which means the copy of it in |sequence| is stale
+    fn is_revoked(&self, block: u32, sequence: u32) -> bool {
+        (0..self.count).any(|i| {
+                           self.records.get::<u32>(i * 2) == block &&
+                           !is_after(sequence,
+                                     self.records.get::<u32>(i * 2 + 1))
+                       })
+    }
+}
+
+// Checks whether the transaction |sequence| comes after |other|. Sequence
+// numbers wrap around, so it's whichever is less than half the range ahead.
+fn is_after(sequence: u32, other: u32) -> bool {
+    (sequence.wrapping_sub(other) as i32) > 0
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 |
+    (bytes[1] as u32) << 16 |
+    (bytes[2] as u32) << 8 |
+    bytes[3] as u32
+}
@@ -10,24 +10,180 @@
 // How To Read the Root Directory
 // The root directory's inode is defined to always be 2. Read/parse the contents of inode 2.
 
-use crate::global_constants::{BG_DESC_SIZE, INODE_SIZE, SUPERBLOCK_MAGIC};
+use crate::global_constants::{BG_DESC_SIZE,
+                              CLOCK_FREQ,
+                              GOOD_OLD_FIRST_INODE,
+                              GOOD_OLD_INODE_SIZE,
+                              ROOT_INODE,
+                              SUPERBLOCK_MAGIC};
 
+use crate::block::{self, BlockDevice, BlockError, RamDisk, SECTOR_SIZE};
 use crate::console::Console;
+use crate::trap::timer::get_current_time;
+use crate::utils::heapvec::HeapVec;
 use crate::{print, println};
+use core::cmp::min;
 use core::fmt::Write;
-use core::ptr::null_mut;
-use core::ptr::read_volatile;
 
-// Filesystem Label from Assembly
-extern "C" {
-    static FILE_SYSTEM: u8;
+pub mod bitmap;
+pub mod dir;
+pub mod extent;
+pub mod file;
+pub mod fsck;
+pub mod htree;
+pub mod journal;
+pub mod namespace;
+pub mod symlink;
+pub mod vfs;
+
+// Revision level that added dynamic inode sizes and feature flags
+const EXT2_DYNAMIC_REV: u32 = 1;
+
+// Largest s_log_block_size, for 64K blocks. Block arithmetic counts on
+// blocks being no bigger than that.
+const EXT2_MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+// Compatible features: the image stays readable and writable without them
+const EXT3_FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const EXT2_FEATURE_COMPAT_RESIZE_INODE: u32 = 0x0010;
+const EXT2_FEATURE_COMPAT_DIR_INDEX: u32 = 0x0020;
+// Incompatible features: the on-disk format can't be read without them
+const EXT2_FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const EXT3_FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+const EXT4_FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+const EXT4_FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+// Read-only compatible features: the filesystem can still be read without
+// them, but writing could corrupt it
+const EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const EXT2_FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+// Features this driver knows how to handle. Compatible features are always
+// safe to ignore.
+const SUPPORTED_INCOMPAT: u32 = EXT2_FEATURE_INCOMPAT_FILETYPE |
+                                EXT3_FEATURE_INCOMPAT_RECOVER |
+                                EXT4_FEATURE_INCOMPAT_EXTENTS |
+                                EXT4_FEATURE_INCOMPAT_64BIT |
+                                EXT4_FEATURE_INCOMPAT_FLEX_BG;
+// Incompatible features this driver can read but not write
+const READ_ONLY_INCOMPAT: u32 =
+    EXT4_FEATURE_INCOMPAT_EXTENTS | EXT4_FEATURE_INCOMPAT_64BIT;
+const SUPPORTED_RO_COMPAT: u32 =
+    EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER | EXT2_FEATURE_RO_COMPAT_LARGE_FILE;
+
+// Mask for the file format bits of i_mode
+const EXT2_S_IFMT: u16 = 0xF000;
+// File formats in i_mode
+const EXT2_S_IFSOCK: u16 = 0xC000;
+const EXT2_S_IFLNK: u16 = 0xA000;
+const EXT2_S_IFREG: u16 = 0x8000;
+const EXT2_S_IFBLK: u16 = 0x6000;
+const EXT2_S_IFDIR: u16 = 0x4000;
+const EXT2_S_IFCHR: u16 = 0x2000;
+const EXT2_S_IFIFO: u16 = 0x1000;
+// Mask for the permission bits of i_mode, including setuid, setgid and sticky
+const EXT2_S_IPERM: u16 = 0o7777;
+
+// Set in i_flags when a directory has a hashed index
+const EXT2_INDEX_FL: u32 = 0x0000_1000;
+// Set in i_flags when i_block holds an extent tree instead of a block map
+const EXT4_EXTENTS_FL: u32 = 0x0008_0000;
+
+// Most links an inode can have
+const EXT2_LINK_MAX: u16 = 32000;
+
+// Number of direct block pointers in i_block
+const DIRECT_BLOCKS: u32 = 12;
+// Positions of the indirect block pointers in i_block
+const SINGLE_INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+
+// Errors returned when walking the filesystem
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FsError {
+    // A path component doesn't exist
+    NotFound,
+    // A path component that must be a directory is something else
+    NotADirectory,
+    // The inode number is 0 or past the last inode
+    InvalidInode,
+    // The inode's block group has no descriptor
+    BadGroupDescriptor,
+    // The Superblock is missing or describes an impossible layout
+    BadSuperblock,
+    // The image needs incompatible features this driver doesn't support
+    UnsupportedFeatures(u32),
+    // The image hasn't been copied to RAM or can only be mounted read-only
+    ReadOnly,
+    // The journal has transactions to replay, but the disk can't be written
+    NeedsRecovery,
+    // There are no free blocks or inodes left
+    NoSpace,
+    // The kernel heap couldn't hold a copy of the image
+    OutOfMemory,
+    // The block number is out of range or isn't allocated
+    InvalidBlock,
+    // Something already exists at the path
+    AlreadyExists,
+    // A directory was given where a file is needed
+    IsADirectory,
+    // The directory still has entries besides "." and ".."
+    NotEmpty,
+    // The final path component can't be used as a name
+    InvalidName,
+    // The inode already has as many links as it can hold
+    TooManyLinks,
+    // The operation doesn't make sense, like moving a directory into itself
+    InvalidArgument,
+    // Resolving the path followed too many symbolic links, likely a loop
+    TooManySymlinks,
+    // The block device failed to read or write a block
+    DeviceError(BlockError),
+    // The filesystem doesn't provide the operation
+    NotSupported,
+    // A filesystem is mounted on the path, or one below it is still mounted
+    Busy,
+    // The paths are on different filesystems
+    CrossDevice,
+    // Every slot of the mount table is taken
+    TooManyMounts,
+    // The file descriptor isn't open, or wasn't opened for the operation
+    BadDescriptor,
+    // The process or the system has no room left for another open file
+    TooManyOpenFiles,
+    // The file is a device like the console, which has no offset to move
+    IllegalSeek,
 }
 
-pub struct Device {
-    device: *const u8,
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> FsError {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::OutOfMemory => FsError::OutOfMemory,
+            _ => FsError::DeviceError(error),
+        }
+    }
+}
+
+pub struct Device<B: BlockDevice> {
+    // Where the image is stored
+    disk: B,
     pub superblock: SuperBlock,
-    pub group_descriptor_table: GroupDescriptor,
+    // Descriptors of every block group, read in with the Superblock
+    group_descriptors: Option<HeapVec<GroupDescriptor>>,
+    group_count: u32,
     block_size: u32,
+    // Size of each entry in the inode tables
+    inode_size: u32,
+    // First inode that isn't reserved by the filesystem
+    first_inode: u32,
+    // Size of each entry in the group descriptor table
+    descriptor_size: usize,
+    // Set when the image has features that are only safe to read
+    read_only: bool,
+    // Set when both the image and the disk holding it can be written
+    writable: bool,
 }
 
 #[repr(C)]
@@ -63,9 +219,89 @@ pub struct SuperBlock {
     // Maximal mount count
     s_max_mnt_count: u16,
     // Magic signature
-    s_magic: u32,
+    s_magic: u16,
+    // File system state
+    s_state: u16,
+    // Behaviour when detecting errors
+    s_errors: u16,
+    // Minor revision level
+    s_minor_rev_level: u16,
+    // Time of last check
+    s_lastcheck: u32,
+    // Max time between checks
+    s_checkinterval: u32,
+    // OS that created the filesystem
+    s_creator_os: u32,
+    // Revision level
+    s_rev_level: u32,
+    // Default uid for reserved blocks
+    s_def_resuid: u16,
+    // Default gid for reserved blocks
+    s_def_resgid: u16,
+    // The following fields are only valid for EXT2_DYNAMIC_REV
+    // First non-reserved inode
+    s_first_ino: u32,
+    // Size of inode structure
+    s_inode_size: u16,
+    // Block group number of this Superblock
+    s_block_group_nr: u16,
+    // Compatible feature set
+    s_feature_compat: u32,
+    // Incompatible feature set
+    s_feature_incompat: u32,
+    // Read-only compatible feature set
+    s_feature_ro_compat: u32,
+    // Volume id
+    s_uuid: [u8; 16],
+    // Volume name
+    s_volume_name: [u8; 16],
+    // Directory where last mounted
+    s_last_mounted: [u8; 64],
+    // Compression algorithms in use
+    s_algo_bitmap: u32,
+    // Number of blocks to preallocate for files
+    s_prealloc_blocks: u8,
+    // Number of blocks to preallocate for directories
+    s_prealloc_dir_blocks: u8,
+    // Blocks reserved for growing the descriptor table
+    s_reserved_gdt_blocks: u16,
+    // Id of the journal's Superblock
+    s_journal_uuid: [u8; 16],
+    // Inode number of the journal file
+    s_journal_inum: u32,
+    // Device number of an external journal
+    s_journal_dev: u32,
+    // Start of the list of inodes to delete
+    s_last_orphan: u32,
+    // Seed for the directory index hashes
+    s_hash_seed: [u32; 4],
+    // Default hash version for directory indexes
+    s_def_hash_version: u8,
+    // Kind of journal backup kept in the inode
+    s_jnl_backup_type: u8,
+    // Size of group descriptors when the 64bit feature is set
+    s_desc_size: u16,
+    // Default mount options
+    s_default_mount_opts: u32,
+    // First metablock block group
+    s_first_meta_bg: u32,
+    // When the filesystem was created
+    s_mkfs_time: u32,
+    // Backup of the journal inode's i_block and size
+    s_jnl_blocks: [u32; 17],
+    // High 32 bits of the block counts when the 64bit feature is set
+    s_blocks_count_hi: u32,
+    s_r_blocks_count_hi: u32,
+    s_free_blocks_hi: u32,
+    // Extra space all inodes have past the original 128 bytes
+    s_min_extra_isize: u16,
+    // Extra space new inodes should have
+    s_want_extra_isize: u16,
+    // Miscellaneous flags, such as how directory index hashes treat chars
+    s_flags: u32,
 }
 
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct GroupDescriptor {
     // Block id for first block of block bitmap
@@ -80,6 +316,10 @@ pub struct GroupDescriptor {
     bg_free_inodes_count: u16,
     // Number of inodes allocated to directories
     bg_used_dirs_count: u16,
+    // Padding to a 32-bit boundary
+    bg_pad: u16,
+    // Reserved for future revisions
+    bg_reserved: [u32; 3],
 }
 
 #[repr(C)]
@@ -117,225 +357,548 @@ pub struct Inode {
     i_faddr: u32,
 }
 
-impl Device {
-    // Create an empty Device
-    pub fn new() -> Device {
-        Device { device: null_mut(),
+impl<B: BlockDevice> Device<B> {
+    // Create a Device for the image on |disk|, which can be used once the
+    // Superblock has been read
+    pub fn new(disk: B) -> Device<B> {
+        Device { disk: disk,
                  superblock: SuperBlock::new(),
-                 group_descriptor_table: GroupDescriptor::new(),
-                 block_size: 0 }
+                 group_descriptors: None,
+                 group_count: 0,
+                 block_size: 0,
+                 inode_size: GOOD_OLD_INODE_SIZE,
+                 first_inode: GOOD_OLD_FIRST_INODE,
+                 descriptor_size: BG_DESC_SIZE,
+                 read_only: true,
+                 writable: false }
     }
 
     // Read in the Superblock appropriately
-    pub fn read_superblock(&mut self) -> Result<(), ()> {
-        // Read in the Superblock
-        unsafe {
-            self.device = &FILE_SYSTEM as *const u8;
-            self.superblock = read_volatile(self.device.add(1024)
-                                            as *const SuperBlock)
-                              as SuperBlock;
+    pub fn read_superblock(&mut self) -> Result<(), FsError> {
+        // Device blocks have to fit in the buffer used to read them
+        let sector_size = self.disk.block_size();
+        if sector_size == 0 || sector_size > SECTOR_SIZE {
+            return Err(FsError::DeviceError(BlockError::BadBuffer));
         }
 
+        // Read in the Superblock
+        self.superblock = self.read_struct_at(1024)?;
+
         // Check magic signature
         if self.superblock.s_magic != SUPERBLOCK_MAGIC {
-            return Err(());
+            return Err(FsError::BadSuperblock);
         }
 
         // Store the actual block size as part of the Device
-        self.block_size =
-            (1024 as u32).wrapping_shl(self.superblock.s_log_block_size);
-        return Ok(());
-    }
-
-    // Reads in the current relative GroupDescriptor
-    pub fn read_group_descriptor_table(&mut self,
-                                       group_number: u32)
-                                       -> Result<(), ()> {
-        let gd_count = self.superblock.s_blocks_count /
-                       self.superblock.s_blocks_per_group +
-                       1;
-
-        // Check if the group_number is valid
-        if group_number > gd_count {
-            return Err(());
+        if self.superblock.s_log_block_size > EXT2_MAX_LOG_BLOCK_SIZE {
+            return Err(FsError::BadSuperblock);
+        }
+        self.block_size = 1024 << self.superblock.s_log_block_size;
+
+        self.read_revision()?;
+        self.read_group_descriptors()?;
+        self.writable = self.disk.is_writable() && !self.read_only;
+
+        // An image that wasn't unmounted cleanly can have half-written
+        // metadata, which the journal holds the whole of. The Superblock and
+        // group descriptors may be among it, so they're read again after.
+        if self.has_incompat_feature(EXT3_FEATURE_INCOMPAT_RECOVER) {
+            self.recover_journal()?;
+            return self.read_superblock();
         }
+        Ok(())
+    }
 
-        // Read in the descriptor
-        unsafe {
-            self.group_descriptor_table =
-                read_volatile(self.device
-                                  .add(2048 +
-                                       group_number as usize * BG_DESC_SIZE)
-                              as *const GroupDescriptor)
-                as GroupDescriptor;
+    // Sets up the inode layout and feature checks from the revision 1 fields
+    // of the Superblock. Revision 0 images always use 128 byte inodes and
+    // have no feature flags.
+    fn read_revision(&mut self) -> Result<(), FsError> {
+        if self.superblock.s_rev_level < EXT2_DYNAMIC_REV {
+            self.inode_size = GOOD_OLD_INODE_SIZE;
+            self.first_inode = GOOD_OLD_FIRST_INODE;
+            self.descriptor_size = BG_DESC_SIZE;
+            self.read_only = false;
+            return Ok(());
+        }
+
+        // Refuse to mount images whose format we can't parse
+        let incompat = self.superblock.s_feature_incompat;
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::UnsupportedFeatures(incompat &
+                                                    !SUPPORTED_INCOMPAT));
+        }
+
+        // The inode size must be a power of two that is at least the original
+        // size and fits in a block
+        let inode_size = self.superblock.s_inode_size as u32;
+        if inode_size < GOOD_OLD_INODE_SIZE ||
+           inode_size > self.block_size ||
+           !inode_size.is_power_of_two()
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        // 64 bit images can have larger group descriptors, of which only the
+        // first 32 bytes are needed for images under 2^32 blocks
+        if incompat & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+            let descriptor_size = self.superblock.s_desc_size as usize;
+            if descriptor_size < BG_DESC_SIZE ||
+               descriptor_size > self.block_size as usize ||
+               !descriptor_size.is_power_of_two()
+            {
+                return Err(FsError::BadSuperblock);
+            }
+            self.descriptor_size = descriptor_size;
         }
 
-        return Ok(());
+        self.inode_size = inode_size;
+        self.first_inode = self.superblock.s_first_ino;
+        // Unknown read-only compatible features can still be read safely, and
+        // so can the ext4 layouts this driver doesn't know how to write
+        self.read_only =
+            self.superblock.s_feature_ro_compat & !SUPPORTED_RO_COMPAT != 0 ||
+            incompat & READ_ONLY_INCOMPAT != 0;
+        Ok(())
+    }
+
+    // Checks whether the image uses the compatible |feature|
+    fn has_compat_feature(&self, feature: u32) -> bool {
+        self.superblock.s_rev_level >= EXT2_DYNAMIC_REV &&
+        self.superblock.s_feature_compat & feature != 0
+    }
+
+    // Checks whether the image uses the incompatible |feature|
+    fn has_incompat_feature(&self, feature: u32) -> bool {
+        self.superblock.s_rev_level >= EXT2_DYNAMIC_REV &&
+        self.superblock.s_feature_incompat & feature != 0
+    }
+
+    // Checks whether the image can only be mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Reads in the descriptor of every block group. The descriptor table
+    // starts in the block after the one holding the Superblock, which depends
+    // on the block size.
+    fn read_group_descriptors(&mut self) -> Result<(), FsError> {
+        let sb = &self.superblock;
+        if sb.s_blocks_per_group == 0 ||
+           sb.s_inodes_per_group == 0 ||
+           sb.s_blocks_count <= sb.s_first_data_block
+        {
+            return Err(FsError::BadSuperblock);
+        }
+
+        let data_blocks = sb.s_blocks_count - sb.s_first_data_block;
+        let group_count =
+            (data_blocks + sb.s_blocks_per_group - 1) / sb.s_blocks_per_group;
+
+        // Every inode has to belong to one of the groups
+        let inode_groups = (sb.s_inodes_count + sb.s_inodes_per_group - 1) /
+                           sb.s_inodes_per_group;
+        if inode_groups > group_count {
+            return Err(FsError::BadSuperblock);
+        }
+
+        let table = sb.s_first_data_block + 1;
+        let mut descriptors = HeapVec::new(group_count as usize);
+        for group_number in 0..group_count as usize {
+            descriptors.push(self.read_struct(table,
+                                              group_number *
+                                              self.descriptor_size)?);
+        }
+
+        self.group_count = group_count;
+        self.group_descriptors = Some(descriptors);
+        Ok(())
+    }
+
+    // Returns the descriptor of the block group |group_number|
+    pub fn group_descriptor(&self,
+                            group_number: u32)
+                            -> Result<&GroupDescriptor, FsError> {
+        match self.group_descriptors {
+            Some(ref descriptors) if group_number < self.group_count => {
+                Ok(&descriptors[group_number as usize])
+            }
+            _ => Err(FsError::BadGroupDescriptor),
+        }
     }
 
     // Loads an Inode in
-    pub fn load_inode(&mut self, inode_number: u32) -> Inode {
+    pub fn load_inode(&self, inode_number: u32) -> Result<Inode, FsError> {
+        if inode_number == 0 || inode_number > self.superblock.s_inodes_count {
+            return Err(FsError::InvalidInode);
+        }
+
         let group_number: u32 =
             (inode_number - 1) / self.superblock.s_inodes_per_group;
         let inode_local_number: u32 =
             (inode_number - 1) % self.superblock.s_inodes_per_group;
 
-        // Read in the group descriptor
-        match self.read_group_descriptor_table(group_number) {
-            Ok(()) => (),
-            Err(()) => panic!("Bad Group Descriptor"),
+        let inode_table_block: u32 =
+            self.group_descriptor(group_number)?.bg_inode_table;
+
+        // Read in an inode, which only fills the start of larger entries
+        let offset = inode_local_number as usize * self.inode_size as usize;
+        self.read_struct(inode_table_block, offset)
+    }
+
+    // Resolves a path such as "/etc/motd" to an inode number by starting at
+    // the root directory and looking up each component in turn. Symbolic
+    // links are followed, including one at the end of the path.
+    pub fn lookup(&self, path: &str) -> Result<u32, FsError> {
+        crate::vfs::path::resolve(self, ROOT_INODE, path, true)
+    }
+
+    // Translates the |index|th block of an inode's data into a block number
+    // on the device by walking the direct and indirect block maps. Returns 0
+    // if that block of the file was never allocated.
+    fn get_block_number(&self,
+                        inode: &Inode,
+                        index: u32)
+                        -> Result<u32, FsError> {
+        // ext4 inodes can map their blocks with extents instead
+        if inode.i_flags & EXT4_EXTENTS_FL != 0 {
+            return self.extent_block_number(inode, index);
         }
-        let inode_table_block: u32 = self.group_descriptor_table.bg_inode_table;
-
-        // Read in an inode
-        let cur_inode: Inode;
-        unsafe {
-            cur_inode =
-                read_volatile(self.device.add(inode_table_block as usize *
-                                              self.block_size as usize +
-                                              inode_local_number as usize *
-                                              INODE_SIZE)
-                              as *const Inode) as Inode;
+
+        let (slot, depth, relative) = self.block_path(index);
+        let mut block = inode.i_block[slot];
+
+        // Each level of indirection narrows the index down by a factor of
+        // the number of pointers that fit in a block
+        let per_block = self.block_size / 4;
+        for level in (0..depth).rev() {
+            let span = per_block.pow(level);
+            block =
+                self.read_block_pointer(block, (relative / span) % per_block)?;
         }
 
-        return cur_inode;
+        Ok(block)
     }
 
-    // Read and print the data from an inode
-    pub fn read_inode(&mut self, inode_number: u32) {
-        let cur_inode = self.load_inode(inode_number);
-        // let arr = self.load_inode(inode_number);
-
-        let mut index = 0 as usize;
-        let mut block_number = cur_inode.i_block[index] as usize;
-        let mut size = cur_inode.i_size;
-
-        while block_number != 0 && size != 0 {
-            let block: *const u8;
-            unsafe {
-                block = self.device.add(self.block_size as usize * block_number)
-                        as *const u8;
-            }
-            // Handle direct blocks... directly
-            if index < 12 {
-                size = self.print_block(block, size);
-            }
-            // Handle singly indirect blocks
-            else if index == 12 {
-                size = self.single_indirect_read(block as *const u32, size);
-            }
-            // Handle doubly indirect blocks
-            else if index == 13 {
-                size = self.double_indirect_read(block as *const u32, size);
-            }
-            // Handle triply indirect blocks
-            else {
-                size = self.triple_indirect_read(block as *const u32, size);
-            }
-            index = index + 1;
-            if index == 15 {
-                break;
-            }
-            block_number = cur_inode.i_block[index] as usize;
+    // Finds where the |index|th block of a file is mapped from. Returns the
+    // position in i_block to start at, how many indirect blocks have to be
+    // followed, and the index relative to the start of that indirect tree.
+    fn block_path(&self, index: u32) -> (usize, u32, u32) {
+        let per_block = self.block_size / 4;
+        let mut index = index;
+
+        // Direct blocks
+        if index < DIRECT_BLOCKS {
+            return (index as usize, 0, 0);
         }
+        index -= DIRECT_BLOCKS;
+
+        // Singly indirect blocks
+        if index < per_block {
+            return (SINGLE_INDIRECT_BLOCK, 1, index);
+        }
+        index -= per_block;
+
+        // Doubly indirect blocks
+        if index < per_block * per_block {
+            return (DOUBLE_INDIRECT_BLOCK, 2, index);
+        }
+        index -= per_block * per_block;
+
+        // Triply indirect blocks
+        (TRIPLE_INDIRECT_BLOCK, 3, index)
     }
 
-    // Print the contents of a block, but don't read too much
-    pub fn print_block(&mut self, block: *const u8, mut size: u32) -> u32 {
-        unsafe {
-            for i in 0..self.block_size {
-                print!("{}", {
-                    read_volatile(block.add(i as usize) as *const u8) as char
-                });
-                size = size - 1;
-                if size == 0 {
-                    break;
-                }
-            }
+    // Reads the |index|th block number out of an indirect block
+    fn read_block_pointer(&self,
+                          block: u32,
+                          index: u32)
+                          -> Result<u32, FsError> {
+        if block == 0 {
+            return Ok(0);
         }
 
-        return size;
+        self.read_struct(block, index as usize * 4)
     }
 
-    // Reads a singly indirect inode block
-    pub fn single_indirect_read(&mut self,
-                                block: *const u32,
-                                mut size: u32)
-                                -> u32 {
-        let mut index = 0 as usize;
-        unsafe {
-            let mut block_number =
-                read_volatile(block.add(index) as *const u32) as usize;
+    // Reads a T stored |offset| bytes into the block |block_number|. T has
+    // to be one of the plain on-disk structures, for which any bytes make a
+    // valid value.
+    fn read_struct<T>(&self,
+                      block_number: u32,
+                      offset: usize)
+                      -> Result<T, FsError> {
+        self.read_struct_at(self.position(block_number, offset))
+    }
 
-            while block_number != 0 && size != 0 {
-                let b = self.device.add(self.block_size as usize * block_number)
-                        as *const u8;
-                size = self.print_block(b, size);
+    // Reads a T stored at the byte |position| of the disk
+    fn read_struct_at<T>(&self, position: u64) -> Result<T, FsError> {
+        Ok(block::read_struct_at(&self.disk, position)?)
+    }
 
-                index = index + 1;
-                if index as u32 >= self.block_size / 32 {
-                    break;
-                }
-                block_number =
-                    read_volatile(block.add(index) as *const u32) as usize;
+    // Writes |value| |offset| bytes into the block |block_number|
+    fn write_struct<T>(&mut self,
+                       block_number: u32,
+                       offset: usize,
+                       value: &T)
+                       -> Result<(), FsError> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(value as *const T as *const u8,
+                                        core::mem::size_of::<T>())
+        };
+        self.write_bytes(block_number, offset, bytes)
+    }
+
+    // Reads |buf.len()| bytes starting |offset| bytes into the block
+    // |block_number|. The bytes can run on into the blocks after it.
+    fn read_bytes(&self,
+                  block_number: u32,
+                  offset: usize,
+                  buf: &mut [u8])
+                  -> Result<(), FsError> {
+        self.read_disk(self.position(block_number, offset), buf)
+    }
+
+    // Writes |buf| starting |offset| bytes into the block |block_number|
+    fn write_bytes(&mut self,
+                   block_number: u32,
+                   offset: usize,
+                   buf: &[u8])
+                   -> Result<(), FsError> {
+        self.check_writable()?;
+        self.write_disk(self.position(block_number, offset), buf)
+    }
+
+    // Fills |length| bytes starting |offset| bytes into the block
+    // |block_number| with zeros
+    fn zero_bytes(&mut self,
+                  block_number: u32,
+                  offset: usize,
+                  length: usize)
+                  -> Result<(), FsError> {
+        let zeros = [0u8; 64];
+        let mut done = 0;
+        while done < length {
+            let count = min(zeros.len(), length - done);
+            self.write_bytes(block_number, offset + done, &zeros[..count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Returns the byte position on the disk of |offset| bytes into the block
+    // |block_number|
+    fn position(&self, block_number: u32, offset: usize) -> u64 {
+        block_number as u64 * self.block_size as u64 + offset as u64
+    }
+
+    // Copies the bytes at |position| on the disk into |buf|
+    fn read_disk(&self, position: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_at(&self.disk, position, buf)?)
+    }
+
+    // Copies |buf| onto the disk at |position|
+    fn write_disk(&mut self, position: u64, buf: &[u8]) -> Result<(), FsError> {
+        Ok(block::write_at(&mut self.disk, position, buf)?)
+    }
+
+    // Copies the image onto a RAM disk so that it can be modified, and
+    // returns a Device for the copy
+    pub fn copy_to_ram(&self) -> Result<Device<RamDisk>, FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+
+        let mut device = Device::new(RamDisk::copy_of(&self.disk)?);
+        device.read_superblock()?;
+        Ok(device)
+    }
+
+    // Gives back the disk holding the image
+    pub fn into_disk(self) -> B {
+        self.disk
+    }
+
+    // Makes sure every change to the image has reached the disk
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.disk.flush()?;
+        Ok(())
+    }
+
+    // Returns the time to record in inodes. There's no real-time clock, so
+    // this estimates it as the last time the image was written plus the time
+    // since boot.
+    pub fn timestamp(&self) -> u32 {
+        self.superblock.s_wtime + (get_current_time() / CLOCK_FREQ) as u32
+    }
+
+    // Checks that the image can be modified
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(FsError::ReadOnly)
+        }
+    }
+
+    // Writes the in-memory Superblock back to the image
+    fn write_superblock(&mut self) -> Result<(), FsError> {
+        self.check_writable()?;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&self.superblock as *const SuperBlock
+                                        as *const u8,
+                                        core::mem::size_of::<SuperBlock>())
+        };
+        self.write_disk(1024, bytes)
+    }
+
+    // Writes the in-memory descriptor of |group_number| back to the
+    // descriptor table in the image
+    fn write_group_descriptor(&mut self,
+                              group_number: u32)
+                              -> Result<(), FsError> {
+        let descriptor = self.group_descriptor(group_number)?.clone();
+        let table = self.superblock.s_first_data_block + 1;
+        self.write_struct(table,
+                          group_number as usize * self.descriptor_size,
+                          &descriptor)
+    }
+
+    // Returns the descriptor of the block group |group_number| for updating
+    fn group_descriptor_mut(&mut self,
+                            group_number: u32)
+                            -> Result<&mut GroupDescriptor, FsError> {
+        match self.group_descriptors {
+            Some(ref mut descriptors) if group_number < self.group_count => {
+                Ok(&mut descriptors[group_number as usize])
             }
+            _ => Err(FsError::BadGroupDescriptor),
         }
+    }
+
+    // Writes |inode| back to the inode table as |inode_number|
+    pub fn write_inode(&mut self,
+                       inode_number: u32,
+                       inode: &Inode)
+                       -> Result<(), FsError> {
+        self.check_writable()?;
+        if inode_number == 0 || inode_number > self.superblock.s_inodes_count {
+            return Err(FsError::InvalidInode);
+        }
+
+        let group_number: u32 =
+            (inode_number - 1) / self.superblock.s_inodes_per_group;
+        let inode_local_number: u32 =
+            (inode_number - 1) % self.superblock.s_inodes_per_group;
+        let inode_table_block: u32 =
+            self.group_descriptor(group_number)?.bg_inode_table;
 
-        return size;
+        // Only the start of larger inode entries is rewritten
+        let offset = inode_local_number as usize * self.inode_size as usize;
+        self.write_struct(inode_table_block, offset, inode)
     }
 
-    // Reads a doubly indirect inode block
-    pub fn double_indirect_read(&mut self,
-                                block: *const u32,
-                                mut size: u32)
-                                -> u32 {
-        let mut index = 0 as usize;
-        unsafe {
-            let mut block_number =
-                read_volatile(block.add(index * 4) as *const u32) as usize;
+    // Stores |value| as the |index|th block number in an indirect block
+    fn write_block_pointer(&mut self,
+                           block: u32,
+                           index: u32,
+                           value: u32)
+                           -> Result<(), FsError> {
+        self.write_struct(block, index as usize * 4, &value)
+    }
 
-            while block_number != 0 && size != 0 {
-                let b = self.device.add(self.block_size as usize * block_number)
-                        as *const u32;
-                size = self.single_indirect_read(b, size);
+    // Fills a block with zeros
+    fn zero_block(&mut self, block_number: u32) -> Result<(), FsError> {
+        self.zero_bytes(block_number, 0, self.block_size as usize)
+    }
 
-                index = index + 1;
-                if index as u32 >= self.block_size / 32 {
+    // Reads from the data of the file |inode_number| starting at |offset|
+    // into |buf|, returning the number of bytes read. Reading stops at the end
+    // of the file, and blocks that were never allocated read as zeros.
+    pub fn read_at(&self,
+                   inode_number: u32,
+                   offset: u32,
+                   buf: &mut [u8])
+                   -> Result<usize, FsError> {
+        let inode = self.load_inode(inode_number)?;
+        self.read_inode_data(&inode, offset, buf)
+    }
+
+    // Read and print the data from an inode
+    pub fn read_inode(&self, inode_number: u32) {
+        let inode = match self.load_inode(inode_number) {
+            Ok(inode) => inode,
+            Err(e) => {
+                println!("Cannot load inode {}: {:?}", inode_number, e);
+                return;
+            }
+        };
+        let mut buffer = [0u8; 64];
+        let mut offset = 0;
+
+        loop {
+            let count = match self.read_inode_data(&inode, offset, &mut buffer)
+            {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) => {
+                    println!("Cannot read inode {}: {:?}", inode_number, e);
                     break;
                 }
-                block_number =
-                    read_volatile(block.add(index) as *const u32) as usize;
+            };
+            for c in buffer[..count].iter() {
+                print!("{}", *c as char);
             }
+            offset += count as u32;
         }
-
-        return size;
     }
 
-    // Reads a triply indirect inode block
-    pub fn triple_indirect_read(&mut self,
-                                block: *const u32,
-                                mut size: u32)
-                                -> u32 {
-        let mut index = 0 as usize;
-        unsafe {
-            let mut block_number =
-                read_volatile(block.add(index * 4) as *const u32) as usize;
+    // Copies the data of |inode| starting at |offset| into |buf| one block at
+    // a time, returning the number of bytes copied
+    fn read_inode_data(&self,
+                       inode: &Inode,
+                       offset: u32,
+                       buf: &mut [u8])
+                       -> Result<usize, FsError> {
+        if offset >= inode.i_size {
+            return Ok(0);
+        }
 
-            while block_number != 0 && size != 0 {
-                let b = self.device.add(self.block_size as usize * block_number)
-                        as *const u32;
-                size = self.double_indirect_read(b, size);
+        // Don't read past the end of the file
+        let length = min(buf.len(), (inode.i_size - offset) as usize);
+        let mut done = 0;
 
-                index = index + 1;
-                if index as u32 >= self.block_size / 32 {
-                    break;
+        // Short symlink targets are kept in i_block rather than a data block
+        if self.is_fast_symlink(inode) {
+            let inline = symlink::inline_data(inode);
+            let start = min(offset as usize, inline.len());
+            let count = min(length, inline.len() - start);
+            buf[..count].copy_from_slice(&inline[start..start + count]);
+            return Ok(count);
+        }
+
+        while done < length {
+            let position = offset + done as u32;
+            let block_offset = position % self.block_size;
+            let count =
+                min((self.block_size - block_offset) as usize, length - done);
+            let block_number =
+                self.get_block_number(inode, position / self.block_size)?;
+
+            if block_number == 0 {
+                // Holes in sparse files read as zeros
+                for byte in buf[done..done + count].iter_mut() {
+                    *byte = 0;
                 }
-                block_number =
-                    read_volatile(block.add(index) as *const u32) as usize;
+            } else {
+                self.read_bytes(block_number,
+                                block_offset as usize,
+                                &mut buf[done..done + count])?;
             }
+
+            done += count;
         }
 
-        return size;
+        Ok(done)
     }
 }
 
@@ -357,7 +920,47 @@ impl SuperBlock {
                      s_wtime: 0,
                      s_mnt_count: 0,
                      s_max_mnt_count: 0,
-                     s_magic: 0 }
+                     s_magic: 0,
+                     s_state: 0,
+                     s_errors: 0,
+                     s_minor_rev_level: 0,
+                     s_lastcheck: 0,
+                     s_checkinterval: 0,
+                     s_creator_os: 0,
+                     s_rev_level: 0,
+                     s_def_resuid: 0,
+                     s_def_resgid: 0,
+                     s_first_ino: 0,
+                     s_inode_size: 0,
+                     s_block_group_nr: 0,
+                     s_feature_compat: 0,
+                     s_feature_incompat: 0,
+                     s_feature_ro_compat: 0,
+                     s_uuid: [0; 16],
+                     s_volume_name: [0; 16],
+                     s_last_mounted: [0; 64],
+                     s_algo_bitmap: 0,
+                     s_prealloc_blocks: 0,
+                     s_prealloc_dir_blocks: 0,
+                     s_reserved_gdt_blocks: 0,
+                     s_journal_uuid: [0; 16],
+                     s_journal_inum: 0,
+                     s_journal_dev: 0,
+                     s_last_orphan: 0,
+                     s_hash_seed: [0; 4],
+                     s_def_hash_version: 0,
+                     s_jnl_backup_type: 0,
+                     s_desc_size: 0,
+                     s_default_mount_opts: 0,
+                     s_first_meta_bg: 0,
+                     s_mkfs_time: 0,
+                     s_jnl_blocks: [0; 17],
+                     s_blocks_count_hi: 0,
+                     s_r_blocks_count_hi: 0,
+                     s_free_blocks_hi: 0,
+                     s_min_extra_isize: 0,
+                     s_want_extra_isize: 0,
+                     s_flags: 0 }
     }
 
     // Print some of the various Superblock fields for testing
@@ -378,6 +981,16 @@ impl SuperBlock {
         println!("Number of Inodes per Group is {}", {
             self.s_inodes_per_group
         });
+        println!("Revision Level is {}", { self.s_rev_level });
+        if self.s_rev_level >= EXT2_DYNAMIC_REV {
+            println!("Inode Size is {}", { self.s_inode_size });
+            println!("First Non-reserved Inode is {}", { self.s_first_ino });
+            println!("Features are compat {:#x}, incompat {:#x}, ro_compat \
+                      {:#x}",
+                     { self.s_feature_compat },
+                     { self.s_feature_incompat },
+                     { self.s_feature_ro_compat });
+        }
     }
 }
 
@@ -389,7 +1002,9 @@ impl GroupDescriptor {
                           bg_inode_table: 0,
                           bg_free_blocks_count: 0,
                           bg_free_inodes_count: 0,
-                          bg_used_dirs_count: 0 }
+                          bg_used_dirs_count: 0,
+                          bg_pad: 0,
+                          bg_reserved: [0; 3] }
     }
 }
 
@@ -414,4 +1029,40 @@ impl Inode {
                 i_dir_acl: 0,
                 i_faddr: 0 }
     }
+
+    // Checks whether this inode is a directory
+    pub fn is_directory(&self) -> bool {
+        self.i_mode & EXT2_S_IFMT == EXT2_S_IFDIR
+    }
+
+    // Checks whether this inode is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.i_mode & EXT2_S_IFMT == EXT2_S_IFLNK
+    }
+
+    // Checks whether this inode is a regular file
+    pub fn is_regular_file(&self) -> bool {
+        self.i_mode & EXT2_S_IFMT == EXT2_S_IFREG
+    }
+
+    // Returns the directory entry file type matching this inode's format
+    pub fn file_type(&self) -> u8 {
+        match self.i_mode & EXT2_S_IFMT {
+            EXT2_S_IFIFO => dir::EXT2_FT_FIFO,
+            EXT2_S_IFCHR => dir::EXT2_FT_CHRDEV,
+            EXT2_S_IFDIR => dir::EXT2_FT_DIR,
+            EXT2_S_IFBLK => dir::EXT2_FT_BLKDEV,
+            EXT2_S_IFREG => dir::EXT2_FT_REG_FILE,
+            EXT2_S_IFLNK => dir::EXT2_FT_SYMLINK,
+            EXT2_S_IFSOCK => dir::EXT2_FT_SOCK,
+            _ => dir::EXT2_FT_UNKNOWN,
+        }
+    }
+
+    // Sets the access, change and modification times to |time|
+    fn touch(&mut self, time: u32) {
+        self.i_atime = time;
+        self.i_ctime = time;
+        self.i_mtime = time;
+    }
 }
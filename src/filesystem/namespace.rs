@@ -0,0 +1,433 @@
+// Operations on the directory tree: creating and linking files, unlinking
+// them, making and removing directories, and renaming. Each operation keeps
+// link counts, directory counts and the ".." entries consistent so that the
+// image stays clean for e2fsck.
+
+use super::dir::{EXT2_FT_DIR, MAX_NAME_LEN};
+use super::{Device,
+            FsError,
+            Inode,
+            EXT2_LINK_MAX,
+            EXT2_S_IFDIR,
+            EXT2_S_IFREG,
+            EXT2_S_IPERM};
+use crate::block::BlockDevice;
+use crate::global_constants::ROOT_INODE;
+
+impl<B: BlockDevice> Device<B> {
+    // Creates an empty regular file at |path| with the permission bits of
+    // |mode| and returns its inode number
+    pub fn create(&mut self, path: &str, mode: u16) -> Result<u32, FsError> {
+        let (parent, name) = self.entry_location(path)?;
+        self.create_in(parent, name, mode)
+    }
+
+    // Like create, but makes the file as |name| in the directory |parent|
+    pub fn create_in(&mut self,
+                     parent: u32,
+                     name: &str,
+                     mode: u16)
+                     -> Result<u32, FsError> {
+        self.check_new_entry(parent, name)?;
+
+        let inode_number = self.alloc_inode(false, self.inode_group(parent))?;
+        let mut inode = Inode::new();
+        inode.i_mode = EXT2_S_IFREG | (mode & EXT2_S_IPERM);
+        inode.i_links_count = 1;
+        inode.touch(self.timestamp());
+        self.write_inode(inode_number, &inode)?;
+
+        if let Err(e) =
+            self.add_entry(parent, name, inode_number, inode.file_type())
+        {
+            self.free_inode(inode_number, false)?;
+            return Err(e);
+        }
+
+        Ok(inode_number)
+    }
+
+    // Adds |new_path| as another name for the file at |existing_path|
+    pub fn link(&mut self,
+                existing_path: &str,
+                new_path: &str)
+                -> Result<(), FsError> {
+        self.check_writable()?;
+        // Like link(2) on Linux, a symlink gets a new name rather than its
+        // target
+        let inode_number = self.lookup_link(existing_path)?;
+        let (parent, name) = self.entry_location(new_path)?;
+        self.link_in(inode_number, parent, name)
+    }
+
+    // Adds |name| in the directory |parent| as another name for the file
+    // |inode_number|
+    pub fn link_in(&mut self,
+                   inode_number: u32,
+                   parent: u32,
+                   name: &str)
+                   -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut inode = self.load_inode(inode_number)?;
+
+        // Hard links to directories would make the tree a graph
+        if inode.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        if inode.i_links_count >= EXT2_LINK_MAX {
+            return Err(FsError::TooManyLinks);
+        }
+
+        self.check_new_entry(parent, name)?;
+        self.add_entry(parent, name, inode_number, inode.file_type())?;
+
+        inode.i_links_count += 1;
+        inode.i_ctime = self.timestamp();
+        self.write_inode(inode_number, &inode)
+    }
+
+    // Removes the name |path| of a file, freeing the file once no names are
+    // left
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.entry_location(path)?;
+        self.unlink_in(parent, name)
+    }
+
+    // Removes the entry |name| from the directory |parent| like unlink
+    pub fn unlink_in(&mut self,
+                     parent: u32,
+                     name: &str)
+                     -> Result<(), FsError> {
+        self.check_entry(parent, name)?;
+        let inode_number = self.find_entry(self.load_inode(parent)?, name)?;
+        if self.load_inode(inode_number)?.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.remove_entry(parent, name)?;
+        self.touch_directory(parent)?;
+        self.drop_link(inode_number)
+    }
+
+    // Creates an empty directory at |path| with the permission bits of |mode|
+    // and returns its inode number
+    pub fn mkdir(&mut self, path: &str, mode: u16) -> Result<u32, FsError> {
+        let (parent, name) = self.entry_location(path)?;
+        self.mkdir_in(parent, name, mode)
+    }
+
+    // Like mkdir, but makes the directory as |name| in the directory |parent|
+    pub fn mkdir_in(&mut self,
+                    parent: u32,
+                    name: &str,
+                    mode: u16)
+                    -> Result<u32, FsError> {
+        self.check_new_entry(parent, name)?;
+        let mut parent_inode = self.load_inode(parent)?;
+        if parent_inode.i_links_count >= EXT2_LINK_MAX {
+            return Err(FsError::TooManyLinks);
+        }
+
+        let inode_number = self.alloc_inode(true, self.inode_group(parent))?;
+        let mut inode = Inode::new();
+        inode.i_mode = EXT2_S_IFDIR | (mode & EXT2_S_IPERM);
+        // One link from the parent's entry and one from "."
+        inode.i_links_count = 2;
+        inode.touch(self.timestamp());
+        self.write_inode(inode_number, &inode)?;
+
+        // The first block holds "." followed by ".." taking the rest of it
+        let result =
+            self.add_entry(inode_number, ".", inode_number, EXT2_FT_DIR)
+                .and_then(|_| {
+                    self.add_entry(inode_number, "..", parent, EXT2_FT_DIR)
+                })
+                .and_then(|_| {
+                    self.add_entry(parent, name, inode_number, EXT2_FT_DIR)
+                });
+        if let Err(e) = result {
+            self.release_inode(inode_number)?;
+            return Err(e);
+        }
+
+        // The new directory's ".." links back to the parent
+        parent_inode = self.load_inode(parent)?;
+        parent_inode.i_links_count += 1;
+        parent_inode.i_mtime = self.timestamp();
+        parent_inode.i_ctime = parent_inode.i_mtime;
+        self.write_inode(parent, &parent_inode)?;
+
+        Ok(inode_number)
+    }
+
+    // Removes the empty directory at |path|
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.entry_location(path)?;
+        self.rmdir_in(parent, name)
+    }
+
+    // Removes the empty directory |name| from the directory |parent|
+    pub fn rmdir_in(&mut self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_entry(parent, name)?;
+        let inode_number = self.find_entry(self.load_inode(parent)?, name)?;
+        if !self.load_inode(inode_number)?.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        if !self.is_empty_directory(inode_number)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.remove_entry(parent, name)?;
+        self.unlink_directory(parent, inode_number)
+    }
+
+    // Moves the entry at |old_path| to |new_path|, replacing whatever
+    // |new_path| named before. Directories can only replace empty
+    // directories and files can only replace files.
+    pub fn rename(&mut self,
+                  old_path: &str,
+                  new_path: &str)
+                  -> Result<(), FsError> {
+        let (old_parent, old_name) = self.entry_location(old_path)?;
+        let (new_parent, new_name) = self.entry_location(new_path)?;
+        self.rename_in(old_parent, old_name, new_parent, new_name)
+    }
+
+    // Moves the entry |old_name| of the directory |old_parent| to |new_name|
+    // in the directory |new_parent| like rename
+    pub fn rename_in(&mut self,
+                     old_parent: u32,
+                     old_name: &str,
+                     new_parent: u32,
+                     new_name: &str)
+                     -> Result<(), FsError> {
+        self.check_entry(old_parent, old_name)?;
+        let inode_number =
+            self.find_entry(self.load_inode(old_parent)?, old_name)?;
+        let inode = self.load_inode(inode_number)?;
+
+        self.check_entry_name(new_parent, new_name)?;
+        let replaced =
+            match self.find_entry(self.load_inode(new_parent)?, new_name) {
+                Ok(replaced) => Some(replaced),
+                Err(FsError::NotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+        // Renaming a file onto another name for itself does nothing
+        if replaced == Some(inode_number) {
+            return Ok(());
+        }
+
+        if inode.is_directory() {
+            // A directory can't be moved inside itself
+            if self.is_ancestor(inode_number, new_parent)? {
+                return Err(FsError::InvalidArgument);
+            }
+            if new_parent != old_parent && replaced.is_none() {
+                let parent_inode = self.load_inode(new_parent)?;
+                if parent_inode.i_links_count >= EXT2_LINK_MAX {
+                    return Err(FsError::TooManyLinks);
+                }
+            }
+        }
+
+        match replaced {
+            Some(replaced) => {
+                let replaced_is_directory =
+                    self.load_inode(replaced)?.is_directory();
+                if inode.is_directory() && !replaced_is_directory {
+                    return Err(FsError::NotADirectory);
+                }
+                if !inode.is_directory() && replaced_is_directory {
+                    return Err(FsError::IsADirectory);
+                }
+                if replaced_is_directory && !self.is_empty_directory(replaced)?
+                {
+                    return Err(FsError::NotEmpty);
+                }
+
+                self.replace_entry(new_parent,
+                                   new_name,
+                                   inode_number,
+                                   inode.file_type())?;
+                if replaced_is_directory {
+                    self.unlink_directory(new_parent, replaced)?;
+                } else {
+                    self.drop_link(replaced)?;
+                }
+            }
+            None => {
+                self.add_entry(new_parent,
+                               new_name,
+                               inode_number,
+                               inode.file_type())?;
+            }
+        }
+
+        self.remove_entry(old_parent, old_name)?;
+        self.touch_directory(old_parent)?;
+        self.touch_directory(new_parent)?;
+
+        // A directory that changed parents has to point its ".." at the new
+        // one, which moves a link from the old parent to the new parent
+        if inode.is_directory() && new_parent != old_parent {
+            self.replace_entry(inode_number, "..", new_parent, EXT2_FT_DIR)?;
+
+            let mut old_parent_inode = self.load_inode(old_parent)?;
+            remove_link(&mut old_parent_inode)?;
+            self.write_inode(old_parent, &old_parent_inode)?;
+
+            let mut new_parent_inode = self.load_inode(new_parent)?;
+            new_parent_inode.i_links_count += 1;
+            self.write_inode(new_parent, &new_parent_inode)?;
+        }
+
+        let mut inode = self.load_inode(inode_number)?;
+        inode.i_ctime = self.timestamp();
+        self.write_inode(inode_number, &inode)
+    }
+
+    // Splits |path| into the inode number of its parent directory and its
+    // final component, which are about to be changed
+    pub(super) fn entry_location<'a>(&self,
+                                     path: &'a str)
+                                     -> Result<(u32, &'a str), FsError> {
+        self.check_writable()?;
+        crate::vfs::path::entry_location(self, path)
+    }
+
+    // Checks that |name| can be an entry of the directory |parent|
+    fn check_entry_name(&self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        if name.is_empty() ||
+           name.len() > MAX_NAME_LEN ||
+           name == "." ||
+           name == ".." ||
+           name.contains('/')
+        {
+            return Err(FsError::InvalidName);
+        }
+
+        if !self.load_inode(parent)?.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(())
+    }
+
+    // Like check_entry_name, but the entry must not exist yet
+    pub(super) fn check_new_entry(&self,
+                                  parent: u32,
+                                  name: &str)
+                                  -> Result<(), FsError> {
+        self.check_entry_name(parent, name)?;
+        match self.find_entry(self.load_inode(parent)?, name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Like check_entry_name, but the entry must already exist
+    fn check_entry(&self, parent: u32, name: &str) -> Result<(), FsError> {
+        self.check_entry_name(parent, name)?;
+        self.find_entry(self.load_inode(parent)?, name)?;
+        Ok(())
+    }
+
+    // Checks whether the directory |inode_number| holds nothing but "." and
+    // ".."
+    fn is_empty_directory(&self, inode_number: u32) -> Result<bool, FsError> {
+        for entry in self.readdir(inode_number)? {
+            if entry.name() != "." && entry.name() != ".." {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Checks whether the directory |ancestor| is |inode_number| or one of the
+    // directories above it. No path up to the root is longer than the number
+    // of inodes, so a longer one means the ".." entries of a damaged image
+    // go around in a cycle.
+    fn is_ancestor(&self,
+                   ancestor: u32,
+                   inode_number: u32)
+                   -> Result<bool, FsError> {
+        let mut current = inode_number;
+        for _ in 0..self.superblock.s_inodes_count {
+            if current == ancestor {
+                return Ok(true);
+            }
+            if current == ROOT_INODE {
+                return Ok(false);
+            }
+            current = self.find_entry(self.load_inode(current)?, "..")?;
+        }
+        Err(FsError::InvalidInode)
+    }
+
+    // Drops one link to a non-directory inode, freeing it when none are left
+    fn drop_link(&mut self, inode_number: u32) -> Result<(), FsError> {
+        let mut inode = self.load_inode(inode_number)?;
+        remove_link(&mut inode)?;
+        inode.i_ctime = self.timestamp();
+        self.write_inode(inode_number, &inode)?;
+
+        if inode.i_links_count == 0 {
+            self.release_inode(inode_number)?;
+        }
+        Ok(())
+    }
+
+    // Frees a directory whose entry in |parent| has already been removed,
+    // along with the link its ".." held on |parent|
+    fn unlink_directory(&mut self,
+                        parent: u32,
+                        inode_number: u32)
+                        -> Result<(), FsError> {
+        let mut parent_inode = self.load_inode(parent)?;
+        remove_link(&mut parent_inode)?;
+        self.release_inode(inode_number)?;
+
+        parent_inode.i_mtime = self.timestamp();
+        parent_inode.i_ctime = parent_inode.i_mtime;
+        self.write_inode(parent, &parent_inode)
+    }
+
+    // Frees the blocks of an inode that nothing links to anymore, records
+    // when it was deleted, and returns it to the free pool
+    pub(super) fn release_inode(&mut self,
+                                inode_number: u32)
+                                -> Result<(), FsError> {
+        self.truncate(inode_number, 0)?;
+
+        let mut inode = self.load_inode(inode_number)?;
+        inode.i_links_count = 0;
+        inode.i_dtime = self.timestamp();
+        self.write_inode(inode_number, &inode)?;
+
+        self.free_inode(inode_number, inode.is_directory())
+    }
+
+    // Updates the modification and change times of a directory whose entries
+    // changed
+    fn touch_directory(&mut self, inode_number: u32) -> Result<(), FsError> {
+        let mut inode = self.load_inode(inode_number)?;
+        inode.i_mtime = self.timestamp();
+        inode.i_ctime = inode.i_mtime;
+        self.write_inode(inode_number, &inode)
+    }
+}
+
+// Takes one link away from |inode|. A damaged image can have an inode in use
+// that counts no links, which mustn't wrap around to 65535.
+fn remove_link(inode: &mut Inode) -> Result<(), FsError> {
+    inode.i_links_count = match inode.i_links_count.checked_sub(1) {
+        Some(count) => count,
+        None => return Err(FsError::InvalidInode),
+    };
+    Ok(())
+}
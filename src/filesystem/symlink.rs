@@ -0,0 +1,145 @@
+// Symbolic links. Targets shorter than the 60 bytes of i_block are stored
+// right in the inode ("fast" symlinks), and longer ones are kept in a data
+// block like the contents of a regular file ("slow" symlinks).
+
+use super::{Device, FsError, Inode, EXT2_S_IFLNK};
+use crate::block::BlockDevice;
+use crate::global_constants::ROOT_INODE;
+use crate::vfs::path::{self, Namespace};
+
+// Bytes of i_block that can hold a fast symlink's target
+const FAST_SYMLINK_SIZE: usize = 60;
+
+impl<B: BlockDevice> Device<B> {
+    // Creates a symbolic link at |path| pointing to |target| and returns its
+    // inode number
+    pub fn symlink(&mut self,
+                   target: &str,
+                   path: &str)
+                   -> Result<u32, FsError> {
+        let (parent, name) = self.entry_location(path)?;
+        self.symlink_in(target, parent, name)
+    }
+
+    // Like symlink, but makes the link as |name| in the directory |parent|
+    pub fn symlink_in(&mut self,
+                      target: &str,
+                      parent: u32,
+                      name: &str)
+                      -> Result<u32, FsError> {
+        // A slow symlink's target has to fit in its one data block
+        if target.is_empty() || target.len() >= self.block_size as usize {
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_new_entry(parent, name)?;
+
+        let inode_number = self.alloc_inode(false, self.inode_group(parent))?;
+        let mut inode = Inode::new();
+        inode.i_mode = EXT2_S_IFLNK | 0o777;
+        inode.i_links_count = 1;
+        inode.touch(self.timestamp());
+
+        if target.len() < FAST_SYMLINK_SIZE {
+            inline_data_mut(&mut inode)[..target.len()]
+                .copy_from_slice(target.as_bytes());
+            inode.i_size = target.len() as u32;
+            self.write_inode(inode_number, &inode)?;
+        } else {
+            self.write_inode(inode_number, &inode)?;
+            match self.write_at(inode_number, 0, target.as_bytes()) {
+                Ok(count) if count == target.len() => {}
+                // A short write means the disk filled up
+                result => {
+                    self.release_inode(inode_number)?;
+                    return Err(result.err().unwrap_or(FsError::NoSpace));
+                }
+            }
+        }
+
+        let file_type = self.load_inode(inode_number)?.file_type();
+        if let Err(e) = self.add_entry(parent, name, inode_number, file_type) {
+            self.release_inode(inode_number)?;
+            return Err(e);
+        }
+
+        Ok(inode_number)
+    }
+
+    // Copies the target of the symbolic link |inode_number| into |buf| and
+    // returns its length. Like readlink, the target is cut short if |buf| is
+    // too small and isn't nul terminated.
+    pub fn read_link(&self,
+                     inode_number: u32,
+                     buf: &mut [u8])
+                     -> Result<usize, FsError> {
+        let inode = self.load_inode(inode_number)?;
+        if !inode.is_symlink() {
+            return Err(FsError::InvalidArgument);
+        }
+
+        self.read_inode_data(&inode, 0, buf)
+    }
+
+    // Resolves a path like lookup, but if the final component is a symbolic
+    // link, returns the link itself rather than what it points to
+    pub fn lookup_link(&self, path: &str) -> Result<u32, FsError> {
+        path::resolve(self, ROOT_INODE, path, false)
+    }
+
+    // Checks whether |inode| is a symbolic link whose target is kept in
+    // i_block instead of a data block. Extended attributes can take a block
+    // of their own, which doesn't count towards the target.
+    pub(super) fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let attribute_sectors = if inode.i_file_acl != 0 {
+            self.block_size / 512
+        } else {
+            0
+        };
+
+        inode.is_symlink() && inode.i_blocks == attribute_sectors
+    }
+}
+
+// Paths on a Device are walked by the same code as paths through the VFS
+impl<B: BlockDevice> Namespace for Device<B> {
+    type Node = u32;
+
+    fn root_node(&self) -> Result<u32, FsError> {
+        Ok(ROOT_INODE)
+    }
+
+    fn step(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let dir = self.load_inode(dir)?;
+        if !dir.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        self.find_entry(dir, name)
+    }
+
+    fn is_link(&self, node: u32) -> Result<bool, FsError> {
+        Ok(self.load_inode(node)?.is_symlink())
+    }
+
+    fn link_length(&self, node: u32) -> Result<usize, FsError> {
+        Ok(self.load_inode(node)?.i_size as usize)
+    }
+
+    fn link_target(&self, node: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_link(node, buf)
+    }
+}
+
+// Views the i_block array of |inode| as the bytes of a fast symlink's target
+pub(super) fn inline_data(inode: &Inode) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(inode.i_block.as_ptr() as *const u8,
+                                    FAST_SYMLINK_SIZE)
+    }
+}
+
+fn inline_data_mut(inode: &mut Inode) -> &mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(inode.i_block.as_mut_ptr() as *mut u8,
+                                        FAST_SYMLINK_SIZE)
+    }
+}
@@ -0,0 +1,175 @@
+// ext2 as a backend of the VFS. The VFS walks paths itself, so this only
+// maps its single-directory operations onto the ones the driver already has.
+
+use super::dir::{EXT2_FT_BLKDEV,
+                 EXT2_FT_CHRDEV,
+                 EXT2_FT_DIR,
+                 EXT2_FT_FIFO,
+                 EXT2_FT_REG_FILE,
+                 EXT2_FT_SOCK,
+                 EXT2_FT_SYMLINK};
+use super::{Device, FsError, Inode, EXT2_S_IPERM};
+use crate::block::BlockDevice;
+use crate::global_constants::ROOT_INODE;
+use crate::vfs::{DirEntry,
+                 FileOperations,
+                 FileType,
+                 FsStats,
+                 InodeOperations,
+                 Metadata};
+
+impl<B: BlockDevice> InodeOperations for Device<B> {
+    fn root(&self) -> u32 {
+        ROOT_INODE
+    }
+
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError> {
+        Ok(vfs_file_type(self.load_inode(inode)?.file_type()))
+    }
+
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError> {
+        Ok(self.load_inode(inode)?.metadata(inode))
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let inode = self.load_inode(dir)?;
+        if !inode.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        self.find_entry(inode, name)
+    }
+
+    fn read_link(&self, inode: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        Device::read_link(self, inode, buf)
+    }
+
+    fn create(&mut self,
+              dir: u32,
+              name: &str,
+              mode: u16)
+              -> Result<u32, FsError> {
+        self.create_in(dir, name, mode)
+    }
+
+    fn mkdir(&mut self,
+             dir: u32,
+             name: &str,
+             mode: u16)
+             -> Result<u32, FsError> {
+        self.mkdir_in(dir, name, mode)
+    }
+
+    fn symlink(&mut self,
+               dir: u32,
+               name: &str,
+               target: &str)
+               -> Result<u32, FsError> {
+        self.symlink_in(target, dir, name)
+    }
+
+    fn link(&mut self,
+            inode: u32,
+            dir: u32,
+            name: &str)
+            -> Result<(), FsError> {
+        self.link_in(inode, dir, name)
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        self.unlink_in(dir, name)
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        self.rmdir_in(dir, name)
+    }
+
+    fn rename(&mut self,
+              old_dir: u32,
+              old_name: &str,
+              new_dir: u32,
+              new_name: &str)
+              -> Result<(), FsError> {
+        self.rename_in(old_dir, old_name, new_dir, new_name)
+    }
+}
+
+impl<B: BlockDevice> FileOperations for Device<B> {
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError> {
+        self.read_at(inode, offset, buf)
+    }
+
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        self.write_at(inode, offset, buf)
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        Device::truncate(self, inode, size)
+    }
+
+    // Positions are byte offsets of entries within the directory
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError> {
+        let mut entries = self.readdir(dir)?;
+        entries.seek(position);
+
+        Ok(entries.next().map(|entry| {
+                             (DirEntry::new(entry.inode,
+                                            vfs_file_type(entry.file_type),
+                                            entry.name_bytes()),
+                              entries.offset())
+                         }))
+    }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        Ok(FsStats { block_size: self.block_size,
+                     blocks: self.superblock.s_blocks_count,
+                     free_blocks: self.superblock.s_free_blocks_count,
+                     inodes: self.superblock.s_inodes_count,
+                     free_inodes: self.superblock.s_free_inodes_count })
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Device::sync(self)
+    }
+}
+
+impl Inode {
+    // Returns the metadata of this inode, which is numbered |inode_number|
+    pub fn metadata(&self, inode_number: u32) -> Metadata {
+        Metadata { inode: inode_number,
+                   file_type: vfs_file_type(self.file_type()),
+                   permissions: self.i_mode & EXT2_S_IPERM,
+                   uid: self.i_uid,
+                   gid: self.i_gid,
+                   size: self.i_size,
+                   links: self.i_links_count,
+                   atime: self.i_atime,
+                   mtime: self.i_mtime,
+                   ctime: self.i_ctime,
+                   blocks: self.i_blocks }
+    }
+}
+
+// Converts the file type of a directory entry or inode
+fn vfs_file_type(file_type: u8) -> FileType {
+    match file_type {
+        EXT2_FT_REG_FILE => FileType::Regular,
+        EXT2_FT_DIR => FileType::Directory,
+        EXT2_FT_CHRDEV => FileType::CharDevice,
+        EXT2_FT_BLKDEV => FileType::BlockDevice,
+        EXT2_FT_FIFO => FileType::Fifo,
+        EXT2_FT_SOCK => FileType::Socket,
+        EXT2_FT_SYMLINK => FileType::Symlink,
+        _ => FileType::Unknown,
+    }
+}
@@ -20,14 +20,67 @@ pub const MAX_PROC_COUNT: usize = 16;
 // Allocated size for one process
 pub const PROC_ALLOC_SIZE: usize = 1 << 8;
 
+// Stack size for processes running programs loaded from the filesystem, whose
+// compiled code needs more room than the kernel's own small processes
+pub const PROGRAM_STACK_SIZE: usize = 1 << 10;
+
 // Number of CPU registers
 pub const NUM_CPU_REGISTERS: usize = 32;
 
 // Magic number in superblock to check validity
-pub const SUPERBLOCK_MAGIC: u32 = 0xef53;
+pub const SUPERBLOCK_MAGIC: u16 = 0xef53;
 
 // Size of a block descriptor for ext2
 pub const BG_DESC_SIZE: usize = 32;
 
-// Size of an inode for ext2
-pub const INODE_SIZE: usize = 128;
+// Size of an inode for revision 0 of ext2, later revisions store it in the
+// superblock
+pub const GOOD_OLD_INODE_SIZE: u32 = 128;
+
+// First non-reserved inode for revision 0 of ext2
+pub const GOOD_OLD_FIRST_INODE: u32 = 11;
+
+// Inode number of the root directory for ext2
+pub const ROOT_INODE: u32 = 2;
+
+// Number of blocks held by the buffer cache. Looking a name up in a directory
+// touches a 1K directory block (two sectors), the 1K indirect block mapping
+// it in large directories (two more) and the inode table sector of what it
+// finds, and the sixth keeps the superblock or a bitmap around while files
+// are written. Each buffer costs a sector of the heap, so going past what a
+// directory walk uses would only take memory from everything else.
+pub const BUFFER_CACHE_SIZE: usize = 6;
+
+// Max number of block devices that can share the buffer cache
+pub const MAX_CACHED_DEVICES: usize = 4;
+
+// Max number of partitions read from a partition table
+pub const MAX_PARTITIONS: usize = 8;
+
+// Partition the root filesystem is mounted from when the image has a
+// partition table, numbered from 1. With 0, it's the first partition whose
+// type is ROOT_PARTITION_TYPE.
+pub const ROOT_PARTITION: u32 = 0;
+
+// Type GUID of the partition the root filesystem is mounted from, which
+// also matches MBR partitions of the equivalent type
+pub const ROOT_PARTITION_TYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+// Where the first FAT32 partition of the image is mounted, for files moved
+// to and from the board on cards formatted by Windows
+pub const FAT_MOUNT_POINT: &str = "/mnt";
+
+// Max number of filesystems that can be mounted at one time
+pub const MAX_MOUNTS: usize = 8;
+
+// Max number of files open across every process at one time
+pub const MAX_OPEN_FILES: usize = 16;
+
+// Max number of file descriptors each process can have open
+pub const MAX_FDS: usize = 8;
+
+// Max number of files a tmpfs can hold at one time
+pub const MAX_TMPFS_INODES: usize = 64;
+
+// Size of each page of file data a tmpfs allocates
+pub const TMPFS_PAGE_SIZE: usize = 512;
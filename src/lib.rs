@@ -14,15 +14,19 @@
 #![no_mangle]
 #![allow(dead_code, unused_variables)]
 
+mod block;
 mod console;
+mod fat;
 mod filesystem;
 mod global_constants;
+mod loader;
 mod lock;
 mod memman;
 mod scheduler;
 mod sys;
 mod trap;
 mod utils;
+mod vfs;
 
 use console::Console;
 use core::fmt::Write;
@@ -96,6 +100,89 @@ fn print_to_console() -> i32 {
     0
 }
 
+// Mounts the image linked into the kernel as the root of the VFS. If the
+// image has a partition table, the root is the partition chosen by
+// ROOT_PARTITION and ROOT_PARTITION_TYPE.
+fn mount_root() -> Result<(), filesystem::FsError> {
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let image = block::FlashImage::new();
+    let disk = match block::PartitionTable::read(&image)? {
+        Some(table) => {
+            let partition = match root_partition(&table) {
+                Some(partition) => partition,
+                None => return Err(filesystem::FsError::NotFound),
+            };
+            cache.register(block::Partition::new(image, &partition)?)?
+        }
+        None => cache.register(image)?,
+    };
+    let mut root = filesystem::Device::new(disk);
+    root.read_superblock()?;
+    unsafe {
+        (*vfs::VFS).mount("/", root)?;
+    }
+    Ok(())
+}
+
+// Picks the partition of |table| holding the root filesystem
+fn root_partition(table: &block::PartitionTable)
+                  -> Option<block::partition::PartitionInfo> {
+    use global_constants::{ROOT_PARTITION, ROOT_PARTITION_TYPE};
+
+    if ROOT_PARTITION != 0 {
+        return table.by_number(ROOT_PARTITION);
+    }
+    let kind = block::partition::Guid::parse(ROOT_PARTITION_TYPE)?;
+    table.by_type(&kind)
+}
+
+// Mounts the first FAT32 partition of the image linked into the kernel at
+// FAT_MOUNT_POINT. Returns Ok(false) if the image has no such partition.
+fn mount_fat() -> Result<bool, filesystem::FsError> {
+    use block::partition::MICROSOFT_BASIC_DATA;
+
+    let image = block::FlashImage::new();
+    let partition = match block::PartitionTable::read(&image)? {
+        Some(table) => match table.by_type(&MICROSOFT_BASIC_DATA) {
+            Some(partition) => partition,
+            None => return Ok(false),
+        },
+        None => return Ok(false),
+    };
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let disk = cache.register(block::Partition::new(image, &partition)?)?;
+    let mut volume = fat::Fat32::new(disk);
+    volume.read_bpb()?;
+    unsafe {
+        (*vfs::VFS).mount(global_constants::FAT_MOUNT_POINT, volume)?;
+    }
+    Ok(true)
+}
+
+// Lists the partitions of the image linked into the kernel, if it has a
+// partition table
+fn print_partitions() {
+    match block::PartitionTable::read(&block::FlashImage::new()) {
+        Ok(Some(table)) => {
+            println!("{:?} partition table:", table.scheme());
+            for partition in table.partitions() {
+                println!("  {}", partition);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => println!("Couldn't read the partition table: {:?}", e),
+    }
+}
+
+// Mounts a tmpfs as the root and unpacks the linked-in archive into it
+fn mount_initramfs() -> Result<(), vfs::initramfs::ArchiveError> {
+    let root = vfs::tmpfs::TmpFs::new()?;
+    unsafe {
+        (*vfs::VFS).mount("/", root)?;
+        vfs::initramfs::unpack(&mut *vfs::VFS, vfs::initramfs::archive())
+    }
+}
+
 fn echo_from_console() -> i32 {
     println!("Type into the console:");
     loop {
@@ -212,6 +299,21 @@ fn test_memman() -> () {
         let pts = pt as *mut u32;
         *pts = 14;
         assert_eq!(*pts, 14);
+
+        // Splitting a block puts a Descriptor for the free rest where the
+        // block's old contents were, so fill the block with words that read
+        // as taken Descriptors and show the rest is still free afterwards
+        for i in 0..6 {
+            *pts.add(i) = 0x0001_0010;
+        }
+        assert!(MemManager::kfree(pt).is_ok());
+        MemManager::kcoalesce();
+        let before = MemManager::usage();
+        let pt = MemManager::kmalloc(16).unwrap();
+        assert_eq!(p, pt);
+        let after = MemManager::usage();
+        assert_eq!(after.allocations, before.allocations + 1);
+        assert_eq!(after.used, before.used + 20);
         assert!(MemManager::kfree(pt).is_ok());
     }
 }
@@ -292,19 +394,1492 @@ fn test_heapvec() {
 #[cfg(feature = "testing")]
 fn test_scheduler() {}
 
+#[cfg(feature = "testing")]
+fn test_block_devices() {
+    use block::BlockDevice;
+
+    println!("### Testing Block Devices ###");
+    let mut disk = block::RamDisk::new(4).unwrap();
+    assert_eq!(disk.block_count(), 4);
+    assert_eq!(disk.memory_used(), 0);
+
+    // Only the half of the block that isn't zeros takes memory
+    let mut buffer = [0xAAu8; block::SECTOR_SIZE as usize];
+    for byte in buffer[256..].iter_mut() {
+        *byte = 0;
+    }
+    assert!(disk.write_block(3, &buffer).is_ok());
+    assert_eq!(disk.memory_used(), 256);
+    assert_eq!(disk.write_block(4, &buffer), Err(block::BlockError::OutOfRange));
+    assert_eq!(disk.read_block(0, &mut buffer[..16]),
+               Err(block::BlockError::BadBuffer));
+
+    let copy = block::RamDisk::copy_of(&disk).unwrap();
+    assert_eq!(copy.memory_used(), 256);
+    assert!(copy.read_block(3, &mut buffer).is_ok());
+    assert!(buffer[..256].iter().all(|byte| *byte == 0xAA));
+    assert!(buffer[256..].iter().all(|byte| *byte == 0));
+    assert!(copy.read_block(0, &mut buffer).is_ok());
+    assert!(buffer.iter().all(|byte| *byte == 0));
+    assert!(disk.write_block(3, &buffer).is_ok());
+    assert_eq!(disk.memory_used(), 0);
+
+    let mut memory = [0u8; 600];
+    let mut slice = block::SliceDisk::new(&mut memory);
+    assert_eq!(slice.block_count(), 1);
+    for byte in buffer.iter_mut() {
+        *byte = 0xAA;
+    }
+    assert!(slice.write_block(0, &buffer).is_ok());
+    assert_eq!(memory[511], 0xAA);
+    assert_eq!(memory[512], 0);
+
+    let mut flash = block::FlashImage::new();
+    assert!(!flash.is_writable());
+    assert_eq!(flash.write_block(0, &buffer),
+               Err(block::BlockError::ReadOnly));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_partitions() {
+    use block::partition::{Guid, PartitionType, Scheme, LINUX_FILESYSTEM};
+    use block::{BlockDevice, BlockError, Partition, PartitionTable};
+
+    println!("### Testing partition tables ###");
+    let text = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+    assert_eq!(Guid::parse(text), Some(LINUX_FILESYSTEM));
+    assert_eq!(Guid::parse("0FC63DAF-8483-4772-8E79"), None);
+    assert!(PartitionType::Mbr(0x83).matches(&LINUX_FILESYSTEM));
+    assert!(!PartitionType::Mbr(0x0C).matches(&LINUX_FILESYSTEM));
+
+    // An MBR whose second slot holds a Linux partition covering block 1
+    let mut memory = [0u8; 1024];
+    memory[462 + 4] = 0x83;
+    memory[462 + 8] = 1;
+    memory[462 + 12] = 1;
+    memory[510] = 0x55;
+    memory[511] = 0xAA;
+    memory[512] = 0xAB;
+
+    {
+        let disk = block::SliceDisk::new(&mut memory);
+        let table = PartitionTable::read(&disk).unwrap().unwrap();
+        assert_eq!(table.scheme(), Scheme::Mbr);
+        assert_eq!(table.partitions().len(), 1);
+        assert_eq!(table.by_number(1), None);
+        let partition = table.by_type(&LINUX_FILESYSTEM).unwrap();
+        assert_eq!(partition.number, 2);
+
+        let partition = Partition::new(disk, &partition).unwrap();
+        assert_eq!(partition.block_count(), 1);
+        let mut block = [0u8; block::SECTOR_SIZE as usize];
+        assert!(partition.read_block(0, &mut block).is_ok());
+        assert_eq!(block[0], 0xAB);
+        assert_eq!(partition.read_block(1, &mut block),
+                   Err(BlockError::OutOfRange));
+    }
+
+    println!("Rejecting partitions past the end of the disk");
+    memory[462 + 12] = 2;
+    let disk = block::SliceDisk::new(&mut memory);
+    assert_eq!(PartitionTable::read(&disk).map(|table| table.is_some()),
+               Err(BlockError::BadPartitionTable));
+    println!("Success");
+
+    println!("Reading a GPT table and its backup");
+    // A protective MBR, the primary header and entries in blocks 1 and 2,
+    // a Linux partition in block 3 and the backup entries and header in
+    // blocks 4 and 5. The backup lists the partition as number 2 so it's
+    // clear which copy was read.
+    let mut disk = block::RamDisk::new(6).unwrap();
+    write_bytes(&mut disk, 446 + 4, &[0xEE]);
+    write_words(&mut disk, 446 + 8, &[1, 5]);
+    write_bytes(&mut disk, 510, &[0x55, 0xAA]);
+    // The words of a GPT header in |block|, whose entries are in |entries|,
+    // given its own checksum and that of its entries
+    let header = |block: u32, backup: u32, entries: u32, crcs: [u32; 2]| {
+        [u32::from_le_bytes(*b"EFI "), u32::from_le_bytes(*b"PART"),
+         0x0001_0000, 92, crcs[0], 0,
+         // This header's block, the other header's and the usable blocks
+         block, 0, backup, 0, 3, 0, 3, 0,
+         // The disk's GUID
+         0, 0, 0, 0,
+         // Four entries of 128 bytes
+         entries, 0, 4, 128, crcs[1]]
+    };
+    let unique = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    for &(entry, crcs) in [(2 * 512, [0x78C4_8FB3, 0x11AB_D6D2]),
+                           (4 * 512 + 128, [0x91A9_26DE, 0x8EB3_6492])]
+                          .iter()
+    {
+        write_bytes(&mut disk, entry, &LINUX_FILESYSTEM.0);
+        write_bytes(&mut disk, entry + 16, &unique);
+        write_words(&mut disk, entry + 32, &[3, 0, 3, 0]);
+        let (block, backup) = if entry < 4 * 512 { (1, 5) } else { (5, 1) };
+        write_words(&mut disk,
+                    block * 512,
+                    &header(block, backup, entry / 512, crcs));
+    }
+    // Returns the number of the Linux partition on |disk|
+    let number = |disk: &block::RamDisk| -> Result<u32, BlockError> {
+        let table = PartitionTable::read(disk)?.unwrap();
+        assert_eq!(table.scheme(), Scheme::Gpt);
+        let partition = table.by_type(&LINUX_FILESYSTEM).unwrap();
+        assert_eq!((partition.first_block, partition.block_count), (3, 1));
+        Ok(partition.number)
+    };
+    assert_eq!(number(&disk), Ok(1));
+
+    // Changing the disk's GUID breaks the header's checksum
+    write_bytes(&mut disk, 512 + 56, &[0xFF]);
+    assert_eq!(number(&disk), Ok(2));
+    write_bytes(&mut disk, 512 + 56, &[0]);
+    assert_eq!(number(&disk), Ok(1));
+
+    // Naming the partition breaks the checksum of the entries
+    write_bytes(&mut disk, 2 * 512 + 56, b"r");
+    assert_eq!(number(&disk), Ok(2));
+    write_bytes(&mut disk, 4 * 512 + 128 + 56, b"r");
+    assert_eq!(number(&disk), Err(BlockError::BadPartitionTable));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_buffer_cache() {
+    use block::BlockDevice;
+
+    println!("### Testing Buffer Cache ###");
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let blocks = global_constants::BUFFER_CACHE_SIZE as u32 + 2;
+    let mut disk = cache.register(block::RamDisk::new(blocks).unwrap())
+                        .unwrap();
+
+    // Writing more blocks than fit in the cache has to write some back
+    let mut buffer = [0u8; block::SECTOR_SIZE as usize];
+    for block_number in 0..blocks {
+        buffer[0] = block_number as u8 + 1;
+        assert!(disk.write_block(block_number, &buffer).is_ok());
+    }
+    for block_number in 0..blocks {
+        assert!(disk.read_block(block_number, &mut buffer).is_ok());
+        assert_eq!(buffer[0], block_number as u8 + 1);
+    }
+
+    let hits = cache.hits();
+    let misses = cache.misses();
+    assert!(disk.read_block(blocks - 1, &mut buffer).is_ok());
+    assert_eq!(cache.hits(), hits + 1);
+    assert_eq!(cache.misses(), misses);
+    assert!(disk.flush().is_ok());
+    assert_eq!(disk.write_block(blocks, &buffer),
+               Err(block::BlockError::OutOfRange));
+    println!("Success");
+}
+
+// Builds an empty ext2 image on a RAM disk for the tests that write to one.
+// It has a single group of 64 1K blocks and 16 inodes, and holds the root
+// directory and lost+found. The RAM disk only keeps the parts that aren't
+// zeros, which comes to about a kilobyte of the heap.
+#[cfg(feature = "testing")]
+fn ext2_image() -> block::RamDisk {
+    use block::BlockDevice;
+
+    // (sector, offset, value, size) of every field that isn't zero, in order
+    let fields: [(u32, usize, u32, usize); 50] =
+        [// Superblock: 16 inodes and 65 blocks, 5 and 56 of them free
+         (2, 0, 16, 4),
+         (2, 4, 65, 4),
+         (2, 12, 56, 4),
+         (2, 16, 5, 4),
+         (2, 20, 1, 4),
+         (2, 32, 64, 4),
+         (2, 36, 64, 4),
+         (2, 40, 16, 4),
+         (2, 56, 0xEF53, 2),
+         (2, 58, 1, 2),
+         (2, 60, 1, 2),
+         // Group descriptor: bitmaps in blocks 3 and 4, inode table in 5-6
+         (4, 0, 3, 4),
+         (4, 4, 4, 4),
+         (4, 8, 5, 4),
+         (4, 12, 56, 2),
+         (4, 14, 5, 2),
+         (4, 16, 2, 2),
+         // Blocks 1-8 and inodes 1-11 are taken
+         (6, 0, 0xFF, 1),
+         (8, 0, 0x07FF, 2),
+         // Inode 2, the root directory, in block 7
+         (10, 128, 0o40755, 2),
+         (10, 132, 1024, 4),
+         (10, 154, 3, 2),
+         (10, 156, 2, 4),
+         (10, 168, 7, 4),
+         // Inode 11, lost+found, in block 8
+         (12, 256, 0o40700, 2),
+         (12, 260, 1024, 4),
+         (12, 282, 2, 2),
+         (12, 284, 2, 4),
+         (12, 296, 8, 4),
+         // Entries of the root directory
+         (14, 0, 2, 4),
+         (14, 4, 12, 2),
+         (14, 6, 1, 1),
+         (14, 8, u32::from(b'.'), 1),
+         (14, 12, 2, 4),
+         (14, 16, 12, 2),
+         (14, 18, 2, 1),
+         (14, 20, u32::from_le_bytes(*b"..\0\0"), 2),
+         (14, 24, 11, 4),
+         (14, 28, 1000, 2),
+         (14, 30, 10, 1),
+         (14, 32, u32::from_le_bytes(*b"lost"), 4),
+         (14, 36, u32::from_le_bytes(*b"+fou"), 4),
+         (14, 40, u32::from_le_bytes(*b"nd\0\0"), 2),
+         // Entries of lost+found
+         (16, 0, 11, 4),
+         (16, 4, 12, 2),
+         (16, 6, 1, 1),
+         (16, 8, u32::from(b'.'), 1),
+         (16, 12, 2, 4),
+         (16, 16, 1012, 2),
+         (16, 18, 2, 1)];
+
+    let mut disk = block::RamDisk::new(130).unwrap();
+    let mut sector = [0u8; block::SECTOR_SIZE as usize];
+    let mut current = fields[0].0;
+    for &(number, offset, value, size) in fields.iter() {
+        if number != current {
+            disk.write_block(current, &sector).unwrap();
+            sector = [0u8; block::SECTOR_SIZE as usize];
+            current = number;
+        }
+        sector[offset..offset + size].copy_from_slice(&value.to_le_bytes()
+                                                          [..size]);
+    }
+    disk.write_block(current, &sector).unwrap();
+    // The name of lost+found's ".." entry
+    disk.read_block(16, &mut sector).unwrap();
+    sector[20..22].copy_from_slice(b"..");
+    disk.write_block(16, &sector).unwrap();
+    disk
+}
+
+// Returns the byte offset of the inode |number| in ext2_image(), whose inode
+// table starts at block 5 with 128 byte inodes
+#[cfg(feature = "testing")]
+fn ext2_image_inode(number: u32) -> u32 {
+    5 * 1024 + (number - 1) * 128
+}
+
+// Writes |bytes| to |disk| starting at the byte |offset|, for tests that lay
+// out on-disk structures by hand
+#[cfg(feature = "testing")]
+fn write_bytes<D: block::BlockDevice>(disk: &mut D,
+                                     offset: u32,
+                                     bytes: &[u8]) {
+    let mut sector = [0u8; block::SECTOR_SIZE as usize];
+    let mut position = offset;
+    for byte in bytes.iter() {
+        let number = position / block::SECTOR_SIZE;
+        let start = (position % block::SECTOR_SIZE) as usize;
+        if position == offset || start == 0 {
+            disk.read_block(number, &mut sector).unwrap();
+        }
+        sector[start] = *byte;
+        position += 1;
+        if position == offset + bytes.len() as u32 ||
+           position % block::SECTOR_SIZE == 0
+        {
+            disk.write_block(number, &sector).unwrap();
+        }
+    }
+}
+
+// Writes |words| to |disk| as little-endian u32s starting at the byte
+// |offset|
+#[cfg(feature = "testing")]
+fn write_words<D: block::BlockDevice>(disk: &mut D,
+                                     offset: u32,
+                                     words: &[u32]) {
+    for (i, word) in words.iter().enumerate() {
+        write_bytes(disk, offset + 4 * i as u32, &word.to_le_bytes());
+    }
+}
+
 #[cfg(feature = "testing")]
 fn test_filesystem() {
+    use vfs::FileOperations;
+
     println!("### Testing Filesystem ###");
     println!("Printing Superblock Information");
-    let mut dev = filesystem::Device::new();
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let disk = cache.register(block::FlashImage::new()).unwrap();
+    let mut dev = filesystem::Device::new(disk);
     match dev.read_superblock() {
         Ok(()) => println!("Successfully read Superblock"),
-        Err(()) => println!("ERROR: Cannot read Superblock"),
+        Err(e) => println!("ERROR: Cannot read Superblock: {:?}", e),
     }
 
     dev.superblock.print();
+    assert!(dev.group_descriptor(0).is_ok());
 
     dev.read_inode(12);
+    println!();
+
+    println!("Looking up paths from the root directory");
+    assert_eq!(dev.lookup("/"), Ok(global_constants::ROOT_INODE));
+    assert_eq!(dev.lookup("/lost+found"), Ok(11));
+    assert_eq!(dev.lookup("//lost+found/"), Ok(11));
+    assert_eq!(dev.lookup("/lost+found/.."),
+               Ok(global_constants::ROOT_INODE));
+    assert_eq!(dev.lookup("/does_not_exist"),
+               Err(filesystem::FsError::NotFound));
+    println!("Success");
+
+    println!("Listing the root directory");
+    let mut found_lost_and_found = false;
+    for entry in dev.readdir(global_constants::ROOT_INODE).unwrap() {
+        println!("  {:>6} {}", entry.inode, entry.name());
+        if entry.name() == "lost+found" {
+            found_lost_and_found = true;
+        }
+    }
+    assert!(found_lost_and_found);
+    println!("Success");
+
+    println!("Reading inode 12 into a buffer");
+    let mut whole = [0u8; 16];
+    let mut halves = [0u8; 16];
+    let count = dev.read_at(12, 0, &mut whole).unwrap();
+    let first = dev.read_at(12, 0, &mut halves[..8]).unwrap();
+    let second = dev.read_at(12, first as u32, &mut halves[first..]).unwrap();
+    assert_eq!(first + second, count);
+    assert_eq!(whole, halves);
+    assert_eq!(dev.read_at(12, 0xFFFF_FFF0, &mut whole), Ok(0));
+    println!("Success");
+
+    println!("Writing to an image on a RAM disk");
+    let mut dev = filesystem::Device::new(ext2_image());
+    dev.read_superblock().unwrap();
+    let free = dev.statfs().unwrap().free_blocks;
+    let block = dev.alloc_block(0).unwrap();
+    assert!(dev.free_block(block).is_ok());
+    assert_eq!(dev.free_block(block),
+               Err(filesystem::FsError::InvalidBlock));
+
+    let file = dev.create("/file", 0o644).unwrap();
+    assert_eq!(dev.write_at(file, 0, b"advos"), Ok(5));
+    let mut buffer = [0u8; 5];
+    assert_eq!(dev.read_at(file, 0, &mut buffer), Ok(5));
+    assert_eq!(&buffer, b"advos");
+
+    // The 13th block of the file is the first behind an indirect block
+    assert_eq!(dev.write_at(file, 12 * 1024, b"!"), Ok(1));
+    assert_eq!(dev.statfs().unwrap().free_blocks, free - 3);
+    assert_eq!(dev.read_at(file, 12 * 1024 - 2, &mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"\0\0!");
+    assert!(dev.truncate(file, 5).is_ok());
+    assert_eq!(dev.statfs().unwrap().free_blocks, free - 1);
+    dev.unlink("/file").unwrap();
+    assert_eq!(dev.statfs().unwrap().free_blocks, free);
+    println!("Success");
+
+    println!("Creating, renaming and removing entries");
+    let dir = dev.mkdir("/test", 0o755).unwrap();
+    let file = dev.create("/test/file", 0o644).unwrap();
+    assert_eq!(dev.create("/test/file", 0o644),
+               Err(filesystem::FsError::AlreadyExists));
+    dev.link("/test/file", "/test/link").unwrap();
+    dev.rename("/test/file", "/test/renamed").unwrap();
+    assert_eq!(dev.lookup("/test/renamed"), Ok(file));
+    assert_eq!(dev.lookup("/test/file"), Err(filesystem::FsError::NotFound));
+    assert_eq!(dev.rmdir("/test"), Err(filesystem::FsError::NotEmpty));
+    dev.unlink("/test/renamed").unwrap();
+    dev.unlink("/test/link").unwrap();
+    dev.rmdir("/test").unwrap();
+    assert_eq!(dev.lookup("/test"), Err(filesystem::FsError::NotFound));
+    println!("Success");
+
+    println!("Following symbolic links");
+    let link = dev.symlink("lost+found", "/found").unwrap();
+    assert_eq!(dev.lookup("/found"), Ok(11));
+    assert_eq!(dev.lookup_link("/found"), Ok(link));
+    let mut target = [0u8; 16];
+    let count = dev.read_link(link, &mut target).unwrap();
+    assert_eq!(&target[..count], b"lost+found");
+    dev.symlink("/loop", "/loop").unwrap();
+    assert_eq!(dev.lookup("/loop"), Err(filesystem::FsError::TooManySymlinks));
+    dev.unlink("/found").unwrap();
+    dev.unlink("/loop").unwrap();
+    assert_eq!(dev.statfs().unwrap().free_blocks, free);
+    println!("Success");
+
+    println!("Refusing to change a damaged image");
+    let file = dev.create("/file", 0o644).unwrap();
+    let parent = dev.mkdir("/parent", 0o755).unwrap();
+    let child = dev.mkdir("/parent/child", 0o755).unwrap();
+    let mut disk = dev.into_disk();
+    // The file counts no links, and the ".." of /parent leads back down to
+    // its child, so walking up from the child never reaches the root
+    write_words(&mut disk, ext2_image_inode(file) + 24, &[0]);
+    let block = {
+        let mut words = [0u8; 4];
+        block::read_at(&disk, ext2_image_inode(parent) as u64 + 40, &mut words)
+            .unwrap();
+        u32::from_le_bytes(words)
+    };
+    write_words(&mut disk, block * 1024 + 12, &[child]);
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    assert_eq!(dev.lookup("/parent/.."), Ok(child));
+    assert_eq!(dev.unlink("/file"), Err(filesystem::FsError::InvalidInode));
+    assert_eq!(dev.rename("/lost+found", "/parent/child/lost+found"),
+               Err(filesystem::FsError::InvalidInode));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_extents() {
+    use vfs::FileOperations;
+
+    println!("### Testing Extent Trees ###");
+    let mut disk = ext2_image();
+    // Words of a node header, and of an extent or index entry
+    let header = |entries: u32, depth: u32| [0xF30A | entries << 16,
+                                              4 | depth << 16,
+                                              0];
+    let extents_fl = 0x0008_0000;
+
+    // Blocks 20-23 start with 1-4 so reads show which one they came from
+    for i in 0..4 {
+        write_words(&mut disk, (20 + i) * 1024, &[1 + i]);
+    }
+
+    // Inode 12 keeps its only leaf in i_block: blocks 0-1 of the file are in
+    // blocks 20-21, 2-3 are a hole, 4 is unwritten and 5 is block 23
+    write_words(&mut disk, ext2_image_inode(12), &[0o100644, 6 * 1024]);
+    write_words(&mut disk, ext2_image_inode(12) + 32, &[extents_fl]);
+    write_words(&mut disk, ext2_image_inode(12) + 40, &header(3, 0));
+    write_words(&mut disk,
+                ext2_image_inode(12) + 52,
+                &[0, 2, 20, 4, 32768 + 1, 22, 5, 1, 23]);
+
+    // Inode 13 has two leaves under the root: block 0 of the file is in
+    // block 20 and block 100 is in block 23, with a hole in between
+    write_words(&mut disk, ext2_image_inode(13), &[0o100644, 101 * 1024]);
+    write_words(&mut disk, ext2_image_inode(13) + 32, &[extents_fl]);
+    write_words(&mut disk, ext2_image_inode(13) + 40, &header(2, 1));
+    write_words(&mut disk, ext2_image_inode(13) + 52, &[0, 30, 0, 100, 31, 0]);
+    write_words(&mut disk, 30 * 1024, &header(1, 0));
+    write_words(&mut disk, 30 * 1024 + 12, &[0, 1, 20]);
+    write_words(&mut disk, 31 * 1024, &header(1, 0));
+    write_words(&mut disk, 31 * 1024 + 12, &[100, 1, 23]);
+
+    // Inode 14 has an index node claiming to be a leaf, which reads as holes
+    write_words(&mut disk, ext2_image_inode(14), &[0o100644, 1024]);
+    write_words(&mut disk, ext2_image_inode(14) + 32, &[extents_fl]);
+    write_words(&mut disk, ext2_image_inode(14) + 40, &header(1, 1));
+    write_words(&mut disk, ext2_image_inode(14) + 52, &[0, 32, 0]);
+    write_words(&mut disk, 32 * 1024, &header(1, 1));
+    write_words(&mut disk, 32 * 1024 + 12, &[0, 30, 0]);
+
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    let mut byte = [0u8; 1];
+
+    println!("Reading extents in the root node");
+    for (block, expected) in [1, 2, 0, 0, 0, 4].iter().enumerate() {
+        assert_eq!(dev.read(12, block as u32 * 1024, &mut byte), Ok(1));
+        assert_eq!(byte[0], *expected);
+    }
+    println!("Success");
+
+    println!("Reading extents through an index node");
+    for &(block, expected) in [(0, 1), (50, 0), (99, 0), (100, 4)].iter() {
+        assert_eq!(dev.read(13, block * 1024, &mut byte), Ok(1));
+        assert_eq!(byte[0], expected);
+    }
+    assert_eq!(dev.read(14, 0, &mut byte), Ok(1));
+    assert_eq!(byte[0], 0);
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_htree() {
+    use filesystem::htree::hash_name;
+    use filesystem::FsError;
+
+    println!("### Testing Hashed Directory Indexes ###");
+    println!("Hashing names");
+    // Hashes of each version as computed by e2fsprogs
+    let name = b"caf\xE9";
+    let zero = [0; 4];
+    let expected = [0x65F2_3BCE,
+                    0x9BE4_A372,
+                    0x84B3_A194,
+                    0x7C38_49D0,
+                    0xAB40_8964,
+                    0xE665_CC26];
+    for (version, hash) in expected.iter().enumerate() {
+        assert_eq!(hash_name(version as u8, name, &zero), Some(*hash));
+    }
+    assert_eq!(hash_name(1, &[b'a'; 48], &zero), Some(0x1C65_09D8));
+    let seed = [0x3322_1100, 0x7766_5544, 0xBBAA_9988, 0xFFEE_DDCC];
+    assert_eq!(hash_name(2, b"advos", &seed), Some(0x10B3_FF70));
+    assert_eq!(hash_name(6, name, &zero), None);
+    println!("Success");
+
+    println!("Looking up names through an index");
+    let mut disk = ext2_image();
+    // Make the image revision 1 with the dir_index feature
+    write_words(&mut disk, 1024 + 76, &[1, 0, 11, 128, 0x20]);
+    // Shorten lost+found's entry to make room for "indexed" in the root
+    write_bytes(&mut disk, 7 * 1024 + 28, &20u16.to_le_bytes());
+    write_words(&mut disk, 7 * 1024 + 44, &[12, 980 | 7 << 16]);
+    write_bytes(&mut disk, 7 * 1024 + 52, b"indexed");
+
+    // Inode 12 is an indexed directory of three blocks: the root of the index
+    // in block 40, and leaves in blocks 41 and 42
+    let inode = 5 * 1024 + 11 * 128;
+    write_words(&mut disk, inode, &[0o40755, 3 * 1024]);
+    write_words(&mut disk, inode + 24, &[2 << 16 | 2, 6, 0x1000]);
+    write_words(&mut disk, inode + 40, &[40, 41, 42]);
+
+    // "." and "..", then the root info for half-MD4 and two entries, with
+    // hashes from 0x60000000 up in the second leaf
+    write_words(&mut disk, 40 * 1024, &[12, 12 | 1 << 16, 0x2E]);
+    write_words(&mut disk, 40 * 1024 + 12, &[2, 1012 | 2 << 16, 0x2E2E]);
+    write_words(&mut disk,
+                40 * 1024 + 24,
+                &[0, 1 | 8 << 8, 124 | 2 << 16, 1, 0x6000_0000, 2]);
+
+    // "misplaced" hashes to 0x908E2342, so it's in the wrong leaf
+    let leaves: [(u32, u32, u32, &[u8]); 5] =
+        [(41 * 1024, 2, 16, b"advos"),
+         (41 * 1024 + 16, 11, 20, b"lost+found"),
+         (41 * 1024 + 36, 2, 988, b"misplaced"),
+         (42 * 1024, 2, 16, b"kernel"),
+         (42 * 1024 + 16, 11, 1008, b"readme")];
+    for &(offset, number, length, name) in leaves.iter() {
+        let name_length = name.len() as u32;
+        write_words(&mut disk, offset, &[number, length | name_length << 16]);
+        write_bytes(&mut disk, offset + 8, name);
+    }
+
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    assert_eq!(dev.lookup("/indexed/advos"), Ok(2));
+    assert_eq!(dev.lookup("/indexed/lost+found"), Ok(11));
+    assert_eq!(dev.lookup("/indexed/kernel"), Ok(2));
+    assert_eq!(dev.lookup("/indexed/readme"), Ok(11));
+    assert_eq!(dev.lookup("/indexed/.."), Ok(2));
+    // Only the leaf the index points to is searched
+    assert_eq!(dev.lookup("/indexed/misplaced"), Err(FsError::NotFound));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_vfs() {
+    use filesystem::FsError;
+
+    println!("### Testing VFS ###");
+    let allocations = MemManager::usage().allocations;
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let disk = cache.register(block::FlashImage::new()).unwrap();
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.lookup("/"), Err(FsError::NotFound));
+    assert_eq!(vfs.mount("/", dev), Ok(0));
+    let root = vfs.root().unwrap();
+    assert_eq!(vfs.lookup("/lost+found"),
+               Ok(vfs::VNode { mount: 0,
+                               inode: 11 }));
+    assert_eq!(vfs.lookup("/lost+found/.."), Ok(root));
+    println!("Success");
+
+    println!("Mounting a RAM disk on /lost+found");
+    let mut ram = filesystem::Device::new(ext2_image());
+    ram.read_superblock().unwrap();
+    assert_eq!(vfs.mount("/lost+found", ram), Ok(1));
+    let ram_root = vfs::VNode { mount: 1,
+                                inode: global_constants::ROOT_INODE };
+    assert_eq!(vfs.lookup("/lost+found"), Ok(ram_root));
+    assert_eq!(vfs.lookup("/lost+found/lost+found"),
+               Ok(vfs::VNode { mount: 1,
+                               inode: 11 }));
+    assert_eq!(vfs.lookup("/lost+found/.."), Ok(root));
+    assert_eq!(vfs.rmdir("/lost+found"), Err(FsError::Busy));
+
+    let file = vfs.create("/lost+found/file", 0o644).unwrap();
+    assert_eq!(file.mount, 1);
+    assert_eq!(vfs.link("/lost+found/file", "/file"),
+               Err(FsError::CrossDevice));
+    vfs.unlink("/lost+found/file").unwrap();
+    println!("Success");
+
+    println!("Following symbolic links across mounts");
+    vfs.symlink("lost+found", "/lost+found/relative").unwrap();
+    vfs.symlink("/lost+found", "/lost+found/absolute").unwrap();
+    vfs.symlink("../lost+found/..", "/lost+found/up").unwrap();
+    vfs.symlink("loop", "/lost+found/loop").unwrap();
+    assert_eq!(vfs.lookup("/lost+found/relative"),
+               Ok(vfs::VNode { mount: 1,
+                               inode: 11 }));
+    assert_eq!(vfs.lookup("/lost+found/absolute/relative/.."),
+               Ok(ram_root));
+    assert_eq!(vfs.lookup("/lost+found/up"), Ok(root));
+    assert_eq!(vfs.lookup("/lost+found/loop"), Err(FsError::TooManySymlinks));
+    let link = vfs.lookup_link("/lost+found/up").unwrap();
+    assert_eq!(vfs.file_type(link), Ok(vfs::FileType::Symlink));
+    println!("Success");
+
+    println!("Unmounting");
+    assert_eq!(vfs.unmount("/"), Err(FsError::Busy));
+    assert!(vfs.unmount("/lost+found").is_ok());
+    assert_eq!(vfs.lookup("/lost+found"),
+               Ok(vfs::VNode { mount: 0,
+                               inode: 11 }));
+
+    // Dropping the VFS unmounts the root and gives its disk back to the
+    // buffer cache
+    drop(vfs);
+    assert_eq!(MemManager::usage().allocations, allocations);
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_file_descriptors() {
+    use filesystem::FsError;
+    use vfs::file::{O_APPEND,
+                    O_CREAT,
+                    O_RDONLY,
+                    O_RDWR,
+                    O_TRUNC,
+                    O_WRONLY,
+                    SEEK_CUR,
+                    SEEK_END,
+                    SEEK_SET};
+
+    println!("### Testing File Descriptors ###");
+    let mut files = vfs::file::FdTable::new();
+    files.open_console().unwrap();
+    assert_eq!(files.open_console(), Err(FsError::AlreadyExists));
+    assert_eq!(files.write(1, b"Writing to fd 1\r\n"), Ok(17));
+    assert_eq!(files.dup(2), Ok(3));
+    assert_eq!(files.lseek(3, 0, SEEK_SET), Err(FsError::IllegalSeek));
+    files.close(3).unwrap();
+    assert_eq!(files.close(3), Err(FsError::BadDescriptor));
+    assert_eq!(files.write(7, b""), Err(FsError::BadDescriptor));
+    println!("Success");
+
+    let vfs = vfs::vfs().unwrap();
+
+    println!("Opening files on the root filesystem");
+    let fd = files.open("/lost+found", O_RDONLY, 0).unwrap();
+    assert_eq!(fd, 3);
+    let mut buffer = [0u8; 8];
+    assert_eq!(files.read(fd, &mut buffer), Err(FsError::IsADirectory));
+    assert_eq!(files.write(fd, b"x"), Err(FsError::BadDescriptor));
+    files.close(fd).unwrap();
+    assert_eq!(files.open("/lost+found", O_RDWR, 0),
+               Err(FsError::IsADirectory));
+    assert_eq!(files.open("/does_not_exist", O_RDONLY, 0),
+               Err(FsError::NotFound));
+    assert_eq!(files.open("/does_not_exist", O_WRONLY | O_CREAT, 0o644),
+               Err(FsError::ReadOnly));
+    println!("Success");
+
+    println!("Reading and writing through a RAM disk on /lost+found");
+    let mut dev = filesystem::Device::new(ext2_image());
+    dev.read_superblock().unwrap();
+    vfs.mount("/lost+found", dev).unwrap();
+
+    // With every open file of the system taken, nothing gets created
+    let mut others = [vfs::file::FdTable::new(),
+                      vfs::file::FdTable::new(),
+                      vfs::file::FdTable::new()];
+    for table in others.iter_mut() {
+        while table.open("/lost+found", O_RDONLY, 0).is_ok() {}
+    }
+    assert_eq!(files.open("/lost+found/notes", O_RDWR | O_CREAT, 0o644),
+               Err(FsError::TooManyOpenFiles));
+    assert_eq!(vfs.lookup("/lost+found/notes"), Err(FsError::NotFound));
+    for table in others.iter_mut() {
+        table.close_all();
+    }
+
+    let fd = files.open("/lost+found/notes", O_RDWR | O_CREAT, 0o644)
+                  .unwrap();
+    assert_eq!(files.write(fd, b"hello"), Ok(5));
+    assert_eq!(files.lseek(fd, 0, SEEK_CUR), Ok(5));
+    assert_eq!(files.lseek(fd, -5, SEEK_END), Ok(0));
+    assert_eq!(files.lseek(fd, -1, SEEK_SET), Err(FsError::InvalidArgument));
+
+    // Duplicates share the offset of the descriptor they were made from
+    let dup = files.dup(fd).unwrap();
+    assert_eq!(files.read(dup, &mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(files.read(fd, &mut buffer), Ok(0));
+
+    let append = files.open("/lost+found/notes", O_WRONLY | O_APPEND, 0)
+                      .unwrap();
+    assert_eq!(files.lseek(append, 0, SEEK_SET), Ok(0));
+    assert_eq!(files.write(append, b"!"), Ok(1));
+    assert_eq!(files.lseek(fd, 0, SEEK_END), Ok(6));
+    assert_eq!(files.read(append, &mut buffer), Err(FsError::BadDescriptor));
+
+    let truncated = files.open("/lost+found/notes", O_RDWR | O_TRUNC, 0)
+                         .unwrap();
+    assert_eq!(files.lseek(truncated, 0, SEEK_END), Ok(0));
+
+    // The RAM disk stays mounted while files on it are open
+    assert_eq!(vfs.unmount("/lost+found"), Err(FsError::Busy));
+    files.close_all();
+    assert_eq!(files.write(1, b"x"), Err(FsError::BadDescriptor));
+    vfs.unlink("/lost+found/notes").unwrap();
+    vfs.unmount("/lost+found").unwrap();
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_stat() {
+    use filesystem::FsError;
+
+    println!("### Testing stat ###");
+    let mut files = vfs::file::FdTable::new();
+    files.open_console().unwrap();
+    assert_eq!(files.stat(1).map(|metadata| metadata.file_type),
+               Ok(vfs::FileType::CharDevice));
+    assert_eq!(files.stat(5), Err(FsError::BadDescriptor));
+    println!("Success");
+
+    let vfs = vfs::vfs().unwrap();
+    let root = vfs.root().unwrap();
+
+    println!("Reading metadata from the root filesystem");
+    let metadata = vfs.stat("/").unwrap();
+    assert!(metadata.is_dir());
+    assert_eq!(metadata.inode, global_constants::ROOT_INODE);
+    // ".", ".." and the ".." of lost+found
+    assert!(metadata.links >= 3);
+    assert_eq!(vfs.stat("/lost+found/.."), Ok(metadata));
+    assert_eq!(vfs.stat("/does_not_exist"), Err(FsError::NotFound));
+
+    let fd = files.open("/lost+found", vfs::file::O_RDONLY, 0).unwrap();
+    assert_eq!(files.stat(fd), vfs.stat("/lost+found"));
+    files.close_all();
+    println!("Success");
+
+    println!("Listing the root directory like ls -l");
+    let mut position = 0;
+    while let Some((entry, next)) = vfs.read_dir(root, position).unwrap() {
+        let node = vfs::VNode { mount: root.mount,
+                                inode: entry.inode };
+        println!("  {} {}", vfs.metadata(node).unwrap(), entry.name());
+        position = next;
+    }
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_syscalls() {
+    use sys::file;
+    use vfs::file::{O_CREAT, O_RDWR, SEEK_END, SEEK_SET};
+
+    println!("### Testing system calls ###");
+    // The boot process doesn't start with the console open
+    let files = unsafe { &mut (*GLOBAL_SCHED).get_current_proc().files };
+    files.open_console().unwrap();
+    let message = b"Writing to fd 1 with an ecall\r\n";
+    assert_eq!(file::write(1, message), message.len() as i32);
+    assert_eq!(file::write(7, message), -9);
+    println!("Success");
+
+    println!("Reading a file in /tmp back through ecalls");
+    let fd = file::open("/tmp/syscalls", O_RDWR | O_CREAT, 0o644);
+    assert_eq!(fd, 3);
+    let fd = fd as u32;
+    assert_eq!(file::write(fd, b"hello"), 5);
+    assert_eq!(file::lseek(fd, 0, SEEK_SET), 0);
+    let mut buffer = [0u8; 8];
+    assert_eq!(file::read(fd, &mut buffer), 5);
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(file::lseek(fd, -2, SEEK_END), 3);
+
+    // Duplicates share the offset of the descriptor they were made from
+    let dup = file::dup(fd);
+    assert_eq!(dup, 4);
+    let dup = dup as u32;
+    assert_eq!(file::read(dup, &mut buffer), 2);
+    assert_eq!(&buffer[..2], b"lo");
+
+    let vfs = vfs::vfs().unwrap();
+    let mut metadata = vfs.stat("/").unwrap();
+    assert_eq!(file::fstat(dup, &mut metadata), 0);
+    assert_eq!(metadata.size, 5);
+    assert_eq!(vfs.stat("/tmp/syscalls"), Ok(metadata));
+    assert_eq!(file::stat("/tmp/syscalls", &mut metadata), 0);
+    assert_eq!(vfs.stat("/tmp/syscalls"), Ok(metadata));
+    assert_eq!(file::stat("/tmp/missing", &mut metadata), -2);
+    assert_eq!(file::open("/tmp/missing", O_RDWR, 0), -2);
+
+    assert_eq!(file::close(dup), 0);
+    assert_eq!(file::close(dup), -9);
+    assert_eq!(file::close(fd), 0);
+    assert_eq!(sys::spawn::spawn("/tmp/missing"), -2);
+    files.close_all();
+    vfs.unlink("/tmp/syscalls").unwrap();
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_fsck() {
+    use filesystem::fsck::Problem;
+
+    println!("### Testing fsck ###");
+    let mut report = |problem: Problem, _: bool| {
+        println!("  {}", problem);
+    };
+
+    println!("Refusing to repair the flash image");
+    let cache = unsafe { &mut *block::cache::BUFFER_CACHE };
+    let disk = cache.register(block::FlashImage::new()).unwrap();
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    assert_eq!(dev.fsck(true, &mut report),
+               Err(filesystem::FsError::ReadOnly));
+    drop(dev);
+    println!("Success");
+
+    println!("Checking a clean image");
+    let mut dev = filesystem::Device::new(ext2_image());
+    dev.read_superblock().unwrap();
+    assert_eq!(dev.fsck(false, &mut report).map(|s| s.problems), Ok(0));
+    println!("Success");
+
+    println!("Repairing a leaked block and inode");
+    // Allocating without using them leaves both marked in use
+    let block = dev.alloc_block(0).unwrap();
+    let inode = dev.alloc_inode(false, 0).unwrap();
+    let expected = [Problem::BlockBitmap { block: block,
+                                           marked: true },
+                    Problem::InodeBitmap { inode: inode,
+                                           marked: true }];
+    let mut seen = 0;
+    let summary = dev.fsck(true, &mut |problem, repaired| {
+                         assert!(repaired);
+                         if expected.contains(&problem) {
+                             seen += 1;
+                         }
+                     })
+                     .unwrap();
+    assert_eq!(summary.problems, summary.repaired);
+    assert_eq!(seen, expected.len());
+    assert_eq!(dev.fsck(false, &mut report).map(|s| s.problems), Ok(0));
+    println!("Success");
+
+    println!("Repairing a damaged image");
+    let mut disk = ext2_image();
+    // lost+found's entry in the root directory ends early, and the empty
+    // entry after it has a record length too short for its header
+    write_words(&mut disk, 7 * 1024 + 28, &[20 | 10 << 16]);
+    write_words(&mut disk, 7 * 1024 + 44, &[0, 3]);
+    // lost+found claims 5 links instead of 2
+    write_words(&mut disk, ext2_image_inode(11) + 24, &[5 << 16]);
+    // Inode 12 is a file in use that no directory names. The group
+    // descriptor has it counted, but is 6 blocks short.
+    write_words(&mut disk, ext2_image_inode(12), &[0o100644]);
+    write_words(&mut disk, ext2_image_inode(12) + 24, &[1 << 16]);
+    write_words(&mut disk, 4 * 1024, &[0x0FFF]);
+    write_words(&mut disk, 1024 + 16, &[4]);
+    write_words(&mut disk, 2048 + 12, &[50 | 4 << 16]);
+
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    let expected = [(Problem::BadDirectoryEntry { directory: 2,
+                                                  block: 7,
+                                                  offset: 44 },
+                     true),
+                    (Problem::LinkCount { inode: 11,
+                                          on_disk: 5,
+                                          counted: 2 },
+                     true),
+                    (Problem::GroupFreeBlocks { group: 0,
+                                                on_disk: 50,
+                                                counted: 56 },
+                     true),
+                    // Orphans are only reported, never reattached
+                    (Problem::UnattachedInode { inode: 12 }, false)];
+    let mut seen = 0;
+    let summary = dev.fsck(true, &mut |problem, repaired| {
+                         assert!(expected.contains(&(problem, repaired)));
+                         seen += 1;
+                     })
+                     .unwrap();
+    assert_eq!(seen, expected.len());
+    assert_eq!(summary.problems, 4);
+    assert_eq!(summary.repaired, 3);
+
+    // The lost+found entry got back the rest of the block
+    assert_eq!(dev.lookup("/lost+found"), Ok(11));
+
+    // What's left is the orphan, until it's given a name the way e2fsck
+    // would in lost+found. Linking it counts one link too many, which the
+    // next repair fixes.
+    let summary = dev.fsck(false, &mut |problem, _| {
+                         assert_eq!(problem,
+                                    Problem::UnattachedInode { inode: 12 })
+                     })
+                     .unwrap();
+    assert_eq!(summary.problems, 1);
+    dev.link_in(12, 11, "#12").unwrap();
+    let summary = dev.fsck(true, &mut |problem, repaired| {
+                         assert!(repaired);
+                         assert_eq!(problem,
+                                    Problem::LinkCount { inode: 12,
+                                                         on_disk: 2,
+                                                         counted: 1 })
+                     })
+                     .unwrap();
+    assert_eq!(summary.problems, 1);
+    assert_eq!(dev.fsck(false, &mut report).map(|s| s.problems), Ok(0));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_journal() {
+    println!("### Testing journal replay ###");
+    println!("Replaying committed, revoked and uncommitted blocks");
+    let mut disk = ext2_image();
+    // Everything in the journal is big-endian
+    let write_be = |disk: &mut block::RamDisk, offset: u32, words: &[u32]| {
+        for (i, word) in words.iter().enumerate() {
+            write_bytes(disk, offset + 4 * i as u32, &word.to_be_bytes());
+        }
+    };
+    let magic = 0xC03B_3998;
+    let block = |number: u32| number * 1024;
+
+    // A revision 1 image with the journal in inode 12 that needs recovery
+    write_words(&mut disk, 1024 + 76, &[1, 0, 11, 128, 0x4, 0x4]);
+    write_words(&mut disk, 1024 + 224, &[12]);
+    let inode = ext2_image_inode(12);
+    write_words(&mut disk, inode, &[0o100600, 10 * 1024]);
+    write_words(&mut disk, inode + 24, &[1 << 16, 20]);
+    write_words(&mut disk,
+                inode + 40,
+                &[20, 21, 22, 23, 24, 25, 26, 27, 28, 29]);
+
+    // The journal is blocks 20-29. Its log starts at the second of them
+    // with transaction 1.
+    write_be(&mut disk, block(20), &[magic, 4, 0, 1024, 10, 1, 1, 1]);
+    // Transaction 1 writes blocks 40 and 41. Block 40 starts with the magic
+    // number, so its copy is escaped.
+    write_be(&mut disk, block(21), &[magic, 1, 1, 40, 0x1]);
+    write_be(&mut disk, block(21) + 36, &[41, 0xA]);
+    write_bytes(&mut disk, block(22) + 4, b"committed");
+    write_bytes(&mut disk, block(23), b"revoked");
+    write_be(&mut disk, block(24), &[magic, 2, 1]);
+    // Transaction 2 revokes block 41
+    write_be(&mut disk, block(25), &[magic, 5, 2, 20, 41]);
+    write_be(&mut disk, block(26), &[magic, 2, 2]);
+    // Transaction 3 writes block 42 but never commits
+    write_be(&mut disk, block(27), &[magic, 1, 3, 42, 0xA]);
+    write_bytes(&mut disk, block(28), b"uncommitted");
+
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    let disk = dev.into_disk();
+    let read = |position: u32| {
+        let mut bytes = [0u8; 13];
+        block::read_at(&disk, position as u64, &mut bytes).unwrap();
+        bytes
+    };
+    assert_eq!(read(block(40))[..4], u32::to_be_bytes(magic));
+    assert_eq!(&read(block(40))[4..], b"committed");
+    assert_eq!(read(block(41)), [0; 13]);
+    assert_eq!(read(block(42)), [0; 13]);
+    // needs_recovery is cleared, and the journal is empty and starts with
+    // the transaction after the uncommitted one
+    assert_eq!(read(1024 + 96)[..4], [0; 4]);
+    assert_eq!(read(block(20) + 24)[..8], [0, 0, 0, 4, 0, 0, 0, 0]);
+
+    let mut dev = filesystem::Device::new(disk);
+    dev.read_superblock().unwrap();
+    assert!(!dev.is_read_only());
+    assert_eq!(dev.lookup("/lost+found"), Ok(11));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_procfs() {
+    use filesystem::FsError;
+    use scheduler::pcb::ProcessState;
+
+    println!("### Testing procfs ###");
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.mount("/", vfs::procfs::ProcFs::new()), Ok(0));
+    let root = vfs.root().unwrap();
+    assert!(vfs.stat("/").unwrap().is_dir());
+    assert_eq!(vfs.lookup("/0/.."), Ok(root));
+    assert_eq!(vfs.lookup("/uptime/x"), Err(FsError::NotADirectory));
+    assert_eq!(vfs.lookup("/4096"), Err(FsError::NotFound));
+    assert_eq!(vfs.create("/file", 0o644), Err(FsError::ReadOnly));
+    println!("Success");
+
+    println!("Listing /proc");
+    let mut found_init = false;
+    let mut position = 0;
+    while let Some((entry, next)) = vfs.read_dir(root, position).unwrap() {
+        println!("  {:>6} {}", entry.inode, entry.name());
+        if entry.name() == "0" {
+            found_init = true;
+        }
+        position = next;
+    }
+    assert!(found_init);
+    println!("Success");
+
+    println!("Reading /proc/0/status");
+    let status = vfs.lookup("/0/status").unwrap();
+    let mut whole = [0u8; 96];
+    let mut pieces = [0u8; 96];
+    let count = vfs.read(status, 0, &mut whole).unwrap();
+    assert_eq!(vfs.size(status), Ok(count as u32));
+    assert!(whole[..count].starts_with(b"Pid:\t0\n"));
+    let mut offset = 0;
+    while offset < count {
+        let end = core::cmp::min(offset + 5, pieces.len());
+        let read = vfs.read(status, offset as u32, &mut pieces[offset..end])
+                      .unwrap();
+        assert!(read > 0);
+        offset += read;
+    }
+    assert_eq!(&whole[..count], &pieces[..count]);
+    assert_eq!(vfs.write(status, 0, b"x"), Err(FsError::ReadOnly));
+    println!("Success");
+
+    println!("Reading the stack of a new process");
+    let scheduler = unsafe { &mut *GLOBAL_SCHED };
+    let pid = scheduler.create_proc(print_to_console).unwrap();
+    let slot = scheduler.processes()
+                        .iter()
+                        .position(|process| process.pid as u32 == pid)
+                        .unwrap();
+    // Sleeping keeps it from being run or reaped while it's read
+    unsafe {
+        (*PROC_LIST)[slot].state = ProcessState::Sleeping;
+    }
+    let mut directory = None;
+    let mut position = 0;
+    while let Some((entry, next)) = vfs.read_dir(root, position).unwrap() {
+        if entry.name().parse() == Ok(pid) {
+            directory = Some(vfs::VNode { mount: root.mount,
+                                          inode: entry.inode });
+        }
+        position = next;
+    }
+    let status = vfs.lookup_from(directory.unwrap(), "status").unwrap();
+    let count = vfs.read(status, 0, &mut whole).unwrap();
+    let text = core::str::from_utf8(&whole[..count]).unwrap();
+    let stack = text.lines()
+                    .find(|line| line.starts_with("Stack:\t"))
+                    .unwrap();
+    let mut bounds = stack["Stack:\t".len()..].split('-').map(|bound| {
+                         u32::from_str_radix(&bound[2..], 16).unwrap()
+                     });
+    let low = bounds.next().unwrap();
+    let high = bounds.next().unwrap();
+    assert_eq!((high - low) as usize, global_constants::PROC_ALLOC_SIZE);
+    unsafe {
+        (*PROC_LIST)[slot].state = ProcessState::Exited;
+    }
+    scheduler.reap();
+    println!("Success");
+
+    println!("Reading the system files");
+    for &(path, first) in [("/meminfo", "HeapTotal"),
+                           ("/uptime", ""),
+                           ("/superblock", "BlockSize")].iter()
+    {
+        let node = vfs.lookup(path).unwrap();
+        let mut buf = [0u8; 128];
+        let count = vfs.read(node, 0, &mut buf).unwrap();
+        let text = core::str::from_utf8(&buf[..count]).unwrap();
+        assert!(text.ends_with('\n'));
+        assert!(text.starts_with(first));
+        println!("{}:", path);
+        for line in text.lines() {
+            println!("  {}", line);
+        }
+    }
+
+    // /superblock describes the root of the kernel's VFS, not this one
+    let kernel_vfs = vfs::vfs().unwrap();
+    let stats = kernel_vfs.filesystem(kernel_vfs.root().unwrap().mount)
+                          .unwrap()
+                          .statfs()
+                          .unwrap();
+    let mut buf = [0u8; 128];
+    let superblock = vfs.lookup("/superblock").unwrap();
+    let count = vfs.read(superblock, 0, &mut buf).unwrap();
+    let text = core::str::from_utf8(&buf[..count]).unwrap();
+    let line = text.lines().next().unwrap();
+    assert_eq!(line.split('\t').nth(1).map(str::parse),
+               Some(Ok(stats.block_size)));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_devfs() {
+    use filesystem::FsError;
+    use vfs::file::{O_RDWR, O_TRUNC, O_WRONLY};
+
+    println!("### Testing devfs ###");
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.mount("/", vfs::devfs::DevFs::new()), Ok(0));
+    assert_eq!(vfs.stat("/null").map(|metadata| metadata.file_type),
+               Ok(vfs::FileType::CharDevice));
+    assert_eq!(vfs.lookup("/null/x"), Err(FsError::NotADirectory));
+    assert_eq!(vfs.lookup("/tty"), Err(FsError::NotFound));
+    assert_eq!(vfs.create("/file", 0o644), Err(FsError::NotSupported));
+
+    let mut buf = [0xAAu8; 16];
+    let null = vfs.lookup("/null").unwrap();
+    assert_eq!(vfs.read(null, 0, &mut buf), Ok(0));
+    assert_eq!(vfs.write(null, 0, b"discarded"), Ok(9));
+    let zero = vfs.lookup("/zero").unwrap();
+    assert_eq!(vfs.read(zero, 0, &mut buf), Ok(16));
+    assert!(buf.iter().all(|byte| *byte == 0));
+
+    let random = vfs.lookup("/random").unwrap();
+    let mut more = [0u8; 16];
+    assert_eq!(vfs.read(random, 0, &mut buf), Ok(16));
+    assert_eq!(vfs.read(random, 0, &mut more), Ok(16));
+    assert_ne!(buf, more);
+
+    let console = vfs.lookup("/console").unwrap();
+    assert_eq!(vfs.write(console, 0, b"Writing to /console\r\n"), Ok(21));
+    println!("Success");
+
+    // The kernel mounts devfs on /dev at boot
+    let vfs = vfs::vfs().unwrap();
+    assert_eq!(vfs.stat("/dev/null").map(|metadata| metadata.file_type),
+               Ok(vfs::FileType::CharDevice));
+
+    println!("Using /dev through file descriptors");
+    let mut files = vfs::file::FdTable::new();
+    files.open_console().unwrap();
+    assert_eq!(files.stat(1), vfs.stat("/dev/console"));
+    let fd = files.open("/dev/null", O_WRONLY | O_TRUNC, 0).unwrap();
+    assert_eq!(files.write(fd, b"discarded"), Ok(9));
+    let fd = files.open("/dev/console", O_RDWR, 0).unwrap();
+    assert_eq!(files.write(fd, b"Writing to /dev/console\r\n"), Ok(25));
+    files.close_all();
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_tmpfs() {
+    println!("### Testing tmpfs ###");
+    let allocations = MemManager::usage().allocations;
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.mount("/", vfs::tmpfs::TmpFs::new().unwrap()), Ok(0));
+
+    println!("Writing, truncating and removing files");
+    assert_eq!(tmpfs_round_trip(&mut vfs), Ok(()));
+    println!("Success");
+
+    println!("Checking that unmounting frees everything");
+    assert!(vfs.unmount("/").is_ok());
+    assert_eq!(MemManager::usage().allocations, allocations);
+    println!("Success");
+}
+
+// Makes files and directories in the tmpfs mounted at the root of |vfs| and
+// removes them again
+#[cfg(feature = "testing")]
+fn tmpfs_round_trip(vfs: &mut vfs::Vfs) -> Result<(), filesystem::FsError> {
+    use filesystem::FsError;
+
+    let root = vfs.root()?;
+    let dir = vfs.mkdir("/dir", 0o755)?;
+    assert_eq!(vfs.mkdir("/dir", 0o755), Err(FsError::AlreadyExists));
+    assert_eq!(vfs.lookup("/dir/.."), Ok(root));
+    let file = vfs.create("/dir/file", 0o644)?;
+
+    // The write spans two pages and leaves a hole at the start of the file
+    let mut buf = [0xAAu8; 64];
+    let offset = global_constants::TMPFS_PAGE_SIZE as u32 - 16;
+    assert_eq!(vfs.write(file, offset, &buf[..32])?, 32);
+    assert_eq!(vfs.size(file), Ok(offset + 32));
+    assert_eq!(vfs.read(file, 0, &mut buf)?, 64);
+    assert!(buf.iter().all(|byte| *byte == 0));
+    assert_eq!(vfs.read(file, offset, &mut buf)?, 32);
+    assert!(buf[..32].iter().all(|byte| *byte == 0xAA));
+
+    vfs.truncate(file, offset + 8)?;
+    vfs.truncate(file, offset + 32)?;
+    assert_eq!(vfs.read(file, offset, &mut buf)?, 32);
+    assert!(buf[..8].iter().all(|byte| *byte == 0xAA));
+    assert!(buf[8..32].iter().all(|byte| *byte == 0));
+
+    assert_eq!(vfs.rmdir("/dir"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.unlink("/dir"), Err(FsError::IsADirectory));
+    vfs.unlink("/dir/file")?;
+    assert_eq!(vfs.size(file), Err(FsError::InvalidInode));
+    vfs.rmdir("/dir")?;
+    assert_eq!(vfs.lookup("/dir"), Err(FsError::NotFound));
+    assert_eq!(vfs.stat("/").map(|metadata| metadata.links), Ok(2));
+    Ok(())
+}
+
+#[cfg(feature = "testing")]
+fn test_fat32() {
+    use block::BlockDevice;
+    use filesystem::FsError;
+
+    println!("### Testing FAT32 ###");
+    // A boot sector, an empty FSInfo sector, one FAT sector and three
+    // clusters of one sector each, the first holding the root directory
+    let mut disk = block::RamDisk::new(6).unwrap();
+    let mut sector = [0u8; block::SECTOR_SIZE as usize];
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 1;
+    sector[14] = 2;
+    sector[16] = 1;
+    sector[21] = 0xF8;
+    sector[32] = 6;
+    sector[36] = 1;
+    sector[44] = 2;
+    sector[48] = 1;
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    disk.write_block(0, &sector).unwrap();
+    sector = [0u8; block::SECTOR_SIZE as usize];
+    sector[0..12].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF,
+                                    0x0F, 0xFF, 0xFF, 0xFF, 0x0F]);
+    disk.write_block(2, &sector).unwrap();
+
+    let mut volume = fat::Fat32::new(disk);
+    assert_eq!(volume.read_bpb(), Ok(()));
+    assert_eq!(volume.cluster_count(), 3);
+    assert_eq!(volume.free_clusters(), Ok(2));
+
+    println!("Writing a file with a long name across two clusters");
+    let root = fat::FAT_ROOT_INODE;
+    let file = volume.create_in(root, "Field notes.txt", 0o644).unwrap();
+    assert_eq!(volume.create_in(root, "FIELD NOTES.TXT", 0o644),
+               Err(FsError::AlreadyExists));
+    assert_eq!(volume.write_at(file, 508, b"notes"), Ok(5));
+    assert_eq!(volume.free_clusters(), Ok(0));
+    assert_eq!(volume.lookup("/field NOTES.txt"), Ok(file));
+    let mut buf = [0xFFu8; 8];
+    assert_eq!(volume.read_at(file, 506, &mut buf), Ok(7));
+    assert_eq!(&buf[..7], b"\0\0notes");
+    assert!(volume.readdir(root)
+                  .unwrap()
+                  .any(|entry| entry.name() == "Field notes.txt"));
+
+    println!("Running out of clusters");
+    let empty = volume.create_in(root, "empty", 0o644).unwrap();
+    assert_eq!(volume.write_at(empty, 0, b"x"), Err(FsError::NoSpace));
+    assert_eq!(volume.unlink_in(root, "field notes.txt"), Ok(()));
+    assert_eq!(volume.lookup("/Field notes.txt"), Err(FsError::NotFound));
+    assert_eq!(volume.free_clusters(), Ok(2));
+    assert_eq!(volume.write_at(empty, 0, b"x"), Ok(1));
+    println!("Success");
+}
+
+#[cfg(feature = "testing")]
+fn test_elf_loader() {
+    use filesystem::FsError;
+    use loader::LoadError;
+    use scheduler::pcb::ProcessState;
+
+    println!("### Testing ELF loader ###");
+    let allocations = MemManager::usage().allocations;
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.mount("/", vfs::tmpfs::TmpFs::new().unwrap()), Ok(0));
+
+    // Text is 8 bytes linked at 0x1000 with the entry point 4 bytes in. Data
+    // is 4 bytes at 0x1010 followed by 12 bytes of .bss.
+    let mut elf = [0u8; 128];
+    elf[..4].copy_from_slice(b"\x7FELF");
+    elf[4] = 1;
+    elf[5] = 1;
+    elf[6] = 1;
+    let header: [(usize, u32, usize); 8] = [(16, 2, 2),
+                                            (18, 243, 2),
+                                            (20, 1, 4),
+                                            (24, 0x1004, 4),
+                                            (28, 52, 4),
+                                            (40, 52, 2),
+                                            (42, 32, 2),
+                                            (44, 2, 2)];
+    let segments: [[u32; 8]; 2] = [[1, 116, 0x1000, 0x1000, 8, 8, 5, 4],
+                                   [1, 124, 0x1010, 0x1010, 4, 16, 6, 4]];
+    for &(offset, value, size) in header.iter() {
+        for i in 0..size {
+            elf[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+    for (i, segment) in segments.iter().enumerate() {
+        for (j, value) in segment.iter().enumerate() {
+            for k in 0..4 {
+                elf[52 + i * 32 + j * 4 + k] = (value >> (k * 8)) as u8;
+            }
+        }
+    }
+    for (i, byte) in elf[116..128].iter_mut().enumerate() {
+        *byte = 0xA0 + i as u8;
+    }
+
+    let file = vfs.create("/program", 0o755).unwrap();
+    assert_eq!(vfs.write(file, 0, &elf), Ok(elf.len()));
+
+    println!("Loading segments and zeroing .bss");
+    let image = loader::load_from(&mut vfs, "/program").unwrap();
+    assert_eq!(image.size(), 32);
+    assert_eq!(image.entry(), image.base() + 4);
+    let memory = unsafe {
+        core::slice::from_raw_parts(image.base() as *const u8, 32)
+    };
+    assert_eq!(&memory[..8], &elf[116..124]);
+    assert!(memory[8..16].iter().all(|byte| *byte == 0));
+    assert_eq!(&memory[16..20], &elf[124..128]);
+    assert!(memory[20..].iter().all(|byte| *byte == 0));
+    println!("Success");
+
+    println!("Freeing the image once its process has exited");
+    let scheduler = unsafe { &mut *GLOBAL_SCHED };
+    let loaded = MemManager::usage().allocations;
+    let pid = scheduler.exec(image).unwrap();
+    let processes = scheduler.processes().size();
+    let slot = scheduler.processes()
+                        .iter()
+                        .position(|process| process.pid as u32 == pid)
+                        .unwrap();
+    // The program starts with its stack pointer at the top of its own stack
+    let (low, high) = scheduler.processes()[slot].stack_bounds().unwrap();
+    assert_eq!((high - low) as usize, global_constants::PROGRAM_STACK_SIZE);
+    unsafe {
+        (*PROC_LIST)[slot].state = ProcessState::Exited;
+    }
+    scheduler.reap();
+    // The stack and the image are both gone
+    assert_eq!(MemManager::usage().allocations, loaded - 1);
+    // The next process takes over the exited one's slot
+    let image = loader::load_from(&mut vfs, "/program").unwrap();
+    let pid = scheduler.exec(image).unwrap();
+    assert_eq!(scheduler.processes().size(), processes);
+    assert_eq!(scheduler.processes()[slot].pid as u32, pid);
+    unsafe {
+        (*PROC_LIST)[slot].state = ProcessState::Exited;
+    }
+    scheduler.reap();
+    println!("Success");
+
+    println!("Running out of memory for the stack");
+    let image = loader::load_from(&mut vfs, "/program").unwrap();
+    let loaded = MemManager::usage().allocations;
+    // Take what's left of the heap, in smaller pieces as it runs out
+    let mut taken = [0u32; 64];
+    let mut count = 0;
+    let mut size = 4096;
+    while size >= 8 && count < taken.len() {
+        match MemManager::kmalloc(size) {
+            Ok(address) => {
+                taken[count] = address;
+                count += 1;
+            }
+            Err(_) => size /= 2,
+        }
+    }
+    assert_eq!(scheduler.exec(image),
+               Err(scheduler::SchedulerError::OutOfMemory));
+    for &address in taken[..count].iter() {
+        MemManager::kfree(address).unwrap();
+    }
+    // The image went with the process that couldn't be made
+    assert_eq!(MemManager::usage().allocations, loaded - 1);
+    println!("Success");
+
+    println!("Rejecting files that aren't RISC-V executables");
+    let file = vfs.lookup("/program").unwrap();
+    assert!(vfs.write(file, 18, &[62, 0]).is_ok());
+    assert_eq!(loader::load_from(&mut vfs, "/program").map(|_| ()),
+               Err(LoadError::WrongMachine));
+    assert!(vfs.write(file, 0, b"#!/bin/sh").is_ok());
+    assert_eq!(loader::load_from(&mut vfs, "/program").map(|_| ()),
+               Err(LoadError::NotElf));
+    assert_eq!(loader::load_from(&mut vfs, "/").map(|_| ()),
+               Err(LoadError::Fs(FsError::IsADirectory)));
+    assert_eq!(loader::load_from(&mut vfs, "/missing").map(|_| ()),
+               Err(LoadError::Fs(FsError::NotFound)));
+    println!("Success");
+
+    assert!(vfs.unmount("/").is_ok());
+    assert_eq!(MemManager::usage().allocations, allocations);
+}
+
+#[cfg(feature = "testing")]
+fn test_initramfs() {
+    use vfs::initramfs::{self, ArchiveError};
+
+    // A newc archive holding /etc/motd, a symbolic link /motd to it and
+    // /var/log/empty, whose directories aren't listed
+    static ARCHIVE: &[u8] = b"07070100000001000041ED00000000000000000000000200\
+                            000000000000000000000000000000000000000000000000\
+                            00000200000000.\0\
+                            07070100000002000041ED00000000000000000000000200\
+                            000000000000000000000000000000000000000000000000\
+                            00000400000000etc\0\0\0\
+                            07070100000003000081A400000000000000000000000100\
+                            000000000000060000000000000000000000000000000000\
+                            00000900000000etc/motd\0\0hello\n\0\0\
+                            070701000000040000A1FF00000000000000000000000100\
+                            000000000000080000000000000000000000000000000000\
+                            00000500000000motd\0\0etc/motd\
+                            070701000000050000818000000000000000000000000100\
+                            000000000000000000000000000000000000000000000000\
+                            00000E00000000var/log/empty\0\
+                            070701000000000000000000000000000000000000000100\
+                            000000000000000000000000000000000000000000000000\
+                            00000B00000000TRAILER!!!\0\0\0\0";
+
+    println!("### Testing initramfs ###");
+    let allocations = MemManager::usage().allocations;
+    let mut vfs = vfs::Vfs::new();
+    assert_eq!(vfs.mount("/", vfs::tmpfs::TmpFs::new().unwrap()), Ok(0));
+
+    println!("Unpacking a cpio archive");
+    assert_eq!(initramfs::unpack(&mut vfs, ARCHIVE), Ok(()));
+    let mut buf = [0u8; 16];
+    let motd = vfs.lookup("/motd");
+    assert_eq!(motd, vfs.lookup("/etc/motd"));
+    assert_eq!(motd.and_then(|motd| vfs.read(motd, 0, &mut buf)), Ok(6));
+    assert_eq!(&buf[..6], b"hello\n");
+    assert_eq!(vfs.stat("/etc/motd").map(|m| m.permissions), Ok(0o644));
+    let link = vfs.lookup_link("/motd");
+    assert_eq!(link.and_then(|link| vfs.file_type(link)),
+               Ok(vfs::FileType::Symlink));
+    let empty = vfs.lookup("/var/log/empty");
+    assert_eq!(empty.and_then(|empty| vfs.size(empty)), Ok(0));
+    println!("Success");
+
+    println!("Rejecting archives it can't read");
+    assert_eq!(initramfs::unpack(&mut vfs, b"not an archive"),
+               Err(ArchiveError::UnknownFormat));
+    assert_eq!(initramfs::unpack(&mut vfs, &ARCHIVE[..200]),
+               Err(ArchiveError::Corrupt));
+    println!("Success");
+
+    assert!(vfs.unmount("/").is_ok());
+    assert_eq!(MemManager::usage().allocations, allocations);
 }
 
 #[cfg(feature = "testing")]
@@ -315,7 +1890,24 @@ fn run_tests() {
     test_stackvec();
     test_heapvec();
     test_scheduler();
+    test_block_devices();
+    test_partitions();
+    test_buffer_cache();
     test_filesystem();
+    test_extents();
+    test_htree();
+    test_vfs();
+    test_file_descriptors();
+    test_stat();
+    test_syscalls();
+    test_fsck();
+    test_journal();
+    test_procfs();
+    test_devfs();
+    test_tmpfs();
+    test_fat32();
+    test_elf_loader();
+    test_initramfs();
 }
 
 static mut PROC_LIST: *mut HeapVec<ProcessControlBlock> = core::ptr::null_mut();
@@ -341,6 +1933,59 @@ fn main() {
     MemManager::init();
     println!("Done");
 
+    print!("Initializing buffer cache...");
+    block::BufferCache::init().unwrap();
+    println!("Done");
+
+    print!("Initializing VFS...");
+    vfs::Vfs::init().unwrap();
+    vfs::file::FileTable::init().unwrap();
+    println!("Done");
+
+    if vfs::initramfs::archive().is_empty() {
+        print_partitions();
+        print!("Mounting root filesystem...");
+        match mount_root() {
+            Ok(()) => println!("Done"),
+            Err(e) => println!("Failed: {:?}", e),
+        }
+        print!("Mounting FAT32 partition...");
+        match mount_fat() {
+            Ok(true) => println!("Done"),
+            Ok(false) => println!("None found"),
+            Err(e) => println!("Failed: {:?}", e),
+        }
+    } else {
+        print!("Unpacking initramfs...");
+        match mount_initramfs() {
+            Ok(()) => println!("Done"),
+            Err(e) => println!("Failed: {:?}", e),
+        }
+    }
+
+    print!("Mounting devfs...");
+    let devfs = vfs::devfs::DevFs::new();
+    match unsafe { (*vfs::VFS).mount("/dev", devfs) } {
+        Ok(_) => println!("Done"),
+        Err(e) => println!("Failed: {:?}", e),
+    }
+
+    print!("Mounting procfs...");
+    let procfs = vfs::procfs::ProcFs::new();
+    match unsafe { (*vfs::VFS).mount("/proc", procfs) } {
+        Ok(_) => println!("Done"),
+        Err(e) => println!("Failed: {:?}", e),
+    }
+
+    print!("Mounting tmpfs...");
+    let mounted = vfs::tmpfs::TmpFs::new().and_then(|tmpfs| unsafe {
+                      (*vfs::VFS).mount("/tmp", tmpfs)
+                  });
+    match mounted {
+        Ok(_) => println!("Done"),
+        Err(e) => println!("Failed: {:?}", e),
+    }
+
     print!("Initializing scheduler...");
     unsafe {
         PROC_LIST = MemManager::kmalloc(core::mem::size_of::<HeapVec<ProcessControlBlock>>()).unwrap() as *mut HeapVec<ProcessControlBlock>;
@@ -379,6 +2024,13 @@ fn main() {
         let echo_pid = (*GLOBAL_SCHED).create_proc(print_to_console).unwrap();
     }
 
+    // Programs in the root filesystem start from /bin/init, if there is one
+    match loader::spawn("/bin/init") {
+        Ok(pid) => println!("started /bin/init as pid {}", pid),
+        Err(loader::LoadError::Fs(filesystem::FsError::NotFound)) => {}
+        Err(e) => println!("couldn't start /bin/init: {:?}", e),
+    }
+
     // Main loop doesn't return, simply wait for interrupt
     loop {
         unsafe {
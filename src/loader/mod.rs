@@ -0,0 +1,291 @@
+// Loading ELF32 RISC-V executables from the filesystem so they can be run as
+// processes. Programs ship by putting them in rootfs/ and rebuilding
+// asm/filesystem.bin.
+//
+// There's no MMU, so a program can't be given the addresses it was linked
+// at. Every PT_LOAD segment is copied into one region from MemManager, keeping
+// their distances from each other, and the program runs wherever that region
+// happens to be. Programs have to be position independent for that to work:
+// built with -mcmodel=medany and without absolute addresses in their data,
+// the way -fPIE with a static link and no relocations leaves them.
+
+use crate::filesystem::FsError;
+use crate::memman::MemManager;
+use crate::scheduler::SchedulerError;
+use crate::vfs::{FileType, VNode, Vfs};
+use crate::GLOBAL_SCHED;
+
+// What the first bytes of e_ident must be
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+// Values of e_ident[EI_CLASS] and e_ident[EI_DATA] for 32 bit little endian
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+
+// Values of e_type for executables and position independent executables
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+// Value of e_machine for RISC-V
+const EM_RISCV: u16 = 243;
+
+// Value of p_type for segments that are loaded into memory
+const PT_LOAD: u32 = 1;
+
+// MemManager describes each allocation with a 16 bit length, so no image can
+// be this big
+const MAX_IMAGE_SIZE: u32 = 1 << 16;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    // The file doesn't start with an ELF header
+    NotElf,
+    // The file is for a 64 bit or big endian machine
+    WrongClass,
+    // The file is for a machine other than RISC-V
+    WrongMachine,
+    // The file is an object file or core dump rather than an executable
+    NotExecutable,
+    // The program headers are missing, truncated or describe impossible
+    // segments
+    BadProgramHeaders,
+    // The segments don't fit in one allocation from MemManager
+    TooBig,
+    // The kernel heap couldn't hold the segments or the process's stack
+    OutOfMemory,
+    // The process list is full
+    TooManyProcesses,
+    // The file couldn't be found or read
+    Fs(FsError),
+}
+
+impl From<FsError> for LoadError {
+    fn from(error: FsError) -> LoadError {
+        LoadError::Fs(error)
+    }
+}
+
+#[repr(C)]
+struct ElfHeader {
+    e_ident: [u8; 16],
+    // Type of file, like executable or shared object
+    e_type: u16,
+    // Architecture the file is for
+    e_machine: u16,
+    e_version: u32,
+    // Virtual address execution starts at
+    e_entry: u32,
+    // Offset of the program header table in the file
+    e_phoff: u32,
+    // Offset of the section header table in the file
+    e_shoff: u32,
+    e_flags: u32,
+    // Size of this header
+    e_ehsize: u16,
+    // Size of each program header
+    e_phentsize: u16,
+    // Number of program headers
+    e_phnum: u16,
+    // Size of each section header
+    e_shentsize: u16,
+    // Number of section headers
+    e_shnum: u16,
+    // Index of the section header holding the section names
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+struct ProgramHeader {
+    // Kind of segment, like PT_LOAD
+    p_type: u32,
+    // Offset of the segment's bytes in the file
+    p_offset: u32,
+    // Virtual address the segment is linked at
+    p_vaddr: u32,
+    p_paddr: u32,
+    // Number of bytes the file holds for the segment
+    p_filesz: u32,
+    // Number of bytes the segment takes up in memory. Anything past
+    // p_filesz is .bss and starts as zeros.
+    p_memsz: u32,
+    p_flags: u32,
+    p_align: u32,
+}
+
+// A program copied into memory. The memory is freed when the image is
+// dropped, so the process running it has to hold on to it.
+#[derive(Debug)]
+pub struct Image {
+    // Address the lowest segment was copied to
+    base: u32,
+    // Number of bytes from |base| the segments take up
+    size: u32,
+    // Address execution starts at
+    entry: u32,
+}
+
+impl Image {
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        MemManager::kfree(self.base).unwrap();
+    }
+}
+
+// Loads the executable at |path| and starts a process running it, returning
+// the process's pid
+pub fn spawn(path: &str) -> Result<u32, LoadError> {
+    let image = load(path)?;
+    match unsafe { (*GLOBAL_SCHED).exec(image) } {
+        Ok(pid) => Ok(pid),
+        Err(SchedulerError::TooManyProcesses) => {
+            Err(LoadError::TooManyProcesses)
+        }
+        Err(SchedulerError::OutOfMemory) => Err(LoadError::OutOfMemory),
+    }
+}
+
+// Loads the executable at |path| through the global VFS
+pub fn load(path: &str) -> Result<Image, LoadError> {
+    load_from(crate::vfs::vfs()?, path)
+}
+
+// Loads the executable at |path| through |vfs|
+pub fn load_from(vfs: &mut Vfs, path: &str) -> Result<Image, LoadError> {
+    let node = vfs.lookup(path)?;
+    match vfs.file_type(node)? {
+        FileType::Regular => {}
+        FileType::Directory => return Err(FsError::IsADirectory.into()),
+        _ => return Err(LoadError::NotExecutable),
+    }
+
+    let header: ElfHeader = read_struct(vfs, node, 0, LoadError::NotElf)?;
+    check_header(&header)?;
+
+    // Find the span of addresses the segments are linked at
+    let mut low = core::u32::MAX;
+    let mut high = 0;
+    for i in 0..header.e_phnum as u32 {
+        let segment = program_header(vfs, node, &header, i)?;
+        if segment.p_type != PT_LOAD || segment.p_memsz == 0 {
+            continue;
+        }
+        if segment.p_filesz > segment.p_memsz {
+            return Err(LoadError::BadProgramHeaders);
+        }
+        let end = match segment.p_vaddr.checked_add(segment.p_memsz) {
+            Some(end) => end,
+            None => return Err(LoadError::BadProgramHeaders),
+        };
+        low = core::cmp::min(low, segment.p_vaddr);
+        high = core::cmp::max(high, end);
+    }
+    if low >= high {
+        return Err(LoadError::BadProgramHeaders);
+    }
+    if header.e_entry < low || header.e_entry >= high {
+        return Err(LoadError::BadProgramHeaders);
+    }
+    if high - low >= MAX_IMAGE_SIZE {
+        return Err(LoadError::TooBig);
+    }
+
+    let size = high - low;
+    let base = match MemManager::kmalloc(size as usize) {
+        Ok(base) => base,
+        Err(_) => return Err(LoadError::OutOfMemory),
+    };
+    // From here the image frees the memory if loading fails
+    let image = Image { base: base,
+                        size: size,
+                        entry: base + (header.e_entry - low) };
+    let memory = unsafe {
+        core::slice::from_raw_parts_mut(base as *mut u8, size as usize)
+    };
+
+    // Gaps between segments are zeroed along with .bss
+    for byte in memory.iter_mut() {
+        *byte = 0;
+    }
+
+    for i in 0..header.e_phnum as u32 {
+        let segment = program_header(vfs, node, &header, i)?;
+        if segment.p_type != PT_LOAD || segment.p_memsz == 0 {
+            continue;
+        }
+
+        let start = (segment.p_vaddr - low) as usize;
+        let bytes = &mut memory[start..start + segment.p_filesz as usize];
+        if vfs.read(node, segment.p_offset, bytes)? != bytes.len() {
+            return Err(LoadError::BadProgramHeaders);
+        }
+    }
+
+    Ok(image)
+}
+
+// Checks that |header| is for an executable this kernel can run
+fn check_header(header: &ElfHeader) -> Result<(), LoadError> {
+    if header.e_ident[..4] != ELF_MAGIC {
+        return Err(LoadError::NotElf);
+    }
+    if header.e_ident[4] != ELFCLASS32 || header.e_ident[5] != ELFDATA2LSB {
+        return Err(LoadError::WrongClass);
+    }
+    if header.e_machine != EM_RISCV {
+        return Err(LoadError::WrongMachine);
+    }
+    if header.e_type != ET_EXEC && header.e_type != ET_DYN {
+        return Err(LoadError::NotExecutable);
+    }
+    if header.e_phentsize as usize != core::mem::size_of::<ProgramHeader>() {
+        return Err(LoadError::BadProgramHeaders);
+    }
+    Ok(())
+}
+
+// Reads program header |index| of the file |node|
+fn program_header(vfs: &mut Vfs,
+                  node: VNode,
+                  header: &ElfHeader,
+                  index: u32)
+                  -> Result<ProgramHeader, LoadError> {
+    let offset = index.checked_mul(header.e_phentsize as u32)
+                      .and_then(|offset| offset.checked_add(header.e_phoff));
+    match offset {
+        Some(offset) => {
+            read_struct(vfs, node, offset, LoadError::BadProgramHeaders)
+        }
+        None => Err(LoadError::BadProgramHeaders),
+    }
+}
+
+// Reads a T stored |offset| bytes into the file |node|, failing with
+// |truncated| if the file ends first
+fn read_struct<T>(vfs: &mut Vfs,
+                  node: VNode,
+                  offset: u32,
+                  truncated: LoadError)
+                  -> Result<T, LoadError> {
+    let mut value: T = unsafe { core::mem::zeroed() };
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8,
+                                        core::mem::size_of::<T>())
+    };
+    if vfs.read(node, offset, bytes)? != bytes.len() {
+        return Err(truncated);
+    }
+    Ok(value)
+}
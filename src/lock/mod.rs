@@ -54,6 +54,58 @@ impl Mutex {
     }
 }
 
+// Bit of mstatus that enables machine interrupts
+const MSTATUS_MIE: u32 = 1 << 3;
+
+// A mutex that also keeps interrupts off while it's held, for data that trap
+// handlers use as well as the code they interrupt. With interrupts on, a trap
+// arriving while the lock is held would spin on it forever.
+#[repr(align(4))]
+pub struct InterruptMutex {
+    mutex: Mutex,
+    // Whether interrupts were on before the lock was taken
+    enabled: bool,
+}
+
+impl InterruptMutex {
+    pub fn new() -> InterruptMutex {
+        InterruptMutex { mutex: Mutex::new(),
+                         enabled: false }
+    }
+
+    // Turns interrupts off and then takes the lock
+    pub fn lock(&mut self) {
+        let enabled = disable_interrupts();
+        self.mutex.lock();
+        self.enabled = enabled;
+    }
+
+    // Releases the lock and turns interrupts back on if they were on before
+    pub fn unlock(&mut self) {
+        let enabled = self.enabled;
+        self.mutex.unlock();
+        restore_interrupts(enabled);
+    }
+}
+
+// Turns machine interrupts off and returns whether they were on
+pub fn disable_interrupts() -> bool {
+    let status: u32;
+    unsafe {
+        asm!("csrrci $0, mstatus, 8" : "=r"(status) ::: "volatile");
+    }
+    status & MSTATUS_MIE != 0
+}
+
+// Turns machine interrupts back on if |enabled| says they were on
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe {
+            asm!("csrsi mstatus, 8" :::: "volatile");
+        }
+    }
+}
+
 pub struct Barrier {
     arrive_counter: u32, // How many procs have entered, 0 at start
     leave_counter: u32,  // How many procs have exited, N at start
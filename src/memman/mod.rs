@@ -12,6 +12,20 @@ pub struct Descriptor {
 
 pub struct MemManager;
 
+// How the heap is divided up, with sizes in bytes that include the
+// descriptors in front of each block
+#[derive(Debug, Copy, Clone)]
+pub struct HeapUsage {
+    pub total: u32,
+    pub used: u32,
+    pub free: u32,
+    // Largest block that's free, which can be smaller than what's free
+    // overall until the heap is coalesced
+    pub largest_free: u32,
+    // Number of blocks that are taken
+    pub allocations: u32,
+}
+
 impl MemManager {
     // Initialize by setting the first descriptor at the start of the heap
     #[no_mangle]
@@ -76,16 +90,12 @@ impl MemManager {
                                             (core::mem::size_of::<Descriptor>()
                                              as u32))
                                            as *mut Descriptor;
-                            let new_taken =
-                                read_volatile(&((*new_desc).taken)) as u16;
-                            if new_taken != 1 {
-                                write_volatile(&mut ((*new_desc).taken),
-                                               0 as u16);
-                                write_volatile(&mut ((*new_desc).len),
-                                               s -
-                                               read_volatile(&((*desc).len))
+                            // The rest of the block is still free, whatever
+                            // was last written where its Descriptor goes
+                            write_volatile(&mut ((*new_desc).taken), 0 as u16);
+                            write_volatile(&mut ((*new_desc).len),
+                                           s - read_volatile(&((*desc).len))
                                                as u16);
-                            }
                         }
 
                         // Set the pointer that we'll return
@@ -170,4 +180,38 @@ impl MemManager {
             }
         }
     }
+
+    // Walks the heap to add up the blocks that are taken and free
+    pub fn usage() -> HeapUsage {
+        let mut usage = HeapUsage { total: 0,
+                                    used: 0,
+                                    free: 0,
+                                    largest_free: 0,
+                                    allocations: 0 };
+        unsafe {
+            let mut start = HEAP_START as u32;
+            let end = HEAP_END as u32;
+            usage.total = end - start;
+
+            while start < end {
+                let desc = start as *mut Descriptor;
+                let len = read_volatile(&((*desc).len)) as u32;
+                if len == 0 {
+                    break;
+                }
+
+                if read_volatile(&((*desc).taken)) == 1 {
+                    usage.used += len;
+                    usage.allocations += 1;
+                } else {
+                    usage.free += len;
+                    if len > usage.largest_free {
+                        usage.largest_free = len;
+                    }
+                }
+                start += len;
+            }
+        }
+        usage
+    }
 }
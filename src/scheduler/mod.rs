@@ -1,5 +1,6 @@
 use crate::console::Console;
-use crate::global_constants::MAX_PROC_COUNT;
+use crate::global_constants::{PROC_ALLOC_SIZE, PROGRAM_STACK_SIZE};
+use crate::loader::Image;
 use crate::sys::ecall::ecall;
 use crate::sys::table::SyscallTable;
 use crate::utils::heapvec::HeapVec;
@@ -13,6 +14,14 @@ pub mod pcb;
 // round-robin scheduler switches to another process
 const TIME_QUANTUM: u64 = 10000;
 
+#[derive(Debug, PartialEq)]
+pub enum SchedulerError {
+    // Every slot of the process list holds a process that hasn't exited
+    TooManyProcesses,
+    // The kernel heap couldn't hold the new process's stack
+    OutOfMemory,
+}
+
 pub extern "C" fn recover() {
     ecall(SyscallTable::EXIT, 0);
 }
@@ -76,23 +85,53 @@ impl Scheduler {
 
     // Create a new process and add it to the process list where it will be run
     // periodically from the round robin scheduler
-    pub fn create_proc(&mut self, func: fn() -> i32) -> Result<u32, ()> {
+    pub fn create_proc(&mut self,
+                       func: fn() -> i32)
+                       -> Result<u32, SchedulerError> {
         // We create a process by setting the memory address of the provided
         // function as the program counter for the new pcb
+        self.add_proc(func as u32, PROC_ALLOC_SIZE, None)
+    }
+
+    // Create a new process that runs a program loaded into memory, starting at
+    // its entry point. The process owns |image| and frees it when it's done.
+    pub fn exec(&mut self, image: Image) -> Result<u32, SchedulerError> {
+        self.add_proc(image.entry(), PROGRAM_STACK_SIZE, Some(image))
+    }
 
+    fn add_proc(&mut self,
+                start: u32,
+                stack_size: usize,
+                image: Option<Image>)
+                -> Result<u32, SchedulerError> {
         let pid: u32;
         unsafe {
             let p_list = self.processes.as_mut().unwrap();
-            // We currently don't support expanding the process vector
-            if p_list.size() > MAX_PROC_COUNT {
-                return Err(());
+            // Exited processes give up their slots, and otherwise there has to
+            // be room in the vector since it doesn't grow
+            let current_index = self.current_index;
+            let slot = (0..p_list.size()).find(|&i| {
+                                             i != current_index &&
+                                             p_list[i].state ==
+                                             ProcessState::Exited
+                                         });
+            if slot.is_none() && p_list.size() >= p_list.capacity() {
+                return Err(SchedulerError::TooManyProcesses);
             }
 
-            // If there weren't any spots taken by |ProcessState::Exited| processes,
-            // push a new process onto the vector.
-            p_list.push(ProcessControlBlock::init_new(self.pid_counter,
-                                                         func as u32,
-                                                         recover as u32));
+            let mut pcb = ProcessControlBlock::init_new(self.pid_counter,
+                                                        start,
+                                                        recover as u32,
+                                                        stack_size)?;
+            if let Some(image) = image {
+                pcb.set_image(image);
+            }
+
+            // Replacing an exited process drops whatever it still held
+            match slot {
+                Some(slot) => p_list[slot] = pcb,
+                None => p_list.push(pcb),
+            }
 
             pid = self.pid_counter as u32;
             self.pid_counter += 1;
@@ -102,12 +141,35 @@ impl Scheduler {
         Ok(pid)
     }
 
+    // Frees the stacks, programs and files of exited processes. The running
+    // process keeps them until it's been switched away from, since it's still
+    // on its stack.
+    pub fn reap(&mut self) {
+        let p_list: &mut HeapVec<ProcessControlBlock>;
+        unsafe {
+            p_list = self.processes.as_mut().unwrap();
+        }
+
+        for i in 0..p_list.size() {
+            if i != self.current_index &&
+               p_list[i].state == ProcessState::Exited
+            {
+                p_list[i].release();
+            }
+        }
+    }
+
     pub fn get_current_proc(&mut self) -> &mut ProcessControlBlock {
         let p_list: &mut HeapVec<ProcessControlBlock>;
         unsafe { p_list = self.processes.as_mut().unwrap(); }
         &mut p_list[self.current_index]
     }
 
+    // Returns every process the scheduler knows about
+    pub fn processes(&self) -> &HeapVec<ProcessControlBlock> {
+        unsafe { &*self.processes }
+    }
+
     // Print a nice table of PIDs with states
     // TODO: Add other things to print, like names, total running time, priority, etc.
     pub fn print(&mut self) {
@@ -155,6 +217,7 @@ impl Scheduler {
         p_list[new_index].start_time = crate::trap::timer::get_current_time();
 
         scheduler.current_index = new_index;
+        scheduler.reap();
         new_pc
     }
 }
@@ -1,5 +1,9 @@
-use crate::global_constants::{NUM_CPU_REGISTERS, PROC_ALLOC_SIZE};
+use crate::global_constants::NUM_CPU_REGISTERS;
+use crate::loader::Image;
 use crate::memman::MemManager;
+use crate::vfs::file::FdTable;
+
+use super::SchedulerError;
 
 extern "C" {
     static mut GLOBAL_CTX: [u32; 32];
@@ -17,7 +21,7 @@ pub enum ProcessState {
 // Scheduler::Print()
 impl core::fmt::Display for ProcessState {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        let width = f.width().unwrap();
+        let width = f.width().unwrap_or(0);
         match self {
             ProcessState::None => write!(f, "{:>w$}", "None", w = width),
             ProcessState::Running => write!(f, "{:>w$}", "Running", w = width),
@@ -50,42 +54,65 @@ pub struct ProcessControlBlock {
 
     // We'll have to allocate a region of memory for the stack.
     // |stack_start| will point to the bottom of the region and |stack_end| will
-    // point to the top, i.e. |stack_start| = |stack_end| + PROC_ALLOC_SIZE
+    // point to the top, i.e. |stack_start| = |stack_end| + the stack's size in
+    // bytes
     stack_end: *const u32,
     stack_start: *mut u32,
+
+    // Files the process has open
+    pub files: FdTable,
+
+    // The program the process runs, if it was loaded from a file rather than
+    // being a function in the kernel
+    image: Option<Image>,
 }
 
 impl ProcessControlBlock {
-    // Creates a new process
-    fn new(id: usize, start_func: u32, end_func: u32) -> ProcessControlBlock {
-        ProcessControlBlock { state: ProcessState::Running,
-                              pid: id,
-                              start_time: 0,
-                              registers: [0; NUM_CPU_REGISTERS],
-                              program_counter: start_func,
-                              start_fn: start_func,
-                              end_fn: end_func,
-                              stack_end:
-                                  MemManager::kmalloc(PROC_ALLOC_SIZE).unwrap()
-                                  as *const u32,
-                              stack_start: core::ptr::null_mut() }
+    // Creates a new process with a stack of |stack_size| bytes
+    fn new(id: usize,
+           start_func: u32,
+           end_func: u32,
+           stack_size: usize)
+           -> Result<ProcessControlBlock, SchedulerError> {
+        let stack_end = match MemManager::kmalloc(stack_size) {
+            Ok(address) => address as *const u32,
+            Err(_) => return Err(SchedulerError::OutOfMemory),
+        };
+        Ok(ProcessControlBlock { state: ProcessState::Running,
+                                 pid: id,
+                                 start_time: 0,
+                                 registers: [0; NUM_CPU_REGISTERS],
+                                 program_counter: start_func,
+                                 start_fn: start_func,
+                                 end_fn: end_func,
+                                 stack_end: stack_end,
+                                 stack_start: core::ptr::null_mut(),
+                                 files: FdTable::new(),
+                                 image: None })
     }
 
     pub fn init_new(pid: usize,
                     start_func: u32,
-                    end_func: u32)
-                    -> ProcessControlBlock {
-        let mut pcb = ProcessControlBlock::new(pid, start_func, end_func);
+                    end_func: u32,
+                    stack_size: usize)
+                    -> Result<ProcessControlBlock, SchedulerError> {
+        let mut pcb =
+            ProcessControlBlock::new(pid, start_func, end_func, stack_size)?;
         unsafe {
             // Set the stack pointer to be the bottom of the allocated stack
-            // region
-            pcb.stack_start = pcb.stack_end.add(PROC_ALLOC_SIZE) as *mut u32;
+            // region, counting the size in bytes rather than words
+            pcb.stack_start =
+                (pcb.stack_end as *const u8).add(stack_size) as *mut u32;
             pcb.registers[RETURN_ADDRESS_REGISTER_OFFSET] = pcb.end_fn;
             pcb.registers[STACK_POINTER_REGISTER_OFFSET] =
                 pcb.stack_start as u32;
         }
 
-        pcb
+        // Without the console the process can still run, it just has no
+        // standard input or output
+        pcb.files.open_console().ok();
+
+        Ok(pcb)
     }
 
     // Loads the cpu registers so another process can run
@@ -108,16 +135,43 @@ impl ProcessControlBlock {
     pub fn set_pid(&mut self, pid: usize) {
         self.pid = pid;
     }
-}
 
-impl Drop for ProcessControlBlock {
-    fn drop(&mut self) {
+    // Gives the process the memory holding the program it runs, which is freed
+    // along with the process
+    pub fn set_image(&mut self, image: Image) {
+        self.image = Some(image);
+    }
+
+    // Closes the files of a process that has exited and frees its stack and
+    // program. The PCB keeps its slot in the process list until a new
+    // process takes it.
+    pub fn release(&mut self) {
+        self.files.close_all();
         if !self.stack_end.is_null() {
             MemManager::kfree(self.stack_end as u32).unwrap();
+            self.stack_end = core::ptr::null();
+            self.stack_start = core::ptr::null_mut();
+        }
+        self.image = None;
+    }
+
+    // Returns the lowest and highest addresses of the process's stack, or
+    // None for the default process, which runs on the stack from boot
+    pub fn stack_bounds(&self) -> Option<(u32, u32)> {
+        if self.stack_end.is_null() {
+            None
+        } else {
+            Some((self.stack_end as u32, self.stack_start as u32))
         }
     }
 }
 
+impl Drop for ProcessControlBlock {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
 // Do not allocate anything or set any meaningful state, this is primarily used
 // for the initial Scheduler process during initialization.
 impl Default for ProcessControlBlock {
@@ -130,6 +184,8 @@ impl Default for ProcessControlBlock {
                               start_fn: 0,
                               end_fn: crate::scheduler::recover as u32,
                               stack_end: core::ptr::null(),
-                              stack_start: core::ptr::null_mut() }
+                              stack_start: core::ptr::null_mut(),
+                              files: FdTable::new(),
+                              image: None }
     }
 }
@@ -1,5 +1,13 @@
 use super::table::SyscallTable;
 
+extern "C" {
+    static mut GLOBAL_CTX: [u32; 32];
+}
+
+// Registers holding the arguments and return value of a system call, a0-a3
+const ARGUMENT_REGISTER_OFFSET: usize = 10;
+const ARGUMENT_COUNT: usize = 4;
+
 pub extern "C" fn ecall(syscall: SyscallTable, arg: u32) {
     unsafe {
         asm!("mv t0, $0" :: "r"(syscall) :: "volatile");
@@ -7,3 +15,43 @@ pub extern "C" fn ecall(syscall: SyscallTable, arg: u32) {
         asm!("ecall" :::: "volatile");
     }
 }
+
+// Makes a system call that takes its arguments in a0-a3 and returns a value
+// in a0, which is negative if the call failed
+pub extern "C" fn ecall_args(syscall: SyscallTable,
+                             arg0: u32,
+                             arg1: u32,
+                             arg2: u32,
+                             arg3: u32)
+                             -> i32 {
+    let result: i32;
+    unsafe {
+        asm!("ecall"
+             : "={x10}"(result)
+             : "{x5}"(syscall), "{x10}"(arg0), "{x11}"(arg1), "{x12}"(arg2),
+               "{x13}"(arg3)
+             : "memory"
+             : "volatile");
+    }
+    result
+}
+
+// Returns the arguments of the system call being handled, which the trap
+// handler saved with the rest of the registers
+pub fn syscall_args() -> [u32; ARGUMENT_COUNT] {
+    let mut args = [0; ARGUMENT_COUNT];
+    unsafe {
+        args.copy_from_slice(&GLOBAL_CTX[ARGUMENT_REGISTER_OFFSET..
+                                         ARGUMENT_REGISTER_OFFSET +
+                                         ARGUMENT_COUNT]);
+    }
+    args
+}
+
+// Sets the value the system call being handled returns, which lands in a0
+// once the trap handler restores the registers
+pub fn set_syscall_return(value: i32) {
+    unsafe {
+        GLOBAL_CTX[ARGUMENT_REGISTER_OFFSET] = value as u32;
+    }
+}
@@ -14,5 +14,6 @@ pub fn _exit(status: u32) {
         p = (*GLOBAL_SCHED).get_current_proc();
     }
 
+    p.files.close_all();
     p.state = ProcessState::Exited;
 }
@@ -0,0 +1,187 @@
+// System calls on file descriptors. Each call returns what it produces, such
+// as a file descriptor or a byte count, or a negated Linux error number if it
+// fails. The functions starting with _ carry the calls out for the trap
+// handler.
+
+use crate::filesystem::FsError;
+use crate::scheduler::pcb::ProcessControlBlock;
+use crate::vfs::Metadata;
+use crate::GLOBAL_SCHED;
+
+use super::ecall::ecall_args;
+use super::table::SyscallTable;
+
+// Linux error numbers for the ways a call can fail
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EBUSY: i32 = 16;
+const EEXIST: i32 = 17;
+const EXDEV: i32 = 18;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const EMFILE: i32 = 24;
+const ENOSPC: i32 = 28;
+const ESPIPE: i32 = 29;
+const EROFS: i32 = 30;
+const EMLINK: i32 = 31;
+const ENOSYS: i32 = 38;
+const ENOTEMPTY: i32 = 39;
+const ELOOP: i32 = 40;
+
+pub fn open(path: &str, flags: u32, mode: u16) -> i32 {
+    ecall_args(SyscallTable::OPEN,
+               path.as_ptr() as u32,
+               path.len() as u32,
+               flags,
+               mode as u32)
+}
+
+pub fn read(fd: u32, buf: &mut [u8]) -> i32 {
+    ecall_args(SyscallTable::READ,
+               fd,
+               buf.as_mut_ptr() as u32,
+               buf.len() as u32,
+               0)
+}
+
+pub fn write(fd: u32, buf: &[u8]) -> i32 {
+    ecall_args(SyscallTable::WRITE,
+               fd,
+               buf.as_ptr() as u32,
+               buf.len() as u32,
+               0)
+}
+
+pub fn close(fd: u32) -> i32 {
+    ecall_args(SyscallTable::CLOSE, fd, 0, 0, 0)
+}
+
+pub fn lseek(fd: u32, offset: i32, whence: u32) -> i32 {
+    ecall_args(SyscallTable::LSEEK, fd, offset as u32, whence, 0)
+}
+
+pub fn dup(fd: u32) -> i32 {
+    ecall_args(SyscallTable::DUP, fd, 0, 0, 0)
+}
+
+pub fn stat(path: &str, metadata: &mut Metadata) -> i32 {
+    ecall_args(SyscallTable::STAT,
+               path.as_ptr() as u32,
+               path.len() as u32,
+               metadata as *mut Metadata as u32,
+               0)
+}
+
+pub fn fstat(fd: u32, metadata: &mut Metadata) -> i32 {
+    ecall_args(SyscallTable::FSTAT,
+               fd,
+               metadata as *mut Metadata as u32,
+               0,
+               0)
+}
+
+pub fn _open(path: u32, length: u32, flags: u32, mode: u32) -> i32 {
+    let path = unsafe {
+        core::slice::from_raw_parts(path as *const u8, length as usize)
+    };
+    match core::str::from_utf8(path) {
+        Ok(path) => {
+            to_return(current_proc().files.open(path, flags, mode as u16))
+        }
+        Err(_) => error_number(FsError::InvalidName),
+    }
+}
+
+pub fn _read(fd: u32, buf: u32, length: u32) -> i32 {
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(buf as *mut u8, length as usize)
+    };
+    to_return(current_proc().files.read(fd as usize, buf))
+}
+
+pub fn _write(fd: u32, buf: u32, length: u32) -> i32 {
+    let buf = unsafe {
+        core::slice::from_raw_parts(buf as *const u8, length as usize)
+    };
+    to_return(current_proc().files.write(fd as usize, buf))
+}
+
+pub fn _close(fd: u32) -> i32 {
+    to_return(current_proc().files.close(fd as usize).map(|_| 0))
+}
+
+pub fn _lseek(fd: u32, offset: u32, whence: u32) -> i32 {
+    to_return(current_proc().files
+                            .lseek(fd as usize, offset as i32, whence)
+                            .map(|offset| offset as usize))
+}
+
+pub fn _dup(fd: u32) -> i32 {
+    to_return(current_proc().files.dup(fd as usize))
+}
+
+pub fn _stat(path: u32, length: u32, metadata: u32) -> i32 {
+    let path = unsafe {
+        core::slice::from_raw_parts(path as *const u8, length as usize)
+    };
+    let path = match core::str::from_utf8(path) {
+        Ok(path) => path,
+        Err(_) => return error_number(FsError::InvalidName),
+    };
+
+    let result = crate::vfs::vfs().and_then(|vfs| vfs.stat(path));
+    to_return(result.map(|result| store_metadata(metadata, result)))
+}
+
+pub fn _fstat(fd: u32, metadata: u32) -> i32 {
+    let result = current_proc().files.stat(fd as usize);
+    to_return(result.map(|result| store_metadata(metadata, result)))
+}
+
+// Copies |metadata| to the caller's buffer at |address|, returning the 0 the
+// stat calls give back when they succeed
+fn store_metadata(address: u32, metadata: Metadata) -> usize {
+    unsafe {
+        core::ptr::write_volatile(address as *mut Metadata, metadata);
+    }
+    0
+}
+
+fn current_proc() -> &'static mut ProcessControlBlock {
+    unsafe { (*GLOBAL_SCHED).get_current_proc() }
+}
+
+fn to_return(result: Result<usize, FsError>) -> i32 {
+    match result {
+        Ok(value) => value as i32,
+        Err(e) => error_number(e),
+    }
+}
+
+// Converts |error| to the negated Linux errno that means the same thing
+pub fn error_number(error: FsError) -> i32 {
+    let errno = match error {
+        FsError::NotFound => ENOENT,
+        FsError::BadDescriptor => EBADF,
+        FsError::OutOfMemory => ENOMEM,
+        FsError::Busy => EBUSY,
+        FsError::AlreadyExists => EEXIST,
+        FsError::CrossDevice => EXDEV,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::InvalidName | FsError::InvalidArgument => EINVAL,
+        FsError::TooManyOpenFiles => EMFILE,
+        FsError::NoSpace => ENOSPC,
+        FsError::IllegalSeek => ESPIPE,
+        FsError::ReadOnly | FsError::NeedsRecovery => EROFS,
+        FsError::TooManyLinks => EMLINK,
+        FsError::NotSupported => ENOSYS,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::TooManySymlinks => ELOOP,
+        _ => EIO,
+    };
+    -errno
+}
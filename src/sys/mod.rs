@@ -1,3 +1,5 @@
 pub mod ecall;
 pub mod exit;
+pub mod file;
+pub mod spawn;
 pub mod table;
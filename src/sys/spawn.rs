@@ -0,0 +1,42 @@
+// Starting processes that run programs from the filesystem. spawn returns the
+// new process's pid, or a negated Linux error number if it fails.
+
+use crate::filesystem::FsError;
+use crate::loader::{self, LoadError};
+
+use super::ecall::ecall_args;
+use super::file::error_number;
+use super::table::SyscallTable;
+
+// Linux error numbers for the ways loading a program can fail that aren't
+// filesystem errors
+const E2BIG: i32 = 7;
+const ENOEXEC: i32 = 8;
+const EAGAIN: i32 = 11;
+
+pub fn spawn(path: &str) -> i32 {
+    ecall_args(SyscallTable::SPAWN,
+               path.as_ptr() as u32,
+               path.len() as u32,
+               0,
+               0)
+}
+
+pub fn _spawn(path: u32, length: u32) -> i32 {
+    let path = unsafe {
+        core::slice::from_raw_parts(path as *const u8, length as usize)
+    };
+    let path = match core::str::from_utf8(path) {
+        Ok(path) => path,
+        Err(_) => return error_number(FsError::InvalidName),
+    };
+
+    match loader::spawn(path) {
+        Ok(pid) => pid as i32,
+        Err(LoadError::Fs(e)) => error_number(e),
+        Err(LoadError::OutOfMemory) => error_number(FsError::OutOfMemory),
+        Err(LoadError::TooBig) => -E2BIG,
+        Err(LoadError::TooManyProcesses) => -EAGAIN,
+        Err(_) => -ENOEXEC,
+    }
+}
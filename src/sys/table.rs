@@ -6,4 +6,12 @@ pub enum SyscallTable {
     SLEEP = 3,
     READ = 4,
     PRINT = 5,
+    OPEN = 6,
+    WRITE = 7,
+    CLOSE = 8,
+    LSEEK = 9,
+    DUP = 10,
+    STAT = 11,
+    FSTAT = 12,
+    SPAWN = 13,
 }
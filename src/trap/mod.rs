@@ -120,6 +120,55 @@ pub extern "C" fn handle_trap(mcause: u32, mut mepc: u32) -> u32 {
                   sys::exit::_exit(arg);
                   unsafe { (*GLOBAL_SCHED).run(mepc); }
                 },
+                SyscallTable::OPEN => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_open(args[0],
+                                                                    args[1],
+                                                                    args[2],
+                                                                    args[3]));
+                }
+                SyscallTable::READ => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_read(args[0],
+                                                                    args[1],
+                                                                    args[2]));
+                }
+                SyscallTable::WRITE => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_write(args[0],
+                                                                     args[1],
+                                                                     args[2]));
+                }
+                SyscallTable::CLOSE => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_close(args[0]));
+                }
+                SyscallTable::LSEEK => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_lseek(args[0],
+                                                                     args[1],
+                                                                     args[2]));
+                }
+                SyscallTable::DUP => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_dup(args[0]));
+                }
+                SyscallTable::STAT => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_stat(args[0],
+                                                                    args[1],
+                                                                    args[2]));
+                }
+                SyscallTable::FSTAT => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::file::_fstat(args[0],
+                                                                     args[1]));
+                }
+                SyscallTable::SPAWN => {
+                    let args = sys::ecall::syscall_args();
+                    sys::ecall::set_syscall_return(sys::spawn::_spawn(args[0],
+                                                                      args[1]));
+                }
                 _ => {
                     println!("Unimplemented, panic-ing");
                     panic!();
@@ -148,13 +197,13 @@ pub extern "C" fn handle_trap(mcause: u32, mut mepc: u32) -> u32 {
     }
 
     // Compressed instructions are 2 bytes, while uncompressed are 4 bytes.
-    // If the lowest 2 bits of the instruction are 0b00, then the instruction is
+    // If the lowest 2 bits of the instruction are 0b11, then the instruction is
     // uncompressed, and if anything else, then the instruction is compressed, so
     // we can then determine how much to increment mepc by to return to the
     // correct instruction after the trap has been handled.
-    if (next_instruction & 0x3) != 0 {
-        mepc + 2
-    } else {
+    if next_instruction & 0x3 == 0x3 {
         mepc + 4
+    } else {
+        mepc + 2
     }
 }
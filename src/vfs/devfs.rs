@@ -0,0 +1,281 @@
+// devfs, a filesystem whose files are character devices. Reading or writing
+// one of them goes straight to its driver, so processes can open /dev/null or
+// /dev/console and use them through file descriptors like any other file.
+//
+// /dev/console  the UART
+// /dev/null     reads nothing and throws away what's written
+// /dev/zero     reads as many zero bytes as asked for
+// /dev/random   reads pseudorandom bytes, seeded from jitter in mtime
+
+use super::{DirEntry, FileOperations, FileType, InodeOperations, Metadata};
+use crate::console::Console;
+use crate::filesystem::FsError;
+use crate::trap::timer::get_current_time;
+
+// Inode number of the root directory. The devices follow it in the order of
+// DEVICES.
+const ROOT_INODE: u32 = 1;
+
+// Inode number of the console, which processes are also given as their
+// standard input and output without going through /dev
+pub const CONSOLE_INODE: u32 = ROOT_INODE + 1;
+
+// The console as a device
+pub const CONSOLE: &dyn CharDevice = &Console;
+
+// Every device in /dev
+const DEVICES: [Device; 4] = [Device { name: "console",
+                                       device: CONSOLE,
+                                       permissions: 0o620 },
+                              Device { name: "null",
+                                       device: &Null,
+                                       permissions: 0o666 },
+                              Device { name: "zero",
+                                       device: &Zero,
+                                       permissions: 0o666 },
+                              Device { name: "random",
+                                       device: &Random,
+                                       permissions: 0o666 }];
+
+// Most times jitter spins waiting for mtime to change, in case the timer
+// isn't running
+const MAX_JITTER_SPINS: u32 = 1 << 16;
+
+// Number of jitter samples folded into the state of /dev/random before each
+// read
+const JITTER_SAMPLES: u32 = 8;
+
+// State of the generator behind /dev/random
+static mut RANDOM_STATE: u32 = 0;
+
+// A device that reads and writes a stream of bytes rather than blocks at an
+// offset. Devices keep any state of their own, so they're shared between
+// every file that opens them.
+pub trait CharDevice {
+    // Reads into |buf| and returns the number of bytes read, which is 0 at
+    // the end of the input
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    // Writes |buf| and returns the number of bytes written
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError>;
+}
+
+pub struct DevFs;
+
+struct Device {
+    name: &'static str,
+    device: &'static dyn CharDevice,
+    permissions: u16,
+}
+
+struct Null;
+
+struct Zero;
+
+// Not suitable for anything that needs to be hard to guess, since it's a
+// xorshift generator with at most 32 bits of state
+struct Random;
+
+impl DevFs {
+    pub fn new() -> DevFs {
+        DevFs
+    }
+}
+
+impl CharDevice for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(Console::read_bytes(buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Console::write_bytes(buf).ok();
+        Ok(buf.len())
+    }
+}
+
+impl CharDevice for Null {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut state = unsafe { RANDOM_STATE };
+        for _ in 0..JITTER_SAMPLES {
+            state = state.rotate_left(7) ^ jitter();
+        }
+
+        for chunk in buf.chunks_mut(4) {
+            // xorshift gets stuck at 0
+            if state == 0 {
+                state = 0x2545_F491;
+            }
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (state >> (i * 8)) as u8;
+            }
+        }
+
+        unsafe {
+            RANDOM_STATE = state;
+        }
+        Ok(buf.len())
+    }
+
+    // What's written is mixed into the state, like on Unix
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        unsafe {
+            for byte in buf.iter() {
+                RANDOM_STATE = RANDOM_STATE.rotate_left(8) ^ *byte as u32;
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+impl InodeOperations for DevFs {
+    fn root(&self) -> u32 {
+        ROOT_INODE
+    }
+
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError> {
+        Ok(self.metadata(inode)?.file_type)
+    }
+
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError> {
+        let (file_type, permissions, links) = if inode == ROOT_INODE {
+            (FileType::Directory, 0o755, 2)
+        } else {
+            (FileType::CharDevice, device(inode)?.permissions, 1)
+        };
+
+        Ok(Metadata { inode: inode,
+                      file_type: file_type,
+                      permissions: permissions,
+                      uid: 0,
+                      gid: 0,
+                      size: 0,
+                      links: links,
+                      atime: 0,
+                      mtime: 0,
+                      ctime: 0,
+                      blocks: 0 })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        if dir != ROOT_INODE {
+            device(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+        if name == "." || name == ".." {
+            return Ok(ROOT_INODE);
+        }
+
+        match DEVICES.iter().position(|device| device.name == name) {
+            Some(index) => Ok(ROOT_INODE + 1 + index as u32),
+            None => Err(FsError::NotFound),
+        }
+    }
+}
+
+// Devices don't have offsets, so those are ignored
+impl FileOperations for DevFs {
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError> {
+        if inode == ROOT_INODE {
+            return Err(FsError::IsADirectory);
+        }
+        device(inode)?.device.read(buf)
+    }
+
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        if inode == ROOT_INODE {
+            return Err(FsError::IsADirectory);
+        }
+        device(inode)?.device.write(buf)
+    }
+
+    // Devices have no size to change, so opening one with O_TRUNC works
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        if inode == ROOT_INODE {
+            return Err(FsError::IsADirectory);
+        }
+        device(inode).map(|_| ())
+    }
+
+    // The root directory lists ".", ".." and then the devices in order
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError> {
+        if dir != ROOT_INODE {
+            device(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+
+        let entry = match position {
+            0 => DirEntry::new(ROOT_INODE, FileType::Directory, b"."),
+            1 => DirEntry::new(ROOT_INODE, FileType::Directory, b".."),
+            _ => {
+                let index = position - 2;
+                match DEVICES.get(index as usize) {
+                    Some(device) => DirEntry::new(ROOT_INODE + 1 + index,
+                                                  FileType::CharDevice,
+                                                  device.name.as_bytes()),
+                    None => return Ok(None),
+                }
+            }
+        };
+        Ok(Some((entry, position + 1)))
+    }
+}
+
+// Returns the device numbered |inode|
+fn device(inode: u32) -> Result<&'static Device, FsError> {
+    if inode <= ROOT_INODE {
+        return Err(FsError::InvalidInode);
+    }
+    match DEVICES.get((inode - ROOT_INODE - 1) as usize) {
+        Some(device) => Ok(device),
+        None => Err(FsError::InvalidInode),
+    }
+}
+
+// Spins until mtime ticks over and returns how many times it went around,
+// mixed with the time. How long that takes depends on interrupts, the UART
+// and the board, so the low bits vary from run to run.
+fn jitter() -> u32 {
+    let start = get_current_time();
+    let mut spins: u32 = 0;
+    while get_current_time() == start && spins < MAX_JITTER_SPINS {
+        spins += 1;
+    }
+    spins ^ (get_current_time() as u32).rotate_left(16)
+}
@@ -0,0 +1,349 @@
+// Open files. Every process has a table of file descriptors, small numbers
+// that refer to entries of the system-wide table of open files. An open file
+// holds the offset that reads and writes move along, so descriptors made by
+// dup share it, like on Unix.
+
+use super::devfs::{DevFs, CONSOLE, CONSOLE_INODE};
+use super::{vfs, FileType, InodeOperations, Metadata, VNode};
+use crate::filesystem::FsError;
+use crate::global_constants::{MAX_FDS, MAX_OPEN_FILES};
+use crate::memman::MemManager;
+use core::ptr::write_volatile;
+
+// Access modes, one of which is in the flags of every open file
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+
+// Flags that change how open works, with the same values as Linux
+pub const O_CREAT: u32 = 0o100;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+// Where lseek measures its offset from
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+// The open files of every process
+pub static mut FILE_TABLE: *mut FileTable = core::ptr::null_mut();
+
+// What an open file reads from and writes to
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FileKind {
+    // The console device of devfs, which every process starts out with
+    // whether or not devfs is mounted
+    Console,
+    // A file in the VFS
+    Node(VNode),
+}
+
+#[derive(Copy, Clone)]
+struct OpenFile {
+    kind: FileKind,
+    // The flags the file was opened with
+    flags: u32,
+    // Where the next read or write starts
+    offset: u32,
+    // Number of file descriptors referring to this file
+    references: usize,
+}
+
+pub struct FileTable {
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+// The file descriptors of a process. Each one holds the index of an open
+// file in FILE_TABLE, which is kept to a byte since every process control
+// block holds a table.
+pub struct FdTable {
+    fds: [Option<u8>; MAX_FDS],
+}
+
+impl FileTable {
+    // Sets up the system-wide table with no files open
+    pub fn init() -> Result<(), FsError> {
+        unsafe {
+            let address =
+                match MemManager::kmalloc(core::mem::size_of::<FileTable>()) {
+                    Ok(address) => address as *mut FileTable,
+                    Err(_) => return Err(FsError::OutOfMemory),
+                };
+            write_volatile(address,
+                           FileTable { files: [None; MAX_OPEN_FILES] });
+            FILE_TABLE = address;
+        }
+        Ok(())
+    }
+
+    // Opens |kind| with |flags| and a single reference, and returns its
+    // index in the table
+    fn open(&mut self, kind: FileKind, flags: u32) -> Result<u8, FsError> {
+        let index = self.free_index()?;
+        self.files[index as usize] = Some(OpenFile { kind: kind,
+                                                     flags: flags,
+                                                     offset: 0,
+                                                     references: 1 });
+        Ok(index)
+    }
+
+    // Returns the index of the first free entry
+    fn free_index(&self) -> Result<u8, FsError> {
+        match self.files.iter().position(|file| file.is_none()) {
+            Some(index) => Ok(index as u8),
+            None => Err(FsError::TooManyOpenFiles),
+        }
+    }
+
+    // Adds a reference to the console, which every process shares the same
+    // open file for
+    fn open_console(&mut self) -> Result<u8, FsError> {
+        let console =
+            self.files.iter().position(|file| match file {
+                                 Some(file) => file.kind == FileKind::Console,
+                                 None => false,
+                             });
+        match console {
+            Some(index) => {
+                self.retain(index as u8)?;
+                Ok(index as u8)
+            }
+            None => self.open(FileKind::Console, O_RDWR),
+        }
+    }
+
+    // Adds a reference to the open file |index|
+    fn retain(&mut self, index: u8) -> Result<(), FsError> {
+        self.get(index)?.references += 1;
+        Ok(())
+    }
+
+    // Drops a reference to the open file |index|, closing it after the last
+    fn release(&mut self, index: u8) -> Result<(), FsError> {
+        let file = self.get(index)?;
+        file.references -= 1;
+        if file.references == 0 {
+            self.files[index as usize] = None;
+        }
+        Ok(())
+    }
+
+    // Checks whether any open file is in the filesystem in the slot |mount|
+    // of the kernel's VFS
+    pub fn has_files_on(&self, mount: usize) -> bool {
+        self.files.iter().any(|file| match file {
+                              Some(OpenFile { kind: FileKind::Node(node),
+                                              .. }) => node.mount == mount,
+                              _ => false,
+                          })
+    }
+
+    fn get(&mut self, index: u8) -> Result<&mut OpenFile, FsError> {
+        match self.files.get_mut(index as usize) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(FsError::BadDescriptor),
+        }
+    }
+}
+
+impl FdTable {
+    // Makes a table with no descriptors open
+    pub fn new() -> FdTable {
+        FdTable { fds: [None; MAX_FDS] }
+    }
+
+    // Opens the console as standard input, output and error, descriptors 0,
+    // 1 and 2
+    pub fn open_console(&mut self) -> Result<(), FsError> {
+        for fd in 0..3 {
+            if self.fds[fd].is_some() {
+                return Err(FsError::AlreadyExists);
+            }
+        }
+
+        for fd in 0..3 {
+            let file = match files().open_console() {
+                Ok(file) => file,
+                Err(e) => {
+                    self.close_all();
+                    return Err(e);
+                }
+            };
+            self.fds[fd] = Some(file);
+        }
+        Ok(())
+    }
+
+    // Opens the file at |path| and returns the lowest free descriptor for
+    // it. With O_CREAT, a missing file is created with the permission bits
+    // of |mode|.
+    pub fn open(&mut self,
+                path: &str,
+                flags: u32,
+                mode: u16)
+                -> Result<usize, FsError> {
+        let access = flags & O_ACCMODE;
+        if access != O_RDONLY && access != O_WRONLY && access != O_RDWR {
+            return Err(FsError::InvalidArgument);
+        }
+        // Nothing is created or truncated unless the file can be opened, so
+        // a descriptor and an entry of the system table have to be free.
+        // Nothing below opens other files, so the entry is still free at the
+        // end.
+        let fd = self.free_fd()?;
+        files().free_index()?;
+
+        let vfs = vfs()?;
+        let node = match vfs.lookup(path) {
+            Ok(node) => node,
+            Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+                vfs.create(path, mode)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        if vfs.file_type(node)? == FileType::Directory && access != O_RDONLY {
+            return Err(FsError::IsADirectory);
+        }
+        if flags & O_TRUNC != 0 && access != O_RDONLY {
+            vfs.truncate(node, 0)?;
+        }
+
+        self.fds[fd] = Some(files().open(FileKind::Node(node), flags)?);
+        Ok(fd)
+    }
+
+    // Reads from |fd| into |buf| and returns the number of bytes read, which
+    // is 0 at the end of the file
+    pub fn read(&mut self,
+                fd: usize,
+                buf: &mut [u8])
+                -> Result<usize, FsError> {
+        let file = files().get(self.file(fd)?)?;
+        if file.flags & O_ACCMODE == O_WRONLY {
+            return Err(FsError::BadDescriptor);
+        }
+
+        match file.kind {
+            FileKind::Console => CONSOLE.read(buf),
+            FileKind::Node(node) => {
+                let vfs = vfs()?;
+                if vfs.file_type(node)? == FileType::Directory {
+                    return Err(FsError::IsADirectory);
+                }
+                let count = vfs.read(node, file.offset, buf)?;
+                file.offset += count as u32;
+                Ok(count)
+            }
+        }
+    }
+
+    // Writes |buf| to |fd| and returns the number of bytes written. Files
+    // opened with O_APPEND are always written at their end.
+    pub fn write(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let file = files().get(self.file(fd)?)?;
+        if file.flags & O_ACCMODE == O_RDONLY {
+            return Err(FsError::BadDescriptor);
+        }
+
+        match file.kind {
+            FileKind::Console => CONSOLE.write(buf),
+            FileKind::Node(node) => {
+                let vfs = vfs()?;
+                if file.flags & O_APPEND != 0 {
+                    file.offset = vfs.size(node)?;
+                }
+                let count = vfs.write(node, file.offset, buf)?;
+                file.offset += count as u32;
+                Ok(count)
+            }
+        }
+    }
+
+    // Moves the offset of |fd| to |offset| bytes from the start, the current
+    // offset or the end of the file as |whence| says, and returns the new
+    // offset. The offset can go past the end of the file, but not before its
+    // start.
+    pub fn lseek(&mut self,
+                 fd: usize,
+                 offset: i32,
+                 whence: u32)
+                 -> Result<u32, FsError> {
+        let file = files().get(self.file(fd)?)?;
+        let node = match file.kind {
+            FileKind::Console => return Err(FsError::IllegalSeek),
+            FileKind::Node(node) => node,
+        };
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => file.offset,
+            SEEK_END => vfs()?.size(node)?,
+            _ => return Err(FsError::InvalidArgument),
+        };
+        let position = base as i64 + offset as i64;
+        if position < 0 || position > core::u32::MAX as i64 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        file.offset = position as u32;
+        Ok(file.offset)
+    }
+
+    // Returns the metadata of the file |fd| refers to
+    pub fn stat(&self, fd: usize) -> Result<Metadata, FsError> {
+        match files().get(self.file(fd)?)?.kind {
+            FileKind::Console => DevFs::new().metadata(CONSOLE_INODE),
+            FileKind::Node(node) => vfs()?.metadata(node),
+        }
+    }
+
+    // Makes the lowest free descriptor refer to the same open file as |fd|
+    // and returns it
+    pub fn dup(&mut self, fd: usize) -> Result<usize, FsError> {
+        let file = self.file(fd)?;
+        let new_fd = self.free_fd()?;
+        files().retain(file)?;
+        self.fds[new_fd] = Some(file);
+        Ok(new_fd)
+    }
+
+    // Closes |fd|, which closes its open file if no other descriptor refers
+    // to it
+    pub fn close(&mut self, fd: usize) -> Result<(), FsError> {
+        let file = self.file(fd)?;
+        self.fds[fd] = None;
+        files().release(file)
+    }
+
+    // Closes every descriptor, such as when the process exits
+    pub fn close_all(&mut self) {
+        for fd in 0..MAX_FDS {
+            if self.fds[fd].is_some() {
+                self.close(fd).ok();
+            }
+        }
+    }
+
+    // Returns the index of the open file |fd| refers to
+    fn file(&self, fd: usize) -> Result<u8, FsError> {
+        match self.fds.get(fd) {
+            Some(Some(file)) => Ok(*file),
+            _ => Err(FsError::BadDescriptor),
+        }
+    }
+
+    // Returns the lowest descriptor that isn't open
+    fn free_fd(&self) -> Result<usize, FsError> {
+        match self.fds.iter().position(|fd| fd.is_none()) {
+            Some(fd) => Ok(fd),
+            None => Err(FsError::TooManyOpenFiles),
+        }
+    }
+}
+
+// Returns the system-wide table of open files
+fn files() -> &'static mut FileTable {
+    unsafe { &mut *FILE_TABLE }
+}
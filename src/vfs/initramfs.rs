@@ -0,0 +1,352 @@
+// Unpacking the archive linked into the kernel by asm/initramfs.S, so the
+// root filesystem can be kept in tmpfs rather than read from the ext2 image.
+// The archive can be newc cpio, which is what `cpio -o -H newc` makes, or
+// ustar, which is what `tar --format=ustar` makes.
+//
+// Regular files, directories, symbolic links and hard links are unpacked
+// with their permission bits. Devices, FIFOs and sockets are skipped, since
+// devfs provides the devices, and owners and times aren't kept.
+
+use super::{VNode, Vfs};
+use crate::filesystem::FsError;
+
+extern "C" {
+    static INITRAMFS: u8;
+    static INITRAMFS_END: u8;
+}
+
+// Magic numbers of newc headers, without and with checksums
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+
+// Size of a newc header, which is 6 bytes of magic and then 13 fields of 8
+// hex digits
+const CPIO_HEADER_SIZE: usize = 110;
+
+// Name of the entry that ends a cpio archive
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+// Size of a ustar header and of the blocks file data is padded to
+const TAR_BLOCK_SIZE: usize = 512;
+
+// Where the magic number of a ustar header is, and what it starts with
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+// Longest path a ustar header can hold, a 155 byte prefix and a 100 byte name
+// joined by a slash
+const TAR_MAX_PATH: usize = 256;
+
+// Most files with several names in a cpio archive that can be tracked at
+// once. Further ones are unpacked as separate files.
+const MAX_HARD_LINKS: usize = 16;
+
+// File type bits of a cpio mode
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    // The archive is neither newc cpio nor ustar
+    UnknownFormat,
+    // A header is damaged or runs past the end of the archive
+    Corrupt,
+    // Making one of the files failed
+    Fs(FsError),
+}
+
+impl From<FsError> for ArchiveError {
+    fn from(error: FsError) -> ArchiveError {
+        ArchiveError::Fs(error)
+    }
+}
+
+// What an entry of an archive makes
+enum Kind<'a> {
+    Regular,
+    Directory,
+    Symlink,
+    // Another name for the file at the path
+    HardLink(&'a str),
+    // Something that isn't unpacked, like a device
+    Other,
+}
+
+// Returns the archive linked into the kernel, which is empty unless the
+// kernel was built to boot from one
+pub fn archive() -> &'static [u8] {
+    unsafe {
+        let start = &INITRAMFS as *const u8;
+        let length = &INITRAMFS_END as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+// Unpacks |archive| into the root filesystem of |vfs|. Files already there
+// are added to rather than replaced.
+pub fn unpack(vfs: &mut Vfs, archive: &[u8]) -> Result<(), ArchiveError> {
+    if archive.starts_with(CPIO_MAGIC) || archive.starts_with(CPIO_CRC_MAGIC) {
+        unpack_cpio(vfs, archive)
+    } else if archive.len() >= TAR_BLOCK_SIZE &&
+              archive[TAR_MAGIC_OFFSET..].starts_with(TAR_MAGIC)
+    {
+        unpack_tar(vfs, archive)
+    } else {
+        Err(ArchiveError::UnknownFormat)
+    }
+}
+
+// Unpacks a newc cpio archive. The names and data of each entry are padded
+// to a multiple of 4 bytes from the start of the archive.
+fn unpack_cpio(vfs: &mut Vfs, archive: &[u8]) -> Result<(), ArchiveError> {
+    // The first name unpacked for each file with more than one, by inode
+    // number. The file's data comes with the last of its names.
+    let mut links: [(u32, &str); MAX_HARD_LINKS] = [(0, ""); MAX_HARD_LINKS];
+    let mut link_count = 0;
+
+    let mut offset = 0;
+    loop {
+        let header = slice(archive, offset, CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) &&
+           !header.starts_with(CPIO_CRC_MAGIC)
+        {
+            return Err(ArchiveError::Corrupt);
+        }
+        let field = |index: usize| {
+            let start = CPIO_MAGIC.len() + index * 8;
+            parse_hex(&header[start..start + 8])
+        };
+        let inode = field(0)?;
+        let mode = field(1)?;
+        let links_to_file = field(4)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name includes its terminating NUL
+        let name_start = offset + CPIO_HEADER_SIZE;
+        if name_size == 0 {
+            return Err(ArchiveError::Corrupt);
+        }
+        let name = to_str(slice(archive, name_start, name_size - 1)?)?;
+        let data_start = align(name_start + name_size, 4);
+        let data = slice(archive, data_start, size)?;
+        offset = align(data_start + size, 4);
+
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+
+        let mut kind = match mode & S_IFMT {
+            S_IFREG => Kind::Regular,
+            S_IFDIR => Kind::Directory,
+            S_IFLNK => Kind::Symlink,
+            _ => Kind::Other,
+        };
+        if let Kind::Regular = kind {
+            if links_to_file > 1 {
+                let first = links[..link_count].iter()
+                                               .find(|link| link.0 == inode)
+                                               .map(|link| link.1);
+                match first {
+                    Some(first) => kind = Kind::HardLink(first),
+                    None if link_count < MAX_HARD_LINKS => {
+                        links[link_count] = (inode, name);
+                        link_count += 1;
+                    }
+                    None => {}
+                }
+            }
+        }
+        add(vfs, name, kind, mode as u16, data)?;
+    }
+}
+
+// Unpacks a ustar archive, which ends at the first header of zeros
+fn unpack_tar(vfs: &mut Vfs, archive: &[u8]) -> Result<(), ArchiveError> {
+    let mut offset = 0;
+    while offset + TAR_BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + TAR_BLOCK_SIZE];
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+
+        // The checksum is the sum of the header's bytes with the checksum
+        // field itself counted as spaces
+        let checksum = header.iter()
+                             .enumerate()
+                             .map(|(i, byte)| match i {
+                                 148..=155 => b' ' as u32,
+                                 _ => *byte as u32,
+                             })
+                             .sum::<u32>();
+        if parse_octal(&header[148..156])? != checksum {
+            return Err(ArchiveError::Corrupt);
+        }
+
+        let mode = parse_octal(&header[100..108])?;
+        let size = parse_octal(&header[124..136])? as usize;
+        let data = slice(archive, offset + TAR_BLOCK_SIZE, size)?;
+        offset += TAR_BLOCK_SIZE + align(size, TAR_BLOCK_SIZE);
+
+        let name = to_str(until_nul(&header[..100]))?;
+        let prefix = to_str(until_nul(&header[345..500]))?;
+        let target = to_str(until_nul(&header[157..257]))?;
+        let kind = match header[156] {
+            b'0' | 0 => Kind::Regular,
+            b'1' => Kind::HardLink(target),
+            b'2' => Kind::Symlink,
+            b'5' => Kind::Directory,
+            _ => Kind::Other,
+        };
+        let data = match kind {
+            Kind::Symlink => target.as_bytes(),
+            _ => data,
+        };
+
+        if prefix.is_empty() {
+            add(vfs, name, kind, mode as u16, data)?;
+        } else {
+            let mut path = [0u8; TAR_MAX_PATH];
+            let length = prefix.len() + 1 + name.len();
+            path[..prefix.len()].copy_from_slice(prefix.as_bytes());
+            path[prefix.len()] = b'/';
+            path[prefix.len() + 1..length].copy_from_slice(name.as_bytes());
+            add(vfs, to_str(&path[..length])?, kind, mode as u16, data)?;
+        }
+    }
+
+    // Archives are meant to end with two blocks of zeros, but one that stops
+    // right after its last file is still whole
+    Ok(())
+}
+
+// Makes the file at |path|, relative to the root, along with any
+// directories leading to it that the archive didn't list. Regular files are
+// filled with |data| and symbolic links point to it.
+fn add(vfs: &mut Vfs,
+       path: &str,
+       kind: Kind,
+       mode: u16,
+       data: &[u8])
+       -> Result<(), ArchiveError> {
+    let path = normalize(path);
+    if path.is_empty() {
+        return Ok(());
+    }
+    for (i, byte) in path.bytes().enumerate() {
+        if byte == b'/' {
+            match vfs.mkdir(&path[..i], 0o755) {
+                Ok(_) | Err(FsError::AlreadyExists) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    match kind {
+        Kind::Regular => {
+            let file = vfs.create(path, mode)?;
+            write_all(vfs, file, data)?;
+        }
+        Kind::Directory => match vfs.mkdir(path, mode) {
+            Ok(_) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e.into()),
+        },
+        Kind::Symlink => {
+            vfs.symlink(to_str(data)?, path)?;
+        }
+        Kind::HardLink(existing) => {
+            vfs.link(normalize(existing), path)?;
+            let file = vfs.lookup_link(path)?;
+            write_all(vfs, file, data)?;
+        }
+        Kind::Other => {}
+    }
+    Ok(())
+}
+
+// Writes |data| to the start of |file|
+fn write_all(vfs: &mut Vfs, file: VNode, data: &[u8]) -> Result<(), FsError> {
+    let mut done = 0;
+    while done < data.len() {
+        match vfs.write(file, done as u32, &data[done..])? {
+            0 => return Err(FsError::NoSpace),
+            count => done += count,
+        }
+    }
+    Ok(())
+}
+
+// Strips the "./" and slashes archives put around paths, leaving one
+// relative to the root, or nothing for the root itself
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_matches('/');
+    while path.starts_with("./") {
+        path = path[2..].trim_start_matches('/');
+    }
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+// Returns the |length| bytes of |archive| starting at |offset|
+fn slice(archive: &[u8],
+         offset: usize,
+         length: usize)
+         -> Result<&[u8], ArchiveError> {
+    match offset.checked_add(length) {
+        Some(end) if end <= archive.len() => Ok(&archive[offset..end]),
+        _ => Err(ArchiveError::Corrupt),
+    }
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, ArchiveError> {
+    core::str::from_utf8(bytes).map_err(|_| ArchiveError::Corrupt)
+}
+
+// Returns |field| up to its first NUL
+fn until_nul(field: &[u8]) -> &[u8] {
+    match field.iter().position(|byte| *byte == 0) {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+// Rounds |value| up to a multiple of |alignment|, which is a power of 2
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// Parses a cpio field of hex digits
+fn parse_hex(field: &[u8]) -> Result<u32, ArchiveError> {
+    let mut value: u32 = 0;
+    for byte in field.iter() {
+        let digit = match (*byte as char).to_digit(16) {
+            Some(digit) => digit,
+            None => return Err(ArchiveError::Corrupt),
+        };
+        value = value << 4 | digit;
+    }
+    Ok(value)
+}
+
+// Parses a ustar field of octal digits, which can have spaces before them
+// and a space or NUL after
+fn parse_octal(field: &[u8]) -> Result<u32, ArchiveError> {
+    let mut value: u32 = 0;
+    let digits = field.iter().skip_while(|byte| **byte == b' ');
+    for byte in digits.take_while(|byte| **byte != b' ' && **byte != 0) {
+        let digit = match (*byte as char).to_digit(8) {
+            Some(digit) => digit,
+            None => return Err(ArchiveError::Corrupt),
+        };
+        value = match value.checked_mul(8) {
+            Some(value) => value | digit,
+            None => return Err(ArchiveError::Corrupt),
+        };
+    }
+    Ok(value)
+}
@@ -0,0 +1,298 @@
+// The virtual filesystem layer. Each filesystem implements the inode and
+// file operations below in terms of its own inode numbers, and the mount
+// table stitches the mounted filesystems together into one tree. Paths are
+// walked by the VFS rather than by the filesystems, so that a walk can cross
+// from one filesystem into another.
+
+use crate::filesystem::FsError;
+
+pub mod devfs;
+pub mod file;
+pub mod initramfs;
+pub mod mount;
+pub mod path;
+pub mod procfs;
+pub mod tmpfs;
+
+pub use mount::{vfs, Vfs, VFS};
+
+// Longest name a directory entry can have
+pub const MAX_NAME_LEN: usize = 255;
+
+// Longest symbolic link target that will be followed
+pub const MAX_LINK_LEN: usize = 4095;
+
+// Most symlinks followed while resolving one path before giving up on it as
+// a loop
+pub const MAX_SYMLINK_FOLLOWS: u32 = 8;
+
+// Kinds of files a filesystem can hold
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+// A file anywhere in the tree, named by the mounted filesystem it's on and
+// its inode number within that filesystem
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct VNode {
+    // Index of the filesystem in the mount table
+    pub mount: usize,
+    pub inode: u32,
+}
+
+// What stat reports about a file, in terms that don't depend on the
+// filesystem holding it
+#[repr(C)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Metadata {
+    pub inode: u32,
+    pub file_type: FileType,
+    // Permission bits, along with the setuid, setgid and sticky bits
+    pub permissions: u16,
+    pub uid: u16,
+    pub gid: u16,
+    // Size of the file in bytes
+    pub size: u32,
+    // Number of directory entries naming the file
+    pub links: u16,
+    // Times the file was last accessed, modified, and had its inode changed,
+    // in seconds since Jan 1st 1970
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    // Number of 512-byte sectors the file takes up on disk
+    pub blocks: u32,
+}
+
+// What statfs reports about a mounted filesystem
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct FsStats {
+    // Size of a block in bytes
+    pub block_size: u32,
+    pub blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+}
+
+// A directory entry read through the VFS
+pub struct DirEntry {
+    pub inode: u32,
+    pub file_type: FileType,
+    name_len: usize,
+    name: [u8; MAX_NAME_LEN],
+}
+
+// Operations on the directories and links of a filesystem. Each one works
+// on a single directory and a name within it.
+pub trait InodeOperations {
+    // Returns the inode number of the root directory
+    fn root(&self) -> u32;
+
+    // Returns the type of the file |inode|
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError>;
+
+    // Returns the metadata of the file |inode|
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError>;
+
+    // Returns the size of the file |inode| in bytes
+    fn size(&self, inode: u32) -> Result<u32, FsError> {
+        Ok(self.metadata(inode)?.size)
+    }
+
+    // Finds the entry |name| in the directory |dir|. Symbolic links are
+    // returned as they are.
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError>;
+
+    // Copies the target of the symbolic link |inode| into |buf| and returns
+    // its length
+    fn read_link(&self, inode: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    // Creates an empty regular file |name| in |dir| and returns its inode
+    fn create(&mut self,
+              dir: u32,
+              name: &str,
+              mode: u16)
+              -> Result<u32, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Creates an empty directory |name| in |dir| and returns its inode
+    fn mkdir(&mut self,
+             dir: u32,
+             name: &str,
+             mode: u16)
+             -> Result<u32, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Creates a symbolic link |name| in |dir| pointing to |target| and
+    // returns its inode
+    fn symlink(&mut self,
+               dir: u32,
+               name: &str,
+               target: &str)
+               -> Result<u32, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Adds |name| in |dir| as another name for |inode|
+    fn link(&mut self,
+            inode: u32,
+            dir: u32,
+            name: &str)
+            -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Removes the entry |name| for a file other than a directory from |dir|
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Removes the empty directory |name| from |dir|
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Moves the entry |old_name| of |old_dir| to |new_name| in |new_dir|
+    fn rename(&mut self,
+              old_dir: u32,
+              old_name: &str,
+              new_dir: u32,
+              new_name: &str)
+              -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+// Operations on the contents of files and directories
+pub trait FileOperations {
+    // Reads from |inode| starting at |offset| into |buf| and returns the
+    // number of bytes read, which is 0 at the end of the file
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError>;
+
+    // Writes |buf| into |inode| starting at |offset| and returns the number
+    // of bytes written
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Sets the size of |inode| to |size|
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Reads the first entry of the directory |dir| at or after |position|,
+    // which starts at 0. Returns the entry with the position to continue
+    // from, or None after the last entry.
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError>;
+
+    // Returns how big the filesystem is and how much of it is free
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Writes back any changes the filesystem is holding on to
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+// A filesystem that can be mounted
+pub trait FileSystem: InodeOperations + FileOperations {}
+
+impl<T: InodeOperations + FileOperations> FileSystem for T {}
+
+impl Metadata {
+    // Checks whether the file is a directory
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    // Checks whether the file is a regular file
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::Regular
+    }
+
+    // Checks whether the file is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+}
+
+// Formats the metadata like the start of a line of `ls -l`, such as
+// "drwxr-xr-x  2    0    0     1024", so a listing only needs to add the name
+impl core::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let kind = match self.file_type {
+            FileType::Regular | FileType::Unknown => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+        };
+        write!(f, "{}", kind)?;
+
+        // Owner, group, then everyone else
+        for shift in [6, 3, 0].iter() {
+            let bits = self.permissions >> shift;
+            write!(f,
+                   "{}{}{}",
+                   if bits & 0o4 != 0 { 'r' } else { '-' },
+                   if bits & 0o2 != 0 { 'w' } else { '-' },
+                   if bits & 0o1 != 0 { 'x' } else { '-' })?;
+        }
+
+        write!(f,
+               " {:>2} {:>4} {:>4} {:>8}",
+               self.links, self.uid, self.gid, self.size)
+    }
+}
+
+impl DirEntry {
+    // Makes an entry for |inode| named |name|, which is cut short if it's
+    // longer than MAX_NAME_LEN
+    pub fn new(inode: u32, file_type: FileType, name: &[u8]) -> DirEntry {
+        let mut entry = DirEntry { inode: inode,
+                                   file_type: file_type,
+                                   name_len: 0,
+                                   name: [0; MAX_NAME_LEN] };
+        entry.name_len = core::cmp::min(name.len(), MAX_NAME_LEN);
+        entry.name[..entry.name_len].copy_from_slice(&name[..entry.name_len]);
+        entry
+    }
+
+    // Returns the bytes of the entry's name
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    // Returns the entry's name, or "?" if it isn't valid UTF-8
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(self.name_bytes()).unwrap_or("?")
+    }
+}
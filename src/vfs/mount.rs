@@ -0,0 +1,244 @@
+// The mount table. The root filesystem is mounted at "/" first, and every
+// other filesystem is mounted on a directory of one mounted before it, which
+// it hides until it's unmounted. The VFS owns the mounted filesystems and
+// drops them when they're unmounted.
+
+use super::file::FILE_TABLE;
+use super::{FileSystem, FileType, VNode};
+use crate::filesystem::FsError;
+use crate::global_constants::MAX_MOUNTS;
+use crate::memman::MemManager;
+use core::cmp::max;
+use core::ptr::write_volatile;
+
+// Slot of the root filesystem in the mount table
+const ROOT_MOUNT: usize = 0;
+
+// The VFS the kernel opens files through
+pub static mut VFS: *mut Vfs = core::ptr::null_mut();
+
+// Returns the kernel's VFS, if it's been set up
+pub fn vfs() -> Result<&'static mut Vfs, FsError> {
+    unsafe {
+        if VFS.is_null() {
+            return Err(FsError::NotFound);
+        }
+        Ok(&mut *VFS)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Mount {
+    fs: *mut dyn FileSystem,
+    // Directory the filesystem is mounted on, or None for the root
+    // filesystem
+    covered: Option<VNode>,
+}
+
+pub struct Vfs {
+    mounts: [Option<Mount>; MAX_MOUNTS],
+}
+
+impl Vfs {
+    // Sets up the kernel's VFS with nothing mounted yet
+    pub fn init() -> Result<(), FsError> {
+        unsafe {
+            let address = match MemManager::kmalloc(core::mem::size_of::<Vfs>())
+            {
+                Ok(address) => address as *mut Vfs,
+                Err(_) => return Err(FsError::OutOfMemory),
+            };
+            write_volatile(address, Vfs::new());
+            VFS = address;
+        }
+        Ok(())
+    }
+
+    // Creates a VFS with nothing mounted
+    pub fn new() -> Vfs {
+        Vfs { mounts: [None; MAX_MOUNTS] }
+    }
+
+    // Mounts |fs| on the directory at |path| and returns its slot in the
+    // mount table. The first filesystem has to be mounted at "/".
+    pub fn mount<F: FileSystem + 'static>(&mut self,
+                                          path: &str,
+                                          fs: F)
+                                          -> Result<usize, FsError> {
+        let covered = if self.mounts[ROOT_MOUNT].is_none() {
+            if !path.starts_with('/') || !path.trim_matches('/').is_empty() {
+                return Err(FsError::NotFound);
+            }
+            None
+        } else {
+            // Mounting on the root of another mount would hide it without
+            // any way to get back to what's below
+            let node = self.lookup(path)?;
+            if self.is_mount_root(node) {
+                return Err(FsError::Busy);
+            }
+            if self.filesystem(node.mount)?.file_type(node.inode)? !=
+               FileType::Directory
+            {
+                return Err(FsError::NotADirectory);
+            }
+            Some(node)
+        };
+
+        let slot = match self.mounts.iter().position(|mount| mount.is_none()) {
+            Some(slot) => slot,
+            None => return Err(FsError::TooManyMounts),
+        };
+
+        // The VFS owns the filesystem from here on, so it gets a copy on the
+        // heap
+        let size = max(core::mem::size_of::<F>(), 1);
+        let address = match MemManager::kmalloc(size) {
+            Ok(address) => address as *mut F,
+            Err(_) => return Err(FsError::OutOfMemory),
+        };
+        unsafe {
+            write_volatile(address, fs);
+        }
+
+        self.mounts[slot] = Some(Mount { fs: address as *mut dyn FileSystem,
+                                         covered: covered });
+        Ok(slot)
+    }
+
+    // Syncs and drops the filesystem mounted at |path|, uncovering the
+    // directory it was mounted on. It stays mounted while files in it are
+    // open.
+    pub fn unmount(&mut self, path: &str) -> Result<(), FsError> {
+        let node = self.lookup(path)?;
+        if !self.is_mount_root(node) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        // Filesystems mounted inside this one have to go first
+        if self.has_children(node.mount) {
+            return Err(FsError::Busy);
+        }
+        // Open files refer to the filesystem by its slot, which the next
+        // mount would take over
+        if self.has_open_files(node.mount) {
+            return Err(FsError::Busy);
+        }
+
+        self.filesystem_mut(node.mount)?.sync()?;
+        self.release(node.mount);
+        Ok(())
+    }
+
+    // Drops the filesystem in the slot |mount| and frees the slot
+    fn release(&mut self, mount: usize) {
+        if let Some(mount) = self.mounts[mount].take() {
+            unsafe {
+                core::ptr::drop_in_place(mount.fs);
+            }
+            MemManager::kfree(mount.fs as *mut u8 as u32).ok();
+        }
+    }
+
+    // Checks whether any filesystem is mounted on a directory of the one in
+    // the slot |mount|
+    fn has_children(&self, mount: usize) -> bool {
+        self.mounts.iter().any(|child| match child {
+                              Some(Mount { covered: Some(covered),
+                                           .. }) => covered.mount == mount,
+                              _ => false,
+                          })
+    }
+
+    // Checks whether any file in the slot |mount| is open. Files are only
+    // ever opened through the kernel's VFS.
+    fn has_open_files(&self, mount: usize) -> bool {
+        unsafe {
+            self as *const Vfs == VFS as *const Vfs &&
+            !FILE_TABLE.is_null() &&
+            (*FILE_TABLE).has_files_on(mount)
+        }
+    }
+
+    // Returns the root directory of the whole tree
+    pub fn root(&self) -> Result<VNode, FsError> {
+        Ok(VNode { mount: ROOT_MOUNT,
+                   inode: self.filesystem(ROOT_MOUNT)?.root() })
+    }
+
+    // Returns the filesystem mounted in the slot |mount|
+    pub fn filesystem(&self,
+                      mount: usize)
+                      -> Result<&(dyn FileSystem + 'static), FsError> {
+        match self.mounts.get(mount) {
+            Some(Some(mount)) => Ok(unsafe { &*mount.fs }),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    pub fn filesystem_mut(
+        &mut self,
+        mount: usize)
+        -> Result<&mut (dyn FileSystem + 'static), FsError> {
+        match self.mounts.get(mount) {
+            Some(Some(mount)) => Ok(unsafe { &mut *mount.fs }),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    // Writes back the changes held by every mounted filesystem
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        let mut result = Ok(());
+        for slot in 0..MAX_MOUNTS {
+            if let Ok(fs) = self.filesystem_mut(slot) {
+                if let Err(e) = fs.sync() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    // Checks whether |node| is the root directory of its filesystem
+    pub(super) fn is_mount_root(&self, node: VNode) -> bool {
+        match self.filesystem(node.mount) {
+            Ok(fs) => fs.root() == node.inode,
+            Err(_) => false,
+        }
+    }
+
+    // Returns the slot of the filesystem mounted on |node|, if any
+    pub(super) fn mounted_on(&self, node: VNode) -> Option<usize> {
+        self.mounts.iter().position(|mount| match mount {
+                              Some(mount) => mount.covered == Some(node),
+                              None => false,
+                          })
+    }
+
+    // Returns the directory the filesystem in |mount| is mounted on, or
+    // None for the root filesystem
+    pub(super) fn covered(&self, mount: usize) -> Option<VNode> {
+        match self.mounts.get(mount) {
+            Some(Some(mount)) => mount.covered,
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Vfs {
+    // Syncs and drops everything that's still mounted, which also gives the
+    // disks they were on back to the buffer cache
+    fn drop(&mut self) {
+        // Filesystems go before the ones they're mounted on
+        while let Some(mount) = (0..MAX_MOUNTS).find(|mount| {
+                                    self.mounts[*mount].is_some() &&
+                                    !self.has_children(*mount)
+                                })
+        {
+            if let Ok(fs) = self.filesystem_mut(mount) {
+                fs.sync().ok();
+            }
+            self.release(mount);
+        }
+    }
+}
@@ -0,0 +1,378 @@
+// Walking paths through the mounted filesystems, and the operations that
+// take paths. Symbolic links are followed here rather than inside each
+// filesystem, since a link's target can lead onto another filesystem. ext2
+// Devices used on their own walk their paths with the same code.
+
+use super::{DirEntry,
+            FileType,
+            Metadata,
+            VNode,
+            Vfs,
+            MAX_LINK_LEN,
+            MAX_SYMLINK_FOLLOWS};
+use crate::filesystem::FsError;
+use crate::memman::MemManager;
+
+// A tree of directories that paths can be walked through: the whole VFS, or
+// a single filesystem that isn't mounted
+pub trait Namespace {
+    // What names a file in the tree
+    type Node: Copy;
+
+    // Returns the root directory of the tree
+    fn root_node(&self) -> Result<Self::Node, FsError>;
+
+    // Moves from the directory |dir| to its entry |name|. Symbolic links are
+    // returned as they are.
+    fn step(&self, dir: Self::Node, name: &str) -> Result<Self::Node, FsError>;
+
+    // Checks whether |node| is a symbolic link
+    fn is_link(&self, node: Self::Node) -> Result<bool, FsError>;
+
+    // Returns the length of the target of the symbolic link |node|
+    fn link_length(&self, node: Self::Node) -> Result<usize, FsError>;
+
+    // Copies the target of the symbolic link |node| into |buf| and returns
+    // its length
+    fn link_target(&self,
+                   node: Self::Node,
+                   buf: &mut [u8])
+                   -> Result<usize, FsError>;
+}
+
+// Walks |path| from the directory |start|, or from the root if the path is
+// absolute. Symbolic links met along the way are followed, and so is the
+// final component when |follow_last| is set.
+pub fn resolve<N: Namespace + ?Sized>(namespace: &N,
+                                      start: N::Node,
+                                      path: &str,
+                                      follow_last: bool)
+                                      -> Result<N::Node, FsError> {
+    let mut followed = 0;
+    walk(namespace, start, path, follow_last, &mut followed)
+}
+
+// Splits |path| into the directory holding its final component and the
+// component's name
+pub fn entry_location<'a, N: Namespace + ?Sized>(
+    namespace: &N,
+    path: &'a str)
+    -> Result<(N::Node, &'a str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent_path, name) = match path.rfind('/') {
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => ("", path),
+    };
+
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidName);
+    }
+
+    let root = namespace.root_node()?;
+    Ok((resolve(namespace, root, parent_path, true)?, name))
+}
+
+// Does the work of resolve. |followed| counts the links followed so far so
+// that loops can be caught.
+fn walk<N: Namespace + ?Sized>(namespace: &N,
+                               start: N::Node,
+                               path: &str,
+                               follow_last: bool,
+                               followed: &mut u32)
+                               -> Result<N::Node, FsError> {
+    let mut node = if path.starts_with('/') {
+        namespace.root_node()?
+    } else {
+        start
+    };
+
+    // Leading, trailing and repeated slashes give empty components
+    let mut components =
+        path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = components.next() {
+        let next = namespace.step(node, name)?;
+        let is_last = components.peek().is_none();
+        if (follow_last || !is_last) && namespace.is_link(next)? {
+            // The target is relative to the directory holding the link
+            node = follow_link(namespace, node, next, followed)?;
+        } else {
+            node = next;
+        }
+    }
+
+    Ok(node)
+}
+
+// Resolves the target of the symbolic link |link| found in the directory
+// |dir|
+fn follow_link<N: Namespace + ?Sized>(namespace: &N,
+                                      dir: N::Node,
+                                      link: N::Node,
+                                      followed: &mut u32)
+                                      -> Result<N::Node, FsError> {
+    *followed += 1;
+    if *followed > MAX_SYMLINK_FOLLOWS {
+        return Err(FsError::TooManySymlinks);
+    }
+
+    // Like an empty path, an empty target doesn't name anything
+    let length = namespace.link_length(link)?;
+    if length == 0 {
+        return Err(FsError::NotFound);
+    }
+    if length > MAX_LINK_LEN {
+        return Err(FsError::InvalidArgument);
+    }
+
+    // Targets kept in a block can be as long as one, which is too much for
+    // the stack
+    let address = match MemManager::kmalloc(length) {
+        Ok(address) => address,
+        Err(_) => return Err(FsError::OutOfMemory),
+    };
+    let target = unsafe {
+        core::slice::from_raw_parts_mut(address as *mut u8, length)
+    };
+
+    let result = match namespace.link_target(link, target) {
+        Ok(count) => match core::str::from_utf8(&target[..count]) {
+            Ok(path) => walk(namespace, dir, path, true, followed),
+            Err(_) => Err(FsError::NotFound),
+        },
+        Err(e) => Err(e),
+    };
+
+    let _ = MemManager::kfree(address);
+    result
+}
+
+impl Namespace for Vfs {
+    type Node = VNode;
+
+    fn root_node(&self) -> Result<VNode, FsError> {
+        self.root()
+    }
+
+    // Crosses into a filesystem mounted on the entry or back out of the root
+    // of one
+    fn step(&self, dir: VNode, name: &str) -> Result<VNode, FsError> {
+        if self.filesystem(dir.mount)?.file_type(dir.inode)? !=
+           FileType::Directory
+        {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut dir = dir;
+        if name == "." {
+            return Ok(dir);
+        }
+        if name == ".." {
+            // The parent of a mounted root is the parent of the directory
+            // it's mounted on, and the root of the tree is its own parent
+            while self.is_mount_root(dir) {
+                match self.covered(dir.mount) {
+                    Some(covered) => dir = covered,
+                    None => return Ok(dir),
+                }
+            }
+        }
+
+        let inode = self.filesystem(dir.mount)?.lookup(dir.inode, name)?;
+        let mut node = VNode { mount: dir.mount,
+                               inode: inode };
+
+        // Filesystems can be mounted on top of each other's roots
+        while let Some(mount) = self.mounted_on(node) {
+            node = VNode { mount: mount,
+                           inode: self.filesystem(mount)?.root() };
+        }
+
+        Ok(node)
+    }
+
+    fn is_link(&self, node: VNode) -> Result<bool, FsError> {
+        Ok(self.file_type(node)? == FileType::Symlink)
+    }
+
+    fn link_length(&self, node: VNode) -> Result<usize, FsError> {
+        Ok(self.size(node)? as usize)
+    }
+
+    fn link_target(&self,
+                   node: VNode,
+                   buf: &mut [u8])
+                   -> Result<usize, FsError> {
+        self.filesystem(node.mount)?.read_link(node.inode, buf)
+    }
+}
+
+impl Vfs {
+    // Resolves an absolute path such as "/etc/motd" to the file it names,
+    // following symbolic links along the way
+    pub fn lookup(&self, path: &str) -> Result<VNode, FsError> {
+        resolve(self, self.root()?, path, true)
+    }
+
+    // Like lookup, but relative paths start at the directory |dir|
+    pub fn lookup_from(&self,
+                       dir: VNode,
+                       path: &str)
+                       -> Result<VNode, FsError> {
+        resolve(self, dir, path, true)
+    }
+
+    // Resolves a path like lookup, but if the final component is a symbolic
+    // link, returns the link itself rather than what it points to
+    pub fn lookup_link(&self, path: &str) -> Result<VNode, FsError> {
+        resolve(self, self.root()?, path, false)
+    }
+
+    // Checks that the entry |name| of |dir| doesn't have a filesystem
+    // mounted on it, which would be left hanging if it were removed or moved
+    fn check_not_mounted_on(&self,
+                            dir: VNode,
+                            name: &str)
+                            -> Result<(), FsError> {
+        if let Ok(inode) = self.filesystem(dir.mount)?.lookup(dir.inode, name) {
+            let node = VNode { mount: dir.mount,
+                               inode: inode };
+            if self.mounted_on(node).is_some() {
+                return Err(FsError::Busy);
+            }
+        }
+        Ok(())
+    }
+
+    // Creates an empty regular file at |path| with the permission bits of
+    // |mode|
+    pub fn create(&mut self, path: &str, mode: u16) -> Result<VNode, FsError> {
+        let (dir, name) = entry_location(self, path)?;
+        let inode = self.filesystem_mut(dir.mount)?
+                        .create(dir.inode, name, mode)?;
+        Ok(VNode { mount: dir.mount,
+                   inode: inode })
+    }
+
+    // Creates an empty directory at |path| with the permission bits of
+    // |mode|
+    pub fn mkdir(&mut self, path: &str, mode: u16) -> Result<VNode, FsError> {
+        let (dir, name) = entry_location(self, path)?;
+        let inode = self.filesystem_mut(dir.mount)?
+                        .mkdir(dir.inode, name, mode)?;
+        Ok(VNode { mount: dir.mount,
+                   inode: inode })
+    }
+
+    // Creates a symbolic link at |path| pointing to |target|
+    pub fn symlink(&mut self,
+                   target: &str,
+                   path: &str)
+                   -> Result<VNode, FsError> {
+        let (dir, name) = entry_location(self, path)?;
+        let inode = self.filesystem_mut(dir.mount)?
+                        .symlink(dir.inode, name, target)?;
+        Ok(VNode { mount: dir.mount,
+                   inode: inode })
+    }
+
+    // Adds |new_path| as another name for the file at |existing_path|, which
+    // has to be on the same filesystem
+    pub fn link(&mut self,
+                existing_path: &str,
+                new_path: &str)
+                -> Result<(), FsError> {
+        let node = self.lookup_link(existing_path)?;
+        let (dir, name) = entry_location(self, new_path)?;
+        if dir.mount != node.mount {
+            return Err(FsError::CrossDevice);
+        }
+
+        self.filesystem_mut(dir.mount)?
+            .link(node.inode, dir.inode, name)
+    }
+
+    // Removes the name |path| of a file
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (dir, name) = entry_location(self, path)?;
+        self.check_not_mounted_on(dir, name)?;
+        self.filesystem_mut(dir.mount)?.unlink(dir.inode, name)
+    }
+
+    // Removes the empty directory at |path|
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (dir, name) = entry_location(self, path)?;
+        self.check_not_mounted_on(dir, name)?;
+        self.filesystem_mut(dir.mount)?.rmdir(dir.inode, name)
+    }
+
+    // Moves the entry at |old_path| to |new_path| on the same filesystem
+    pub fn rename(&mut self,
+                  old_path: &str,
+                  new_path: &str)
+                  -> Result<(), FsError> {
+        let (old_dir, old_name) = entry_location(self, old_path)?;
+        let (new_dir, new_name) = entry_location(self, new_path)?;
+        if old_dir.mount != new_dir.mount {
+            return Err(FsError::CrossDevice);
+        }
+        self.check_not_mounted_on(old_dir, old_name)?;
+        self.check_not_mounted_on(new_dir, new_name)?;
+
+        self.filesystem_mut(old_dir.mount)?
+            .rename(old_dir.inode, old_name, new_dir.inode, new_name)
+    }
+
+    // Reads from the file |node| starting at |offset| into |buf|
+    pub fn read(&mut self,
+                node: VNode,
+                offset: u32,
+                buf: &mut [u8])
+                -> Result<usize, FsError> {
+        self.filesystem_mut(node.mount)?
+            .read(node.inode, offset, buf)
+    }
+
+    // Writes |buf| into the file |node| starting at |offset|
+    pub fn write(&mut self,
+                 node: VNode,
+                 offset: u32,
+                 buf: &[u8])
+                 -> Result<usize, FsError> {
+        self.filesystem_mut(node.mount)?
+            .write(node.inode, offset, buf)
+    }
+
+    // Sets the size of the file |node| to |size|
+    pub fn truncate(&mut self, node: VNode, size: u32) -> Result<(), FsError> {
+        self.filesystem_mut(node.mount)?.truncate(node.inode, size)
+    }
+
+    // Returns the type of the file |node|
+    pub fn file_type(&self, node: VNode) -> Result<FileType, FsError> {
+        self.filesystem(node.mount)?.file_type(node.inode)
+    }
+
+    // Returns the metadata of the file at |path|, following symbolic links
+    pub fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        self.metadata(self.lookup(path)?)
+    }
+
+    // Returns the metadata of the file |node|
+    pub fn metadata(&self, node: VNode) -> Result<Metadata, FsError> {
+        self.filesystem(node.mount)?.metadata(node.inode)
+    }
+
+    // Returns the size of the file |node| in bytes
+    pub fn size(&self, node: VNode) -> Result<u32, FsError> {
+        self.filesystem(node.mount)?.size(node.inode)
+    }
+
+    // Reads the entry of the directory |dir| at or after |position| like
+    // FileOperations::read_dir
+    pub fn read_dir(&self,
+                    dir: VNode,
+                    position: u32)
+                    -> Result<Option<(DirEntry, u32)>, FsError> {
+        self.filesystem(dir.mount)?.read_dir(dir.inode, position)
+    }
+}
@@ -0,0 +1,413 @@
+// procfs, a read-only filesystem whose files are made up when they're read.
+// It shows what the kernel is doing right now: a directory for every process
+// the scheduler knows about, along with how much of the heap is in use, how
+// long the system has been up and the Superblock of the root filesystem.
+//
+// /proc/meminfo      heap usage
+// /proc/superblock   size and free space of the root filesystem
+// /proc/uptime       seconds since boot
+// /proc/<pid>/status state, start time and stack of the process
+
+use super::{DirEntry,
+            FileOperations,
+            FileType,
+            FsStats,
+            InodeOperations,
+            Metadata};
+use crate::filesystem::FsError;
+use crate::global_constants::CLOCK_FREQ;
+use crate::memman::MemManager;
+use crate::scheduler::pcb::ProcessControlBlock;
+use crate::trap::timer::get_current_time;
+use crate::utils::heapvec::HeapVec;
+use crate::GLOBAL_SCHED;
+use core::fmt::Write;
+
+// Inode numbers of the files that are always there
+const ROOT_INODE: u32 = 1;
+const MEMINFO_INODE: u32 = 2;
+const SUPERBLOCK_INODE: u32 = 3;
+const UPTIME_INODE: u32 = 4;
+
+// Each process gets a pair of inodes from here on, its directory and then
+// its status file, numbered by its pid
+const FIRST_PROCESS_INODE: u32 = 16;
+
+// The files in the root directory ahead of the process directories
+const ROOT_FILES: [(&str, u32); 3] = [("meminfo", MEMINFO_INODE),
+                                      ("superblock", SUPERBLOCK_INODE),
+                                      ("uptime", UPTIME_INODE)];
+
+// Number of entries in the root directory before the first process, counting
+// "." and ".."
+const ROOT_FIXED_ENTRIES: u32 = 2 + ROOT_FILES.len() as u32;
+
+pub struct ProcFs;
+
+// What an inode number refers to
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Node {
+    Root,
+    MemInfo,
+    Superblock,
+    Uptime,
+    // The directory of the process with this pid
+    Process(u32),
+    // The status file of the process with this pid
+    Status(u32),
+}
+
+// Formats text straight into the part of a file that a read covers, so the
+// whole file never has to be held in memory. Counts the length of all of the
+// text along the way, which is the size of the file.
+struct Window<'a> {
+    buf: &'a mut [u8],
+    // Offset in the file of the first byte of |buf|
+    offset: u32,
+    // Length of the text formatted so far
+    length: u32,
+}
+
+impl ProcFs {
+    pub fn new() -> ProcFs {
+        ProcFs
+    }
+}
+
+impl Node {
+    // Finds what |inode| refers to. Processes come and go, so the inodes of
+    // one that has gone away aren't found.
+    fn from_inode(inode: u32) -> Result<Node, FsError> {
+        let node = match inode {
+            ROOT_INODE => Node::Root,
+            MEMINFO_INODE => Node::MemInfo,
+            SUPERBLOCK_INODE => Node::Superblock,
+            UPTIME_INODE => Node::Uptime,
+            _ if inode >= FIRST_PROCESS_INODE => {
+                let pid = (inode - FIRST_PROCESS_INODE) / 2;
+                if find_process(pid).is_none() {
+                    return Err(FsError::NotFound);
+                }
+                if (inode - FIRST_PROCESS_INODE) % 2 == 0 {
+                    Node::Process(pid)
+                } else {
+                    Node::Status(pid)
+                }
+            }
+            _ => return Err(FsError::InvalidInode),
+        };
+        Ok(node)
+    }
+
+    fn inode(self) -> u32 {
+        match self {
+            Node::Root => ROOT_INODE,
+            Node::MemInfo => MEMINFO_INODE,
+            Node::Superblock => SUPERBLOCK_INODE,
+            Node::Uptime => UPTIME_INODE,
+            Node::Process(pid) => FIRST_PROCESS_INODE + pid * 2,
+            Node::Status(pid) => FIRST_PROCESS_INODE + pid * 2 + 1,
+        }
+    }
+
+    fn file_type(self) -> FileType {
+        match self {
+            Node::Root | Node::Process(_) => FileType::Directory,
+            _ => FileType::Regular,
+        }
+    }
+
+    // Writes the contents of the file into |out|
+    fn generate(self, out: &mut Window) -> Result<(), FsError> {
+        let result = match self {
+            Node::Root | Node::Process(_) => return Err(FsError::IsADirectory),
+            Node::MemInfo => write_meminfo(out),
+            Node::Superblock => write_superblock(&root_stats()?, out),
+            Node::Uptime => write_uptime(out),
+            Node::Status(pid) => match find_process(pid) {
+                Some(process) => write_status(process, out),
+                None => return Err(FsError::NotFound),
+            },
+        };
+
+        // Formatting into a window never fails
+        result.ok();
+        Ok(())
+    }
+}
+
+impl<'a> Window<'a> {
+    // Makes a window onto the text starting at |offset| that fills |buf|
+    fn new(buf: &'a mut [u8], offset: u32) -> Window<'a> {
+        Window { buf: buf,
+                 offset: offset,
+                 length: 0 }
+    }
+
+    // Returns the number of bytes copied into the buffer
+    fn copied(&self) -> usize {
+        let available = self.length.saturating_sub(self.offset) as usize;
+        core::cmp::min(available, self.buf.len())
+    }
+}
+
+impl<'a> Write for Window<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.length >= self.offset {
+                let index = (self.length - self.offset) as usize;
+                if index < self.buf.len() {
+                    self.buf[index] = byte;
+                }
+            }
+            self.length = self.length.saturating_add(1);
+        }
+        Ok(())
+    }
+}
+
+impl InodeOperations for ProcFs {
+    fn root(&self) -> u32 {
+        ROOT_INODE
+    }
+
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError> {
+        Ok(Node::from_inode(inode)?.file_type())
+    }
+
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError> {
+        let node = Node::from_inode(inode)?;
+        let file_type = node.file_type();
+
+        // Files are as long as the text they hold right now, and the root
+        // has a link from the ".." of every process directory
+        let (permissions, size, links) = match node {
+            Node::Root => (0o555, 0, 2 + process_count()),
+            Node::Process(_) => (0o555, 0, 2),
+            _ => {
+                let mut window = Window::new(&mut [], 0);
+                node.generate(&mut window)?;
+                (0o444, window.length, 1)
+            }
+        };
+
+        Ok(Metadata { inode: inode,
+                      file_type: file_type,
+                      permissions: permissions,
+                      uid: 0,
+                      gid: 0,
+                      size: size,
+                      links: links as u16,
+                      atime: 0,
+                      mtime: 0,
+                      ctime: 0,
+                      blocks: 0 })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        match Node::from_inode(dir)? {
+            Node::Root => {
+                if name == "." || name == ".." {
+                    return Ok(ROOT_INODE);
+                }
+                if let Some(&(_, inode)) =
+                    ROOT_FILES.iter().find(|&&(file, _)| file == name)
+                {
+                    return Ok(inode);
+                }
+                match name.parse::<u32>() {
+                    Ok(pid) if find_process(pid).is_some() => {
+                        Ok(Node::Process(pid).inode())
+                    }
+                    _ => Err(FsError::NotFound),
+                }
+            }
+            Node::Process(pid) => match name {
+                "." => Ok(dir),
+                ".." => Ok(ROOT_INODE),
+                "status" => Ok(Node::Status(pid).inode()),
+                _ => Err(FsError::NotFound),
+            },
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create(&mut self,
+              dir: u32,
+              name: &str,
+              mode: u16)
+              -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn mkdir(&mut self,
+             dir: u32,
+             name: &str,
+             mode: u16)
+             -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&mut self,
+               dir: u32,
+               name: &str,
+               target: &str)
+               -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn link(&mut self,
+            inode: u32,
+            dir: u32,
+            name: &str)
+            -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&mut self,
+              old_dir: u32,
+              old_name: &str,
+              new_dir: u32,
+              new_name: &str)
+              -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl FileOperations for ProcFs {
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError> {
+        let mut window = Window::new(buf, offset);
+        Node::from_inode(inode)?.generate(&mut window)?;
+        Ok(window.copied())
+    }
+
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // The root directory lists ".", "..", the fixed files, then the process
+    // directories in the order of the process list, and each process
+    // directory lists ".", ".." and its status file
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError> {
+        let node = Node::from_inode(dir)?;
+        let entry = match (node, position) {
+            (Node::Root, 0) | (Node::Process(_), 0) => {
+                DirEntry::new(dir, FileType::Directory, b".")
+            }
+            (Node::Root, 1) | (Node::Process(_), 1) => {
+                DirEntry::new(ROOT_INODE, FileType::Directory, b"..")
+            }
+            (Node::Root, _) if position < ROOT_FIXED_ENTRIES => {
+                let (name, inode) = ROOT_FILES[position as usize - 2];
+                DirEntry::new(inode, FileType::Regular, name.as_bytes())
+            }
+            (Node::Root, _) => {
+                let index = (position - ROOT_FIXED_ENTRIES) as usize;
+                let pid = match processes() {
+                    Some(list) if index < list.size() => list[index].pid as u32,
+                    _ => return Ok(None),
+                };
+
+                let mut name = [0u8; 10];
+                let mut window = Window::new(&mut name, 0);
+                write!(window, "{}", pid).ok();
+                let length = window.copied();
+                DirEntry::new(Node::Process(pid).inode(),
+                              FileType::Directory,
+                              &name[..length])
+            }
+            (Node::Process(pid), 2) => DirEntry::new(Node::Status(pid).inode(),
+                                                     FileType::Regular,
+                                                     b"status"),
+            (Node::Process(_), _) => return Ok(None),
+            _ => return Err(FsError::NotADirectory),
+        };
+        Ok(Some((entry, position + 1)))
+    }
+}
+
+// Returns the scheduler's process list, or None before it has started
+fn processes() -> Option<&'static HeapVec<ProcessControlBlock>> {
+    unsafe { GLOBAL_SCHED.as_ref() }.map(|scheduler| scheduler.processes())
+}
+
+// Finds the process |pid| in the scheduler's list
+fn find_process(pid: u32) -> Option<&'static ProcessControlBlock> {
+    processes()?.iter()
+                .find(|process| process.pid as u32 == pid)
+}
+
+fn process_count() -> u32 {
+    processes().map_or(0, |list| list.size() as u32)
+}
+
+// Returns the sizes of the filesystem mounted at the root of the kernel's
+// VFS
+fn root_stats() -> Result<FsStats, FsError> {
+    let vfs = super::vfs()?;
+    vfs.filesystem(vfs.root()?.mount)?.statfs()
+}
+
+fn write_meminfo(out: &mut Window) -> core::fmt::Result {
+    let usage = MemManager::usage();
+    writeln!(out, "HeapTotal:\t{} bytes", usage.total)?;
+    writeln!(out, "HeapUsed:\t{} bytes", usage.used)?;
+    writeln!(out, "HeapFree:\t{} bytes", usage.free)?;
+    writeln!(out, "HeapLargestFree:\t{} bytes", usage.largest_free)?;
+    writeln!(out, "Allocations:\t{}", usage.allocations)
+}
+
+fn write_superblock(stats: &FsStats, out: &mut Window) -> core::fmt::Result {
+    writeln!(out, "BlockSize:\t{}", stats.block_size)?;
+    writeln!(out, "Blocks:\t{}", stats.blocks)?;
+    writeln!(out, "FreeBlocks:\t{}", stats.free_blocks)?;
+    writeln!(out, "Inodes:\t{}", stats.inodes)?;
+    writeln!(out, "FreeInodes:\t{}", stats.free_inodes)
+}
+
+// Writes the time since boot in seconds, to a hundredth of a second
+fn write_uptime(out: &mut Window) -> core::fmt::Result {
+    let time = get_current_time();
+    writeln!(out,
+             "{}.{:02}",
+             time / CLOCK_FREQ,
+             time % CLOCK_FREQ * 100 / CLOCK_FREQ)
+}
+
+fn write_status(process: &ProcessControlBlock,
+                out: &mut Window)
+                -> core::fmt::Result {
+    writeln!(out, "Pid:\t{}", process.pid)?;
+    writeln!(out, "State:\t{}", process.state)?;
+    // Timer ticks at the start of the process's latest time slice
+    writeln!(out, "StartTime:\t{}", process.start_time)?;
+    match process.stack_bounds() {
+        Some((low, high)) => {
+            writeln!(out, "Stack:\t{:#010x}-{:#010x}", low, high)
+        }
+        None => writeln!(out, "Stack:\tboot"),
+    }
+}
@@ -0,0 +1,650 @@
+// tmpfs, a filesystem kept in the kernel heap. Every inode, directory entry
+// and page of file data is its own allocation from MemManager, which is
+// given back as soon as the file is removed or truncated, and everything is
+// freed when the filesystem is unmounted. Nothing survives a reboot.
+
+use super::{DirEntry,
+            FileOperations,
+            FileType,
+            FsStats,
+            InodeOperations,
+            Metadata,
+            MAX_LINK_LEN,
+            MAX_NAME_LEN};
+use crate::filesystem::FsError;
+use crate::global_constants::{CLOCK_FREQ, MAX_TMPFS_INODES, TMPFS_PAGE_SIZE};
+use crate::memman::MemManager;
+use crate::trap::timer::get_current_time;
+use core::cmp::{max, min};
+use core::ptr::{null_mut, write_volatile};
+
+const ROOT_INODE: u32 = 1;
+
+// Most links a directory can have, from the ".." of its subdirectories
+const MAX_LINKS: u16 = core::u16::MAX;
+
+// Most pages a file can have, which keeps its page table within the largest
+// allocation MemManager can make
+const MAX_PAGES: u32 = 0xFFF0 / 4;
+
+pub struct TmpFs {
+    // Every inode, where inode n is at n - 1
+    inodes: [*mut Node; MAX_TMPFS_INODES],
+}
+
+struct Node {
+    file_type: FileType,
+    permissions: u16,
+    links: u16,
+    size: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    // The directory ".." names, for directories
+    parent: u32,
+    // Table of the addresses of the pages of a regular file or the target of
+    // a symbolic link, with room for |page_slots| of them. Pages that were
+    // never written are 0 and read as zeros.
+    pages: *mut u32,
+    page_slots: u32,
+    // Number of pages allocated
+    page_count: u32,
+    // The entries of a directory, in the order they were added
+    entries: *mut Entry,
+}
+
+// A directory entry, which its name follows in the same allocation
+struct Entry {
+    next: *mut Entry,
+    inode: u32,
+    name_len: usize,
+}
+
+impl TmpFs {
+    // Creates a tmpfs holding an empty root directory, which anyone can add
+    // files to
+    pub fn new() -> Result<TmpFs, FsError> {
+        let mut fs = TmpFs { inodes: [null_mut(); MAX_TMPFS_INODES] };
+        fs.alloc_node(FileType::Directory, 0o1777, ROOT_INODE)?;
+        Ok(fs)
+    }
+
+    fn node(&self, inode: u32) -> Result<&Node, FsError> {
+        match self.inodes.get((inode as usize).wrapping_sub(1)) {
+            Some(node) if !node.is_null() => Ok(unsafe { &**node }),
+            _ => Err(FsError::InvalidInode),
+        }
+    }
+
+    fn node_mut(&mut self, inode: u32) -> Result<&mut Node, FsError> {
+        match self.inodes.get((inode as usize).wrapping_sub(1)) {
+            Some(node) if !node.is_null() => Ok(unsafe { &mut **node }),
+            _ => Err(FsError::InvalidInode),
+        }
+    }
+
+    // Returns the directory |inode|
+    fn directory(&self, inode: u32) -> Result<&Node, FsError> {
+        let node = self.node(inode)?;
+        if node.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(node)
+    }
+
+    // Returns the file |inode|, which can't be a directory
+    fn file_mut(&mut self, inode: u32) -> Result<&mut Node, FsError> {
+        let node = self.node_mut(inode)?;
+        if node.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(node)
+    }
+
+    // Makes a new inode with no entries naming it yet and returns its number
+    fn alloc_node(&mut self,
+                  file_type: FileType,
+                  permissions: u16,
+                  parent: u32)
+                  -> Result<u32, FsError> {
+        let index = match self.inodes.iter().position(|node| node.is_null()) {
+            Some(index) => index,
+            None => return Err(FsError::NoSpace),
+        };
+        let address = match MemManager::kmalloc(core::mem::size_of::<Node>()) {
+            Ok(address) => address as *mut Node,
+            Err(_) => return Err(FsError::NoSpace),
+        };
+
+        // Directories are also linked from their own "."
+        let links = if file_type == FileType::Directory {
+            2
+        } else {
+            1
+        };
+        let time = now();
+        unsafe {
+            write_volatile(address,
+                           Node { file_type: file_type,
+                                  permissions: permissions & 0o7777,
+                                  links: links,
+                                  size: 0,
+                                  atime: time,
+                                  mtime: time,
+                                  ctime: time,
+                                  parent: parent,
+                                  pages: null_mut(),
+                                  page_slots: 0,
+                                  page_count: 0,
+                                  entries: null_mut() });
+        }
+        self.inodes[index] = address;
+        Ok(index as u32 + 1)
+    }
+
+    // Frees the inode |inode| along with its pages and entries
+    fn free_node(&mut self, inode: u32) {
+        let index = inode as usize - 1;
+        let address = self.inodes[index];
+        self.inodes[index] = null_mut();
+
+        let node = unsafe { &mut *address };
+        node.free_pages(0);
+        if !node.pages.is_null() {
+            MemManager::kfree(node.pages as u32).ok();
+        }
+        let mut entry = node.entries;
+        while !entry.is_null() {
+            let next = unsafe { (*entry).next };
+            MemManager::kfree(entry as u32).ok();
+            entry = next;
+        }
+        MemManager::kfree(address as u32).ok();
+    }
+
+    // Checks that |name| can be added to the directory |dir|
+    fn check_new_entry(&self, dir: u32, name: &str) -> Result<(), FsError> {
+        let dir = self.directory(dir)?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(FsError::InvalidName);
+        }
+        if dir.find(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(())
+    }
+
+    // Makes a new inode and adds it to |dir| as |name|
+    fn add_node(&mut self,
+                dir: u32,
+                name: &str,
+                file_type: FileType,
+                mode: u16)
+                -> Result<u32, FsError> {
+        self.check_new_entry(dir, name)?;
+        let inode = self.alloc_node(file_type, mode, dir)?;
+        if let Err(e) = self.node_mut(dir)?.add_entry(name, inode) {
+            self.free_node(inode);
+            return Err(e);
+        }
+        Ok(inode)
+    }
+}
+
+impl Node {
+    // Finds the entry |name|
+    fn find(&self, name: &str) -> Option<&Entry> {
+        let mut entry = self.entries;
+        while let Some(current) = unsafe { entry.as_ref() } {
+            if current.name() == name.as_bytes() {
+                return Some(current);
+            }
+            entry = current.next;
+        }
+        None
+    }
+
+    // Adds the entry |name| for |inode| at the end of the directory
+    fn add_entry(&mut self, name: &str, inode: u32) -> Result<(), FsError> {
+        let size = core::mem::size_of::<Entry>() + name.len();
+        let address = match MemManager::kmalloc(size) {
+            Ok(address) => address as *mut Entry,
+            Err(_) => return Err(FsError::NoSpace),
+        };
+        unsafe {
+            write_volatile(address,
+                           Entry { next: null_mut(),
+                                   inode: inode,
+                                   name_len: name.len() });
+            core::ptr::copy_nonoverlapping(name.as_ptr(),
+                                           address.add(1) as *mut u8,
+                                           name.len());
+
+            let mut link = &mut self.entries as *mut *mut Entry;
+            while !(*link).is_null() {
+                link = &mut (**link).next;
+            }
+            *link = address;
+        }
+        self.touch();
+        Ok(())
+    }
+
+    // Removes the entry |name| and returns the inode it named
+    fn remove_entry(&mut self, name: &str) -> Result<u32, FsError> {
+        unsafe {
+            let mut link = &mut self.entries as *mut *mut Entry;
+            while !(*link).is_null() {
+                let entry = *link;
+                if (*entry).name() == name.as_bytes() {
+                    *link = (*entry).next;
+                    let inode = (*entry).inode;
+                    MemManager::kfree(entry as u32).ok();
+                    self.touch();
+                    return Ok(inode);
+                }
+                link = &mut (*entry).next;
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    // Copies the data from |offset| into |buf|, stopping at the end of the
+    // file, and returns the number of bytes copied
+    fn read_at(&self, offset: u32, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+
+        let count = min(buf.len(), (self.size - offset) as usize);
+        let mut done = 0;
+        while done < count {
+            let position = offset as usize + done;
+            let within = position % TMPFS_PAGE_SIZE;
+            let length = min(TMPFS_PAGE_SIZE - within, count - done);
+            let page = self.page((position / TMPFS_PAGE_SIZE) as u32);
+            let part = &mut buf[done..done + length];
+            if page == 0 {
+                for byte in part.iter_mut() {
+                    *byte = 0;
+                }
+            } else {
+                let data = unsafe {
+                    core::slice::from_raw_parts((page as *const u8).add(within),
+                                                length)
+                };
+                part.copy_from_slice(data);
+            }
+            done += length;
+        }
+        count
+    }
+
+    // Returns the address of page |index|, or 0 if it hasn't been written
+    fn page(&self, index: u32) -> u32 {
+        if index < self.page_slots {
+            unsafe { *self.pages.add(index as usize) }
+        } else {
+            0
+        }
+    }
+
+    // Returns the address of page |index|, allocating it full of zeros if
+    // it hasn't been written
+    fn page_or_alloc(&mut self, index: u32) -> Result<u32, FsError> {
+        let page = self.page(index);
+        if page != 0 {
+            return Ok(page);
+        }
+
+        self.reserve_pages(index + 1)?;
+        let page = match MemManager::kmalloc(TMPFS_PAGE_SIZE) {
+            Ok(page) => page,
+            Err(_) => return Err(FsError::NoSpace),
+        };
+        unsafe {
+            core::ptr::write_bytes(page as *mut u8, 0, TMPFS_PAGE_SIZE);
+            *self.pages.add(index as usize) = page;
+        }
+        self.page_count += 1;
+        Ok(page)
+    }
+
+    // Makes room in the page table for at least |count| pages
+    fn reserve_pages(&mut self, count: u32) -> Result<(), FsError> {
+        if count <= self.page_slots {
+            return Ok(());
+        }
+        if count > MAX_PAGES {
+            return Err(FsError::NoSpace);
+        }
+
+        // Doubling the table keeps appending to a file from copying it on
+        // every page
+        let slots = min(max(count, self.page_slots * 2), MAX_PAGES);
+        let table = match MemManager::kmalloc(slots as usize * 4) {
+            Ok(table) => table as *mut u32,
+            Err(_) => return Err(FsError::NoSpace),
+        };
+        for index in 0..slots {
+            unsafe {
+                *table.add(index as usize) = self.page(index);
+            }
+        }
+
+        if !self.pages.is_null() {
+            MemManager::kfree(self.pages as u32).ok();
+        }
+        self.pages = table;
+        self.page_slots = slots;
+        Ok(())
+    }
+
+    // Frees every page from |first| on
+    fn free_pages(&mut self, first: u32) {
+        for index in first..self.page_slots {
+            let page = self.page(index);
+            if page != 0 {
+                MemManager::kfree(page).ok();
+                unsafe {
+                    *self.pages.add(index as usize) = 0;
+                }
+                self.page_count -= 1;
+            }
+        }
+    }
+
+    fn touch(&mut self) {
+        let time = now();
+        self.mtime = time;
+        self.ctime = time;
+    }
+}
+
+impl Entry {
+    fn name(&self) -> &[u8] {
+        unsafe {
+            let name = (self as *const Entry).add(1) as *const u8;
+            core::slice::from_raw_parts(name, self.name_len)
+        }
+    }
+}
+
+impl Drop for TmpFs {
+    fn drop(&mut self) {
+        for index in 0..MAX_TMPFS_INODES {
+            if !self.inodes[index].is_null() {
+                self.free_node(index as u32 + 1);
+            }
+        }
+    }
+}
+
+impl InodeOperations for TmpFs {
+    fn root(&self) -> u32 {
+        ROOT_INODE
+    }
+
+    fn file_type(&self, inode: u32) -> Result<FileType, FsError> {
+        Ok(self.node(inode)?.file_type)
+    }
+
+    fn metadata(&self, inode: u32) -> Result<Metadata, FsError> {
+        let node = self.node(inode)?;
+        let sectors_per_page = (TMPFS_PAGE_SIZE / 512) as u32;
+        Ok(Metadata { inode: inode,
+                      file_type: node.file_type,
+                      permissions: node.permissions,
+                      uid: 0,
+                      gid: 0,
+                      size: node.size,
+                      links: node.links,
+                      atime: node.atime,
+                      mtime: node.mtime,
+                      ctime: node.ctime,
+                      blocks: node.page_count * sectors_per_page })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let node = self.directory(dir)?;
+        match name {
+            "." => Ok(dir),
+            ".." => Ok(node.parent),
+            _ => match node.find(name) {
+                Some(entry) => Ok(entry.inode),
+                None => Err(FsError::NotFound),
+            },
+        }
+    }
+
+    fn create(&mut self,
+              dir: u32,
+              name: &str,
+              mode: u16)
+              -> Result<u32, FsError> {
+        self.add_node(dir, name, FileType::Regular, mode)
+    }
+
+    fn read_link(&self, inode: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node(inode)?;
+        if node.file_type != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(node.read_at(0, buf))
+    }
+
+    fn mkdir(&mut self,
+             dir: u32,
+             name: &str,
+             mode: u16)
+             -> Result<u32, FsError> {
+        if self.directory(dir)?.links == MAX_LINKS {
+            return Err(FsError::TooManyLinks);
+        }
+        let inode = self.add_node(dir, name, FileType::Directory, mode)?;
+        self.node_mut(dir)?.links += 1;
+        Ok(inode)
+    }
+
+    // The target is kept in pages like the data of a regular file
+    fn symlink(&mut self,
+               dir: u32,
+               name: &str,
+               target: &str)
+               -> Result<u32, FsError> {
+        if target.is_empty() || target.len() > MAX_LINK_LEN {
+            return Err(FsError::InvalidArgument);
+        }
+        let inode = self.add_node(dir, name, FileType::Symlink, 0o777)?;
+        match self.write(inode, 0, target.as_bytes()) {
+            Ok(count) if count == target.len() => Ok(inode),
+            // A short write means the heap filled up
+            result => {
+                self.unlink(dir, name)?;
+                Err(result.err().unwrap_or(FsError::NoSpace))
+            }
+        }
+    }
+
+    fn link(&mut self,
+            inode: u32,
+            dir: u32,
+            name: &str)
+            -> Result<(), FsError> {
+        // Hard links to directories would make the tree a graph
+        let node = self.file_mut(inode)?;
+        if node.links == MAX_LINKS {
+            return Err(FsError::TooManyLinks);
+        }
+        self.check_new_entry(dir, name)?;
+        self.node_mut(dir)?.add_entry(name, inode)?;
+
+        let node = self.node_mut(inode)?;
+        node.links += 1;
+        node.ctime = now();
+        Ok(())
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        let inode = self.lookup(dir, name)?;
+        if self.node(inode)?.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.node_mut(dir)?.remove_entry(name)?;
+        let node = self.node_mut(inode)?;
+        node.links -= 1;
+        node.ctime = now();
+        if node.links == 0 {
+            self.free_node(inode);
+        }
+        Ok(())
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        let inode = self.lookup(dir, name)?;
+        let node = self.directory(inode)?;
+        if !node.entries.is_null() {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.node_mut(dir)?.remove_entry(name)?;
+        self.node_mut(dir)?.links -= 1;
+        self.free_node(inode);
+        Ok(())
+    }
+}
+
+impl FileOperations for TmpFs {
+    fn read(&mut self,
+            inode: u32,
+            offset: u32,
+            buf: &mut [u8])
+            -> Result<usize, FsError> {
+        let node = self.file_mut(inode)?;
+        let count = node.read_at(offset, buf);
+        node.atime = now();
+        Ok(count)
+    }
+
+    // Writes as much as there's room for, so a write that runs out of memory
+    // part of the way through returns a short count
+    fn write(&mut self,
+             inode: u32,
+             offset: u32,
+             buf: &[u8])
+             -> Result<usize, FsError> {
+        let node = self.file_mut(inode)?;
+        if offset as u64 + buf.len() as u64 >
+           MAX_PAGES as u64 * TMPFS_PAGE_SIZE as u64
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset as usize + done;
+            let within = position % TMPFS_PAGE_SIZE;
+            let length = min(TMPFS_PAGE_SIZE - within, buf.len() - done);
+            let page =
+                match node.page_or_alloc((position / TMPFS_PAGE_SIZE) as u32) {
+                    Ok(page) => page,
+                    Err(e) if done == 0 => return Err(e),
+                    Err(_) => break,
+                };
+            let data = unsafe {
+                core::slice::from_raw_parts_mut((page as *mut u8).add(within),
+                                                length)
+            };
+            data.copy_from_slice(&buf[done..done + length]);
+            done += length;
+        }
+
+        node.size = max(node.size, offset + done as u32);
+        node.touch();
+        Ok(done)
+    }
+
+    // Pages past the new end are freed, and the rest of the last page is
+    // zeroed so that growing the file again reads zeros
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        let node = self.file_mut(inode)?;
+        if size as u64 > MAX_PAGES as u64 * TMPFS_PAGE_SIZE as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        if size < node.size {
+            let page_size = TMPFS_PAGE_SIZE as u32;
+            let kept = (size + page_size - 1) / page_size;
+            node.free_pages(kept);
+
+            let within = (size % page_size) as usize;
+            let page = if within == 0 { 0 } else { node.page(kept - 1) };
+            if page != 0 {
+                unsafe {
+                    core::ptr::write_bytes((page as *mut u8).add(within),
+                                           0,
+                                           TMPFS_PAGE_SIZE - within);
+                }
+            }
+        }
+
+        node.size = size;
+        node.touch();
+        Ok(())
+    }
+
+    // Directories list ".", ".." and then their entries in the order they
+    // were added
+    fn read_dir(&self,
+                dir: u32,
+                position: u32)
+                -> Result<Option<(DirEntry, u32)>, FsError> {
+        let node = self.directory(dir)?;
+        let entry = match position {
+            0 => DirEntry::new(dir, FileType::Directory, b"."),
+            1 => DirEntry::new(node.parent, FileType::Directory, b".."),
+            _ => {
+                let mut entry = node.entries;
+                for _ in 2..position {
+                    if entry.is_null() {
+                        break;
+                    }
+                    entry = unsafe { (*entry).next };
+                }
+                let entry = match unsafe { entry.as_ref() } {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+                DirEntry::new(entry.inode,
+                              self.node(entry.inode)?.file_type,
+                              entry.name())
+            }
+        };
+        Ok(Some((entry, position + 1)))
+    }
+
+    // The heap is shared with the rest of the kernel, so the free space is
+    // however much of it is left
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let used = self.inodes
+                       .iter()
+                       .filter(|node| !node.is_null())
+                       .map(|node| unsafe { (**node).page_count })
+                       .sum::<u32>();
+        let free = MemManager::usage().free / TMPFS_PAGE_SIZE as u32;
+        let free_inodes =
+            self.inodes.iter().filter(|node| node.is_null()).count() as u32;
+        Ok(FsStats { block_size: TMPFS_PAGE_SIZE as u32,
+                     blocks: used + free,
+                     free_blocks: free,
+                     inodes: MAX_TMPFS_INODES as u32,
+                     free_inodes: free_inodes })
+    }
+}
+
+// Returns the time to record in inodes, in seconds since boot since there's
+// no real-time clock
+fn now() -> u32 {
+    (get_current_time() / CLOCK_FREQ) as u32
+}
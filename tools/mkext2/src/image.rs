@@ -0,0 +1,779 @@
+// Laying out and writing the image. Blocks are handed out in order, group
+// by group, so a file's blocks are contiguous apart from the group metadata
+// they skip over. Every multi-byte field is little-endian.
+
+use crate::tree::{Contents, Node, Tree};
+use std::cmp::{max, min};
+
+// Location of the Superblock, whatever the block size
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_MAGIC: u16 = 0xEF53;
+const EXT2_DYNAMIC_REV: u32 = 1;
+const EXT2_VALID_FS: u16 = 1;
+const EXT2_ERRORS_CONTINUE: u16 = 1;
+
+// Inodes below this one are reserved, and it's used for lost+found
+pub const FIRST_INODE: u32 = 11;
+pub const GOOD_OLD_INODE_SIZE: u32 = 128;
+
+const EXT2_FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const EXT2_FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const BG_DESC_SIZE: usize = 32;
+const DIR_ENTRY_HEADER_SIZE: usize = 8;
+
+// Block pointers in i_block before the single, double and triple indirect
+// ones
+const DIRECT_BLOCKS: usize = 12;
+
+// Symbolic link targets shorter than this are kept in i_block
+const FAST_SYMLINK_SIZE: usize = 60;
+
+// Size of lost+found, which is made big enough up front that e2fsck can
+// reconnect files to it without allocating
+const LOST_AND_FOUND_SIZE: usize = 12 * 1024;
+
+pub struct Options {
+    pub block_size: u32,
+    pub inode_size: u32,
+    // Number of inodes, or None to fit the tree
+    pub inodes: Option<u32>,
+    // Number of blocks, or None to fit the tree
+    pub blocks: Option<u32>,
+    // Time recorded in every inode and the Superblock
+    pub timestamp: u32,
+}
+
+// Where everything goes on an image of a given size
+struct Layout {
+    block_size: u32,
+    inode_size: u32,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    group_count: u32,
+    // Blocks taken by each copy of the descriptor table
+    descriptor_blocks: u32,
+    // Blocks taken by each group's inode table
+    table_blocks: u32,
+}
+
+// The image as it's being written
+struct Image {
+    layout: Layout,
+    bytes: Vec<u8>,
+    // Whether each block is in use
+    used: Vec<bool>,
+    // The block the next allocation starts looking from
+    next_block: u32,
+    // Set if a file is too big for a signed 32 bit size
+    large_file: bool,
+}
+
+// What was written
+pub struct Summary {
+    pub blocks: u32,
+    pub inodes: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+}
+
+impl Layout {
+    // Lays out an image of |blocks| blocks with at least |inodes| inodes. The
+    // last group is dropped if it's too small to hold its own metadata.
+    fn new(options: &Options,
+           blocks: u32,
+           inodes: u32)
+           -> Result<Layout, String> {
+        let block_size = options.block_size;
+        let first_data_block = if block_size == 1024 { 1 } else { 0 };
+        // Each group's block bitmap takes up a single block
+        let bits_per_block = block_size * 8;
+
+        let mut blocks = blocks;
+        loop {
+            if blocks <= first_data_block {
+                return Err("the image is too small".to_string());
+            }
+            // Bitmaps are read in whole bytes, so groups are multiples of 8
+            // blocks even if the last one is cut short
+            let blocks_per_group = min(bits_per_block,
+                                       (blocks - first_data_block).div_ceil(8) *
+                                       8);
+            let group_count =
+                (blocks - first_data_block).div_ceil(blocks_per_group);
+
+            // Inode tables are made of whole blocks, and inode bitmaps of
+            // whole bytes
+            let inodes_per_block = block_size / options.inode_size;
+            let rounding = max(8, inodes_per_block);
+            let inodes_per_group =
+                inodes.div_ceil(group_count).div_ceil(rounding) * rounding;
+            if inodes_per_group > bits_per_block {
+                return Err("too many inodes for the image size".to_string());
+            }
+
+            let descriptor_bytes = group_count * BG_DESC_SIZE as u32;
+            let descriptor_blocks = descriptor_bytes.div_ceil(block_size);
+
+            let layout = Layout { block_size: block_size,
+                                  inode_size: options.inode_size,
+                                  blocks_count: blocks,
+                                  first_data_block: first_data_block,
+                                  blocks_per_group: blocks_per_group,
+                                  inodes_per_group: inodes_per_group,
+                                  group_count: group_count,
+                                  descriptor_blocks: descriptor_blocks,
+                                  table_blocks: inodes_per_group /
+                                                inodes_per_block };
+
+            let last = group_count - 1;
+            if layout.blocks_in_group(last) > layout.overhead(last) {
+                return Ok(layout);
+            }
+            if group_count == 1 {
+                return Err("the image is too small".to_string());
+            }
+            blocks = layout.group_start(last);
+        }
+    }
+
+    fn inodes_count(&self) -> u32 {
+        self.inodes_per_group * self.group_count
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        min(self.blocks_per_group,
+            self.blocks_count - self.group_start(group))
+    }
+
+    // Checks whether |group| starts with a copy of the Superblock and the
+    // descriptor table, which with sparse_super is groups 0, 1 and powers of
+    // 3, 5 and 7
+    fn has_backup(&self, group: u32) -> bool {
+        group <= 1 ||
+        is_power_of(group, 3) ||
+        is_power_of(group, 5) ||
+        is_power_of(group, 7)
+    }
+
+    // Returns the number of metadata blocks at the start of |group|
+    fn overhead(&self, group: u32) -> u32 {
+        let backup = if self.has_backup(group) {
+            1 + self.descriptor_blocks
+        } else {
+            0
+        };
+        backup + 2 + self.table_blocks
+    }
+
+    // Returns the block bitmap, inode bitmap and inode table of |group|
+    fn group_metadata(&self, group: u32) -> (u32, u32, u32) {
+        let bitmap = self.group_start(group) + self.overhead(group) -
+                     self.table_blocks -
+                     2;
+        (bitmap, bitmap + 1, bitmap + 2)
+    }
+
+    // Returns the number of blocks left for files
+    fn data_blocks(&self) -> u32 {
+        (0..self.group_count).map(|group| {
+                                 self.blocks_in_group(group) -
+                                 self.overhead(group)
+                             })
+                             .sum()
+    }
+}
+
+// Builds the image of |tree|
+pub fn build(tree: &Tree,
+             options: &Options)
+             -> Result<(Vec<u8>, Summary), String> {
+    let per_block = options.block_size / 4;
+    let needed_inodes = tree.last_inode();
+    let needed_blocks = tree.nodes
+                            .values()
+                            .map(|node| {
+                                mapped_blocks(data_size(node,
+                                                        options.block_size),
+                                              options.block_size,
+                                              per_block)
+                            })
+                            .sum::<Option<u32>>()
+                            .ok_or("a file is too big for the block size")?;
+
+    let inodes = match options.inodes {
+        Some(inodes) if inodes < needed_inodes => {
+            return Err(format!("the tree needs at least {} inodes",
+                               needed_inodes));
+        }
+        Some(inodes) => inodes,
+        // Leave room for about as many files again
+        None => max(needed_inodes * 2, 64),
+    };
+
+    let layout = match options.blocks {
+        Some(blocks) => Layout::new(options, blocks, inodes)?,
+        None => {
+            // Grow the image until the tree fits with a quarter to spare
+            let wanted = needed_blocks + max(needed_blocks / 4, 64);
+            let mut blocks = wanted;
+            loop {
+                let layout = Layout::new(options, blocks, inodes)?;
+                let available = layout.data_blocks();
+                if available >= wanted {
+                    break layout;
+                }
+                blocks = layout.blocks_count + (wanted - available);
+            }
+        }
+    };
+    if layout.data_blocks() < needed_blocks {
+        return Err(format!("the tree needs {} data blocks, but the image only \
+                            has room for {}",
+                           needed_blocks,
+                           layout.data_blocks()));
+    }
+
+    let size = layout.blocks_count as usize * layout.block_size as usize;
+    let mut image = Image { used: vec![false; layout.blocks_count as usize],
+                            bytes: vec![0; size],
+                            next_block: layout.first_data_block,
+                            large_file: false,
+                            layout: layout };
+
+    // Claim the metadata, so files are only given the blocks after it
+    for group in 0..image.layout.group_count {
+        let start = image.layout.group_start(group);
+        for block in start..start + image.layout.overhead(group) {
+            image.used[block as usize] = true;
+        }
+    }
+    for (&inode, node) in tree.nodes.iter() {
+        image.write_node(inode, node, options.timestamp)?;
+    }
+    Ok(image.finish(tree, options.timestamp))
+}
+
+impl Image {
+    // Writes the data of |node| and its inode
+    fn write_node(&mut self,
+                  inode_number: u32,
+                  node: &Node,
+                  timestamp: u32)
+                  -> Result<(), String> {
+        let block_size = self.layout.block_size as usize;
+        let mut inode = vec![0u8; GOOD_OLD_INODE_SIZE as usize];
+        put_u16(&mut inode, 0, node.mode);
+        put_u32(&mut inode, 8, timestamp);
+        put_u32(&mut inode, 12, timestamp);
+        put_u32(&mut inode, 16, timestamp);
+        put_u16(&mut inode, 26, node.links);
+
+        let data = match node.contents {
+            Contents::Regular(ref data) => Some(data.clone()),
+            Contents::Directory(ref entries) => {
+                let size = if inode_number == FIRST_INODE {
+                    max(LOST_AND_FOUND_SIZE, block_size)
+                } else {
+                    0
+                };
+                Some(directory_data(entries, block_size, size))
+            }
+            Contents::Symlink(ref target)
+                if target.len() >= FAST_SYMLINK_SIZE =>
+            {
+                Some(target.clone())
+            }
+            Contents::Symlink(ref target) => {
+                put_u32(&mut inode, 4, target.len() as u32);
+                inode[40..40 + target.len()].copy_from_slice(target);
+                None
+            }
+            Contents::Device { major, minor } => {
+                // Small numbers use the old encoding, and the rest the new
+                if major < 256 && minor < 256 {
+                    put_u32(&mut inode, 40, major << 8 | minor);
+                } else {
+                    put_u32(&mut inode,
+                            44,
+                            (minor & 0xFF) |
+                            (major << 8) |
+                            ((minor & !0xFF) << 12));
+                }
+                None
+            }
+            Contents::Empty => None,
+        };
+
+        if let Some(data) = data {
+            if data.len() > u32::MAX as usize {
+                return Err(format!("inode {} is too big", inode_number));
+            }
+            if data.len() > i32::MAX as usize {
+                self.large_file = true;
+            }
+            put_u32(&mut inode, 4, data.len() as u32);
+
+            let (pointers, blocks) = self.write_data(&data)?;
+            put_u32(&mut inode, 28, blocks * (block_size as u32 / 512));
+            for (slot, &pointer) in pointers.iter().enumerate() {
+                put_u32(&mut inode, 40 + slot * 4, pointer);
+            }
+        }
+
+        let group = (inode_number - 1) / self.layout.inodes_per_group;
+        let index = (inode_number - 1) % self.layout.inodes_per_group;
+        let (_, _, table) = self.layout.group_metadata(group);
+        let offset = table as usize * block_size +
+                     index as usize * self.layout.inode_size as usize;
+        self.bytes[offset..offset + inode.len()].copy_from_slice(&inode);
+        Ok(())
+    }
+
+    // Writes |data| to newly allocated blocks, and returns the i_block
+    // array mapping them along with the number of blocks used, indirect
+    // blocks included
+    fn write_data(&mut self, data: &[u8]) -> Result<([u32; 15], u32), String> {
+        let chunks = data.chunks(self.layout.block_size as usize)
+                         .collect::<Vec<_>>();
+        let mut pointers = [0u32; 15];
+        let mut next = 0;
+        let mut blocks = 0;
+
+        for pointer in pointers.iter_mut().take(DIRECT_BLOCKS) {
+            if next < chunks.len() {
+                *pointer = self.write_block(chunks[next])?;
+                next += 1;
+                blocks += 1;
+            }
+        }
+        for depth in 1..=3 {
+            if next < chunks.len() {
+                pointers[DIRECT_BLOCKS + depth - 1] =
+                    self.write_indirect(depth,
+                                        &chunks,
+                                        &mut next,
+                                        &mut blocks)?;
+            }
+        }
+
+        Ok((pointers, blocks))
+    }
+
+    // Writes an indirect block |depth| levels above the data blocks, mapping
+    // the chunks from |next| on, and returns its number
+    fn write_indirect(&mut self,
+                      depth: usize,
+                      chunks: &[&[u8]],
+                      next: &mut usize,
+                      blocks: &mut u32)
+                      -> Result<u32, String> {
+        let block = self.allocate()?;
+        *blocks += 1;
+
+        let mut pointers = Vec::new();
+        for _ in 0..self.layout.block_size / 4 {
+            if *next >= chunks.len() {
+                break;
+            }
+            let pointer = if depth == 1 {
+                let pointer = self.write_block(chunks[*next])?;
+                *next += 1;
+                *blocks += 1;
+                pointer
+            } else {
+                self.write_indirect(depth - 1, chunks, next, blocks)?
+            };
+            pointers.extend_from_slice(&pointer.to_le_bytes());
+        }
+
+        let offset = block as usize * self.layout.block_size as usize;
+        self.bytes[offset..offset + pointers.len()].copy_from_slice(&pointers);
+        Ok(block)
+    }
+
+    // Writes |chunk| to a new block and returns its number
+    fn write_block(&mut self, chunk: &[u8]) -> Result<u32, String> {
+        let block = self.allocate()?;
+        let offset = block as usize * self.layout.block_size as usize;
+        self.bytes[offset..offset + chunk.len()].copy_from_slice(chunk);
+        Ok(block)
+    }
+
+    fn allocate(&mut self) -> Result<u32, String> {
+        while self.next_block < self.layout.blocks_count {
+            let block = self.next_block;
+            self.next_block += 1;
+            if !self.used[block as usize] {
+                self.used[block as usize] = true;
+                return Ok(block);
+            }
+        }
+        Err("the image is full".to_string())
+    }
+
+    // Writes the bitmaps, descriptors and Superblocks now that every file is
+    // in place
+    fn finish(mut self, tree: &Tree, timestamp: u32) -> (Vec<u8>, Summary) {
+        let layout = &self.layout;
+        let block_size = layout.block_size as usize;
+        let bits_per_block = layout.block_size * 8;
+
+        let mut descriptors =
+            vec![0u8; layout.descriptor_blocks as usize * block_size];
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+
+        for group in 0..layout.group_count {
+            let (block_bitmap, inode_bitmap, table) =
+                layout.group_metadata(group);
+            let start = layout.group_start(group);
+
+            // Bits past the end of the group are set, so they're never
+            // allocated
+            let mut group_free_blocks = 0;
+            let mut bitmap = vec![0u8; block_size];
+            for bit in 0..bits_per_block {
+                let used = bit >= layout.blocks_in_group(group) ||
+                           self.used[(start + bit) as usize];
+                if used {
+                    bitmap[bit as usize / 8] |= 1 << (bit % 8);
+                } else {
+                    group_free_blocks += 1;
+                }
+            }
+            let offset = block_bitmap as usize * block_size;
+            self.bytes[offset..offset + block_size].copy_from_slice(&bitmap);
+
+            let mut group_free_inodes = 0;
+            let mut directories = 0;
+            let mut bitmap = vec![0u8; block_size];
+            for bit in 0..bits_per_block {
+                let inode = group * layout.inodes_per_group + bit + 1;
+                let node = tree.nodes.get(&inode);
+                let used = bit >= layout.inodes_per_group ||
+                           inode < FIRST_INODE ||
+                           node.is_some();
+                if used {
+                    bitmap[bit as usize / 8] |= 1 << (bit % 8);
+                } else {
+                    group_free_inodes += 1;
+                }
+
+                // Inodes past the end of the group belong to the next one
+                if bit < layout.inodes_per_group &&
+                   node.is_some_and(|node| node.is_directory())
+                {
+                    directories += 1;
+                }
+            }
+            let offset = inode_bitmap as usize * block_size;
+            self.bytes[offset..offset + block_size].copy_from_slice(&bitmap);
+
+            let descriptor = &mut descriptors[group as usize * BG_DESC_SIZE..];
+            put_u32(descriptor, 0, block_bitmap);
+            put_u32(descriptor, 4, inode_bitmap);
+            put_u32(descriptor, 8, table);
+            put_u16(descriptor, 12, group_free_blocks as u16);
+            put_u16(descriptor, 14, group_free_inodes as u16);
+            put_u16(descriptor, 16, directories);
+
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+
+        let mut superblock = vec![0u8; 1024];
+        put_u32(&mut superblock, 0, layout.inodes_count());
+        put_u32(&mut superblock, 4, layout.blocks_count);
+        put_u32(&mut superblock, 12, free_blocks);
+        put_u32(&mut superblock, 16, free_inodes);
+        put_u32(&mut superblock, 20, layout.first_data_block);
+        put_u32(&mut superblock, 24, layout.block_size.trailing_zeros() - 10);
+        put_u32(&mut superblock, 28, layout.block_size.trailing_zeros() - 10);
+        put_u32(&mut superblock, 32, layout.blocks_per_group);
+        put_u32(&mut superblock, 36, layout.blocks_per_group);
+        put_u32(&mut superblock, 40, layout.inodes_per_group);
+        put_u32(&mut superblock, 48, timestamp);
+        // No limit on mounts between checks
+        put_u16(&mut superblock, 54, 0xFFFF);
+        put_u16(&mut superblock, 56, SUPERBLOCK_MAGIC);
+        put_u16(&mut superblock, 58, EXT2_VALID_FS);
+        put_u16(&mut superblock, 60, EXT2_ERRORS_CONTINUE);
+        put_u32(&mut superblock, 64, timestamp);
+        put_u32(&mut superblock, 76, EXT2_DYNAMIC_REV);
+        put_u32(&mut superblock, 84, FIRST_INODE);
+        put_u16(&mut superblock, 88, layout.inode_size as u16);
+        put_u32(&mut superblock, 96, EXT2_FEATURE_INCOMPAT_FILETYPE);
+        let large_file = if self.large_file {
+            EXT2_FEATURE_RO_COMPAT_LARGE_FILE
+        } else {
+            0
+        };
+        put_u32(&mut superblock,
+                100,
+                EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER | large_file);
+        put_u32(&mut superblock, 264, timestamp);
+
+        // Each backup records which group it's in
+        for group in 0..layout.group_count {
+            if !layout.has_backup(group) {
+                continue;
+            }
+            let start = layout.group_start(group) as usize * block_size;
+            let (superblock_offset, descriptor_offset) = if group == 0 {
+                (SUPERBLOCK_OFFSET,
+                 (layout.first_data_block as usize + 1) * block_size)
+            } else {
+                (start, start + block_size)
+            };
+            put_u16(&mut superblock, 90, group as u16);
+            self.bytes[superblock_offset..superblock_offset + superblock.len()]
+                .copy_from_slice(&superblock);
+            self.bytes[descriptor_offset..descriptor_offset + descriptors.len()]
+                .copy_from_slice(&descriptors);
+        }
+
+        let summary = Summary { blocks: layout.blocks_count,
+                                inodes: layout.inodes_count(),
+                                free_blocks: free_blocks,
+                                free_inodes: free_inodes };
+        (self.bytes, summary)
+    }
+}
+
+// Lays |entries| out in directory blocks, each of which ends with an entry
+// that runs to its end. Empty blocks are added to reach |size| bytes.
+fn directory_data(entries: &[crate::tree::Entry],
+                  block_size: usize,
+                  size: usize)
+                  -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut last = 0;
+
+    for entry in entries {
+        let length = entry_size(entry.name.len());
+        let block_end = (data.len() / block_size + 1) * block_size;
+        if !data.is_empty() && data.len() + length > block_end {
+            set_rec_len(&mut data, last, block_end - last);
+            data.resize(block_end, 0);
+        }
+
+        last = data.len();
+        data.extend_from_slice(&entry.inode.to_le_bytes());
+        data.extend_from_slice(&(length as u16).to_le_bytes());
+        data.push(entry.name.len() as u8);
+        data.push(entry.file_type);
+        data.extend_from_slice(&entry.name);
+        data.resize(last + length, 0);
+    }
+    let block_end = (last / block_size + 1) * block_size;
+    set_rec_len(&mut data, last, block_end - last);
+    data.resize(block_end, 0);
+
+    // Unused blocks hold a single empty entry
+    while data.len() < size {
+        let start = data.len();
+        data.resize(start + block_size, 0);
+        set_rec_len(&mut data, start, block_size);
+    }
+    data
+}
+
+fn set_rec_len(data: &mut [u8], entry: usize, rec_len: usize) {
+    put_u16(data, entry + 4, rec_len as u16);
+}
+
+// Returns the space an entry with a name of |name_len| bytes takes up
+fn entry_size(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER_SIZE + name_len + 3) & !3
+}
+
+// Returns the size of the data blocks |node| needs
+fn data_size(node: &Node, block_size: u32) -> u64 {
+    match node.contents {
+        Contents::Regular(ref data) => data.len() as u64,
+        Contents::Directory(ref entries) => {
+            // Directory blocks can have space left at their ends, so they're
+            // laid out to find out
+            directory_data(entries, block_size as usize, 0).len() as u64
+        }
+        Contents::Symlink(ref target) if target.len() >= FAST_SYMLINK_SIZE => {
+            target.len() as u64
+        }
+        _ => 0,
+    }
+}
+
+// Returns the number of blocks, indirect blocks included, that map |size|
+// bytes of data, or None if it's more than an inode can map
+fn mapped_blocks(size: u64, block_size: u32, per_block: u32) -> Option<u32> {
+    let data = size.div_ceil(block_size as u64);
+    let per_block = per_block as u64;
+    let mut remaining = data.saturating_sub(DIRECT_BLOCKS as u64);
+    let mut total = data;
+
+    // Each level maps per_block times as much as the one before, and takes
+    // an indirect block for every per_block blocks below it
+    let mut span = 1;
+    for _ in 0..3 {
+        if remaining == 0 {
+            break;
+        }
+        span *= per_block;
+        let mapped = min(remaining, span);
+        let mut below = span;
+        while below > 1 {
+            below /= per_block;
+            total += mapped.div_ceil(below * per_block);
+        }
+        remaining -= mapped;
+    }
+
+    if remaining > 0 || total > u32::MAX as u64 {
+        None
+    } else {
+        Some(total as u32)
+    }
+}
+
+// Checks whether |n| is a power of |base|
+fn is_power_of(mut n: u32, base: u32) -> bool {
+    while n > 1 && n.is_multiple_of(base) {
+        n /= base;
+    }
+    n == 1
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::ROOT_INODE;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn get_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset],
+                            bytes[offset + 1],
+                            bytes[offset + 2],
+                            bytes[offset + 3]])
+    }
+
+    // Makes a tree with a file big enough to need an indirect block, a
+    // subdirectory, both kinds of symbolic link and a hard link
+    fn make_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("mkext2-{}-{}",
+                                                     name,
+                                                     std::process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("hello.txt"), b"Hello, world!\n").unwrap();
+        let big = (0..20 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(root.join("etc/big"), big).unwrap();
+        fs::hard_link(root.join("hello.txt"), root.join("etc/hello")).unwrap();
+        std::os::unix::fs::symlink("hello.txt", root.join("short")).unwrap();
+        std::os::unix::fs::symlink("x".repeat(FAST_SYMLINK_SIZE),
+                                   root.join("long")).unwrap();
+        root
+    }
+
+    // Counts the clear bits among the first |count| of |bitmap|
+    fn clear_bits(bitmap: &[u8], count: u32) -> u32 {
+        (0..count).filter(|&bit| {
+                      bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0
+                  })
+                  .count() as u32
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = make_tree("round-trip");
+        let options = Options { block_size: 1024,
+                                inode_size: GOOD_OLD_INODE_SIZE,
+                                inodes: None,
+                                blocks: None,
+                                timestamp: 1_000_000 };
+        let tree = Tree::read(&root, FIRST_INODE).unwrap();
+        let (bytes, summary) = build(&tree, &options).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        // The root has ".", its entry in itself, and a ".." from each of
+        // lost+found and etc
+        assert_eq!(tree.nodes[&ROOT_INODE].links, 4);
+        // hello.txt is stored once, under both names
+        assert_eq!(tree.last_inode(), FIRST_INODE + 5);
+
+        let superblock = &bytes[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024];
+        assert_eq!(get_u16(superblock, 56), SUPERBLOCK_MAGIC);
+        assert_eq!(get_u32(superblock, 0), summary.inodes);
+        assert_eq!(get_u32(superblock, 4), summary.blocks);
+        assert_eq!(get_u32(superblock, 12), summary.free_blocks);
+        assert_eq!(get_u32(superblock, 16), summary.free_inodes);
+        assert_eq!(get_u32(superblock, 20), 1);
+        assert_eq!(get_u32(superblock, 84), FIRST_INODE);
+        assert_eq!(bytes.len(), summary.blocks as usize * 1024);
+
+        // Every group's bitmaps agree with its descriptor, and the groups add
+        // up to the Superblock
+        let blocks_per_group = get_u32(superblock, 32);
+        let inodes_per_group = get_u32(superblock, 40);
+        let groups = (summary.blocks - 1).div_ceil(blocks_per_group);
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        for group in 0..groups {
+            let descriptor = &bytes[2 * 1024 + group as usize * BG_DESC_SIZE..];
+            let block_bitmap = get_u32(descriptor, 0) as usize * 1024;
+            let inode_bitmap = get_u32(descriptor, 4) as usize * 1024;
+            let blocks = min(blocks_per_group,
+                             summary.blocks - 1 - group * blocks_per_group);
+
+            let group_free_blocks =
+                clear_bits(&bytes[block_bitmap..block_bitmap + 1024], blocks);
+            let group_free_inodes =
+                clear_bits(&bytes[inode_bitmap..inode_bitmap + 1024],
+                           inodes_per_group);
+            assert_eq!(get_u16(descriptor, 12) as u32, group_free_blocks);
+            assert_eq!(get_u16(descriptor, 14) as u32, group_free_inodes);
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+        assert_eq!(free_blocks, summary.free_blocks);
+        assert_eq!(free_inodes, summary.free_inodes);
+        assert_eq!(summary.free_inodes,
+                   summary.inodes - tree.last_inode());
+    }
+
+    #[test]
+    fn builds_are_identical() {
+        let root = make_tree("identical");
+        let options = Options { block_size: 2048,
+                                inode_size: 256,
+                                inodes: None,
+                                blocks: None,
+                                timestamp: 0 };
+        let first = build(&Tree::read(&root, FIRST_INODE).unwrap(), &options);
+        let second = build(&Tree::read(&root, FIRST_INODE).unwrap(), &options);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(first.unwrap().0 == second.unwrap().0);
+    }
+}
@@ -0,0 +1,107 @@
+// mkext2 builds the ext2 image that asm/fs.S links into the kernel from a
+// directory tree, without needing root, mkfs or mount. The image only
+// depends on the tree and the options: files are owned by root, every
+// timestamp is the same and nothing random goes into the Superblock.
+
+// Field names are spelled out like in the kernel
+#![allow(clippy::redundant_field_names)]
+
+mod image;
+mod tree;
+
+use image::{Options, FIRST_INODE, GOOD_OLD_INODE_SIZE};
+use std::path::Path;
+use std::process::exit;
+use tree::Tree;
+
+const USAGE: &str = "\
+usage: mkext2 [options] <source directory> <image>
+
+options:
+  -b <block size>  1024, 2048 or 4096 bytes (default 1024)
+  -I <inode size>  a power of two from 128 up to the block size (default 128)
+  -N <inodes>      number of inodes (default: twice what the tree needs)
+  -s <blocks>      size of the image in blocks (default: the tree plus a
+                   quarter to spare)
+  -t <time>        seconds since the epoch to give every timestamp (default:
+                   $SOURCE_DATE_EPOCH, or 0)";
+
+fn main() {
+    if let Err(message) = run() {
+        eprintln!("mkext2: {}", message);
+        exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut options = Options { block_size: 1024,
+                                inode_size: GOOD_OLD_INODE_SIZE,
+                                inodes: None,
+                                blocks: None,
+                                timestamp: 0 };
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        options.timestamp = parse_number("SOURCE_DATE_EPOCH", &epoch)?;
+    }
+
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            paths.push(arg);
+            continue;
+        }
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("{} needs a value\n\n{}", arg, USAGE)),
+        };
+        match arg.as_str() {
+            "-b" => options.block_size = parse_number(&arg, &value)?,
+            "-I" => options.inode_size = parse_number(&arg, &value)?,
+            "-N" => options.inodes = Some(parse_number(&arg, &value)?),
+            "-s" => options.blocks = Some(parse_number(&arg, &value)?),
+            "-t" => options.timestamp = parse_number(&arg, &value)?,
+            _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE)),
+        }
+    }
+
+    if paths.len() != 2 {
+        return Err(USAGE.to_string());
+    }
+    if options.block_size != 1024 &&
+       options.block_size != 2048 &&
+       options.block_size != 4096
+    {
+        return Err(format!("bad block size {}", options.block_size));
+    }
+    if options.inode_size < GOOD_OLD_INODE_SIZE ||
+       options.inode_size > options.block_size ||
+       !options.inode_size.is_power_of_two()
+    {
+        return Err(format!("bad inode size {}", options.inode_size));
+    }
+
+    let tree = Tree::read(Path::new(&paths[0]), FIRST_INODE)?;
+    let (bytes, summary) = image::build(&tree, &options)?;
+    std::fs::write(&paths[1], bytes).map_err(|e| {
+                                        format!("{}: {}", paths[1], e)
+                                    })?;
+
+    println!("{}: {} blocks of {} bytes ({} free), {} inodes ({} free)",
+             paths[1],
+             summary.blocks,
+             options.block_size,
+             summary.free_blocks,
+             summary.inodes,
+             summary.free_inodes);
+    Ok(())
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u32, String> {
+    value.parse()
+         .map_err(|_| format!("{} has to be a number, not {}", name, value))
+}
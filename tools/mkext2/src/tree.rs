@@ -0,0 +1,289 @@
+// Reading the source directory into the files the image will hold. Inode
+// numbers are handed out while walking the tree in name order, so the same
+// tree always gives the same image.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+// The root directory always has this inode number
+pub const ROOT_INODE: u32 = 2;
+
+// Longest name a directory entry can hold
+const MAX_NAME_LEN: usize = 255;
+
+// Values of the file_type field of a directory entry
+pub const EXT2_FT_REG_FILE: u8 = 1;
+pub const EXT2_FT_DIR: u8 = 2;
+pub const EXT2_FT_CHRDEV: u8 = 3;
+pub const EXT2_FT_BLKDEV: u8 = 4;
+pub const EXT2_FT_FIFO: u8 = 5;
+pub const EXT2_FT_SOCK: u8 = 6;
+pub const EXT2_FT_SYMLINK: u8 = 7;
+
+// File type bits of i_mode
+pub const EXT2_S_IFSOCK: u16 = 0xC000;
+pub const EXT2_S_IFLNK: u16 = 0xA000;
+pub const EXT2_S_IFREG: u16 = 0x8000;
+pub const EXT2_S_IFBLK: u16 = 0x6000;
+pub const EXT2_S_IFDIR: u16 = 0x4000;
+pub const EXT2_S_IFCHR: u16 = 0x2000;
+pub const EXT2_S_IFIFO: u16 = 0x1000;
+
+// Permission bits of i_mode
+const EXT2_S_IPERM: u32 = 0o7777;
+
+pub enum Contents {
+    Regular(Vec<u8>),
+    Directory(Vec<Entry>),
+    Symlink(Vec<u8>),
+    Device { major: u32, minor: u32 },
+    // FIFOs and sockets have nothing but their inode
+    Empty,
+}
+
+pub struct Entry {
+    pub name: Vec<u8>,
+    pub inode: u32,
+    pub file_type: u8,
+}
+
+pub struct Node {
+    // Type and permission bits
+    pub mode: u16,
+    // Number of directory entries naming the node
+    pub links: u16,
+    pub contents: Contents,
+}
+
+pub struct Tree {
+    // Every node, keyed by inode number
+    pub nodes: BTreeMap<u32, Node>,
+}
+
+// Walks the directory tree
+struct Walker {
+    nodes: BTreeMap<u32, Node>,
+    next_inode: u32,
+    // Inodes of files with more than one name, by device and inode number
+    // on the host, so each one is only stored once
+    hard_links: HashMap<(u64, u64), u32>,
+}
+
+impl Tree {
+    // Reads the tree under |source|, which becomes the root directory. An
+    // empty lost+found directory is added as |first_inode|, the first
+    // inode that isn't reserved.
+    pub fn read(source: &Path, first_inode: u32) -> Result<Tree, String> {
+        let metadata =
+            fs::metadata(source).map_err(|e| {
+                                    format!("{}: {}", source.display(), e)
+                                })?;
+        if !metadata.is_dir() {
+            return Err(format!("{}: not a directory", source.display()));
+        }
+
+        let mut walker = Walker { nodes: BTreeMap::new(),
+                                  next_inode: first_inode + 1,
+                                  hard_links: HashMap::new() };
+        let entries = vec![dot(first_inode), dot_dot(ROOT_INODE)];
+        walker.nodes.insert(first_inode,
+                            Node { mode: EXT2_S_IFDIR | 0o700,
+                                   links: 2,
+                                   contents: Contents::Directory(entries) });
+        walker.add_directory(source,
+                             ROOT_INODE,
+                             ROOT_INODE,
+                             metadata.mode(),
+                             Some(first_inode))?;
+        Ok(Tree { nodes: walker.nodes })
+    }
+
+    // Returns the highest inode number in use
+    pub fn last_inode(&self) -> u32 {
+        self.nodes.keys().next_back().cloned().unwrap_or(ROOT_INODE)
+    }
+}
+
+impl Node {
+    pub fn is_directory(&self) -> bool {
+        matches!(self.contents, Contents::Directory(_))
+    }
+}
+
+impl Walker {
+    // Adds the directory at |path| as |inode|, along with everything under
+    // it. The root directory also gets an entry for |lost_and_found|.
+    fn add_directory(&mut self,
+                     path: &Path,
+                     inode: u32,
+                     parent: u32,
+                     host_mode: u32,
+                     lost_and_found: Option<u32>)
+                     -> Result<(), String> {
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut names =
+            fs::read_dir(path).map_err(error)?
+                              .map(|entry| entry.map(|entry| entry.file_name()))
+                              .collect::<Result<Vec<_>, _>>()
+                              .map_err(error)?;
+        names.sort();
+
+        let mut entries = vec![dot(inode), dot_dot(parent)];
+        if let Some(lost_and_found) = lost_and_found {
+            entries.push(Entry { name: b"lost+found".to_vec(),
+                                 inode: lost_and_found,
+                                 file_type: EXT2_FT_DIR });
+        }
+        // Subdirectories each add a link through their ".."
+        let mut links = 2;
+        if lost_and_found.is_some() {
+            links += 1;
+        }
+
+        for name in names {
+            let child_path = path.join(&name);
+            let name = name.as_bytes().to_vec();
+            if name.len() > MAX_NAME_LEN {
+                return Err(format!("{}: name is too long",
+                                   child_path.display()));
+            }
+            if lost_and_found.is_some() && name == b"lost+found" {
+                eprintln!("mkext2: skipping {}, the image has its own",
+                          child_path.display());
+                continue;
+            }
+
+            let metadata = fs::symlink_metadata(&child_path).map_err(|e| {
+                               format!("{}: {}", child_path.display(), e)
+                           })?;
+            let host_file = (metadata.dev(), metadata.ino());
+            let file_type = entry_file_type(&metadata.file_type());
+
+            // Further names of a file already in the image only need an entry
+            if !metadata.is_dir() && metadata.nlink() > 1 {
+                if let Some(&existing) = self.hard_links.get(&host_file) {
+                    let node = self.nodes.get_mut(&existing).unwrap();
+                    node.links = node.links
+                                     .checked_add(1)
+                                     .ok_or_else(|| {
+                                         format!("{}: too many links",
+                                                 child_path.display())
+                                     })?;
+                    entries.push(Entry { name: name,
+                                         inode: existing,
+                                         file_type: file_type });
+                    continue;
+                }
+            }
+
+            let child = self.next_inode;
+            self.next_inode += 1;
+            entries.push(Entry { name: name,
+                                 inode: child,
+                                 file_type: file_type });
+
+            if metadata.is_dir() {
+                links += 1;
+                self.add_directory(&child_path,
+                                   child,
+                                   inode,
+                                   metadata.mode(),
+                                   None)?;
+                continue;
+            }
+
+            let contents = read_contents(&child_path, &metadata)?;
+            self.nodes.insert(child,
+                              Node { mode: mode(file_type, metadata.mode()),
+                                     links: 1,
+                                     contents: contents });
+            if metadata.nlink() > 1 {
+                self.hard_links.insert(host_file, child);
+            }
+        }
+
+        if links > u16::MAX as u32 {
+            return Err(format!("{}: too many subdirectories", path.display()));
+        }
+        self.nodes.insert(inode,
+                          Node { mode: mode(EXT2_FT_DIR, host_mode),
+                                 links: links as u16,
+                                 contents: Contents::Directory(entries) });
+        Ok(())
+    }
+}
+
+// Reads what the image stores for the file at |path|, which isn't a
+// directory
+fn read_contents(path: &Path,
+                 metadata: &fs::Metadata)
+                 -> Result<Contents, String> {
+    let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let file_type = metadata.file_type();
+
+    if file_type.is_file() {
+        Ok(Contents::Regular(fs::read(path).map_err(error)?))
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(path).map_err(error)?;
+        Ok(Contents::Symlink(target.as_os_str().as_bytes().to_vec()))
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        // The glibc layout of a device number
+        let rdev = metadata.rdev();
+        Ok(Contents::Device { major: (((rdev >> 8) & 0xFFF) |
+                                      ((rdev >> 32) & !0xFFF))
+                                     as u32,
+                              minor: ((rdev & 0xFF) | ((rdev >> 12) & !0xFF))
+                                     as u32 })
+    } else {
+        Ok(Contents::Empty)
+    }
+}
+
+// Returns the directory entry file type for a file on the host
+fn entry_file_type(file_type: &fs::FileType) -> u8 {
+    if file_type.is_dir() {
+        EXT2_FT_DIR
+    } else if file_type.is_symlink() {
+        EXT2_FT_SYMLINK
+    } else if file_type.is_char_device() {
+        EXT2_FT_CHRDEV
+    } else if file_type.is_block_device() {
+        EXT2_FT_BLKDEV
+    } else if file_type.is_fifo() {
+        EXT2_FT_FIFO
+    } else if file_type.is_socket() {
+        EXT2_FT_SOCK
+    } else {
+        EXT2_FT_REG_FILE
+    }
+}
+
+// Builds an i_mode from the entry |file_type| and the permission bits of
+// |host_mode|
+fn mode(file_type: u8, host_mode: u32) -> u16 {
+    let kind = match file_type {
+        EXT2_FT_DIR => EXT2_S_IFDIR,
+        EXT2_FT_SYMLINK => EXT2_S_IFLNK,
+        EXT2_FT_CHRDEV => EXT2_S_IFCHR,
+        EXT2_FT_BLKDEV => EXT2_S_IFBLK,
+        EXT2_FT_FIFO => EXT2_S_IFIFO,
+        EXT2_FT_SOCK => EXT2_S_IFSOCK,
+        _ => EXT2_S_IFREG,
+    };
+    kind | (host_mode & EXT2_S_IPERM) as u16
+}
+
+fn dot(inode: u32) -> Entry {
+    Entry { name: b".".to_vec(),
+            inode: inode,
+            file_type: EXT2_FT_DIR }
+}
+
+fn dot_dot(parent: u32) -> Entry {
+    Entry { name: b"..".to_vec(),
+            inode: parent,
+            file_type: EXT2_FT_DIR }
+}